@@ -1,18 +1,31 @@
 /// Admin database.
 use bytes::{Buf, BufMut, BytesMut};
 use log::{info, trace};
-use std::collections::HashMap;
-use tokio::time::Instant;
-
-use crate::config::{get_config, reload_config, VERSION};
+use once_cell::sync::{Lazy, OnceCell};
+use regex::Regex;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, Ordering};
+use tokio::sync::Notify;
+use tokio::time::{sleep, Duration, Instant};
+
+use crate::config::{get_config, reload_config, save_config, set_config, Address, Role, VERSION};
 use crate::errors::Error;
 use crate::messages::*;
-use crate::pool::get_all_pools;
+use crate::pool::{
+    database_disabled, database_paused, disable_database, enable_database, get_all_pools, get_pool,
+    kill_database, pause_database, reconnect_database, resume_database,
+};
 use crate::stats::{
     get_address_stats, get_client_stats, get_pool_stats, get_server_stats, ClientState, ServerState,
 };
 use crate::ClientServerMap;
 
+/// SHUTDOWN in the admin database, the pooler shuts down like on SIGINT.
+pub static SHUTDOWN: Lazy<Notify> = Lazy::new(Notify::new);
+
+/// Set once the pooler started shutting down, shown in SHOW STATE.
+pub static SHUTTING_DOWN: AtomicBool = AtomicBool::new(false);
+
 pub fn generate_server_info_for_admin() -> BytesMut {
     let mut server_info = BytesMut::new();
 
@@ -25,6 +38,74 @@ pub fn generate_server_info_for_admin() -> BytesMut {
     server_info
 }
 
+/// Regexes used to parse SHOW filters and SET.
+static SHOW_REGEX: OnceCell<Regex> = OnceCell::new();
+static CONDITION_REGEX: OnceCell<Regex> = OnceCell::new();
+static AND_REGEX: OnceCell<Regex> = OnceCell::new();
+static SET_REGEX: OnceCell<Regex> = OnceCell::new();
+
+/// The `WHERE pool = 'db' AND user = 'name'` of a SHOW command.
+#[derive(Debug, Default, PartialEq, Eq)]
+struct Filter {
+    pool: Option<String>,
+    user: Option<String>,
+}
+
+impl Filter {
+    /// Parse the filter of a SHOW command, if it has one.
+    fn parse(query: &str) -> Result<Filter, String> {
+        let show = SHOW_REGEX.get_or_init(|| {
+            Regex::new(r"(?is)^\s*SHOW\s+\w+(?:\s+WHERE\s+(.*?))?\s*;?\s*$").unwrap()
+        });
+        let condition = CONDITION_REGEX.get_or_init(|| {
+            Regex::new(r#"(?s)^(\w+)\s*=\s*(?:'([^']*)'|"([^"]*)"|([^\s'"]+))$"#).unwrap()
+        });
+        let and = AND_REGEX.get_or_init(|| Regex::new(r"(?i)\s+AND\s+").unwrap());
+
+        let conditions =
+            match show.captures(query) {
+                Some(captures) => match captures.get(1) {
+                    Some(conditions) => conditions.as_str(),
+                    None => return Ok(Filter::default()),
+                },
+                None => return Err(String::from(
+                    "syntax error, expected e.g. SHOW CLIENTS WHERE pool = 'db' AND user = 'name'",
+                )),
+            };
+
+        let mut filter = Filter::default();
+
+        for part in and.split(conditions.trim()) {
+            let captures = match condition.captures(part) {
+                Some(captures) => captures,
+                None => return Err(format!("syntax error at: {}", part)),
+            };
+
+            let value = (2..=4)
+                .find_map(|group| captures.get(group))
+                .map(|value| value.as_str().to_string());
+
+            match captures[1].to_lowercase().as_str() {
+                "pool" | "database" => filter.pool = value,
+                "user" | "username" => filter.user = value,
+                other => return Err(format!("can't filter by {}, only by pool and user", other)),
+            };
+        }
+
+        Ok(filter)
+    }
+
+    fn is_empty(&self) -> bool {
+        *self == Filter::default()
+    }
+
+    /// The row of this pool and user is shown.
+    fn matches(&self, pool: &str, user: &str) -> bool {
+        self.pool.as_ref().map_or(true, |filter| filter == pool)
+            && self.user.as_ref().map_or(true, |filter| filter == user)
+    }
+}
+
 /// Handle admin client.
 pub async fn handle_admin<T>(
     stream: &mut T,
@@ -34,6 +115,10 @@ pub async fn handle_admin<T>(
 where
     T: tokio::io::AsyncWrite + std::marker::Unpin,
 {
+    if query.len() < 5 {
+        return Err(Error::ProtocolSyncError(String::from("Query is too short")));
+    }
+
     let code = query.get_u8() as char;
 
     if code != 'Q' {
@@ -44,58 +129,179 @@ where
     }
 
     let len = query.get_i32() as usize;
-    let query = String::from_utf8_lossy(&query[..len - 5])
-        .to_string()
-        .to_ascii_uppercase();
+
+    // The length counts itself, and the query ends with a null byte.
+    let original = match len.checked_sub(5).and_then(|len| query.get(..len)) {
+        Some(original) => String::from_utf8_lossy(original).to_string(),
+        None => return error_response(stream, "Invalid query against the admin database").await,
+    };
+    let query = original.to_ascii_uppercase();
 
     trace!("Admin query: {}", query);
 
     let query_parts: Vec<&str> = query.trim_end_matches(';').split_whitespace().collect();
 
-    match query_parts[0] {
+    // Database and user names are case sensitive, and can be quoted.
+    let arguments: Vec<String> = original
+        .trim_end_matches(';')
+        .split_whitespace()
+        .skip(1)
+        .map(|argument| argument.trim_matches(|c| c == '\'' || c == '"').to_string())
+        .collect();
+    let database = arguments.first().cloned();
+
+    let command = match query_parts.first() {
+        Some(command) => *command,
+        None => {
+            return error_response(stream, "Unsupported query against the admin database").await
+        }
+    };
+
+    match command {
         "RELOAD" => {
             trace!("RELOAD");
             reload(stream, client_server_map).await
         }
         "SET" => {
             trace!("SET");
-            ignore_set(stream).await
+            set(stream, &original, client_server_map).await
         }
-        "SHOW" => match query_parts[1] {
-            "CONFIG" => {
-                trace!("SHOW CONFIG");
-                show_config(stream).await
-            }
-            "DATABASES" => {
-                trace!("SHOW DATABASES");
-                show_databases(stream).await
-            }
-            "LISTS" => {
-                trace!("SHOW LISTS");
-                show_lists(stream).await
-            }
-            "POOLS" => {
-                trace!("SHOW POOLS");
-                show_pools(stream).await
-            }
-            "CLIENTS" => {
-                trace!("SHOW CLIENTS");
-                show_clients(stream).await
-            }
-            "SERVERS" => {
-                trace!("SHOW SERVERS");
-                show_servers(stream).await
-            }
-            "STATS" => {
-                trace!("SHOW STATS");
-                show_stats(stream).await
+        "SAVE" if query_parts.get(1) == Some(&"CONFIG") => {
+            trace!("SAVE CONFIG");
+            save(stream).await
+        }
+        "PAUSE" => {
+            trace!("PAUSE");
+            pause(stream, database).await
+        }
+        "RESUME" => {
+            trace!("RESUME");
+            resume(stream, database).await
+        }
+        "DISABLE" => {
+            trace!("DISABLE");
+            disable(stream, database).await
+        }
+        "ENABLE" => {
+            trace!("ENABLE");
+            enable(stream, database).await
+        }
+        "KILL" => {
+            trace!("KILL");
+            kill(stream, database, client_server_map).await
+        }
+        "RECONNECT" => {
+            trace!("RECONNECT");
+            reconnect(stream, database, client_server_map).await
+        }
+        "SHUTDOWN" => {
+            trace!("SHUTDOWN");
+            shutdown(stream).await
+        }
+        "BAN" => {
+            trace!("BAN");
+            ban(stream, &arguments).await
+        }
+        "UNBAN" => {
+            trace!("UNBAN");
+            unban(stream, &arguments).await
+        }
+        "SHOW" => {
+            let view = match query_parts.get(1) {
+                Some(view) => *view,
+                None => return error_response(stream, "SHOW needs a view, e.g. SHOW POOLS").await,
+            };
+
+            let filter = match Filter::parse(&original) {
+                Ok(filter) => filter,
+                Err(err) => return error_response(stream, &err).await,
+            };
+
+            // The other views are not about pools and users.
+            if !filter.is_empty()
+                && ![
+                    "DATABASES",
+                    "POOLS",
+                    "CLIENTS",
+                    "SERVERS",
+                    "STATS",
+                    "BANS",
+                    "USERS",
+                    "SOCKETS",
+                    "TOTALS",
+                ]
+                .contains(&view)
+            {
+                return error_response(stream, &format!("SHOW {} can't be filtered", view)).await;
             }
-            "VERSION" => {
-                trace!("SHOW VERSION");
-                show_version(stream).await
+
+            match view {
+                "CONFIG" => {
+                    trace!("SHOW CONFIG");
+                    show_config(stream).await
+                }
+                "DATABASES" => {
+                    trace!("SHOW DATABASES");
+                    show_databases(stream, &filter).await
+                }
+                "LISTS" => {
+                    trace!("SHOW LISTS");
+                    show_lists(stream).await
+                }
+                "POOLS" => {
+                    trace!("SHOW POOLS");
+                    show_pools(stream, &filter).await
+                }
+                "CLIENTS" => {
+                    trace!("SHOW CLIENTS");
+                    show_clients(stream, &filter).await
+                }
+                "SERVERS" => {
+                    trace!("SHOW SERVERS");
+                    show_servers(stream, &filter).await
+                }
+                "STATS" => {
+                    trace!("SHOW STATS");
+                    show_stats(stream, &filter).await
+                }
+                "VERSION" => {
+                    trace!("SHOW VERSION");
+                    show_version(stream).await
+                }
+                "BANS" => {
+                    trace!("SHOW BANS");
+                    show_bans(stream, &filter).await
+                }
+                "USERS" => {
+                    trace!("SHOW USERS");
+                    show_users(stream, &filter).await
+                }
+                "MEM" => {
+                    trace!("SHOW MEM");
+                    show_mem(stream).await
+                }
+                "FDS" => {
+                    trace!("SHOW FDS");
+                    show_fds(stream).await
+                }
+                "SOCKETS" => {
+                    trace!("SHOW SOCKETS");
+                    show_sockets(stream, &filter).await
+                }
+                "TOTALS" => {
+                    trace!("SHOW TOTALS");
+                    show_totals(stream, &filter).await
+                }
+                "STATE" => {
+                    trace!("SHOW STATE");
+                    show_state(stream).await
+                }
+                _ => {
+                    error_response(stream, "Unsupported SHOW query against the admin database")
+                        .await
+                }
             }
-            _ => error_response(stream, "Unsupported SHOW query against the admin database").await,
-        },
+        }
         _ => error_response(stream, "Unsupported query against the admin database").await,
     }
 }
@@ -193,7 +399,7 @@ where
 }
 
 /// Show utilization of connection pools for each shard and replicas.
-async fn show_pools<T>(stream: &mut T) -> Result<(), Error>
+async fn show_pools<T>(stream: &mut T, filter: &Filter) -> Result<(), Error>
 where
     T: tokio::io::AsyncWrite + std::marker::Unpin,
 {
@@ -219,6 +425,10 @@ where
     let mut res = BytesMut::new();
     res.put(row_description(&columns));
     for (user_pool, pool) in get_all_pools() {
+        if !filter.matches(&user_pool.db, &user_pool.user) {
+            continue;
+        }
+
         let def = HashMap::default();
         let pool_stats = all_pool_stats
             .get(&(user_pool.db.clone(), user_pool.user.clone()))
@@ -254,7 +464,7 @@ where
 }
 
 /// Show shards and replicas.
-async fn show_databases<T>(stream: &mut T) -> Result<(), Error>
+async fn show_databases<T>(stream: &mut T, filter: &Filter) -> Result<(), Error>
 where
     T: tokio::io::AsyncWrite + std::marker::Unpin,
 {
@@ -273,18 +483,23 @@ where
         ("current_connections", DataType::Int4),
         ("paused", DataType::Int4),
         ("disabled", DataType::Int4),
+        ("replica_lag", DataType::Int4),
     ];
 
     let mut res = BytesMut::new();
 
     res.put(row_description(&columns));
 
-    for (_, pool) in get_all_pools() {
+    for (user_pool, pool) in get_all_pools() {
+        if !filter.matches(&user_pool.db, &user_pool.user) {
+            continue;
+        }
+
         let pool_config = pool.settings.clone();
         for shard in 0..pool.shards() {
-            let database_name = &pool.address(shard, 0).database;
+            let database_name = pool.address(shard, 0).database;
             for server in 0..pool.servers(shard) {
-                let address = pool.address(shard, server);
+                let address = &pool.address(shard, server);
                 let pool_state = pool.pool_state(shard, server);
                 let banned = pool.is_banned(address);
 
@@ -300,12 +515,17 @@ where
                     pool_config.pool_mode.to_string(),      // pool_mode
                     pool_config.user.pool_size.to_string(), // max_connections
                     pool_state.connections.to_string(),     // current_connections
-                    "0".to_string(),                        // paused
-                    match banned {
+                    match database_paused(&address.pool_name) {
+                        // paused
+                        true => "1".to_string(),
+                        false => "0".to_string(),
+                    },
+                    match banned || database_disabled(&address.pool_name) {
                         // disabled
                         true => "1".to_string(),
                         false => "0".to_string(),
                     },
+                    pool.replica_lag(address).unwrap_or(0).to_string(), // replica_lag
                 ]));
             }
         }
@@ -320,13 +540,380 @@ where
     write_all_half(stream, &res).await
 }
 
-/// Ignore any SET commands the client sends.
-/// This is common initialization done by ORMs.
-async fn ignore_set<T>(stream: &mut T) -> Result<(), Error>
+/// Change a setting, e.g. SET pools.sharded_db.pool_mode = 'session'.
+/// Any other SET is ignored: it's common initialization done by ORMs.
+async fn set<T>(
+    stream: &mut T,
+    query: &str,
+    client_server_map: ClientServerMap,
+) -> Result<(), Error>
+where
+    T: tokio::io::AsyncWrite + std::marker::Unpin,
+{
+    let setting = SET_REGEX.get_or_init(|| {
+        Regex::new(r"(?is)^\s*SET\s+([^\s=]+)\s*(?:=|\s+TO\s+)\s*(.*?)\s*;?\s*$").unwrap()
+    });
+
+    let (key, value) = match setting.captures(query) {
+        Some(captures) => (captures[1].to_string(), captures[2].to_string()),
+        None => return custom_protocol_response_ok(stream, "SET").await,
+    };
+
+    // Like the sections of the config file, e.g. [pools.sharded_db]; pool.sharded_db works too.
+    let key = match key.split_once('.') {
+        Some((section, rest)) if section.eq_ignore_ascii_case("general") => {
+            format!("general.{}", rest)
+        }
+        Some((section, rest))
+            if section.eq_ignore_ascii_case("pools") || section.eq_ignore_ascii_case("pool") =>
+        {
+            format!("pools.{}", rest)
+        }
+        _ => return custom_protocol_response_ok(stream, "SET").await,
+    };
+
+    match set_config(&key, &value, client_server_map).await {
+        Ok(()) => {
+            info!("Set {} = {}", key, value);
+            custom_protocol_response_ok(stream, "SET").await
+        }
+        Err(err) => error_response(stream, &err).await,
+    }
+}
+
+/// Write the config in effect to the config file.
+async fn save<T>(stream: &mut T) -> Result<(), Error>
+where
+    T: tokio::io::AsyncWrite + std::marker::Unpin,
+{
+    match save_config().await {
+        Ok(path) => {
+            info!("Saved the config to {}", path);
+            custom_protocol_response_ok(stream, "SAVE").await
+        }
+        Err(err) => error_response(stream, &err).await,
+    }
+}
+
+/// The databases a PAUSE or RESUME is for: the one given, or all of them.
+fn databases(database: Option<String>) -> Result<Vec<String>, String> {
+    let all: HashSet<String> = get_all_pools().into_keys().map(|pool| pool.db).collect();
+
+    match database {
+        Some(database) if all.contains(&database) => Ok(vec![database]),
+        Some(database) => Err(format!("No such database: {}", database)),
+        None => Ok(all.into_iter().collect()),
+    }
+}
+
+/// Hold the new server checkouts of the databases, and wait
+/// for the transactions in flight to release their servers.
+async fn pause<T>(stream: &mut T, database: Option<String>) -> Result<(), Error>
 where
     T: tokio::io::AsyncWrite + std::marker::Unpin,
 {
-    custom_protocol_response_ok(stream, "SET").await
+    let databases = match databases(database) {
+        Ok(databases) => databases,
+        Err(err) => return error_response(stream, &err).await,
+    };
+
+    for database in &databases {
+        pause_database(database);
+    }
+
+    info!(
+        "Pausing {:?}, waiting for the servers to be released",
+        databases
+    );
+
+    // The pools are read again, in case the config is reloaded.
+    while get_all_pools()
+        .iter()
+        .any(|(identifier, pool)| databases.contains(&identifier.db) && !pool.idle())
+    {
+        sleep(Duration::from_millis(50)).await;
+    }
+
+    info!("Paused {:?}", databases);
+
+    custom_protocol_response_ok(stream, "PAUSE").await
+}
+
+/// Let the clients of the paused databases get their servers.
+async fn resume<T>(stream: &mut T, database: Option<String>) -> Result<(), Error>
+where
+    T: tokio::io::AsyncWrite + std::marker::Unpin,
+{
+    let databases = match databases(database) {
+        Ok(databases) => databases,
+        Err(err) => return error_response(stream, &err).await,
+    };
+
+    for database in &databases {
+        resume_database(database);
+    }
+
+    info!("Resumed {:?}", databases);
+
+    custom_protocol_response_ok(stream, "RESUME").await
+}
+
+/// Reject the new clients of a database, the connected ones can keep going.
+async fn disable<T>(stream: &mut T, database: Option<String>) -> Result<(), Error>
+where
+    T: tokio::io::AsyncWrite + std::marker::Unpin,
+{
+    let database = match database {
+        Some(database) => database,
+        None => return error_response(stream, "DISABLE needs a database").await,
+    };
+
+    if let Err(err) = databases(Some(database.clone())) {
+        return error_response(stream, &err).await;
+    }
+
+    disable_database(&database);
+    info!("Disabled {}", database);
+
+    custom_protocol_response_ok(stream, "DISABLE").await
+}
+
+/// Accept the new clients of a disabled database again.
+async fn enable<T>(stream: &mut T, database: Option<String>) -> Result<(), Error>
+where
+    T: tokio::io::AsyncWrite + std::marker::Unpin,
+{
+    let database = match database {
+        Some(database) => database,
+        None => return error_response(stream, "ENABLE needs a database").await,
+    };
+
+    enable_database(&database);
+    info!("Enabled {}", database);
+
+    custom_protocol_response_ok(stream, "ENABLE").await
+}
+
+/// Disconnect the clients of a database right away, and close its servers.
+async fn kill<T>(
+    stream: &mut T,
+    database: Option<String>,
+    client_server_map: ClientServerMap,
+) -> Result<(), Error>
+where
+    T: tokio::io::AsyncWrite + std::marker::Unpin,
+{
+    let database = match database {
+        Some(database) => database,
+        None => return error_response(stream, "KILL needs a database").await,
+    };
+
+    if let Err(err) = databases(Some(database.clone())) {
+        return error_response(stream, &err).await;
+    }
+
+    info!("Killing {}", database);
+
+    kill_database(&database, client_server_map).await;
+
+    custom_protocol_response_ok(stream, "KILL").await
+}
+
+/// Close the server connections of the databases when the clients release them,
+/// new ones are made, e.g. after a DNS change.
+async fn reconnect<T>(
+    stream: &mut T,
+    database: Option<String>,
+    client_server_map: ClientServerMap,
+) -> Result<(), Error>
+where
+    T: tokio::io::AsyncWrite + std::marker::Unpin,
+{
+    let databases = match databases(database) {
+        Ok(databases) => databases,
+        Err(err) => return error_response(stream, &err).await,
+    };
+
+    info!("Reconnecting {:?}", databases);
+
+    for database in &databases {
+        reconnect_database(database, client_server_map.clone()).await;
+    }
+
+    custom_protocol_response_ok(stream, "RECONNECT").await
+}
+
+/// Shut down gracefully: the clients can finish, new ones are rejected.
+async fn shutdown<T>(stream: &mut T) -> Result<(), Error>
+where
+    T: tokio::io::AsyncWrite + std::marker::Unpin,
+{
+    info!("Shutting down, requested from the admin database");
+
+    SHUTDOWN.notify_one();
+
+    custom_protocol_response_ok(stream, "SHUTDOWN").await
+}
+
+/// The replica of `BAN 'pool' 'user' shard replica_number [seconds]` and `UNBAN`,
+/// and how long to ban it.
+fn banned_replica(arguments: &[String]) -> Result<(Address, Option<i64>), String> {
+    let usage = "usage: BAN 'pool' 'user' shard replica_number [seconds]";
+
+    let (pool_name, username, shard, replica_number, duration) = match arguments {
+        [pool_name, username, shard, replica_number, duration @ ..] if duration.len() < 2 => {
+            (pool_name, username, shard, replica_number, duration.first())
+        }
+        _ => return Err(String::from(usage)),
+    };
+
+    let (shard, replica_number, duration) = match (
+        shard.parse::<usize>(),
+        replica_number.parse::<usize>(),
+        duration.map(|duration| duration.parse::<i64>()).transpose(),
+    ) {
+        (Ok(shard), Ok(replica_number), Ok(duration))
+            if duration.map_or(true, |duration| duration > 0) =>
+        {
+            (shard, replica_number, duration)
+        }
+        _ => return Err(String::from(usage)),
+    };
+
+    let pool = match get_pool(pool_name, username) {
+        Some(pool) => pool,
+        None => {
+            return Err(format!(
+                "No pool configured for database: {:?}, user: {:?}",
+                pool_name, username
+            ))
+        }
+    };
+
+    if shard >= pool.shards() {
+        return Err(format!("No shard {} in database: {:?}", shard, pool_name));
+    }
+
+    (0..pool.servers(shard))
+        .map(|server| pool.address(shard, server))
+        .find(|address| address.role == Role::Replica && address.replica_number == replica_number)
+        .map(|address| (address, duration))
+        .ok_or_else(|| format!("No replica {} in shard {}", replica_number, shard))
+}
+
+/// The same server in the pools of all the users.
+fn same_server(a: &Address, b: &Address) -> bool {
+    a.host == b.host && a.port == b.port && a.database == b.database
+}
+
+/// Ban a replica in the pools of all users, e.g. before maintenance.
+async fn ban<T>(stream: &mut T, arguments: &[String]) -> Result<(), Error>
+where
+    T: tokio::io::AsyncWrite + std::marker::Unpin,
+{
+    let (replica, duration) = match banned_replica(arguments) {
+        Ok(replica) => replica,
+        Err(err) => return error_response(stream, &err).await,
+    };
+
+    for pool in get_all_pools().values() {
+        for shard in 0..pool.shards() {
+            for server in 0..pool.servers(shard) {
+                let address = pool.address(shard, server);
+
+                if address.role == Role::Replica && same_server(&address, &replica) {
+                    pool.ban_manually(&address, duration);
+                }
+            }
+        }
+    }
+
+    custom_protocol_response_ok(stream, "BAN").await
+}
+
+/// Let a banned replica serve queries again, in the pools of all users.
+async fn unban<T>(stream: &mut T, arguments: &[String]) -> Result<(), Error>
+where
+    T: tokio::io::AsyncWrite + std::marker::Unpin,
+{
+    let (replica, _) = match arguments.len() {
+        4 => match banned_replica(arguments) {
+            Ok(replica) => replica,
+            Err(err) => return error_response(stream, &err).await,
+        },
+        _ => {
+            return error_response(stream, "usage: UNBAN 'pool' 'user' shard replica_number").await
+        }
+    };
+
+    info!("Unbanning {:?} from the admin database", replica);
+
+    for pool in get_all_pools().values() {
+        for (address, _) in pool.bans() {
+            if same_server(&address, &replica) {
+                pool.unban(&address);
+            }
+        }
+    }
+
+    custom_protocol_response_ok(stream, "UNBAN").await
+}
+
+/// The banned servers, and for how long.
+async fn show_bans<T>(stream: &mut T, filter: &Filter) -> Result<(), Error>
+where
+    T: tokio::io::AsyncWrite + std::marker::Unpin,
+{
+    let columns = vec![
+        ("name", DataType::Text),
+        ("database", DataType::Text),
+        ("user", DataType::Text),
+        ("host", DataType::Text),
+        ("port", DataType::Text),
+        ("shard", DataType::Int4),
+        ("replica_number", DataType::Int4),
+        ("banned_at", DataType::Text),
+        ("manual", DataType::Text),
+        ("remaining_seconds", DataType::Int4),
+    ];
+
+    let mut res = BytesMut::new();
+
+    res.put(row_description(&columns));
+
+    for (user_pool, pool) in get_all_pools() {
+        if !filter.matches(&user_pool.db, &user_pool.user) {
+            continue;
+        }
+
+        for (address, ban) in pool.bans() {
+            res.put(data_row_nullable(&[
+                Some(address.name()),
+                Some(address.pool_name.clone()),
+                Some(address.username.clone()),
+                Some(address.host.clone()),
+                Some(address.port.to_string()),
+                Some(address.shard.to_string()),
+                Some(address.replica_number.to_string()),
+                Some(ban.time.format("%Y-%m-%d %H:%M:%S").to_string()),
+                Some(String::from(match ban.manual {
+                    true => "t",
+                    false => "f",
+                })),
+                ban.remaining()
+                    .map(|remaining| remaining.max(0).to_string()),
+            ]));
+        }
+    }
+
+    res.put(command_complete("SHOW"));
+
+    // ReadyForQuery
+    res.put_u8(b'Z');
+    res.put_i32(5);
+    res.put_u8(b'I');
+
+    write_all_half(stream, &res).await
 }
 
 /// Reload the configuration file without restarting the process.
@@ -399,7 +986,7 @@ where
 }
 
 /// Show shard and replicas statistics.
-async fn show_stats<T>(stream: &mut T) -> Result<(), Error>
+async fn show_stats<T>(stream: &mut T, filter: &Filter) -> Result<(), Error>
 where
     T: tokio::io::AsyncWrite + std::marker::Unpin,
 {
@@ -430,9 +1017,13 @@ where
     res.put(row_description(&columns));
 
     for (user_pool, pool) in get_all_pools() {
+        if !filter.matches(&user_pool.db, &user_pool.user) {
+            continue;
+        }
+
         for shard in 0..pool.shards() {
             for server in 0..pool.servers(shard) {
-                let address = pool.address(shard, server);
+                let address = &pool.address(shard, server);
                 let stats = match all_stats.get(&address.id) {
                     Some(stats) => stats.clone(),
                     None => HashMap::new(),
@@ -459,7 +1050,7 @@ where
 }
 
 /// Show currently connected clients
-async fn show_clients<T>(stream: &mut T) -> Result<(), Error>
+async fn show_clients<T>(stream: &mut T, filter: &Filter) -> Result<(), Error>
 where
     T: tokio::io::AsyncWrite + std::marker::Unpin,
 {
@@ -468,6 +1059,7 @@ where
         ("database", DataType::Text),
         ("user", DataType::Text),
         ("application_name", DataType::Text),
+        ("addr", DataType::Text),
         ("state", DataType::Text),
         ("transaction_count", DataType::Numeric),
         ("query_count", DataType::Numeric),
@@ -480,11 +1072,16 @@ where
     res.put(row_description(&columns));
 
     for (_, client) in new_map {
+        if !filter.matches(&client.pool_name, &client.username) {
+            continue;
+        }
+
         let row = vec![
             format!("{:#010X}", client.client_id),
             client.pool_name,
             client.username,
             client.application_name.clone(),
+            client.client_addr,
             client.state.to_string(),
             client.transaction_count.to_string(),
             client.query_count.to_string(),
@@ -509,7 +1106,7 @@ where
 }
 
 /// Show currently connected servers
-async fn show_servers<T>(stream: &mut T) -> Result<(), Error>
+async fn show_servers<T>(stream: &mut T, filter: &Filter) -> Result<(), Error>
 where
     T: tokio::io::AsyncWrite + std::marker::Unpin,
 {
@@ -532,6 +1129,10 @@ where
     res.put(row_description(&columns));
 
     for (_, server) in new_map {
+        if !filter.matches(&server.pool_name, &server.username) {
+            continue;
+        }
+
         let row = vec![
             format!("{:#010X}", server.server_id),
             server.pool_name,
@@ -561,3 +1162,408 @@ where
 
     write_all_half(stream, &res).await
 }
+
+/// Show the pools: one per database and user.
+async fn show_users<T>(stream: &mut T, filter: &Filter) -> Result<(), Error>
+where
+    T: tokio::io::AsyncWrite + std::marker::Unpin,
+{
+    let columns = vec![
+        ("name", DataType::Text),
+        ("database", DataType::Text),
+        ("pool_mode", DataType::Text),
+    ];
+
+    let mut pools: Vec<_> = get_all_pools()
+        .into_iter()
+        .filter(|(user_pool, _)| filter.matches(&user_pool.db, &user_pool.user))
+        .collect();
+    pools.sort_by(|a, b| (&a.0.user, &a.0.db).cmp(&(&b.0.user, &b.0.db)));
+
+    let mut res = BytesMut::new();
+    res.put(row_description(&columns));
+
+    for (user_pool, pool) in pools {
+        res.put(data_row(&vec![
+            user_pool.user,
+            user_pool.db,
+            pool.settings.pool_mode.to_string(),
+        ]));
+    }
+
+    res.put(command_complete("SHOW"));
+
+    // ReadyForQuery
+    res.put_u8(b'Z');
+    res.put_i32(5);
+    res.put_u8(b'I');
+
+    write_all_half(stream, &res).await
+}
+
+/// Show the memory used by the pooler, from /proc/self/status.
+async fn show_mem<T>(stream: &mut T) -> Result<(), Error>
+where
+    T: tokio::io::AsyncWrite + std::marker::Unpin,
+{
+    let status = match std::fs::read_to_string("/proc/self/status") {
+        Ok(status) => status,
+        Err(err) => {
+            return error_response(stream, &format!("Can't read memory usage: {}", err)).await
+        }
+    };
+
+    let columns = vec![("name", DataType::Text), ("bytes", DataType::Numeric)];
+
+    let mut res = BytesMut::new();
+    res.put(row_description(&columns));
+
+    for (name, field) in [
+        ("rss", "VmRSS:"),
+        ("peak_rss", "VmHWM:"),
+        ("virtual", "VmSize:"),
+        ("data", "VmData:"),
+    ] {
+        // e.g. "VmRSS:	    9876 kB"
+        let kilobytes = status
+            .lines()
+            .find_map(|line| line.strip_prefix(field))
+            .and_then(|value| value.split_whitespace().next())
+            .and_then(|value| value.parse::<u64>().ok());
+
+        if let Some(kilobytes) = kilobytes {
+            res.put(data_row(&vec![
+                name.to_string(),
+                (kilobytes * 1024).to_string(),
+            ]));
+        }
+    }
+
+    res.put(command_complete("SHOW"));
+
+    // ReadyForQuery
+    res.put_u8(b'Z');
+    res.put_i32(5);
+    res.put_u8(b'I');
+
+    write_all_half(stream, &res).await
+}
+
+/// Show the file descriptors open in the pooler.
+async fn show_fds<T>(stream: &mut T) -> Result<(), Error>
+where
+    T: tokio::io::AsyncWrite + std::marker::Unpin,
+{
+    let entries = match std::fs::read_dir("/proc/self/fd") {
+        Ok(entries) => entries,
+        Err(err) => {
+            return error_response(stream, &format!("Can't list file descriptors: {}", err)).await
+        }
+    };
+
+    let mut fds: Vec<(i32, String)> = entries
+        .flatten()
+        .filter_map(|entry| {
+            let fd = entry.file_name().to_string_lossy().parse::<i32>().ok()?;
+            let target = std::fs::read_link(entry.path()).ok()?;
+            Some((fd, target.to_string_lossy().to_string()))
+        })
+        .collect();
+    fds.sort();
+
+    let columns = vec![("fd", DataType::Int4), ("target", DataType::Text)];
+
+    let mut res = BytesMut::new();
+    res.put(row_description(&columns));
+
+    for (fd, target) in fds {
+        res.put(data_row(&vec![fd.to_string(), target]));
+    }
+
+    res.put(command_complete("SHOW"));
+
+    // ReadyForQuery
+    res.put_u8(b'Z');
+    res.put_i32(5);
+    res.put_u8(b'I');
+
+    write_all_half(stream, &res).await
+}
+
+/// Show client (C) and server (S) connections together, with their addresses.
+async fn show_sockets<T>(stream: &mut T, filter: &Filter) -> Result<(), Error>
+where
+    T: tokio::io::AsyncWrite + std::marker::Unpin,
+{
+    let columns = vec![
+        ("type", DataType::Text),
+        ("user", DataType::Text),
+        ("database", DataType::Text),
+        ("state", DataType::Text),
+        ("addr", DataType::Text),
+        ("port", DataType::Text),
+        ("application_name", DataType::Text),
+        ("age_seconds", DataType::Numeric),
+    ];
+
+    let mut res = BytesMut::new();
+    res.put(row_description(&columns));
+
+    for (_, client) in get_client_stats() {
+        if !filter.matches(&client.pool_name, &client.username) {
+            continue;
+        }
+
+        let (addr, port) = match client.client_addr.rsplit_once(':') {
+            Some((addr, port)) => (addr.to_string(), port.to_string()),
+            None => (client.client_addr.clone(), String::new()),
+        };
+
+        res.put(data_row(&vec![
+            "C".to_string(),
+            client.username,
+            client.pool_name,
+            client.state.to_string(),
+            addr,
+            port,
+            client.application_name,
+            Instant::now()
+                .duration_since(client.connect_time)
+                .as_secs()
+                .to_string(),
+        ]));
+    }
+
+    let mut addresses = HashMap::new();
+    for (_, pool) in get_all_pools() {
+        for shard in 0..pool.shards() {
+            for server in 0..pool.servers(shard) {
+                let address = pool.address(shard, server);
+                addresses.insert(address.id, address);
+            }
+        }
+    }
+
+    for (_, server) in get_server_stats() {
+        if !filter.matches(&server.pool_name, &server.username) {
+            continue;
+        }
+
+        let (addr, port) = match addresses.get(&server.address_id) {
+            Some(address) => (address.host.clone(), address.port.to_string()),
+            None => (String::new(), String::new()),
+        };
+
+        res.put(data_row(&vec![
+            "S".to_string(),
+            server.username,
+            server.pool_name,
+            server.state.to_string(),
+            addr,
+            port,
+            server.application_name,
+            Instant::now()
+                .duration_since(server.connect_time)
+                .as_secs()
+                .to_string(),
+        ]));
+    }
+
+    res.put(command_complete("SHOW"));
+
+    // ReadyForQuery
+    res.put_u8(b'Z');
+    res.put_i32(5);
+    res.put_u8(b'I');
+
+    write_all_half(stream, &res).await
+}
+
+/// Show the SHOW STATS columns summed over all servers.
+async fn show_totals<T>(stream: &mut T, filter: &Filter) -> Result<(), Error>
+where
+    T: tokio::io::AsyncWrite + std::marker::Unpin,
+{
+    let all_stats = get_address_stats();
+
+    // Servers shared by pools are only counted once.
+    let mut ids = HashSet::new();
+    for (user_pool, pool) in get_all_pools() {
+        if !filter.matches(&user_pool.db, &user_pool.user) {
+            continue;
+        }
+
+        for shard in 0..pool.shards() {
+            for server in 0..pool.servers(shard) {
+                ids.insert(pool.address(shard, server).id);
+            }
+        }
+    }
+
+    let mut totals: HashMap<String, i64> = HashMap::new();
+    for id in ids {
+        if let Some(stats) = all_stats.get(&id) {
+            for (name, value) in stats {
+                *totals.entry(name.to_string()).or_insert(0) += value;
+            }
+        }
+    }
+
+    let mut totals: Vec<_> = totals.into_iter().collect();
+    totals.sort();
+
+    let columns = vec![("name", DataType::Text), ("value", DataType::Numeric)];
+
+    let mut res = BytesMut::new();
+    res.put(row_description(&columns));
+
+    for (name, value) in totals {
+        res.put(data_row(&vec![name, value.to_string()]));
+    }
+
+    res.put(command_complete("SHOW"));
+
+    // ReadyForQuery
+    res.put_u8(b'Z');
+    res.put_i32(5);
+    res.put_u8(b'I');
+
+    write_all_half(stream, &res).await
+}
+
+/// Show whether the pooler is accepting clients, and what's paused, disabled and banned.
+async fn show_state<T>(stream: &mut T) -> Result<(), Error>
+where
+    T: tokio::io::AsyncWrite + std::marker::Unpin,
+{
+    let mut paused = HashSet::new();
+    let mut disabled = HashSet::new();
+    let mut banned = 0;
+
+    for (user_pool, pool) in get_all_pools() {
+        if database_paused(&user_pool.db) {
+            paused.insert(user_pool.db.clone());
+        }
+
+        if database_disabled(&user_pool.db) {
+            disabled.insert(user_pool.db.clone());
+        }
+
+        banned += pool.bans().len();
+    }
+
+    let mut paused: Vec<_> = paused.into_iter().collect();
+    paused.sort();
+    let mut disabled: Vec<_> = disabled.into_iter().collect();
+    disabled.sort();
+
+    let active = match SHUTTING_DOWN.load(Ordering::Relaxed) {
+        true => "no",
+        false => "yes",
+    };
+
+    let columns = vec![("key", DataType::Text), ("value", DataType::Text)];
+
+    let mut res = BytesMut::new();
+    res.put(row_description(&columns));
+    res.put(data_row(&vec!["active".to_string(), active.to_string()]));
+    res.put(data_row(&vec!["paused".to_string(), paused.join(",")]));
+    res.put(data_row(&vec!["disabled".to_string(), disabled.join(",")]));
+    res.put(data_row(&vec![
+        "banned_servers".to_string(),
+        banned.to_string(),
+    ]));
+    res.put(command_complete("SHOW"));
+
+    // ReadyForQuery
+    res.put_u8(b'Z');
+    res.put_i32(5);
+    res.put_u8(b'I');
+
+    write_all_half(stream, &res).await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_malformed_queries() {
+        let client_server_map: ClientServerMap =
+            std::sync::Arc::new(parking_lot::Mutex::new(HashMap::new()));
+
+        for query in [
+            &b"Q\0\0\0\x04"[..],
+            &b"Q\0\0\0\x05\0"[..],
+            &b"Q\0\0\0\x09SHOW\0"[..],
+            &b"Q\0\0\x10\0SHOW\0"[..],
+            &b"Q\xff\xff\xff\xffSHOW\0"[..],
+            &b"Q\0\0\0\x1aSHOW POOLS WHERE pool\0"[..],
+        ] {
+            let mut response = Vec::new();
+            handle_admin(
+                &mut response,
+                BytesMut::from(query),
+                client_server_map.clone(),
+            )
+            .await
+            .unwrap();
+            assert_eq!(response.first(), Some(&b'E'), "{:?}", query);
+        }
+
+        let mut response = Vec::new();
+        assert!(
+            handle_admin(&mut response, BytesMut::from(&b"Q"[..]), client_server_map)
+                .await
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_banned_replica() {
+        let usage = "usage: BAN 'pool' 'user' shard replica_number [seconds]";
+        let arguments = |arguments: &[&str]| -> Vec<String> {
+            arguments
+                .iter()
+                .map(|argument| argument.to_string())
+                .collect()
+        };
+
+        for duration in ["0", "-1", "soon"] {
+            assert_eq!(
+                banned_replica(&arguments(&["db", "user", "0", "0", duration])),
+                Err(String::from(usage))
+            );
+        }
+
+        assert_eq!(
+            banned_replica(&arguments(&["db", "user", "0"])),
+            Err(String::from(usage))
+        );
+        assert!(banned_replica(&arguments(&["db", "user", "0", "0", "60"]))
+            .unwrap_err()
+            .starts_with("No pool configured"));
+    }
+
+    #[test]
+    fn test_filter() {
+        assert_eq!(Filter::parse("SHOW CLIENTS").unwrap(), Filter::default());
+        assert_eq!(Filter::parse("show clients;").unwrap(), Filter::default());
+
+        let filter =
+            Filter::parse("SHOW CLIENTS WHERE pool = 'Db' and USER = sharding_user;").unwrap();
+        assert_eq!(filter.pool, Some(String::from("Db")));
+        assert_eq!(filter.user, Some(String::from("sharding_user")));
+        assert!(filter.matches("Db", "sharding_user"));
+        assert!(!filter.matches("db", "sharding_user"));
+
+        let filter = Filter::parse(r#"SHOW POOLS WHERE user = "a b""#).unwrap();
+        assert_eq!(filter.pool, None);
+        assert!(filter.matches("any", "a b"));
+
+        assert!(Filter::parse("SHOW").is_err());
+        assert!(Filter::parse("SHOW CLIENTS WHERE").is_err());
+        assert!(Filter::parse("SHOW CLIENTS WHERE pool").is_err());
+        assert!(Filter::parse("SHOW CLIENTS WHERE host = 'localhost'").is_err());
+    }
+}
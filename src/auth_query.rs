@@ -0,0 +1,320 @@
+/// Look up the password hash of users that are not in the config
+/// in the database, like PgBouncer's auth_query, and create their pools.
+use log::{error, info};
+use once_cell::sync::Lazy;
+use parking_lot::{Mutex, RwLock};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::config::{get_config, Address, Pool, Role, User};
+use crate::errors::Error;
+use crate::pool::{
+    address_id, get_pool, ClientServerMap, ConnectionPool, PoolIdentifier, ServerPool,
+};
+use crate::tls::ServerTls;
+
+/// Password hashes we fetched recently, per database/user pair.
+static CACHE: Lazy<Mutex<HashMap<PoolIdentifier, CachedPassword>>> =
+    Lazy::new(|| Mutex::new(HashMap::default()));
+
+/// The server connection running the auth_query of each pool, shared by the clients logging in.
+static AUTH_QUERY_POOLS: Lazy<Mutex<HashMap<String, AuthQueryPool>>> =
+    Lazy::new(|| Mutex::new(HashMap::default()));
+
+struct CachedPassword {
+    /// None if the database doesn't know the user.
+    password: Option<String>,
+    fetched_at: Instant,
+}
+
+struct AuthQueryPool {
+    address: Address,
+    user: User,
+    pool: bb8::Pool<ServerPool>,
+}
+
+/// Get the pool of a user that's not in the config, if the pool has an auth_query
+/// and the database knows the user. The pool is created the first time we see the user,
+/// and again if its password changed since. It's not added to the pools: the client
+/// adds it with `add_pool_if_new` once it logged in.
+pub async fn get_auth_query_pool(
+    pool_name: &str,
+    username: &str,
+    client_server_map: ClientServerMap,
+) -> Result<Option<ConnectionPool>, Error> {
+    let config = get_config();
+
+    let pool_config = match config.pools.get(pool_name) {
+        Some(pool_config) => pool_config,
+        None => return Ok(None),
+    };
+
+    if pool_config.auth_query.is_none()
+        || pool_config
+            .users
+            .values()
+            .any(|user| user.username == username)
+    {
+        return Ok(None);
+    }
+
+    let password =
+        match get_password(pool_name, pool_config, username, client_server_map.clone()).await? {
+            Some(password) => password,
+            None => return Ok(None),
+        };
+
+    if let Some(pool) = get_pool(pool_name, username) {
+        if pool.settings.user.password == password {
+            return Ok(Some(pool));
+        }
+    }
+
+    info!(
+        "[pool: {}][user: {}] creating new pool with auth_query",
+        pool_name, username
+    );
+
+    let user = User {
+        username: username.to_string(),
+        password,
+        pool_size: pool_config.auth_query_pool_size,
+        ..Default::default()
+    };
+
+    Ok(Some(
+        ConnectionPool::new(
+            pool_name,
+            pool_config,
+            &user,
+            &config.general,
+            client_server_map,
+        )
+        .await,
+    ))
+}
+
+/// Get the password hash of the user, from the cache if it's not older than
+/// auth_query_cache_ttl, from the database otherwise. Unknown users are cached too.
+async fn get_password(
+    pool_name: &str,
+    pool_config: &Pool,
+    username: &str,
+    client_server_map: ClientServerMap,
+) -> Result<Option<String>, Error> {
+    let identifier = PoolIdentifier::new(pool_name, username);
+    let ttl = Duration::from_secs(pool_config.auth_query_cache_ttl);
+
+    if let Some(cached) = CACHE.lock().get(&identifier) {
+        if cached.fetched_at.elapsed() < ttl {
+            return Ok(cached.password.clone());
+        }
+    }
+
+    let password = fetch_password(pool_name, pool_config, username, client_server_map).await?;
+
+    CACHE.lock().insert(
+        identifier,
+        CachedPassword {
+            password: password.clone(),
+            fetched_at: Instant::now(),
+        },
+    );
+
+    Ok(password)
+}
+
+/// Run the auth_query on the primary of the first shard (or its first server
+/// if it has no primary). The password is in the second column.
+/// The clients take turns on one server connection per pool.
+async fn fetch_password(
+    pool_name: &str,
+    pool_config: &Pool,
+    username: &str,
+    client_server_map: ClientServerMap,
+) -> Result<Option<String>, Error> {
+    let (auth_query, auth_query_user, auth_query_password) = match (
+        &pool_config.auth_query,
+        &pool_config.auth_query_user,
+        &pool_config.auth_query_password,
+    ) {
+        (Some(query), Some(user), Some(password)) => (query, user, password),
+        _ => return Err(Error::BadConfig),
+    };
+
+    let (shard_idx, shard) = match pool_config
+        .shards
+        .iter()
+        .min_by_key(|(shard_idx, _)| shard_idx.parse::<i64>().unwrap_or(i64::MAX))
+    {
+        Some(shard) => shard,
+        None => return Err(Error::BadConfig),
+    };
+
+    let server = match shard
+        .servers
+        .iter()
+        .find(|server| server.role == Role::Primary)
+        .or_else(|| shard.servers.first())
+    {
+        Some(server) => server,
+        None => return Err(Error::BadConfig),
+    };
+
+    let mut address = Address {
+        host: server.host.clone(),
+        port: server.port,
+        shard: shard_idx.parse::<usize>().unwrap_or(0),
+        database: shard.database.clone(),
+        role: server.role,
+        username: auth_query_user.clone(),
+        pool_name: pool_name.to_string(),
+        ..Default::default()
+    };
+    address.id = address_id(&address);
+
+    let user = User {
+        username: auth_query_user.clone(),
+        password: auth_query_password.clone(),
+        pool_size: 1,
+        ..Default::default()
+    };
+
+    let pool = {
+        let mut pools = AUTH_QUERY_POOLS.lock();
+
+        match pools.get(pool_name) {
+            Some(pool) if pool.address == address && pool.user == user => pool.pool.clone(),
+
+            // New, or the config changed.
+            _ => {
+                let connect_timeout = match pool_config.connect_timeout {
+                    Some(connect_timeout) => connect_timeout,
+                    None => get_config().general.connect_timeout,
+                };
+
+                let manager = ServerPool::new(
+                    Arc::new(RwLock::new(vec![vec![address.clone()]])),
+                    0,
+                    0,
+                    user.clone(),
+                    client_server_map,
+                    Arc::new(RwLock::new(None)),
+                    ServerTls::new(pool_config, shard_idx),
+                );
+
+                let pool = bb8::Pool::builder()
+                    .max_size(1)
+                    .connection_timeout(Duration::from_millis(connect_timeout))
+                    .test_on_check_out(false)
+                    .build_unchecked(manager);
+
+                pools.insert(
+                    pool_name.to_string(),
+                    AuthQueryPool {
+                        address,
+                        user,
+                        pool: pool.clone(),
+                    },
+                );
+
+                pool
+            }
+        }
+    };
+
+    let mut server = match pool.get().await {
+        Ok(server) => server,
+        Err(err) => {
+            error!(
+                "[pool: {}][user: {}] could not connect to run the auth_query: {:?}",
+                pool_name, username, err
+            );
+            return Err(Error::AllServersDown);
+        }
+    };
+
+    let query = replace_parameter(auth_query, &quote_literal(username));
+
+    let rows = match server.fetch_rows(&query).await {
+        Ok(rows) => rows,
+        Err(err) => {
+            error!(
+                "[pool: {}][user: {}] auth_query failed: {:?}",
+                pool_name, username, err
+            );
+            return Err(err);
+        }
+    };
+
+    Ok(rows
+        .into_iter()
+        .next()
+        .and_then(|row| row.into_iter().nth(1))
+        .flatten()
+        .filter(|password| !password.is_empty()))
+}
+
+/// Replace the `$1` of the query with the value, but not `$10`, `$11`, etc.
+fn replace_parameter(query: &str, value: &str) -> String {
+    let mut result = String::with_capacity(query.len() + value.len());
+    let mut rest = query;
+
+    while let Some(position) = rest.find("$1") {
+        let (before, after) = rest.split_at(position);
+        let after = &after[2..];
+
+        result.push_str(before);
+
+        if after.starts_with(|c: char| c.is_ascii_digit()) {
+            result.push_str("$1");
+        } else {
+            result.push_str(value);
+        }
+
+        rest = after;
+    }
+
+    result.push_str(rest);
+    result
+}
+
+/// Quote a value as a string literal, regardless of standard_conforming_strings.
+fn quote_literal(value: &str) -> String {
+    let quoted = value.replace('\'', "''");
+
+    if quoted.contains('\\') {
+        format!("E'{}'", quoted.replace('\\', "\\\\"))
+    } else {
+        format!("'{}'", quoted)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_replace_parameter() {
+        assert_eq!(
+            replace_parameter(
+                "SELECT usename, passwd FROM pg_shadow WHERE usename = $1",
+                "'a'"
+            ),
+            "SELECT usename, passwd FROM pg_shadow WHERE usename = 'a'"
+        );
+        assert_eq!(
+            replace_parameter("$1 $10 $1, $11$1", "'a'"),
+            "'a' $10 'a', $11'a'"
+        );
+        assert_eq!(replace_parameter("SELECT 1", "'a'"), "SELECT 1");
+    }
+
+    #[test]
+    fn test_quote_literal() {
+        assert_eq!(quote_literal("postgres"), "'postgres'");
+        assert_eq!(quote_literal("o'brien"), "'o''brien'");
+        assert_eq!(quote_literal("a\\' OR 1=1 --"), "E'a\\\\'' OR 1=1 --'");
+    }
+}
@@ -1,23 +1,31 @@
 /// Handle clients by pretending to be a PostgreSQL server.
+use bb8::PooledConnection;
 use bytes::{Buf, BufMut, BytesMut};
 use log::{debug, error, info, trace, warn};
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
 use std::time::Instant;
 use tokio::io::{split, AsyncReadExt, BufReader, ReadHalf, WriteHalf};
-use tokio::net::TcpStream;
 use tokio::sync::broadcast::Receiver;
 use tokio::sync::mpsc::Sender;
 
 use crate::admin::{generate_server_info_for_admin, handle_admin};
-use crate::config::{get_config, Address, PoolMode};
+use crate::auth_query::get_auth_query_pool;
+use crate::config::{get_config, Address, AuthMethod, ClientTlsMode, PoolMode, Role};
 use crate::constants::*;
+use crate::copy::CopySplitter;
 use crate::errors::Error;
 use crate::messages::*;
-use crate::pool::{get_pool, ClientServerMap, ConnectionPool};
+use crate::pool::{
+    add_pool_if_new, database_disabled, database_kills, get_pool, parse_lsn, wait_for_kill,
+    ClientServerMap, ConnectionPool, PoolIdentifier, ServerPool,
+};
+use crate::prepared_statements;
 use crate::query_router::{Command, QueryRouter};
+use crate::scram::{ScramSecret, ScramSha256Server};
 use crate::server::Server;
 use crate::stats::{get_reporter, Reporter};
-use crate::tls::Tls;
+use crate::tls::{certificate_common_name, get_tls, Tls};
 
 use tokio_rustls::server::TlsStream;
 
@@ -42,7 +50,7 @@ pub struct Client<S, T> {
     buffer: BytesMut,
 
     /// Address
-    addr: std::net::SocketAddr,
+    addr: ClientAddress,
 
     /// The client was started with the sole reason to cancel another running query.
     cancel_mode: bool,
@@ -51,6 +59,10 @@ pub struct Client<S, T> {
     /// Session mode has slightly higher throughput per client, but lower capacity.
     transaction_mode: bool,
 
+    /// In statement mode, the connection is released after each statement
+    /// and transactions are not allowed.
+    statement_mode: bool,
+
     /// For query cancellation, the client is given a random process ID and secret on startup.
     process_id: i32,
     secret_key: i32,
@@ -89,26 +101,71 @@ pub struct Client<S, T> {
 
     /// Used to notify clients about an impending shutdown
     shutdown: Receiver<()>,
+
+    /// Rewrite named prepared statements so they work in transaction mode.
+    prepared_statements_enabled: bool,
+
+    /// The client's prepared statements, renamed to their pooler-wide name.
+    prepared_statements: HashMap<String, Parse>,
+}
+
+/// A server answering a query sent to several shards.
+struct ShardConnection<'a> {
+    server: PooledConnection<'a, ServerPool>,
+    address: Address,
+
+    /// Messages we received but haven't handled yet.
+    pending: VecDeque<BytesMut>,
+
+    /// The server sent ReadyForQuery.
+    done: bool,
 }
 
-/// Client entrypoint.
-pub async fn client_entrypoint(
-    mut stream: TcpStream,
+/// Where the client connected from.
+#[derive(Debug, Clone)]
+pub enum ClientAddress {
+    Tcp(SocketAddr),
+    Unix,
+}
+
+impl std::fmt::Display for ClientAddress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ClientAddress::Tcp(addr) => write!(f, "{}", addr),
+            ClientAddress::Unix => write!(f, "unix"),
+        }
+    }
+}
+
+/// Client entrypoint, for both TCP and Unix socket connections.
+pub async fn client_entrypoint<S>(
+    mut stream: S,
+    addr: ClientAddress,
     client_server_map: ClientServerMap,
     shutdown: Receiver<()>,
     drain: Sender<i32>,
     admin_only: bool,
-    tls_certificate: Option<String>,
     log_client_connections: bool,
-) -> Result<(), Error> {
+) -> Result<(), Error>
+where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + std::marker::Unpin,
+{
     // Figure out if the client wants TLS or not.
-    let addr = stream.peer_addr().unwrap();
+    // Like Postgres, no TLS on the Unix socket: it doesn't leave the host.
+    let client_tls_mode = match addr {
+        ClientAddress::Unix => ClientTlsMode::Disable,
+        ClientAddress::Tcp(_) => get_config().general.client_tls_mode,
+    };
+    let tls = match client_tls_mode {
+        ClientTlsMode::Disable => None,
+        _ => get_tls(),
+    };
 
-    match get_startup::<TcpStream>(&mut stream).await {
+    match get_startup::<S>(&mut stream).await {
         // Client requested a TLS connection.
         Ok((ClientConnectionType::Tls, _)) => {
             // TLS settings are configured, will setup TLS now.
-            if tls_certificate != None {
+            if let Some(tls) = tls {
                 debug!("Accepting TLS request");
 
                 let mut yes = BytesMut::new();
@@ -116,7 +173,16 @@ pub async fn client_entrypoint(
                 write_all(&mut stream, yes).await?;
 
                 // Negotiate TLS.
-                match startup_tls(stream, client_server_map, shutdown, admin_only).await {
+                match startup_tls(
+                    stream,
+                    addr.clone(),
+                    tls,
+                    client_server_map,
+                    shutdown,
+                    admin_only,
+                )
+                .await
+                {
                     Ok(mut client) => {
                         if log_client_connections {
                             info!("Client {:?} connected (TLS)", addr);
@@ -148,7 +214,7 @@ pub async fn client_entrypoint(
 
                 // Attempting regular startup. Client can disconnect now
                 // if they choose.
-                match get_startup::<TcpStream>(&mut stream).await {
+                match get_startup::<S>(&mut stream).await {
                     // Client accepted unencrypted connection.
                     Ok((ClientConnectionType::Startup, bytes)) => {
                         let (read, write) = split(stream);
@@ -157,7 +223,7 @@ pub async fn client_entrypoint(
                         match Client::startup(
                             read,
                             write,
-                            addr,
+                            addr.clone(),
                             bytes,
                             client_server_map,
                             shutdown,
@@ -201,13 +267,26 @@ pub async fn client_entrypoint(
 
         // Client wants to use plain connection without encryption.
         Ok((ClientConnectionType::Startup, bytes)) => {
+            if client_tls_mode.required() {
+                error_response_terminal(
+                    &mut stream,
+                    &format!("TLS is required (client_tls_mode is {})", client_tls_mode),
+                )
+                .await?;
+
+                return Err(Error::ClientError(format!(
+                    "Client {:?} tried to connect without TLS",
+                    addr
+                )));
+            }
+
             let (read, write) = split(stream);
 
             // Continue with regular startup.
             match Client::startup(
                 read,
                 write,
-                addr,
+                addr.clone(),
                 bytes,
                 client_server_map,
                 shutdown,
@@ -243,7 +322,16 @@ pub async fn client_entrypoint(
             let (read, write) = split(stream);
 
             // Continue with cancel query request.
-            match Client::cancel(read, write, addr, bytes, client_server_map, shutdown).await {
+            match Client::cancel(
+                read,
+                write,
+                addr.clone(),
+                bytes,
+                client_server_map,
+                shutdown,
+            )
+            .await
+            {
                 Ok(mut client) => {
                     info!("Client {:?} issued a cancel query request", addr);
 
@@ -310,16 +398,18 @@ where
 }
 
 /// Handle TLS connection negotiation.
-pub async fn startup_tls(
-    stream: TcpStream,
+pub async fn startup_tls<S>(
+    stream: S,
+    addr: ClientAddress,
+    tls: Tls,
     client_server_map: ClientServerMap,
     shutdown: Receiver<()>,
     admin_only: bool,
-) -> Result<Client<ReadHalf<TlsStream<TcpStream>>, WriteHalf<TlsStream<TcpStream>>>, Error> {
+) -> Result<Client<ReadHalf<TlsStream<S>>, WriteHalf<TlsStream<S>>>, Error>
+where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + std::marker::Unpin,
+{
     // Negotiate TLS.
-    let tls = Tls::new()?;
-    let addr = stream.peer_addr().unwrap();
-
     let mut stream = match tls.acceptor.accept(stream).await {
         Ok(stream) => stream,
 
@@ -332,10 +422,59 @@ pub async fn startup_tls(
 
     // TLS negotiation successful.
     // Continue with regular startup using encrypted connection.
-    match get_startup::<TlsStream<TcpStream>>(&mut stream).await {
+    match get_startup::<TlsStream<S>>(&mut stream).await {
         // Got good startup message, proceeding like normal except we
         // are encrypted now.
         Ok((ClientConnectionType::Startup, bytes)) => {
+            let config = get_config();
+
+            // The certificate was verified during the handshake,
+            // make sure it belongs to the user logging in.
+            if config.general.client_tls_mode == ClientTlsMode::VerifyFull {
+                let username = parse_startup(bytes.clone())?
+                    .get("user")
+                    .cloned()
+                    .unwrap_or_default();
+
+                let common_name = stream
+                    .get_ref()
+                    .1
+                    .peer_certificates()
+                    .and_then(|certs| certs.first())
+                    .and_then(certificate_common_name);
+
+                let allowed = match &common_name {
+                    Some(common_name) => {
+                        match config.general.client_tls_user_map.get(common_name) {
+                            Some(mapped) => *mapped == username,
+                            None => *common_name == username,
+                        }
+                    }
+                    None => false,
+                };
+
+                if !allowed {
+                    warn!(
+                        "Client {:?} certificate common name {:?} doesn't match user {:?}",
+                        addr, common_name, username
+                    );
+
+                    error_response_terminal(
+                        &mut stream,
+                        &format!(
+                            "certificate authentication failed for user \"{}\"",
+                            username
+                        ),
+                    )
+                    .await?;
+
+                    return Err(Error::ClientError(format!(
+                        "Certificate doesn't match user {:?}",
+                        username
+                    )));
+                }
+            }
+
             let (read, write) = split(stream);
 
             Client::startup(
@@ -359,6 +498,177 @@ pub async fn startup_tls(
     }
 }
 
+/// Authenticate the client with the configured method.
+/// The password can be in cleartext, an md5 hash or a SCRAM verifier.
+/// Returns false if the client could not prove it knows the password.
+/// When the client logs in with SCRAM against a verifier, its ClientKey is saved
+/// in `scram_client_key`.
+async fn authenticate<S, T>(
+    read: &mut S,
+    write: &mut T,
+    auth_method: AuthMethod,
+    username: &str,
+    password: &str,
+    client_identifier: &str,
+    scram_client_key: &mut Option<Vec<u8>>,
+) -> Result<bool, Error>
+where
+    S: tokio::io::AsyncRead + std::marker::Unpin,
+    T: tokio::io::AsyncWrite + std::marker::Unpin,
+{
+    let scram_secret = ScramSecret::parse(password);
+
+    // Like Postgres, a SCRAM verifier can't answer an md5 challenge,
+    // so those users always use SCRAM.
+    let auth_method = match scram_secret {
+        Some(_) => AuthMethod::ScramSha256,
+        None => auth_method,
+    };
+
+    match auth_method {
+        AuthMethod::Md5 => {
+            let salt = md5_challenge(write).await?;
+            let password_response = read_password(read, client_identifier).await?;
+
+            // Compare server and client hashes.
+            let password_hash = if is_md5_hash(password) {
+                md5_hash_second_pass(password, &salt)
+            } else {
+                md5_hash_password(username, password, &salt)
+            };
+
+            Ok(password_hash == password_response)
+        }
+
+        AuthMethod::ScramSha256 => {
+            if is_md5_hash(password) {
+                warn!(
+                    "Only the md5 hash of the password is configured, can't use SCRAM {}",
+                    client_identifier
+                );
+                return Ok(false);
+            }
+
+            let mut scram = match &scram_secret {
+                Some(secret) => ScramSha256Server::from_secret(secret),
+                None => ScramSha256Server::new(password),
+            };
+
+            scram_start_challenge(write).await?;
+
+            // SASLInitialResponse: the selected mechanism, followed by the client-first-message.
+            let mut message = read_password(read, client_identifier).await?;
+
+            let mechanism = match message.iter().position(|byte| *byte == 0) {
+                Some(end) => {
+                    let mechanism = message.split_to(end + 1);
+                    String::from_utf8_lossy(&mechanism[..end]).to_string()
+                }
+                None => String::new(),
+            };
+
+            if mechanism != SCRAM_SHA_256 || message.len() < 4 {
+                return Err(Error::ProtocolSyncError(format!(
+                    "Unsupported SASL mechanism {:?} from client {}",
+                    mechanism, client_identifier
+                )));
+            }
+
+            // Skip the length of the client-first-message, it's the rest of the message.
+            let _len = message.get_i32();
+            let client_first = message;
+
+            let server_first = match scram.update(&client_first) {
+                Ok(server_first) => server_first,
+                Err(err) => {
+                    warn!("SCRAM exchange failed {}: {:?}", client_identifier, err);
+                    return Ok(false);
+                }
+            };
+
+            scram_server_response(write, SASL_CONTINUE, &server_first).await?;
+
+            // SASLResponse: the client-final-message with the proof.
+            let client_final = read_password(read, client_identifier).await?;
+
+            let server_final = match scram.finish(&client_final) {
+                Ok(server_final) => server_final,
+                Err(err) => {
+                    warn!("SCRAM exchange failed {}: {:?}", client_identifier, err);
+                    return Ok(false);
+                }
+            };
+
+            scram_server_response(write, SASL_FINAL, &server_final).await?;
+
+            if scram_secret.is_some() {
+                *scram_client_key = scram.client_key();
+            }
+
+            Ok(true)
+        }
+    }
+}
+
+/// The longest PasswordMessage or SASL response we accept, with its length.
+const MAX_PASSWORD_MESSAGE_LENGTH: i32 = 8192;
+
+/// Read a PasswordMessage (or SASL response) from the client and return its contents.
+async fn read_password<S>(read: &mut S, client_identifier: &str) -> Result<BytesMut, Error>
+where
+    S: tokio::io::AsyncRead + std::marker::Unpin,
+{
+    let code = match read.read_u8().await {
+        Ok(p) => p,
+        Err(_) => {
+            return Err(Error::SocketError(format!(
+                "Error reading password code from client {}",
+                client_identifier
+            )))
+        }
+    };
+
+    // PasswordMessage
+    if code as char != 'p' {
+        return Err(Error::ProtocolSyncError(format!(
+            "Expected p, got {}",
+            code as char
+        )));
+    }
+
+    let len = match read.read_i32().await {
+        Ok(len) => len,
+        Err(_) => {
+            return Err(Error::SocketError(format!(
+                "Error reading password message length from client {}",
+                client_identifier
+            )))
+        }
+    };
+
+    // The client isn't authenticated yet, don't let it make us allocate much.
+    if !(4..=MAX_PASSWORD_MESSAGE_LENGTH).contains(&len) {
+        return Err(Error::ProtocolSyncError(format!(
+            "Invalid password message length {} from client {}",
+            len, client_identifier
+        )));
+    }
+
+    let mut password_response = vec![0u8; (len - 4) as usize];
+
+    match read.read_exact(&mut password_response).await {
+        Ok(_) => (),
+        Err(_) => {
+            return Err(Error::SocketError(format!(
+                "Error reading password message from client {}",
+                client_identifier
+            )))
+        }
+    };
+
+    Ok(BytesMut::from(&password_response[..]))
+}
+
 impl<S, T> Client<S, T>
 where
     S: tokio::io::AsyncRead + std::marker::Unpin,
@@ -373,7 +683,7 @@ where
     pub async fn startup(
         mut read: S,
         mut write: T,
-        addr: std::net::SocketAddr,
+        addr: ClientAddress,
         bytes: BytesMut, // The rest of the startup message.
         client_server_map: ClientServerMap,
         shutdown: Receiver<()>,
@@ -422,61 +732,79 @@ where
             return Err(Error::ShuttingDown);
         }
 
-        // Generate random backend ID and secret key
-        let process_id: i32 = rand::random();
-        let secret_key: i32 = rand::random();
-
-        // Perform MD5 authentication.
-        // TODO: Add SASL support.
-        let salt = md5_challenge(&mut write).await?;
-
-        let code = match read.read_u8().await {
-            Ok(p) => p,
-            Err(_) => return Err(Error::SocketError(format!("Error reading password code from client {{ username: {:?}, pool_name: {:?}, application_name: {:?} }}", pool_name, username, application_name))),
-        };
-
-        // PasswordMessage
-        if code as char != 'p' {
-            return Err(Error::ProtocolSyncError(format!(
-                "Expected p, got {}",
-                code as char
+        // The database was disabled with DISABLE in the admin database.
+        if !admin && database_disabled(pool_name) {
+            error_response_terminal(
+                &mut write,
+                &format!("database \"{}\" is disabled", pool_name),
+            )
+            .await?;
+            return Err(Error::ClientError(format!(
+                "Database {} is disabled",
+                pool_name
             )));
         }
 
-        let len = match read.read_i32().await {
-            Ok(len) => len,
-            Err(_) => return Err(Error::SocketError(format!("Error reading password message length from client {{ username: {:?}, pool_name: {:?}, application_name: {:?} }}", pool_name, username, application_name))),
-        };
-
-        let mut password_response = vec![0u8; (len - 4) as usize];
+        // Generate random backend ID and secret key
+        let process_id: i32 = rand::random();
+        let secret_key: i32 = rand::random();
 
-        match read.read_exact(&mut password_response).await {
-            Ok(_) => (),
-            Err(_) => return Err(Error::SocketError(format!("Error reading password message from client {{ username: {:?}, pool_name: {:?}, application_name: {:?} }}", pool_name, username, application_name))),
-        };
+        let client_identifier = format!(
+            "{{ username: {:?}, pool_name: {:?}, application_name: {:?} }}",
+            username, pool_name, application_name
+        );
 
         // Authenticate admin user.
-        let (transaction_mode, server_info) = if admin {
+        let (pool_mode, server_info) = if admin {
             let config = get_config();
-            // Compare server and client hashes.
-            let password_hash = md5_hash_password(
+
+            // SCRAM doesn't hash the username into the proof, so check it separately.
+            let authenticated = authenticate(
+                &mut read,
+                &mut write,
+                config.general.auth_method,
                 &config.general.admin_username,
                 &config.general.admin_password,
-                &salt,
-            );
+                &client_identifier,
+                &mut None,
+            )
+            .await?
+                && *username == config.general.admin_username;
 
-            if password_hash != password_response {
-                warn!("Invalid password {{ username: {:?}, pool_name: {:?}, application_name: {:?} }}", pool_name, username, application_name);
+            if !authenticated {
+                warn!("Invalid password {}", client_identifier);
                 wrong_password(&mut write, username).await?;
 
-                return Err(Error::ClientError(format!("Invalid password {{ username: {:?}, pool_name: {:?}, application_name: {:?} }}", pool_name, username, application_name)));
+                return Err(Error::ClientError(format!(
+                    "Invalid password {}",
+                    client_identifier
+                )));
             }
 
-            (false, generate_server_info_for_admin())
+            (PoolMode::Session, generate_server_info_for_admin())
         }
         // Authenticate normal user.
         else {
-            let pool = match get_pool(pool_name, username) {
+            // Users that are not in the config can be found with the pool's auth_query.
+            let (pool, auth_query) =
+                match get_auth_query_pool(pool_name, username, client_server_map.clone()).await {
+                    Ok(Some(pool)) => (Some(pool), true),
+                    Ok(None) => (get_pool(pool_name, username), false),
+                    Err(err) => {
+                        error_response_terminal(
+                            &mut write,
+                            &format!(
+                                "could not look up user: {:?} in database: {:?}",
+                                username, pool_name
+                            ),
+                        )
+                        .await?;
+
+                        return Err(err);
+                    }
+                };
+
+            let pool = match pool {
                 Some(pool) => pool,
                 None => {
                     error_response(
@@ -488,23 +816,73 @@ where
                     )
                     .await?;
 
-                    return Err(Error::ClientError(format!("Invalid pool name {{ username: {:?}, pool_name: {:?}, application_name: {:?} }}", pool_name, username, application_name)));
+                    return Err(Error::ClientError(format!(
+                        "Invalid pool name {}",
+                        client_identifier
+                    )));
                 }
             };
 
-            // Compare server and client hashes.
-            let password_hash = md5_hash_password(username, &pool.settings.user.password, &salt);
+            let auth_method = match pool.settings.auth_method {
+                Some(auth_method) => auth_method,
+                None => get_config().general.auth_method,
+            };
 
-            if password_hash != password_response {
-                warn!("Invalid password {{ username: {:?}, pool_name: {:?}, application_name: {:?} }}", pool_name, username, application_name);
+            let mut scram_client_key = None;
+
+            let authenticated = authenticate(
+                &mut read,
+                &mut write,
+                auth_method,
+                username,
+                &pool.settings.user.password,
+                &client_identifier,
+                &mut scram_client_key,
+            )
+            .await?;
+
+            if !authenticated {
+                warn!("Invalid password {}", client_identifier);
                 wrong_password(&mut write, username).await?;
 
-                return Err(Error::ClientError(format!("Invalid password {{ username: {:?}, pool_name: {:?}, application_name: {:?} }}", pool_name, username, application_name)));
+                return Err(Error::ClientError(format!(
+                    "Invalid password {}",
+                    client_identifier
+                )));
             }
 
-            let transaction_mode = pool.settings.pool_mode == PoolMode::Transaction;
+            // Only users who logged in get a pool. Another client of the user
+            // may have added it first.
+            let pool = match auth_query {
+                true => add_pool_if_new(PoolIdentifier::new(pool_name, username), pool),
+                false => pool,
+            };
 
-            (transaction_mode, pool.server_info())
+            // The client proved it knows the password, we can use its proof
+            // to log into the servers if we only have the SCRAM verifier.
+            if let Some(client_key) = scram_client_key {
+                pool.set_scram_client_key(client_key);
+            }
+
+            if !pool.validated() {
+                match pool.validate().await {
+                    Ok(_) => (),
+                    Err(err) => {
+                        error_response_terminal(
+                            &mut write,
+                            &format!(
+                                "could not connect to the servers of database: {:?}, user: {:?}",
+                                pool_name, username
+                            ),
+                        )
+                        .await?;
+
+                        return Err(err);
+                    }
+                }
+            }
+
+            (pool.settings.pool_mode, pool.server_info())
         };
 
         debug!("Password authentication successful");
@@ -522,7 +900,8 @@ where
             addr,
             buffer: BytesMut::with_capacity(8196),
             cancel_mode: false,
-            transaction_mode,
+            transaction_mode: pool_mode != PoolMode::Session,
+            statement_mode: pool_mode == PoolMode::Statement,
             process_id,
             secret_key,
             client_server_map,
@@ -536,6 +915,8 @@ where
             application_name: application_name.to_string(),
             shutdown,
             connected_to_server: false,
+            prepared_statements_enabled: get_config().general.prepared_statements,
+            prepared_statements: HashMap::new(),
         })
     }
 
@@ -543,7 +924,7 @@ where
     pub async fn cancel(
         read: S,
         write: T,
-        addr: std::net::SocketAddr,
+        addr: ClientAddress,
         mut bytes: BytesMut, // The rest of the startup message.
         client_server_map: ClientServerMap,
         shutdown: Receiver<()>,
@@ -557,6 +938,7 @@ where
             buffer: BytesMut::with_capacity(8196),
             cancel_mode: true,
             transaction_mode: false,
+            statement_mode: false,
             process_id,
             secret_key,
             client_server_map,
@@ -570,6 +952,8 @@ where
             application_name: String::from("undefined"),
             shutdown,
             connected_to_server: false,
+            prepared_statements_enabled: false,
+            prepared_statements: HashMap::new(),
         })
     }
 
@@ -602,6 +986,30 @@ where
             return Server::cancel(&address, port, process_id, secret_key).await;
         }
 
+        // KILL in the admin database disconnects the clients of the database right away.
+        let mut kills = database_kills();
+        let pool_name = self.pool_name.clone();
+
+        tokio::select! {
+            result = self.serve() => result,
+
+            _ = wait_for_kill(&mut kills, &pool_name), if !self.admin => {
+                error_response_terminal(
+                    &mut self.write,
+                    "terminating connection due to administrator command",
+                )
+                .await?;
+
+                Err(Error::ClientError(format!(
+                    "Client killed {{ username: {:?}, pool_name: {:?}, application_name: {:?} }}",
+                    self.username, self.pool_name, self.application_name
+                )))
+            }
+        }
+    }
+
+    /// Serve the queries of the client until it disconnects.
+    async fn serve(&mut self) -> Result<(), Error> {
         // The query router determines where the query is going to go,
         // e.g. primary, replica, which shard.
         let mut query_router = QueryRouter::new();
@@ -610,6 +1018,7 @@ where
             self.pool_name.clone(),
             self.username.clone(),
             self.application_name.clone(),
+            self.addr.to_string(),
         );
 
         // Our custom protocol loop.
@@ -653,7 +1062,7 @@ where
                 // allocate a connection, we wouldn't be able to send back an error message
                 // to the client so we buffer them and defer the decision to error out or not
                 // to when we get the S message
-                'P' | 'B' | 'D' | 'E' => {
+                'P' | 'B' | 'D' | 'E' | 'C' => {
                     self.buffer.put(&message[..]);
                     continue;
                 }
@@ -697,30 +1106,64 @@ where
                 // Normal query, not a custom command.
                 None => {
                     if query_router.query_parser_enabled() {
-                        query_router.infer(&message);
+                        // On Sync, the extended protocol messages we buffered
+                        // tell us where the query goes.
+                        if message[0] as char == 'S' {
+                            query_router.infer_extended(
+                                &split_messages(self.buffer.clone()).unwrap_or_default(),
+                            );
+                        } else {
+                            query_router.infer(&message);
+                        }
+
+                        if let Some(reason) = query_router.take_rejected() {
+                            self.buffer.clear();
+                            error_response(&mut self.write, &reason).await?;
+                            continue;
+                        }
+
+                        // Read-only queries for more than one shard go to all of them.
+                        if message[0] as char == 'Q' {
+                            if let Some(shards) = query_router.multi_shard().cloned() {
+                                self.multi_shard_query(&message, &shards, &query_router, &pool)
+                                    .await?;
+                                continue;
+                            }
+
+                            // The rows of a COPY go to the shards of their sharding keys.
+                            if let Some(copy) = query_router.take_copy() {
+                                self.sharded_copy(&message, copy, &query_router, &pool)
+                                    .await?;
+                                continue;
+                            }
+                        }
                     }
                 }
 
                 // SET SHARD TO
                 Some((Command::SetShard, _)) => {
-                    // Selected shard is not configured.
-                    if query_router.shard() >= pool.shards() {
-                        // Set the shard back to what it was.
-                        query_router.set_shard(current_shard);
+                    match query_router.take_rejected() {
+                        Some(reason) => error_response(&mut self.write, &reason).await?,
+
+                        // Selected shard is not configured.
+                        None if query_router.shard() >= pool.shards() => {
+                            // Set the shard back to what it was.
+                            query_router.set_shard(current_shard);
+
+                            error_response(
+                                &mut self.write,
+                                &format!(
+                                    "shard {} is more than configured {}, staying on shard {}",
+                                    query_router.shard(),
+                                    pool.shards(),
+                                    current_shard,
+                                ),
+                            )
+                            .await?;
+                        }
 
-                        error_response(
-                            &mut self.write,
-                            &format!(
-                                "shard {} is more than configured {}, staying on shard {}",
-                                query_router.shard(),
-                                pool.shards(),
-                                current_shard,
-                            ),
-                        )
-                        .await?;
-                    } else {
-                        custom_protocol_response_ok(&mut self.write, "SET SHARD").await?;
-                    }
+                        None => custom_protocol_response_ok(&mut self.write, "SET SHARD").await?,
+                    };
                     continue;
                 }
 
@@ -732,7 +1175,12 @@ where
 
                 // SET SHARDING KEY TO
                 Some((Command::SetShardingKey, _)) => {
-                    custom_protocol_response_ok(&mut self.write, "SET SHARDING KEY").await?;
+                    match query_router.take_rejected() {
+                        Some(reason) => error_response(&mut self.write, &reason).await?,
+                        None => {
+                            custom_protocol_response_ok(&mut self.write, "SET SHARDING KEY").await?
+                        }
+                    };
                     continue;
                 }
 
@@ -765,7 +1213,12 @@ where
 
             // Grab a server from the pool.
             let connection = match pool
-                .get(query_router.shard(), query_router.role(), self.process_id)
+                .get(
+                    query_router.shard(),
+                    query_router.role(),
+                    query_router.min_replica_lsn(),
+                    self.process_id,
+                )
                 .await
             {
                 Ok(conn) => {
@@ -864,6 +1317,10 @@ where
                         self.send_and_receive_loop(code, Some(&message), server, &address, &pool)
                             .await?;
 
+                        if self.statement_mode && server.in_transaction() {
+                            return self.reject_transaction(server).await;
+                        }
+
                         if !server.in_transaction() {
                             // Report transaction executed statistics.
                             self.stats.transaction(self.process_id, server.server_id());
@@ -908,6 +1365,12 @@ where
                         self.buffer.put(&message[..]);
                     }
 
+                    // Close
+                    // The client is done with a prepared statement or a portal.
+                    'C' => {
+                        self.buffer.put(&message[..]);
+                    }
+
                     // Sync
                     // Frontend (client) is asking for the query result now.
                     'S' => {
@@ -917,8 +1380,23 @@ where
 
                         let first_message_code = (*self.buffer.get(0).unwrap_or(&0)) as char;
 
+                        // Named prepared statements get a pooler-wide name, so they
+                        // can be used on any server and don't need to be cleared at checkin.
+                        if self.prepared_statements_enabled {
+                            match prepared_statements::rewrite(
+                                self.buffer.split(),
+                                &mut self.prepared_statements,
+                                server.prepared_statements(),
+                            ) {
+                                Ok(buffer) => self.buffer = buffer,
+                                Err(err) => {
+                                    server.mark_bad();
+                                    return Err(err);
+                                }
+                            }
+                        }
                         // Almost certainly true
-                        if first_message_code == 'P' {
+                        else if first_message_code == 'P' {
                             // Message layout
                             // P followed by 32 int followed by null-terminated statement name
                             // So message code should be in offset 0 of the buffer, first character
@@ -936,6 +1414,10 @@ where
 
                         self.buffer.clear();
 
+                        if self.statement_mode && server.in_transaction() {
+                            return self.reject_transaction(server).await;
+                        }
+
                         if !server.in_transaction() {
                             self.stats.transaction(self.process_id, server.server_id());
 
@@ -982,6 +1464,10 @@ where
                             }
                         };
 
+                        if self.statement_mode && server.in_transaction() {
+                            return self.reject_transaction(server).await;
+                        }
+
                         if !server.in_transaction() {
                             self.stats.transaction(self.process_id, server.server_id());
 
@@ -1001,6 +1487,25 @@ where
                 }
             }
 
+            // Read your writes: remember how far the primary got after the client's write,
+            // its next reads only go to the replicas that replayed it.
+            if query_router.write_lsn_pending() && address.role == Role::Primary {
+                match server.fetch_rows("SELECT pg_current_wal_lsn()").await {
+                    Ok(rows) => {
+                        if let Some(lsn) = rows
+                            .first()
+                            .and_then(|row| row.first())
+                            .cloned()
+                            .flatten()
+                            .and_then(|lsn| parse_lsn(&lsn))
+                        {
+                            query_router.set_write_lsn(lsn);
+                        }
+                    }
+                    Err(err) => warn!("Could not get the LSN of {:?}: {:?}", address, err),
+                };
+            }
+
             // The server is no longer bound to us, we can't cancel it's queries anymore.
             debug!("Releasing server back into the pool");
             server.checkin_cleanup().await?;
@@ -1018,6 +1523,562 @@ where
         guard.remove(&(self.process_id, self.secret_key));
     }
 
+    /// Transactions are not allowed in statement mode: roll back the server
+    /// and disconnect the client, like PgBouncer does.
+    async fn reject_transaction(&mut self, server: &mut Server) -> Result<(), Error> {
+        server.checkin_cleanup().await?;
+        self.release();
+
+        error_response_terminal(
+            &mut self.write,
+            "transaction blocks not allowed in statement pooling mode",
+        )
+        .await?;
+
+        Err(Error::ClientError(format!(
+            "Transaction in statement mode {{ username: {:?}, pool_name: {:?}, application_name: {:?} }}",
+            self.username, self.pool_name, self.application_name
+        )))
+    }
+
+    /// Send a read-only query to several shards in parallel and merge their results,
+    /// as if one server answered it. The shards must return the same columns.
+    async fn multi_shard_query(
+        &mut self,
+        message: &BytesMut,
+        shards: &[usize],
+        query_router: &QueryRouter,
+        pool: &ConnectionPool,
+    ) -> Result<(), Error> {
+        let plan = match query_router.merge_plan() {
+            Ok(plan) => plan,
+            Err(reason) => return error_response(&mut self.write, reason).await,
+        };
+
+        let message = match &plan.query {
+            Some(query) => simple_query(query),
+            None => message.clone(),
+        };
+
+        let servers = match pool
+            .get_all(
+                shards,
+                query_router.role(),
+                query_router.min_replica_lsn(),
+                self.process_id,
+            )
+            .await
+        {
+            Ok(servers) => servers,
+            Err((shard, err)) => {
+                error_response(&mut self.write, "could not get connection from the pool").await?;
+
+                error!("Could not get connection from pool: {{ pool_name: {:?}, username: {:?}, shard: {:?}, role: \"{:?}\", error: \"{:?}\" }}",
+                self.pool_name.clone(), self.username.clone(), shard, query_router.role(), err);
+                return Ok(());
+            }
+        };
+
+        let mut connections = Vec::with_capacity(servers.len());
+
+        for (mut server, address) in servers {
+            server.set_name(&self.application_name).await?;
+
+            connections.push(ShardConnection {
+                server,
+                address,
+                pending: VecDeque::new(),
+                done: false,
+            });
+        }
+
+        debug!("Sending query to shards {:?}", shards);
+
+        let query_start = Instant::now();
+
+        for connection in connections.iter_mut() {
+            self.send_server_message(&mut connection.server, &message, &connection.address, pool)
+                .await?;
+        }
+
+        // Check that the shards agree on the columns before sending any rows.
+        // The table OIDs differ between the shard databases, only the names and types must match;
+        // the client gets the first shard's RowDescription.
+        let mut row_description: Option<BytesMut> = None;
+        let mut columns = Vec::new();
+        let mut failure = None;
+        let mut error = None;
+
+        for connection in connections.iter_mut() {
+            while !connection.done {
+                let response = self.next_shard_message(connection, pool).await?;
+
+                match response[0] as char {
+                    'T' => {
+                        match parse_row_description(&response) {
+                            Ok(shard_columns) => match &row_description {
+                                Some(_) if shard_columns != columns => {
+                                    failure.get_or_insert(String::from(
+                                        "shards returned different columns",
+                                    ));
+                                }
+                                Some(_) => (),
+                                None => {
+                                    row_description = Some(response);
+                                    columns = shard_columns;
+                                }
+                            },
+                            Err(_) => {
+                                failure.get_or_insert(String::from(
+                                    "could not parse the columns of the shards",
+                                ));
+                            }
+                        };
+                        break;
+                    }
+                    'E' => {
+                        error.get_or_insert(response);
+                        break;
+                    }
+                    _ => (),
+                }
+            }
+        }
+
+        let sort_columns = match plan.sort_columns(&columns) {
+            Ok(sort_columns) => sort_columns,
+            Err(reason) => {
+                failure.get_or_insert(reason);
+                Vec::new()
+            }
+        };
+
+        let mut buffer = BytesMut::new();
+
+        if let (Some(row_description), None, None) = (&row_description, &failure, &error) {
+            buffer.put(&row_description[..]);
+        }
+
+        // Each shard returns its rows in order, we merge them in the same order.
+        let parse_rows = !plan.order_by.is_empty() || !plan.aggregates.is_empty();
+        let mut heads = Vec::with_capacity(connections.len());
+
+        for connection in connections.iter_mut() {
+            heads.push(
+                self.next_shard_row(connection, pool, parse_rows, &mut error)
+                    .await?,
+            );
+        }
+
+        let mut aggregates = Vec::new();
+        let mut skipped = 0;
+        let mut rows = 0;
+
+        while error.is_none() && failure.is_none() {
+            // The LIMIT of aggregates applies to the row we make with them.
+            if plan.aggregates.is_empty() && plan.limit.map_or(false, |limit| rows >= limit) {
+                break;
+            }
+
+            let index = match heads
+                .iter()
+                .enumerate()
+                .filter_map(|(index, head)| head.as_ref().map(|(_, row)| (index, row)))
+                .min_by(|(_, a), (_, b)| plan.compare(&sort_columns, &columns, a, b))
+            {
+                Some((index, _)) => index,
+                None => break,
+            };
+
+            let (data_row, row) = heads[index].take().unwrap();
+
+            heads[index] = self
+                .next_shard_row(&mut connections[index], pool, parse_rows, &mut error)
+                .await?;
+
+            if !plan.aggregates.is_empty() {
+                aggregates.push(row);
+                continue;
+            }
+
+            if skipped < plan.offset {
+                skipped += 1;
+                continue;
+            }
+
+            buffer.put(&data_row[..]);
+            rows += 1;
+
+            // Want to limit buffer size
+            if buffer.len() > 8196 {
+                if let Err(err) = write_all_half(&mut self.write, &buffer).await {
+                    for connection in connections.iter_mut() {
+                        connection.server.mark_bad();
+                    }
+                    return Err(err);
+                }
+                buffer.clear();
+            }
+        }
+
+        // Aggregates make one row, if the LIMIT and OFFSET keep it.
+        if !plan.aggregates.is_empty()
+            && error.is_none()
+            && failure.is_none()
+            && plan.offset == 0
+            && plan.limit != Some(0)
+        {
+            buffer.put(data_row_nullable(&plan.combine(&columns, &aggregates)));
+            rows = 1;
+        }
+
+        // Read the servers until they're ready for the next query.
+        for connection in connections.iter_mut() {
+            while !connection.done {
+                let response = self.next_shard_message(connection, pool).await?;
+
+                if response[0] as char == 'E' {
+                    error.get_or_insert(response);
+                }
+            }
+
+            self.stats.query(
+                self.process_id,
+                connection.server.server_id(),
+                Instant::now().duration_since(query_start).as_millis(),
+            );
+            self.stats
+                .transaction(self.process_id, connection.server.server_id());
+            self.stats.server_idle(connection.server.server_id());
+        }
+
+        match (error, failure) {
+            (Some(error), _) => buffer.put(error),
+            (None, Some(failure)) => return error_response(&mut self.write, &failure).await,
+            (None, None) => buffer.put(command_complete(&format!("SELECT {}", rows))),
+        };
+
+        write_all_half(&mut self.write, &buffer).await?;
+        ready_for_query(&mut self.write).await
+    }
+
+    /// Copy the rows of a `COPY ... FROM STDIN` to the primaries of the shards in parallel,
+    /// each row to the shard of its sharding key. It's not atomic: if a shard fails,
+    /// the others may have copied their rows.
+    async fn sharded_copy(
+        &mut self,
+        message: &BytesMut,
+        mut copy: CopySplitter,
+        query_router: &QueryRouter,
+        pool: &ConnectionPool,
+    ) -> Result<(), Error> {
+        let shards: Vec<usize> = (0..pool.shards()).collect();
+
+        let servers = match pool
+            .get_all(&shards, Some(Role::Primary), None, self.process_id)
+            .await
+        {
+            Ok(servers) => servers,
+            Err((shard, err)) => {
+                error_response(&mut self.write, "could not get connection from the pool").await?;
+
+                error!("Could not get connection from pool: {{ pool_name: {:?}, username: {:?}, shard: {:?}, role: \"{:?}\", error: \"{:?}\" }}",
+                self.pool_name.clone(), self.username.clone(), shard, query_router.role(), err);
+                return Ok(());
+            }
+        };
+
+        let mut connections = Vec::with_capacity(servers.len());
+
+        for (mut server, address) in servers {
+            server.set_name(&self.application_name).await?;
+
+            connections.push(ShardConnection {
+                server,
+                address,
+                pending: VecDeque::new(),
+                done: false,
+            });
+        }
+
+        debug!("Copying to {} shards", connections.len());
+
+        let query_start = Instant::now();
+
+        for connection in connections.iter_mut() {
+            self.send_server_message(&mut connection.server, message, &connection.address, pool)
+                .await?;
+        }
+
+        // Wait for all the shards to be ready for the rows.
+        let mut copy_in_response = None;
+        let mut error = None;
+
+        for connection in connections.iter_mut() {
+            while !connection.done {
+                let response = self.next_shard_message(connection, pool).await?;
+
+                match response[0] as char {
+                    'G' => {
+                        copy_in_response.get_or_insert(response);
+                        break;
+                    }
+                    'E' => {
+                        error.get_or_insert(response);
+                    }
+                    _ => (),
+                }
+            }
+        }
+
+        let copy_in_response = match (copy_in_response, error) {
+            (Some(copy_in_response), None) => copy_in_response,
+
+            // A shard can't copy, the others don't either.
+            (_, error) => {
+                let fail = copy_fail("COPY failed on another shard");
+
+                for connection in connections.iter_mut().filter(|connection| !connection.done) {
+                    self.send_server_message(
+                        &mut connection.server,
+                        &fail,
+                        &connection.address,
+                        pool,
+                    )
+                    .await?;
+                }
+
+                let (_, shard_error) = self
+                    .finish_copy(&mut connections, pool, query_start)
+                    .await?;
+
+                match error.or(shard_error) {
+                    Some(error) => write_all_half(&mut self.write, &error).await?,
+                    None => return error_response(&mut self.write, "COPY failed").await,
+                };
+
+                return ready_for_query(&mut self.write).await;
+            }
+        };
+
+        if let Err(err) = write_all_half(&mut self.write, &copy_in_response).await {
+            for connection in connections.iter_mut() {
+                connection.server.mark_bad();
+            }
+            return Err(err);
+        }
+
+        let mut buffers = vec![BytesMut::new(); connections.len()];
+        let mut failure = None;
+
+        // Split the rows of the client between the shards, until it's done.
+        let end = loop {
+            let message = match read_message(&mut self.read).await {
+                Ok(message) => message,
+                Err(err) => {
+                    for connection in connections.iter_mut() {
+                        connection.server.mark_bad();
+                    }
+                    return Err(err);
+                }
+            };
+
+            let rows = match message[0] as char {
+                // CopyData
+                'd' if failure.is_none() => copy.split(&message[5..]),
+
+                // The row was bad, the COPY fails when the client is done.
+                'd' => continue,
+
+                // CopyDone
+                'c' if failure.is_none() => match copy.finish() {
+                    Ok(row) => {
+                        if let Some((shard, row)) = row {
+                            split_row(&mut buffers, shard, &row);
+                        }
+
+                        for (connection, buffer) in connections.iter_mut().zip(buffers.iter()) {
+                            if !buffer.is_empty() {
+                                self.send_server_message(
+                                    &mut connection.server,
+                                    &copy_data(buffer),
+                                    &connection.address,
+                                    pool,
+                                )
+                                .await?;
+                            }
+                        }
+
+                        break copy_done();
+                    }
+                    Err(reason) => break copy_fail(&reason),
+                },
+
+                'c' => break copy_fail(failure.as_deref().unwrap_or_default()),
+
+                // CopyFail
+                'f' => break message,
+
+                // Flush and Sync are ignored during COPY.
+                'H' | 'S' => continue,
+
+                _ => break copy_fail("unexpected message type during COPY from stdin"),
+            };
+
+            match rows {
+                Ok(rows) => {
+                    for (shard, row) in rows {
+                        split_row(&mut buffers, shard, &row);
+                    }
+                }
+                Err(reason) => {
+                    failure = Some(reason);
+                    continue;
+                }
+            };
+
+            // Want to limit buffer size
+            for (connection, buffer) in connections.iter_mut().zip(buffers.iter_mut()) {
+                if buffer.len() > 8196 {
+                    self.send_server_message(
+                        &mut connection.server,
+                        &copy_data(buffer),
+                        &connection.address,
+                        pool,
+                    )
+                    .await?;
+                    buffer.clear();
+                }
+            }
+        };
+
+        for connection in connections.iter_mut() {
+            self.send_server_message(&mut connection.server, &end, &connection.address, pool)
+                .await?;
+        }
+
+        let (rows, error) = self
+            .finish_copy(&mut connections, pool, query_start)
+            .await?;
+
+        match error {
+            Some(error) => write_all_half(&mut self.write, &error).await?,
+            None => {
+                write_all_half(
+                    &mut self.write,
+                    &command_complete(&format!("COPY {}", rows)),
+                )
+                .await?
+            }
+        };
+
+        ready_for_query(&mut self.write).await
+    }
+
+    /// Read the servers of a sharded COPY until they're ready for the next query,
+    /// and add up the rows they copied. Returns the first error.
+    async fn finish_copy(
+        &mut self,
+        connections: &mut [ShardConnection<'_>],
+        pool: &ConnectionPool,
+        query_start: Instant,
+    ) -> Result<(u64, Option<BytesMut>), Error> {
+        let mut rows = 0;
+        let mut error = None;
+
+        for connection in connections.iter_mut() {
+            while !connection.done {
+                let response = self.next_shard_message(connection, pool).await?;
+
+                match response[0] as char {
+                    // CommandComplete, e.g. COPY 5
+                    'C' => {
+                        rows += std::str::from_utf8(&response[5..response.len() - 1])
+                            .ok()
+                            .and_then(|tag| tag.strip_prefix("COPY "))
+                            .and_then(|count| count.parse::<u64>().ok())
+                            .unwrap_or(0);
+                    }
+                    'E' => {
+                        error.get_or_insert(response);
+                    }
+                    _ => (),
+                }
+            }
+
+            self.stats.query(
+                self.process_id,
+                connection.server.server_id(),
+                Instant::now().duration_since(query_start).as_millis(),
+            );
+            self.stats
+                .transaction(self.process_id, connection.server.server_id());
+            self.stats.server_idle(connection.server.server_id());
+        }
+
+        Ok((rows, error))
+    }
+
+    /// The next row from a server answering a multi-shard query, with its values
+    /// if we need them to merge it. Errors are kept to send them after the rows.
+    async fn next_shard_row(
+        &mut self,
+        connection: &mut ShardConnection<'_>,
+        pool: &ConnectionPool,
+        parse: bool,
+        error: &mut Option<BytesMut>,
+    ) -> Result<Option<(BytesMut, Vec<Option<String>>)>, Error> {
+        while !connection.done {
+            let response = self.next_shard_message(connection, pool).await?;
+
+            match response[0] as char {
+                'D' if parse => match parse_data_row(response.clone().split_off(5)) {
+                    Ok(row) => return Ok(Some((response, row))),
+                    Err(err) => {
+                        connection.server.mark_bad();
+                        return Err(err);
+                    }
+                },
+                'D' => return Ok(Some((response, Vec::new()))),
+                'E' => {
+                    error.get_or_insert(response);
+                }
+                _ => (),
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// The next message from a server answering a multi-shard query.
+    async fn next_shard_message(
+        &mut self,
+        connection: &mut ShardConnection<'_>,
+        pool: &ConnectionPool,
+    ) -> Result<BytesMut, Error> {
+        while connection.pending.is_empty() {
+            let response = self
+                .receive_server_message(&mut connection.server, &connection.address, pool)
+                .await?;
+
+            match split_messages(response) {
+                Ok(messages) => connection.pending.extend(messages),
+                Err(err) => {
+                    connection.server.mark_bad();
+                    return Err(err);
+                }
+            };
+        }
+
+        let message = connection.pending.pop_front().unwrap();
+
+        if message[0] as char == 'Z' {
+            connection.done = true;
+        }
+
+        Ok(message)
+    }
+
     async fn send_and_receive_loop(
         &mut self,
         code: char,
@@ -1134,6 +2195,18 @@ where
     }
 }
 
+/// Add a row of a sharded COPY to the buffer of its shard, or of all of them.
+fn split_row(buffers: &mut [BytesMut], shard: Option<usize>, row: &[u8]) {
+    match shard {
+        Some(shard) => buffers[shard].put_slice(row),
+        None => {
+            for buffer in buffers.iter_mut() {
+                buffer.put_slice(row);
+            }
+        }
+    }
+}
+
 impl<S, T> Drop for Client<S, T> {
     fn drop(&mut self) {
         let mut guard = self.client_server_map.lock();
@@ -1147,3 +2220,39 @@ impl<S, T> Drop for Client<S, T> {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_read_password() {
+        let message = |len: i32, body: &[u8]| {
+            let mut message = BytesMut::from(&b"p"[..]);
+            message.put_i32(len);
+            message.put_slice(body);
+            message
+        };
+
+        let password = message(4 + 7, b"secret\0");
+        assert_eq!(
+            read_password(&mut &password[..], "client").await,
+            Ok(BytesMut::from(&b"secret\0"[..]))
+        );
+
+        for len in [
+            0,
+            3,
+            -1,
+            i32::MIN,
+            MAX_PASSWORD_MESSAGE_LENGTH + 1,
+            i32::MAX,
+        ] {
+            let invalid = message(len, b"secret\0");
+            assert!(matches!(
+                read_password(&mut &invalid[..], "client").await,
+                Err(Error::ProtocolSyncError(_))
+            ));
+        }
+    }
+}
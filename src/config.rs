@@ -5,21 +5,27 @@ use once_cell::sync::Lazy;
 use serde_derive::{Deserialize, Serialize};
 use std::collections::{BTreeMap, HashMap, HashSet};
 use std::hash::Hash;
+use std::os::unix::fs::PermissionsExt;
 use std::path::Path;
 use std::sync::Arc;
 use tokio::fs::File;
-use tokio::io::AsyncReadExt;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
 use crate::errors::Error;
+use crate::messages::is_md5_hash;
 use crate::pool::{ClientServerMap, ConnectionPool};
-use crate::sharding::ShardingFunction;
-use crate::tls::{load_certs, load_keys};
+use crate::scram::ScramSecret;
+use crate::sharding::{ShardKeys, ShardingFunction, ShardingKeyType};
+use crate::tls::{load_certs, load_keys, reload_tls};
 
 pub const VERSION: &str = env!("CARGO_PKG_VERSION");
 
 /// Globally available configuration.
 static CONFIG: Lazy<ArcSwap<Config>> = Lazy::new(|| ArcSwap::from_pointee(Config::default()));
 
+/// One SET or RELOAD at a time, so they don't undo each other's changes.
+static CONFIG_CHANGE: Lazy<tokio::sync::Mutex<()>> = Lazy::new(|| tokio::sync::Mutex::new(()));
+
 /// Server role: primary or replica.
 #[derive(Clone, PartialEq, Serialize, Deserialize, Hash, std::cmp::Eq, Debug, Copy)]
 pub enum Role {
@@ -121,6 +127,118 @@ impl Address {
     }
 }
 
+/// Authentication method offered to clients:
+/// - md5: MD5 password challenge,
+/// - scram-sha-256: SCRAM-SHA-256 (SASL).
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Copy, Hash)]
+pub enum AuthMethod {
+    #[serde(alias = "md5", alias = "Md5")]
+    Md5,
+
+    #[serde(
+        alias = "scram-sha-256",
+        alias = "scram_sha_256",
+        alias = "ScramSha256"
+    )]
+    ScramSha256,
+}
+
+impl std::fmt::Display for AuthMethod {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match *self {
+            AuthMethod::Md5 => write!(f, "md5"),
+            AuthMethod::ScramSha256 => write!(f, "scram-sha-256"),
+        }
+    }
+}
+
+/// TLS between the pooler and the servers, like libpq's sslmode:
+/// - disable: plain text only,
+/// - prefer: TLS if the server supports it, without verifying the certificate,
+/// - require: TLS only, without verifying the certificate,
+/// - verify-ca: TLS only, the certificate must be signed by the CA,
+/// - verify-full: same as verify-ca, and the certificate must match the host name.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Copy, Hash)]
+pub enum ServerTlsMode {
+    #[serde(alias = "disable", alias = "Disable")]
+    Disable,
+
+    #[serde(alias = "prefer", alias = "Prefer")]
+    Prefer,
+
+    #[serde(alias = "require", alias = "Require")]
+    Require,
+
+    #[serde(alias = "verify-ca", alias = "verify_ca", alias = "VerifyCa")]
+    VerifyCa,
+
+    #[serde(alias = "verify-full", alias = "verify_full", alias = "VerifyFull")]
+    VerifyFull,
+}
+
+impl std::fmt::Display for ServerTlsMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match *self {
+            ServerTlsMode::Disable => write!(f, "disable"),
+            ServerTlsMode::Prefer => write!(f, "prefer"),
+            ServerTlsMode::Require => write!(f, "require"),
+            ServerTlsMode::VerifyCa => write!(f, "verify-ca"),
+            ServerTlsMode::VerifyFull => write!(f, "verify-full"),
+        }
+    }
+}
+
+/// TLS between the clients and the pooler:
+/// - disable: plain text only,
+/// - allow: TLS if the client asks for it and a certificate is configured,
+/// - require: TLS only,
+/// - verify-ca: TLS only, clients must present a certificate signed by the CA,
+/// - verify-full: same as verify-ca, and the certificate common name must match the username.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Copy, Hash)]
+pub enum ClientTlsMode {
+    #[serde(alias = "disable", alias = "Disable")]
+    Disable,
+
+    #[serde(alias = "allow", alias = "Allow")]
+    Allow,
+
+    #[serde(alias = "require", alias = "Require")]
+    Require,
+
+    #[serde(alias = "verify-ca", alias = "verify_ca", alias = "VerifyCa")]
+    VerifyCa,
+
+    #[serde(alias = "verify-full", alias = "verify_full", alias = "VerifyFull")]
+    VerifyFull,
+}
+
+impl ClientTlsMode {
+    /// Clients can't connect in plain text.
+    pub fn required(&self) -> bool {
+        matches!(
+            self,
+            ClientTlsMode::Require | ClientTlsMode::VerifyCa | ClientTlsMode::VerifyFull
+        )
+    }
+
+    /// Clients must present a certificate signed by our CA.
+    pub fn verify(&self) -> bool {
+        matches!(self, ClientTlsMode::VerifyCa | ClientTlsMode::VerifyFull)
+    }
+}
+
+impl std::fmt::Display for ClientTlsMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match *self {
+            ClientTlsMode::Disable => write!(f, "disable"),
+            ClientTlsMode::Allow => write!(f, "allow"),
+            ClientTlsMode::Require => write!(f, "require"),
+            ClientTlsMode::VerifyCa => write!(f, "verify-ca"),
+            ClientTlsMode::VerifyFull => write!(f, "verify-full"),
+        }
+    }
+}
+
 /// PostgreSQL user.
 #[derive(Clone, PartialEq, Hash, Eq, Serialize, Deserialize, Debug)]
 pub struct User {
@@ -129,6 +247,7 @@ pub struct User {
     pub pool_size: u32,
     #[serde(default)] // 0
     pub statement_timeout: u64,
+    pub auth_method: Option<AuthMethod>,
 }
 
 impl Default for User {
@@ -138,6 +257,7 @@ impl Default for User {
             password: String::new(),
             pool_size: 15,
             statement_timeout: 0,
+            auth_method: None,
         }
     }
 }
@@ -151,6 +271,13 @@ pub struct General {
     #[serde(default = "General::default_port")]
     pub port: i16,
 
+    /// Also listen on a Unix socket, `.s.PGSQL.<port>` in this directory.
+    pub unix_socket_dir: Option<String>,
+
+    /// Permissions of the Unix socket, in octal.
+    #[serde(default = "General::default_unix_socket_mode")]
+    pub unix_socket_mode: String,
+
     pub enable_prometheus_exporter: Option<bool>,
     pub prometheus_exporter_port: i16,
 
@@ -178,14 +305,47 @@ pub struct General {
     #[serde(default = "General::default_ban_time")]
     pub ban_time: i64,
 
+    /// How often to ask the servers if they are in recovery, to find out which one
+    /// is the primary (ms). 0 disables the role check.
+    #[serde(default)] // 0
+    pub role_check_interval: u64,
+
+    /// How often to measure the replica lag and LSN of the pools that need them (ms).
+    #[serde(default = "General::default_replica_check_interval")]
+    pub replica_check_interval: u64,
+
     #[serde(default = "General::default_worker_threads")]
     pub worker_threads: usize,
 
     #[serde(default)] // False
     pub autoreload: bool,
 
+    #[serde(default = "General::default_auth_method")]
+    pub auth_method: AuthMethod,
+
+    /// Give the clients' named prepared statements pooler-wide names and prepare them
+    /// on the servers as needed, so they work in transaction mode.
+    #[serde(default)] // False
+    pub prepared_statements: bool,
+
+    /// How many prepared statements each server connection keeps.
+    #[serde(default = "General::default_prepared_statements_cache_size")]
+    pub prepared_statements_cache_size: usize,
+
     pub tls_certificate: Option<String>,
     pub tls_private_key: Option<String>,
+
+    #[serde(default = "General::default_client_tls_mode")]
+    pub client_tls_mode: ClientTlsMode,
+
+    /// CA used to verify the client certificates.
+    pub tls_ca_cert: Option<String>,
+
+    /// Maps client certificate common names to usernames, used by verify-full.
+    /// Without a mapping, the common name must be the username.
+    #[serde(default)]
+    pub client_tls_user_map: BTreeMap<String, String>,
+
     pub admin_username: String,
     pub admin_password: String,
 }
@@ -199,6 +359,14 @@ impl General {
         5432
     }
 
+    pub fn default_unix_socket_mode() -> String {
+        "0777".into()
+    }
+
+    pub fn default_prepared_statements_cache_size() -> usize {
+        500
+    }
+
     pub fn default_connect_timeout() -> u64 {
         1000
     }
@@ -223,9 +391,21 @@ impl General {
         60
     }
 
+    pub fn default_replica_check_interval() -> u64 {
+        1000
+    }
+
     pub fn default_worker_threads() -> usize {
         4
     }
+
+    pub fn default_auth_method() -> AuthMethod {
+        AuthMethod::Md5
+    }
+
+    pub fn default_client_tls_mode() -> ClientTlsMode {
+        ClientTlsMode::Allow
+    }
 }
 
 impl Default for General {
@@ -233,6 +413,8 @@ impl Default for General {
         General {
             host: Self::default_host(),
             port: Self::default_port(),
+            unix_socket_dir: None,
+            unix_socket_mode: Self::default_unix_socket_mode(),
             enable_prometheus_exporter: Some(false),
             prometheus_exporter_port: 9930,
             connect_timeout: General::default_connect_timeout(),
@@ -241,12 +423,20 @@ impl Default for General {
             healthcheck_timeout: Self::default_healthcheck_timeout(),
             healthcheck_delay: Self::default_healthcheck_delay(),
             ban_time: Self::default_ban_time(),
+            role_check_interval: 0,
+            replica_check_interval: Self::default_replica_check_interval(),
             worker_threads: Self::default_worker_threads(),
             log_client_connections: false,
             log_client_disconnections: false,
             autoreload: false,
+            auth_method: Self::default_auth_method(),
+            prepared_statements: false,
+            prepared_statements_cache_size: Self::default_prepared_statements_cache_size(),
             tls_certificate: None,
             tls_private_key: None,
+            client_tls_mode: Self::default_client_tls_mode(),
+            tls_ca_cert: None,
+            client_tls_user_map: BTreeMap::default(),
             admin_username: String::from("admin"),
             admin_password: String::from("admin"),
         }
@@ -263,12 +453,16 @@ pub enum PoolMode {
 
     #[serde(alias = "session", alias = "Session")]
     Session,
+
+    #[serde(alias = "statement", alias = "Statement")]
+    Statement,
 }
 impl ToString for PoolMode {
     fn to_string(&self) -> String {
         match *self {
             PoolMode::Transaction => "transaction".to_string(),
             PoolMode::Session => "session".to_string(),
+            PoolMode::Statement => "statement".to_string(),
         }
     }
 }
@@ -292,6 +486,32 @@ impl ToString for LoadBalancingMode {
     }
 }
 
+/// Read your writes, used with the query parser:
+/// - disabled: reads go to the replicas right after a write,
+/// - window: reads go to the primary for read_your_writes_window after a write,
+/// - lsn: reads go to the replicas that replayed the write, or the primary.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Copy, Hash)]
+pub enum ReadYourWrites {
+    #[serde(alias = "disabled", alias = "Disabled")]
+    Disabled,
+
+    #[serde(alias = "window", alias = "Window")]
+    Window,
+
+    #[serde(alias = "lsn", alias = "LSN", alias = "Lsn")]
+    Lsn,
+}
+
+impl std::fmt::Display for ReadYourWrites {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match *self {
+            ReadYourWrites::Disabled => write!(f, "disabled"),
+            ReadYourWrites::Window => write!(f, "window"),
+            ReadYourWrites::Lsn => write!(f, "lsn"),
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
 pub struct Pool {
     #[serde(default = "Pool::default_pool_mode")]
@@ -312,11 +532,67 @@ pub struct Pool {
 
     pub idle_timeout: Option<u64>,
 
+    /// Replicas further behind the primary than this (ms) don't get any queries,
+    /// unless all of them are. Not checked if unset.
+    pub max_replica_lag: Option<u64>,
+
+    /// Make sure clients read their own writes, see `ReadYourWrites`.
+    #[serde(default = "Pool::default_read_your_writes")]
+    pub read_your_writes: ReadYourWrites,
+
+    /// How long the reads stick to the primary after a write (ms), in window mode.
+    #[serde(default = "Pool::default_read_your_writes_window")]
+    pub read_your_writes_window: u64,
+
     pub sharding_function: ShardingFunction,
 
+    /// The column holding the sharding key, e.g. `id`, or `users.id`
+    /// to only use it for the `users` table.
     #[serde(default = "Pool::default_automatic_sharding_key")]
     pub automatic_sharding_key: Option<String>,
 
+    /// The type of the sharding key, it changes how it's hashed.
+    #[serde(default = "Pool::default_sharding_key_type")]
+    pub sharding_key_type: ShardingKeyType,
+
+    /// Tables that are the same on all shards, their reads can go to any shard.
+    #[serde(default)]
+    pub reference_tables: Vec<String>,
+
+    pub auth_method: Option<AuthMethod>,
+
+    /// Query to fetch the password hash of users that are not in the config,
+    /// e.g. `SELECT usename, passwd FROM pg_shadow WHERE usename = $1`.
+    pub auth_query: Option<String>,
+
+    /// User running the auth_query on the primary of the first shard.
+    pub auth_query_user: Option<String>,
+
+    /// Password of the auth_query user.
+    pub auth_query_password: Option<String>,
+
+    /// How long (in seconds) the password hashes fetched with auth_query are cached.
+    #[serde(default = "Pool::default_auth_query_cache_ttl")]
+    pub auth_query_cache_ttl: u64,
+
+    /// Pool size for the users found with auth_query.
+    #[serde(default = "Pool::default_auth_query_pool_size")]
+    pub auth_query_pool_size: u32,
+
+    /// TLS to the servers, can be overridden for each shard.
+    pub server_tls_mode: Option<ServerTlsMode>,
+
+    /// CA bundle used to verify the servers' certificates.
+    pub server_tls_ca_cert: Option<String>,
+
+    /// Certificate and private key presented to the servers, if they ask for one.
+    pub server_tls_certificate: Option<String>,
+    pub server_tls_private_key: Option<String>,
+
+    /// The sharding key column of each table, instead of the automatic_sharding_key.
+    #[serde(default)]
+    pub sharding_keys: BTreeMap<String, String>,
+
     pub shards: BTreeMap<String, Shard>,
     pub users: BTreeMap<String, User>,
 }
@@ -334,6 +610,46 @@ impl Pool {
         None
     }
 
+    pub fn default_sharding_key_type() -> ShardingKeyType {
+        ShardingKeyType::Bigint
+    }
+
+    /// The sharding keys of the shards, with range and list sharding.
+    pub fn shard_keys(&self) -> ShardKeys {
+        let mut ranges = Vec::new();
+        let mut list = HashMap::new();
+
+        for (shard_idx, shard) in &self.shards {
+            let shard_idx = shard_idx.parse::<usize>().unwrap();
+
+            ranges.extend(
+                shard
+                    .key_ranges
+                    .iter()
+                    .map(|[start, end]| (*start, *end, shard_idx)),
+            );
+            list.extend(shard.keys.iter().map(|key| (*key, shard_idx)));
+        }
+
+        ShardKeys::new(ranges, list)
+    }
+
+    pub fn default_read_your_writes() -> ReadYourWrites {
+        ReadYourWrites::Disabled
+    }
+
+    pub fn default_read_your_writes_window() -> u64 {
+        1000
+    }
+
+    pub fn default_auth_query_cache_ttl() -> u64 {
+        300
+    }
+
+    pub fn default_auth_query_pool_size() -> u32 {
+        5
+    }
+
     pub fn validate(&self) -> Result<(), Error> {
         match self.default_role.as_ref() {
             "any" => (),
@@ -348,6 +664,16 @@ impl Pool {
             }
         };
 
+        if let Some(key) = &self.automatic_sharding_key {
+            if key.is_empty() || key.split('.').count() > 2 || key.split('.').any(str::is_empty) {
+                error!(
+                    "automatic_sharding_key must be 'column' or 'table.column', got: '{}'",
+                    key
+                );
+                return Err(Error::BadConfig);
+            }
+        }
+
         for (shard_idx, shard) in &self.shards {
             match shard_idx.parse::<usize>() {
                 Ok(_) => (),
@@ -362,6 +688,169 @@ impl Pool {
             shard.validate()?;
         }
 
+        for (table, column) in &self.sharding_keys {
+            if column.is_empty() || column.contains('.') {
+                error!(
+                    "The sharding key of table {} must be a column, got: '{}'",
+                    table, column
+                );
+                return Err(Error::BadConfig);
+            }
+
+            if self.reference_tables.contains(table) {
+                error!(
+                    "Table {} can't be a reference table and have a sharding key",
+                    table
+                );
+                return Err(Error::BadConfig);
+            }
+        }
+
+        match self.sharding_function {
+            ShardingFunction::Range | ShardingFunction::List
+                if self.sharding_key_type != ShardingKeyType::Bigint =>
+            {
+                error!(
+                    "{} sharding only supports bigint sharding keys, got: '{}'",
+                    self.sharding_function.to_string(),
+                    self.sharding_key_type
+                );
+                return Err(Error::BadConfig);
+            }
+            _ => (),
+        };
+
+        let mut ranges: Vec<(i64, i64, &String)> = Vec::new();
+        let mut keys: HashMap<i64, &String> = HashMap::new();
+
+        for (shard_idx, shard) in &self.shards {
+            for [start, end] in &shard.key_ranges {
+                if start >= end {
+                    error!(
+                        "Shard {} key range [{}, {}) is empty, the start must be lower than the end",
+                        shard_idx, start, end
+                    );
+                    return Err(Error::BadConfig);
+                }
+
+                ranges.push((*start, *end, shard_idx));
+            }
+
+            for key in &shard.keys {
+                if let Some(other) = keys.insert(*key, shard_idx) {
+                    error!(
+                        "Sharding key {} is in shards {} and {}, it must be in only one",
+                        key, other, shard_idx
+                    );
+                    return Err(Error::BadConfig);
+                }
+            }
+        }
+
+        ranges.sort_unstable();
+
+        for pair in ranges.windows(2) {
+            if pair[0].1 > pair[1].0 {
+                error!(
+                    "Key range [{}, {}) of shard {} overlaps [{}, {}) of shard {}",
+                    pair[0].0, pair[0].1, pair[0].2, pair[1].0, pair[1].1, pair[1].2
+                );
+                return Err(Error::BadConfig);
+            }
+        }
+
+        if self.sharding_function == ShardingFunction::Range && ranges.is_empty() {
+            error!("Range sharding needs key_ranges in the shards");
+            return Err(Error::BadConfig);
+        }
+
+        if self.sharding_function == ShardingFunction::List && keys.is_empty() {
+            error!("List sharding needs keys in the shards");
+            return Err(Error::BadConfig);
+        }
+
+        if let Some(auth_query) = &self.auth_query {
+            if !auth_query.contains("$1") {
+                error!(
+                    "auth_query must use $1 for the username, got: '{}'",
+                    auth_query
+                );
+                return Err(Error::BadConfig);
+            }
+
+            match (&self.auth_query_user, &self.auth_query_password) {
+                (Some(_), Some(password)) => {
+                    // We have to log into the server with it, without a client's proof.
+                    if password.starts_with("SCRAM-SHA-256$") {
+                        error!("auth_query_password can't be a SCRAM-SHA-256 verifier");
+                        return Err(Error::BadConfig);
+                    }
+                }
+                _ => {
+                    error!("auth_query is set, but auth_query_user or auth_query_password is not");
+                    return Err(Error::BadConfig);
+                }
+            };
+        }
+
+        // Verifying the servers' certificates needs the CA.
+        let verify = self
+            .shards
+            .values()
+            .filter_map(|shard| shard.server_tls_mode.or(self.server_tls_mode))
+            .any(|mode| mode == ServerTlsMode::VerifyCa || mode == ServerTlsMode::VerifyFull);
+
+        match &self.server_tls_ca_cert {
+            Some(ca_cert) => match load_certs(Path::new(ca_cert)) {
+                Ok(certs) if !certs.is_empty() => (),
+                Ok(_) => {
+                    error!("server_tls_ca_cert has no certificates: {}", ca_cert);
+                    return Err(Error::BadConfig);
+                }
+                Err(err) => {
+                    error!("server_tls_ca_cert is incorrectly configured: {:?}", err);
+                    return Err(Error::BadConfig);
+                }
+            },
+            None if verify => {
+                error!("server_tls_mode is verify-ca or verify-full, but server_tls_ca_cert is not set");
+                return Err(Error::BadConfig);
+            }
+            None => (),
+        };
+
+        match (&self.server_tls_certificate, &self.server_tls_private_key) {
+            (Some(certificate), Some(private_key)) => {
+                if let Err(err) = load_certs(Path::new(certificate)) {
+                    error!(
+                        "server_tls_certificate is incorrectly configured: {:?}",
+                        err
+                    );
+                    return Err(Error::BadConfig);
+                }
+
+                match load_keys(Path::new(private_key)) {
+                    Ok(keys) if !keys.is_empty() => (),
+                    Ok(_) => {
+                        error!("server_tls_private_key has no keys: {}", private_key);
+                        return Err(Error::BadConfig);
+                    }
+                    Err(err) => {
+                        error!(
+                            "server_tls_private_key is incorrectly configured: {:?}",
+                            err
+                        );
+                        return Err(Error::BadConfig);
+                    }
+                };
+            }
+            (None, None) => (),
+            _ => {
+                error!("server_tls_certificate and server_tls_private_key must be set together");
+                return Err(Error::BadConfig);
+            }
+        };
+
         Ok(())
     }
 }
@@ -378,8 +867,24 @@ impl Default for Pool {
             primary_reads_enabled: false,
             sharding_function: ShardingFunction::PgBigintHash,
             automatic_sharding_key: None,
+            sharding_key_type: ShardingKeyType::Bigint,
+            reference_tables: Vec::new(),
+            sharding_keys: BTreeMap::new(),
+            auth_method: None,
+            auth_query: None,
+            auth_query_user: None,
+            auth_query_password: None,
+            auth_query_cache_ttl: Self::default_auth_query_cache_ttl(),
+            auth_query_pool_size: Self::default_auth_query_pool_size(),
+            server_tls_mode: None,
+            server_tls_ca_cert: None,
+            server_tls_certificate: None,
+            server_tls_private_key: None,
             connect_timeout: None,
             idle_timeout: None,
+            max_replica_lag: None,
+            read_your_writes: Self::default_read_your_writes(),
+            read_your_writes_window: Self::default_read_your_writes_window(),
         }
     }
 }
@@ -396,6 +901,18 @@ pub struct ServerConfig {
 pub struct Shard {
     pub database: String,
     pub servers: Vec<ServerConfig>,
+
+    /// Overrides the pool's server_tls_mode.
+    pub server_tls_mode: Option<ServerTlsMode>,
+
+    /// Start (inclusive) and end (exclusive) of the sharding key ranges of this shard,
+    /// with range sharding.
+    #[serde(default)]
+    pub key_ranges: Vec<[i64; 2]>,
+
+    /// The sharding keys of this shard, with list sharding.
+    #[serde(default)]
+    pub keys: Vec<i64>,
 }
 
 impl Shard {
@@ -445,6 +962,9 @@ impl Default for Shard {
                 role: Role::Primary,
             }],
             database: String::from("postgres"),
+            server_tls_mode: None,
+            key_ranges: Vec::new(),
+            keys: Vec::new(),
         }
     }
 }
@@ -517,6 +1037,36 @@ impl From<&Config> for std::collections::HashMap<String, String> {
                         format!("pools.{}.sharding_function", pool_name),
                         pool.sharding_function.to_string(),
                     ),
+                    (
+                        format!("pools.{}.sharding_key_type", pool_name),
+                        pool.sharding_key_type.to_string(),
+                    ),
+                    (
+                        format!("pools.{}.auth_method", pool_name),
+                        pool.auth_method
+                            .unwrap_or(config.general.auth_method)
+                            .to_string(),
+                    ),
+                    (
+                        format!("pools.{}.server_tls_mode", pool_name),
+                        pool.server_tls_mode
+                            .unwrap_or(ServerTlsMode::Disable)
+                            .to_string(),
+                    ),
+                    (
+                        format!("pools.{}.auth_query", pool_name),
+                        pool.auth_query.clone().unwrap_or_default(),
+                    ),
+                    (
+                        format!("pools.{}.read_your_writes", pool_name),
+                        pool.read_your_writes.to_string(),
+                    ),
+                    (
+                        format!("pools.{}.max_replica_lag", pool_name),
+                        pool.max_replica_lag
+                            .map(|max_replica_lag| max_replica_lag.to_string())
+                            .unwrap_or_default(),
+                    ),
                     (
                         format!("pools.{:?}.shard_count", pool_name),
                         pool.shards.len().to_string(),
@@ -537,6 +1087,10 @@ impl From<&Config> for std::collections::HashMap<String, String> {
         let mut static_settings = vec![
             ("host".to_string(), config.general.host.to_string()),
             ("port".to_string(), config.general.port.to_string()),
+            (
+                "unix_socket_dir".to_string(),
+                config.general.unix_socket_dir.clone().unwrap_or_default(),
+            ),
             (
                 "prometheus_exporter_port".to_string(),
                 config.general.prometheus_exporter_port.to_string(),
@@ -562,6 +1116,26 @@ impl From<&Config> for std::collections::HashMap<String, String> {
                 config.general.healthcheck_delay.to_string(),
             ),
             ("ban_time".to_string(), config.general.ban_time.to_string()),
+            (
+                "role_check_interval".to_string(),
+                config.general.role_check_interval.to_string(),
+            ),
+            (
+                "replica_check_interval".to_string(),
+                config.general.replica_check_interval.to_string(),
+            ),
+            (
+                "auth_method".to_string(),
+                config.general.auth_method.to_string(),
+            ),
+            (
+                "client_tls_mode".to_string(),
+                config.general.client_tls_mode.to_string(),
+            ),
+            (
+                "prepared_statements".to_string(),
+                config.general.prepared_statements.to_string(),
+            ),
         ];
 
         r.append(&mut static_settings);
@@ -589,6 +1163,32 @@ impl Config {
         );
         info!("Shutdown timeout: {}ms", self.general.shutdown_timeout);
         info!("Healthcheck delay: {}ms", self.general.healthcheck_delay);
+        if self.general.prepared_statements {
+            info!(
+                "Prepared statements: enabled, {} per server connection",
+                self.general.prepared_statements_cache_size
+            );
+        }
+        if self.general.role_check_interval > 0 {
+            info!(
+                "Role check interval: {}ms",
+                self.general.role_check_interval
+            );
+        }
+        info!(
+            "Replica check interval: {}ms",
+            self.general.replica_check_interval
+        );
+        if let Some(unix_socket_dir) = &self.general.unix_socket_dir {
+            info!(
+                "Unix socket: {}/.s.PGSQL.{} (mode {})",
+                unix_socket_dir, self.general.port, self.general.unix_socket_mode
+            );
+        }
+        info!(
+            "Authentication method: {}",
+            self.general.auth_method.to_string()
+        );
         match self.general.tls_certificate.clone() {
             Some(tls_certificate) => {
                 info!("TLS certificate: {}", tls_certificate);
@@ -597,6 +1197,11 @@ impl Config {
                     Some(tls_private_key) => {
                         info!("TLS private key: {}", tls_private_key);
                         info!("TLS support is enabled");
+                        info!("Client TLS mode: {}", self.general.client_tls_mode);
+
+                        if let Some(tls_ca_cert) = &self.general.tls_ca_cert {
+                            info!("TLS CA certificate: {}", tls_ca_cert);
+                        }
                     }
 
                     None => (),
@@ -646,6 +1251,47 @@ impl Config {
                 pool_name,
                 pool_config.sharding_function.to_string()
             );
+            info!(
+                "[pool: {}] Sharding key type: {}",
+                pool_name,
+                pool_config.sharding_key_type.to_string()
+            );
+            info!(
+                "[pool: {}] Authentication method: {}",
+                pool_name,
+                pool_config
+                    .auth_method
+                    .unwrap_or(self.general.auth_method)
+                    .to_string()
+            );
+            info!(
+                "[pool: {}] Server TLS mode: {}",
+                pool_name,
+                pool_config
+                    .server_tls_mode
+                    .unwrap_or(ServerTlsMode::Disable)
+            );
+            if let Some(auth_query) = &pool_config.auth_query {
+                info!("[pool: {}] Auth query: {}", pool_name, auth_query);
+                info!(
+                    "[pool: {}] Auth query cache TTL: {}s",
+                    pool_name, pool_config.auth_query_cache_ttl
+                );
+            }
+            if let Some(max_replica_lag) = pool_config.max_replica_lag {
+                info!(
+                    "[pool: {}] Max replica lag: {}ms",
+                    pool_name, max_replica_lag
+                );
+            }
+            match pool_config.read_your_writes {
+                ReadYourWrites::Disabled => (),
+                ReadYourWrites::Window => info!(
+                    "[pool: {}] Read your writes: window of {}ms",
+                    pool_name, pool_config.read_your_writes_window
+                ),
+                ReadYourWrites::Lsn => info!("[pool: {}] Read your writes: lsn", pool_name),
+            };
             info!(
                 "[pool: {}] Primary reads: {}",
                 pool_name, pool_config.primary_reads_enabled
@@ -673,7 +1319,15 @@ impl Config {
                 info!(
                     "[pool: {}][user: {}] Statement timeout: {}",
                     pool_name, user.1.username, user.1.statement_timeout
-                )
+                );
+                if let Some(auth_method) = user.1.auth_method {
+                    info!(
+                        "[pool: {}][user: {}] Authentication method: {}",
+                        pool_name,
+                        user.1.username,
+                        auth_method.to_string()
+                    );
+                }
             }
         }
     }
@@ -710,14 +1364,107 @@ impl Config {
             None => (),
         };
 
+        if self.general.prepared_statements_cache_size == 0 {
+            error!("prepared_statements_cache_size must be greater than 0");
+            return Err(Error::BadConfig);
+        }
+
+        if self.general.replica_check_interval == 0 {
+            error!("replica_check_interval must be greater than 0");
+            return Err(Error::BadConfig);
+        }
+
+        if parse_file_mode(&self.general.unix_socket_mode).is_none() {
+            error!(
+                "unix_socket_mode must be an octal file mode, e.g. 0777, got: '{}'",
+                self.general.unix_socket_mode
+            );
+            return Err(Error::BadConfig);
+        }
+
+        if self.general.client_tls_mode.required() && self.general.tls_certificate.is_none() {
+            error!(
+                "client_tls_mode is {}, but tls_certificate is not set",
+                self.general.client_tls_mode
+            );
+            return Err(Error::BadConfig);
+        }
+
+        match &self.general.tls_ca_cert {
+            Some(tls_ca_cert) => match load_certs(Path::new(tls_ca_cert)) {
+                Ok(certs) if !certs.is_empty() => (),
+                Ok(_) => {
+                    error!("tls_ca_cert has no certificates: {}", tls_ca_cert);
+                    return Err(Error::BadConfig);
+                }
+                Err(err) => {
+                    error!("tls_ca_cert is incorrectly configured: {:?}", err);
+                    return Err(Error::BadConfig);
+                }
+            },
+            None if self.general.client_tls_mode.verify() => {
+                error!(
+                    "client_tls_mode is {}, but tls_ca_cert is not set",
+                    self.general.client_tls_mode
+                );
+                return Err(Error::BadConfig);
+            }
+            None => (),
+        };
+
         for pool in self.pools.values_mut() {
             pool.validate()?;
         }
 
+        // Make sure we can authenticate clients with the configured passwords.
+        validate_password(
+            &self.general.admin_username,
+            &self.general.admin_password,
+            self.general.auth_method,
+        )?;
+
+        for pool in self.pools.values() {
+            for user in pool.users.values() {
+                let auth_method = user
+                    .auth_method
+                    .or(pool.auth_method)
+                    .unwrap_or(self.general.auth_method);
+
+                validate_password(&user.username, &user.password, auth_method)?;
+            }
+        }
+
         Ok(())
     }
 }
 
+/// Parse an octal file mode, e.g. "0777" or "755".
+pub fn parse_file_mode(mode: &str) -> Option<u32> {
+    match u32::from_str_radix(mode.trim_start_matches("0o"), 8) {
+        Ok(mode) if mode <= 0o7777 => Some(mode),
+        _ => None,
+    }
+}
+
+/// Passwords can be in cleartext, or hashed like in pg_authid:
+/// an md5 hash or a SCRAM-SHA-256 verifier. An md5 hash can't be used for SCRAM.
+fn validate_password(username: &str, password: &str, auth_method: AuthMethod) -> Result<(), Error> {
+    if password.starts_with("SCRAM-SHA-256$") && ScramSecret::parse(password).is_none() {
+        error!("User {} has an invalid SCRAM-SHA-256 verifier", username);
+        return Err(Error::BadConfig);
+    }
+
+    if is_md5_hash(password) && auth_method == AuthMethod::ScramSha256 {
+        error!(
+            "User {} has an md5 password hash, which can't be used with {} authentication",
+            username, auth_method
+        );
+        return Err(Error::BadConfig);
+    }
+
+    Ok(())
+}
+
 /// Get a read-only instance of the configuration
 /// from anywhere in the app.
 /// ArcSwap makes this cheap and quick.
@@ -756,6 +1503,9 @@ pub async fn parse(path: &str) -> Result<(), Error> {
 
     config.path = path.to_string();
 
+    // Pick up new client certificates, even if the config didn't change.
+    reload_tls(&config.general)?;
+
     // Update the configuration globally.
     CONFIG.store(Arc::new(config.clone()));
 
@@ -763,6 +1513,8 @@ pub async fn parse(path: &str) -> Result<(), Error> {
 }
 
 pub async fn reload_config(client_server_map: ClientServerMap) -> Result<bool, Error> {
+    let _change = CONFIG_CHANGE.lock().await;
+
     let old_config = get_config();
     match parse(&old_config.path).await {
         Ok(()) => (),
@@ -775,7 +1527,7 @@ pub async fn reload_config(client_server_map: ClientServerMap) -> Result<bool, E
 
     if old_config.pools != new_config.pools {
         info!("Pool configuration changed");
-        ConnectionPool::from_config(client_server_map).await?;
+        ConnectionPool::from_config(&new_config, client_server_map).await?;
         Ok(true)
     } else if old_config != new_config {
         Ok(true)
@@ -784,6 +1536,139 @@ pub async fn reload_config(client_server_map: ClientServerMap) -> Result<bool, E
     }
 }
 
+/// The config with one setting changed, e.g. `general.ban_time` or `pools.sharded_db.pool_mode`.
+/// The value is TOML, e.g. 30, true or 'session'; an unquoted word is a string.
+fn with_setting(config: &Config, key: &str, value: &str) -> Result<Config, String> {
+    let path: Vec<&str> = key.split('.').collect();
+    if path.len() < 2 || !["general", "pools"].contains(&path[0]) {
+        return Err(format!(
+            "unknown setting {}, expected e.g. general.ban_time or pools.<pool>.pool_mode",
+            key
+        ));
+    }
+
+    let value = match toml::from_str::<toml::Table>(&format!("value = {}", value)) {
+        Ok(mut table) => table.remove("value").unwrap(),
+        Err(_) => toml::Value::String(value.to_string()),
+    };
+
+    let mut settings = match toml::Value::try_from(config) {
+        Ok(settings) => settings,
+        Err(err) => return Err(format!("could not serialize the config: {}", err)),
+    };
+
+    let (setting, tables) = path.split_last().unwrap();
+    let mut table = settings.as_table_mut().unwrap();
+    for name in tables {
+        table = match table.get_mut(*name).and_then(|value| value.as_table_mut()) {
+            Some(table) => table,
+            None => return Err(format!("unknown setting {}", key)),
+        };
+    }
+    table.insert(setting.to_string(), value);
+
+    let config: Config = match settings.try_into() {
+        Ok(config) => config,
+        Err(err) => return Err(format!("invalid value for {}: {}", key, err)),
+    };
+
+    // Unknown settings are dropped when deserializing.
+    let known = toml::Value::try_from(&config)
+        .ok()
+        .and_then(|settings| {
+            path.iter()
+                .try_fold(&settings, |value, name| value.get(*name))
+                .cloned()
+        })
+        .is_some();
+
+    match known {
+        true => Ok(config),
+        false => Err(format!("unknown setting {}", key)),
+    }
+}
+
+/// Change a setting at runtime, like editing the config file and reloading it.
+pub async fn set_config(
+    key: &str,
+    value: &str,
+    client_server_map: ClientServerMap,
+) -> Result<(), String> {
+    let _change = CONFIG_CHANGE.lock().await;
+
+    let old_config = get_config();
+    let mut new_config = with_setting(&old_config, key, value)?;
+
+    if new_config.validate().is_err() || reload_tls(&new_config.general).is_err() {
+        return Err(format!("{} = {} is not valid, see the log", key, value));
+    }
+
+    // The clients see the new config once its pools are ready.
+    if old_config.pools != new_config.pools {
+        info!("Pool configuration changed");
+
+        if let Err(err) = ConnectionPool::from_config(&new_config, client_server_map).await {
+            return Err(format!("could not create the pools: {:?}", err));
+        }
+    }
+
+    CONFIG.store(Arc::new(new_config));
+
+    Ok(())
+}
+
+/// Write the config in effect, e.g. after SET, to its file. Comments are not kept.
+pub async fn save_config() -> Result<String, String> {
+    let config = get_config();
+
+    write_config(&config, &config.path).await?;
+
+    Ok(config.path)
+}
+
+/// Replace the config file with the config.
+async fn write_config(config: &Config, path: &str) -> Result<(), String> {
+    let contents = match toml::to_string(config) {
+        Ok(contents) => contents,
+        Err(err) => return Err(format!("could not serialize the config: {}", err)),
+    };
+
+    // The file has passwords: the copy gets the permissions of the original before they go in.
+    let permissions = match tokio::fs::metadata(path).await {
+        Ok(metadata) => metadata.permissions(),
+        Err(_) => std::fs::Permissions::from_mode(0o600),
+    };
+
+    // Write a copy first, so a failed write doesn't leave a broken config behind.
+    let copy = format!("{}.tmp", path);
+    let mut file = match tokio::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&copy)
+        .await
+    {
+        Ok(file) => file,
+        Err(err) => return Err(format!("could not write {}: {}", copy, err)),
+    };
+
+    let written = async {
+        tokio::fs::set_permissions(&copy, permissions).await?;
+        file.write_all(contents.as_bytes()).await?;
+        file.sync_all().await
+    };
+
+    if let Err(err) = written.await {
+        return Err(format!("could not write {}: {}", copy, err));
+    }
+
+    match tokio::fs::rename(&copy, path).await {
+        Ok(()) => Ok(()),
+        Err(err) => Err(format!("could not write {}: {}", path, err)),
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -796,6 +1681,8 @@ mod test {
 
         assert_eq!(get_config().general.ban_time, 60);
         assert_eq!(get_config().general.idle_timeout, 30000);
+        assert_eq!(get_config().general.auth_method, AuthMethod::Md5);
+        assert_eq!(General::default().auth_method, AuthMethod::Md5);
         assert_eq!(get_config().pools.len(), 2);
         assert_eq!(get_config().pools["sharded_db"].shards.len(), 3);
         assert_eq!(get_config().pools["sharded_db"].idle_timeout, Some(40000));
@@ -824,6 +1711,10 @@ mod test {
             "other_user"
         );
         assert_eq!(get_config().pools["sharded_db"].users["1"].pool_size, 21);
+        assert_eq!(
+            get_config().pools["sharded_db"].users["1"].auth_method,
+            Some(AuthMethod::Md5)
+        );
         assert_eq!(get_config().pools["sharded_db"].default_role, "any");
 
         assert_eq!(
@@ -851,9 +1742,176 @@ mod test {
         assert_eq!(get_config().pools["simple_db"].users["0"].pool_size, 5);
     }
 
+    #[test]
+    fn test_validate_password() {
+        let md5 = "md5d5d2b7621f9dfa5d09376c9c966bf109";
+        let scram = "SCRAM-SHA-256$4096:fs3IXBy7U7+IvVjZ$tmgbRn9qfDg3ip++wAxsFIk0Zl9PF0NDB5npDjVeECM=:bSU5hgS4vu9S/BceyyM0+b0RxdaRtdH5LRjmCPcHxhg=";
+
+        assert!(validate_password("user", "foobar", AuthMethod::ScramSha256).is_ok());
+        assert!(validate_password("user", md5, AuthMethod::Md5).is_ok());
+        assert!(validate_password("user", md5, AuthMethod::ScramSha256).is_err());
+        assert!(validate_password("user", scram, AuthMethod::ScramSha256).is_ok());
+        assert!(validate_password("user", scram, AuthMethod::Md5).is_ok());
+        assert!(validate_password("user", "SCRAM-SHA-256$4096:abc", AuthMethod::Md5).is_err());
+    }
+
+    #[test]
+    fn test_validate_shard_keys() {
+        let mut pool = Pool {
+            sharding_function: ShardingFunction::Range,
+            ..Default::default()
+        };
+        assert!(pool.validate().is_err());
+
+        for (shard_idx, key_ranges) in [("0", vec![[0, 100]]), ("1", vec![[100, 200], [300, 400]])]
+        {
+            pool.shards.insert(
+                shard_idx.to_string(),
+                Shard {
+                    key_ranges,
+                    ..Default::default()
+                },
+            );
+        }
+        assert!(pool.validate().is_ok());
+        assert_eq!(
+            pool.shard_keys(),
+            ShardKeys::new(
+                vec![(0, 100, 0), (100, 200, 1), (300, 400, 1)],
+                HashMap::new()
+            )
+        );
+
+        pool.shards.get_mut("0").unwrap().key_ranges = vec![[0, 150]];
+        assert!(pool.validate().is_err());
+
+        pool.shards.get_mut("0").unwrap().key_ranges = vec![[100, 0]];
+        assert!(pool.validate().is_err());
+
+        pool.sharding_function = ShardingFunction::List;
+        pool.shards.get_mut("0").unwrap().keys = vec![1, 2];
+        pool.shards.get_mut("1").unwrap().keys = vec![2];
+        assert!(pool.validate().is_err());
+
+        pool.shards.get_mut("0").unwrap().key_ranges = Vec::new();
+        pool.shards.get_mut("1").unwrap().keys = vec![3];
+        assert!(pool.validate().is_ok());
+
+        pool.sharding_key_type = ShardingKeyType::Text;
+        assert!(pool.validate().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_with_setting() {
+        let mut file = String::new();
+        File::open("pgcat.toml")
+            .await
+            .unwrap()
+            .read_to_string(&mut file)
+            .await
+            .unwrap();
+        let config: Config = toml::from_str(&file).unwrap();
+
+        let changed = with_setting(&config, "general.ban_time", "30").unwrap();
+        assert_eq!(changed.general.ban_time, 30);
+        assert_eq!(changed.pools, config.pools);
+
+        let changed = with_setting(&config, "pools.sharded_db.pool_mode", "'session'").unwrap();
+        assert_eq!(changed.pools["sharded_db"].pool_mode, PoolMode::Session);
+        let changed = with_setting(&config, "pools.sharded_db.pool_mode", "session").unwrap();
+        assert_eq!(changed.pools["sharded_db"].pool_mode, PoolMode::Session);
+
+        let changed = with_setting(&config, "general.tls_certificate", "'server.cert'").unwrap();
+        assert_eq!(
+            changed.general.tls_certificate,
+            Some(String::from("server.cert"))
+        );
+
+        assert!(with_setting(&config, "general.ban_time", "'soon'").is_err());
+        assert!(with_setting(&config, "pools.sharded_db.pool_mode", "'sometimes'").is_err());
+        assert!(with_setting(&config, "general.bantime", "30").is_err());
+        assert!(with_setting(&config, "pools.missing_db.pool_mode", "'session'").is_err());
+        assert!(with_setting(&config, "path", "'other.toml'").is_err());
+        assert!(with_setting(&config, "general", "30").is_err());
+    }
+
+    #[test]
+    fn test_parse_file_mode() {
+        assert_eq!(parse_file_mode("0777"), Some(0o777));
+        assert_eq!(parse_file_mode("755"), Some(0o755));
+        assert_eq!(parse_file_mode("0o700"), Some(0o700));
+        assert_eq!(parse_file_mode("0999"), None);
+        assert_eq!(parse_file_mode("rwx"), None);
+    }
+
+    #[tokio::test]
+    async fn test_scram_config() {
+        let mut file = String::new();
+        File::open("pgcat.toml")
+            .await
+            .unwrap()
+            .read_to_string(&mut file)
+            .await
+            .unwrap();
+        let config: Config = toml::from_str(&file).unwrap();
+
+        let mut config = with_setting(&config, "general.auth_method", "'scram-sha-256'").unwrap();
+        config.validate().unwrap();
+        assert_eq!(config.general.auth_method, AuthMethod::ScramSha256);
+        assert_eq!(
+            config.pools["sharded_db"].users["1"].auth_method,
+            Some(AuthMethod::Md5)
+        );
+
+        // An md5 hash can't be used once the user falls back to SCRAM.
+        let user = config
+            .pools
+            .get_mut("sharded_db")
+            .unwrap()
+            .users
+            .get_mut("0")
+            .unwrap();
+        user.password = "md5d5d2b7621f9dfa5d09376c9c966bf109".to_string();
+        assert!(config.validate().is_err());
+
+        config.pools.get_mut("sharded_db").unwrap().auth_method = Some(AuthMethod::Md5);
+        config.validate().unwrap();
+    }
+
     #[tokio::test]
     async fn test_serialize_configs() {
         parse("pgcat.toml").await.unwrap();
         print!("{}", toml::to_string(&get_config()).unwrap());
     }
+
+    #[tokio::test]
+    async fn test_write_config() {
+        let mut file = String::new();
+        File::open("pgcat.toml")
+            .await
+            .unwrap()
+            .read_to_string(&mut file)
+            .await
+            .unwrap();
+        let mut config: Config = toml::from_str(&file).unwrap();
+        config.validate().unwrap();
+
+        let saved: Config = toml::from_str(&toml::to_string(&config).unwrap()).unwrap();
+        assert_eq!(saved, config);
+
+        // The permissions of the file are kept.
+        let path = std::env::temp_dir().join(format!("pgcat-{}.toml", std::process::id()));
+        let path = path.to_str().unwrap();
+        std::fs::write(path, "").unwrap();
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o640)).unwrap();
+
+        write_config(&config, path).await.unwrap();
+
+        let mode = std::fs::metadata(path).unwrap().permissions().mode();
+        let saved: Config = toml::from_str(&std::fs::read_to_string(path).unwrap()).unwrap();
+        std::fs::remove_file(path).unwrap();
+
+        assert_eq!(mode & 0o777, 0o640);
+        assert_eq!(saved, config);
+    }
 }
@@ -0,0 +1,539 @@
+/// Split the rows of a `COPY ... FROM STDIN` between the shards,
+/// by the value of the sharding key in each row.
+use bytes::BytesMut;
+use sqlparser::ast::{CopyLegacyCsvOption, CopyLegacyOption, CopyOption};
+
+use crate::sharding::{Sharder, ShardingKeyType};
+
+/// The formats of COPY we can split, binary is not one of them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CopyFormat {
+    Text,
+    Csv,
+}
+
+/// A row and the shard it goes to, or None if it goes to all of them, like the header.
+pub type CopyRow = (Option<usize>, BytesMut);
+
+/// Splits the data of a COPY into rows and finds their shard.
+pub struct CopySplitter {
+    format: CopyFormat,
+    delimiter: u8,
+    quote: u8,
+    escape: u8,
+    null: String,
+
+    /// The first row is the header, all the shards get it.
+    header: bool,
+
+    /// The position of the sharding key in the rows.
+    column: usize,
+
+    sharder: Sharder,
+    key_type: ShardingKeyType,
+
+    /// The start of a row that ends in the next CopyData.
+    partial: BytesMut,
+
+    /// How far we looked for the end of the partial row,
+    /// and if we are in a quoted CSV value there.
+    scanned: usize,
+    in_quotes: bool,
+
+    /// How many rows we split, for the errors.
+    rows: usize,
+
+    /// We got the end-of-data marker `\.`, the rest is ignored.
+    ended: bool,
+}
+
+impl CopySplitter {
+    /// Split the rows of a COPY with these options, or explain why we can't.
+    pub fn new(
+        options: &[CopyOption],
+        legacy_options: &[CopyLegacyOption],
+        column: usize,
+        sharder: Sharder,
+        key_type: ShardingKeyType,
+    ) -> Result<CopySplitter, String> {
+        let mut format = CopyFormat::Text;
+        let mut delimiter = None;
+        let mut null = None;
+        let mut header = false;
+        let mut quote = None;
+        let mut escape = None;
+
+        for option in options {
+            match option {
+                CopyOption::Format(name) => {
+                    format = match name.value.to_lowercase().as_str() {
+                        "text" => CopyFormat::Text,
+                        "csv" => CopyFormat::Csv,
+                        other => return Err(unsupported(&format!("the {} format", other))),
+                    }
+                }
+                CopyOption::Delimiter(value) => delimiter = Some(*value),
+                CopyOption::Null(value) => null = Some(value.clone()),
+                CopyOption::Header(value) => header = *value,
+                CopyOption::Quote(value) => quote = Some(*value),
+                CopyOption::Escape(value) => escape = Some(*value),
+                _ => (),
+            };
+        }
+
+        for option in legacy_options {
+            match option {
+                CopyLegacyOption::Binary => return Err(unsupported("the binary format")),
+                CopyLegacyOption::Delimiter(value) => delimiter = Some(*value),
+                CopyLegacyOption::Null(value) => null = Some(value.clone()),
+                CopyLegacyOption::Csv(options) => {
+                    format = CopyFormat::Csv;
+
+                    for option in options {
+                        match option {
+                            CopyLegacyCsvOption::Header => header = true,
+                            CopyLegacyCsvOption::Quote(value) => quote = Some(*value),
+                            CopyLegacyCsvOption::Escape(value) => escape = Some(*value),
+                            _ => (),
+                        };
+                    }
+                }
+            };
+        }
+
+        let (default_delimiter, default_null) = match format {
+            CopyFormat::Text => ('\t', "\\N"),
+            CopyFormat::Csv => (',', ""),
+        };
+
+        let quote = single_byte(quote.unwrap_or('"'))?;
+
+        Ok(CopySplitter {
+            format,
+            delimiter: single_byte(delimiter.unwrap_or(default_delimiter))?,
+            quote,
+            escape: match escape {
+                Some(escape) => single_byte(escape)?,
+                None => quote,
+            },
+            null: null.unwrap_or_else(|| default_null.to_string()),
+            header,
+            column,
+            sharder,
+            key_type,
+            partial: BytesMut::new(),
+            scanned: 0,
+            in_quotes: false,
+            rows: 0,
+            ended: false,
+        })
+    }
+
+    /// Split the data of a CopyData message into rows.
+    /// The last one may end in the next message, we keep it until then.
+    pub fn split(&mut self, data: &[u8]) -> Result<Vec<CopyRow>, String> {
+        self.partial.extend_from_slice(data);
+
+        let mut rows = Vec::new();
+
+        while let Some(end) = self.row_end() {
+            let row = self.partial.split_to(end + 1);
+            self.scanned = 0;
+            rows.extend(self.route(row)?);
+        }
+
+        Ok(rows)
+    }
+
+    /// The last row, if it doesn't end with a newline.
+    pub fn finish(&mut self) -> Result<Option<CopyRow>, String> {
+        if self.partial.is_empty() {
+            return Ok(None);
+        }
+
+        if self.in_quotes {
+            return Err(String::from("unterminated CSV quoted field in COPY data"));
+        }
+
+        let row = self.partial.split();
+        self.scanned = 0;
+        self.route(row)
+    }
+
+    /// Where the partial row ends, if we have all of it.
+    fn row_end(&mut self) -> Option<usize> {
+        while self.scanned < self.partial.len() {
+            let byte = self.partial[self.scanned];
+
+            let escaped = match self.format {
+                // A backslash escapes the next character, even a newline.
+                CopyFormat::Text => byte == b'\\',
+                CopyFormat::Csv => {
+                    self.in_quotes && byte == self.escape && self.escape != self.quote
+                }
+            };
+
+            if escaped {
+                // The escaped character is in the next CopyData.
+                if self.scanned + 1 == self.partial.len() {
+                    return None;
+                }
+
+                self.scanned += 2;
+                continue;
+            }
+
+            if self.format == CopyFormat::Csv && byte == self.quote {
+                self.in_quotes = !self.in_quotes;
+            } else if byte == b'\n' && !self.in_quotes {
+                return Some(self.scanned);
+            }
+
+            self.scanned += 1;
+        }
+
+        None
+    }
+
+    /// The shard of a row.
+    fn route(&mut self, row: BytesMut) -> Result<Option<CopyRow>, String> {
+        if self.ended {
+            return Ok(None);
+        }
+
+        let line = row
+            .strip_suffix(b"\n")
+            .map(|line| line.strip_suffix(b"\r").unwrap_or(line))
+            .unwrap_or(&row);
+
+        if line == b"\\." {
+            self.ended = true;
+            return Ok(None);
+        }
+
+        self.rows += 1;
+
+        if self.header && self.rows == 1 {
+            return Ok(Some((None, row)));
+        }
+
+        let value = match self.format {
+            CopyFormat::Text => self.text_value(line),
+            CopyFormat::Csv => self.csv_value(line),
+        }?;
+
+        let value = match value {
+            Some(value) => value,
+            None => return Err(format!("sharding key is NULL in COPY row {}", self.rows)),
+        };
+
+        match self.sharder.shard_str(&value, self.key_type) {
+            Some(shard) => Ok(Some((Some(shard), row))),
+            None => Err(format!(
+                "sharding key {} in COPY row {} is not a valid {} or not in any shard",
+                value, self.rows, self.key_type
+            )),
+        }
+    }
+
+    /// The sharding key in a row of the text format, None if it's NULL.
+    fn text_value(&self, line: &[u8]) -> Result<Option<String>, String> {
+        let mut start = 0;
+        let mut index = 0;
+        let mut i = 0;
+        let mut field = None;
+
+        while i < line.len() {
+            if line[i] == b'\\' {
+                i += 2;
+                continue;
+            }
+
+            if line[i] == self.delimiter {
+                if index == self.column {
+                    field = Some(&line[start..i]);
+                    break;
+                }
+
+                index += 1;
+                start = i + 1;
+            }
+
+            i += 1;
+        }
+
+        let field = match field {
+            Some(field) => field,
+            None if index == self.column => &line[start..],
+            None => return Err(self.missing_column()),
+        };
+
+        if field == self.null.as_bytes() {
+            return Ok(None);
+        }
+
+        self.utf8(unescape(field)).map(Some)
+    }
+
+    /// The sharding key in a row of the CSV format, None if it's NULL.
+    fn csv_value(&self, line: &[u8]) -> Result<Option<String>, String> {
+        let mut index = 0;
+        let mut value = Vec::new();
+        let mut quoted = false;
+        let mut in_quotes = false;
+        let mut i = 0;
+
+        while i < line.len() {
+            let byte = line[i];
+
+            if in_quotes {
+                match line.get(i + 1) {
+                    Some(next)
+                        if byte == self.escape && (*next == self.quote || *next == self.escape) =>
+                    {
+                        value.push(*next);
+                        i += 2;
+                        continue;
+                    }
+                    _ if byte == self.quote => in_quotes = false,
+                    _ => value.push(byte),
+                };
+            } else if byte == self.quote {
+                in_quotes = true;
+                quoted = true;
+            } else if byte == self.delimiter {
+                if index == self.column {
+                    break;
+                }
+
+                index += 1;
+                value.clear();
+                quoted = false;
+            } else {
+                value.push(byte);
+            }
+
+            i += 1;
+        }
+
+        if index != self.column {
+            return Err(self.missing_column());
+        }
+
+        if !quoted && value == self.null.as_bytes() {
+            return Ok(None);
+        }
+
+        self.utf8(value).map(Some)
+    }
+
+    fn missing_column(&self) -> String {
+        format!(
+            "COPY row {} doesn't have the sharding key column",
+            self.rows
+        )
+    }
+
+    fn utf8(&self, value: Vec<u8>) -> Result<String, String> {
+        String::from_utf8(value)
+            .map_err(|_| format!("sharding key in COPY row {} is not valid UTF-8", self.rows))
+    }
+}
+
+/// Replace the backslash escapes of the text format, e.g. `\t` or `\041`.
+fn unescape(field: &[u8]) -> Vec<u8> {
+    let mut value = Vec::with_capacity(field.len());
+    let mut i = 0;
+
+    while i < field.len() {
+        if field[i] != b'\\' || i + 1 == field.len() {
+            value.push(field[i]);
+            i += 1;
+            continue;
+        }
+
+        let next = field[i + 1];
+        i += 2;
+
+        match next {
+            b'b' => value.push(8),
+            b'f' => value.push(12),
+            b'n' => value.push(b'\n'),
+            b'r' => value.push(b'\r'),
+            b't' => value.push(b'\t'),
+            b'v' => value.push(11),
+
+            // Octal, up to 3 digits.
+            b'0'..=b'7' => {
+                let mut byte = (next - b'0') as u32;
+                let mut digits = 1;
+
+                while let Some(digit @ b'0'..=b'7') = field.get(i) {
+                    if digits == 3 {
+                        break;
+                    }
+
+                    byte = byte * 8 + (digit - b'0') as u32;
+                    digits += 1;
+                    i += 1;
+                }
+
+                value.push(byte as u8);
+            }
+
+            // Hexadecimal, up to 2 digits.
+            b'x' if field.get(i).map_or(false, u8::is_ascii_hexdigit) => {
+                let end = (i + 2).min(field.len());
+                let digits = &field[i..end];
+                let digits = match digits.iter().position(|digit| !digit.is_ascii_hexdigit()) {
+                    Some(position) => &digits[..position],
+                    None => digits,
+                };
+
+                value.push(u8::from_str_radix(std::str::from_utf8(digits).unwrap(), 16).unwrap());
+                i += digits.len();
+            }
+
+            other => value.push(other),
+        };
+    }
+
+    value
+}
+
+/// Delimiters and quotes are one byte.
+fn single_byte(value: char) -> Result<u8, String> {
+    match value.is_ascii() {
+        true => Ok(value as u8),
+        false => Err(unsupported(&format!("the COPY option '{}'", value))),
+    }
+}
+
+fn unsupported(what: &str) -> String {
+    format!(
+        "COPY with {} is not supported on several shards, send it to one shard with SET SHARD",
+        what
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::sharding::ShardingFunction;
+    use sqlparser::ast::Statement;
+    use sqlparser::dialect::PostgreSqlDialect;
+    use sqlparser::parser::Parser;
+
+    fn splitter(query: &str, column: usize) -> Result<CopySplitter, String> {
+        let ast = Parser::parse_sql(&PostgreSqlDialect {}, &format!("{};", query)).unwrap();
+
+        match &ast[0] {
+            Statement::Copy {
+                options,
+                legacy_options,
+                ..
+            } => CopySplitter::new(
+                options,
+                legacy_options,
+                column,
+                Sharder::new(3, ShardingFunction::PgBigintHash),
+                ShardingKeyType::Bigint,
+            ),
+            _ => panic!("not a COPY"),
+        }
+    }
+
+    fn shard(id: i64) -> Option<usize> {
+        Sharder::new(3, ShardingFunction::PgBigintHash).shard(id)
+    }
+
+    #[test]
+    fn test_split_text() {
+        let mut copy = splitter("COPY users (name, id) FROM STDIN", 1).unwrap();
+
+        let rows = copy.split(b"a\t1\nb\\tc\t2\nd\\\ne\t").unwrap();
+        assert_eq!(
+            rows,
+            vec![
+                (shard(1), BytesMut::from(&b"a\t1\n"[..])),
+                (shard(2), BytesMut::from(&b"b\\tc\t2\n"[..])),
+            ]
+        );
+
+        // The row continues in the next CopyData.
+        let rows = copy.split(b"3\r\nf\t\\061\\x32\n").unwrap();
+        assert_eq!(
+            rows,
+            vec![
+                (shard(3), BytesMut::from(&b"d\\\ne\t3\r\n"[..])),
+                (shard(12), BytesMut::from(&b"f\t\\061\\x32\n"[..])),
+            ]
+        );
+
+        assert!(copy.split(b"g\t\\N\n").is_err());
+
+        let mut copy = splitter("COPY users (name, id) FROM STDIN", 1).unwrap();
+        assert_eq!(copy.split(b"a\t4").unwrap(), vec![]);
+        assert_eq!(
+            copy.finish().unwrap(),
+            Some((shard(4), BytesMut::from(&b"a\t4"[..])))
+        );
+
+        assert!(copy.split(b"a\n").is_err());
+        assert!(copy.split(b"a\tnot a number\n").is_err());
+
+        // The end-of-data marker.
+        let mut copy = splitter("COPY users (id) FROM STDIN", 0).unwrap();
+        assert_eq!(copy.split(b"5\n\\.\n6\n").unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_split_csv() {
+        let mut copy = splitter(
+            "COPY users (name, id) FROM STDIN WITH (FORMAT csv, HEADER true)",
+            1,
+        )
+        .unwrap();
+
+        let rows = copy
+            .split(b"name,id\n\"a,\"\"b\nc\",1\n\"d\",\"2\"\n")
+            .unwrap();
+        assert_eq!(
+            rows,
+            vec![
+                (None, BytesMut::from(&b"name,id\n"[..])),
+                (shard(1), BytesMut::from(&b"\"a,\"\"b\nc\",1\n"[..])),
+                (shard(2), BytesMut::from(&b"\"d\",\"2\"\n"[..])),
+            ]
+        );
+
+        assert!(copy.split(b"e,\n").is_err());
+
+        let mut copy = splitter(
+            "COPY users (id, name) FROM STDIN WITH (FORMAT csv, DELIMITER ';', ESCAPE '\\')",
+            0,
+        )
+        .unwrap();
+
+        assert_eq!(copy.split(b"7;\"a\\\"").unwrap(), vec![]);
+        assert_eq!(
+            copy.split(b"\n\";b\n").unwrap(),
+            vec![(shard(7), BytesMut::from(&b"7;\"a\\\"\n\";b\n"[..]))]
+        );
+
+        assert!(copy.split(b"8;\"unterminated").is_ok());
+        assert!(copy.finish().is_err());
+
+        let mut copy = splitter("COPY users (id) FROM STDIN CSV HEADER", 0).unwrap();
+        assert_eq!(copy.split(b"id\n9\n").unwrap().len(), 2);
+
+        assert!(splitter("COPY users (id) FROM STDIN BINARY", 0).is_err());
+        assert!(splitter("COPY users (id) FROM STDIN WITH (FORMAT binary)", 0).is_err());
+    }
+
+    #[test]
+    fn test_unescape() {
+        assert_eq!(unescape(b"a\\tb\\\\c\\N"), b"a\tb\\cN");
+        assert_eq!(unescape(b"\\101\\1\\x41\\x4g\\"), b"A\x01A\x04g\\");
+    }
+}
@@ -1,12 +1,17 @@
+pub mod auth_query;
 pub mod config;
 pub mod constants;
+pub mod copy;
 pub mod errors;
+pub mod merge;
 pub mod messages;
 pub mod pool;
+pub mod prepared_statements;
 pub mod scram;
 pub mod server;
 pub mod sharding;
 pub mod stats;
+pub mod stream;
 pub mod tls;
 
 /// Format chrono::Duration to be more human-friendly.
@@ -47,7 +47,7 @@ static GLOBAL: Jemalloc = Jemalloc;
 use log::{debug, error, info, warn};
 use parking_lot::Mutex;
 use pgcat::format_duration;
-use tokio::net::TcpListener;
+use tokio::net::{TcpListener, UnixListener, UnixStream};
 use tokio::{
     runtime::Builder,
     signal::unix::{signal as unix_signal, SignalKind},
@@ -56,27 +56,35 @@ use tokio::{
 
 use std::collections::HashMap;
 use std::net::SocketAddr;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 use std::sync::Arc;
 use tokio::sync::broadcast;
 
 mod admin;
+mod auth_query;
 mod client;
 mod config;
 mod constants;
+mod copy;
 mod errors;
+mod merge;
 mod messages;
 mod pool;
+mod prepared_statements;
 mod prometheus;
 mod query_router;
 mod scram;
 mod server;
 mod sharding;
 mod stats;
+mod stream;
 mod tls;
 
-use crate::config::{get_config, reload_config, VERSION};
-use crate::pool::{ClientServerMap, ConnectionPool};
+use crate::client::ClientAddress;
+use crate::config::{get_config, parse_file_mode, reload_config, VERSION};
+use crate::pool::{check_replicas, check_roles, ClientServerMap, ConnectionPool};
 use crate::prometheus::start_metric_server;
 use crate::stats::{Collector, Reporter, REPORTER};
 
@@ -152,6 +160,42 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 
 	info!("Running on {}", addr);
 
+	let unix_listener = match &config.general.unix_socket_dir {
+            Some(unix_socket_dir) => {
+		let path = Path::new(unix_socket_dir).join(format!(".s.PGSQL.{}", config.general.port));
+
+		// Don't take the socket of a pooler that's still running.
+		if std::os::unix::net::UnixStream::connect(&path).is_ok() {
+                    error!("Another process is accepting connections on {}", path.display());
+                    std::process::exit(exitcode::CONFIG);
+		}
+
+		// Remove the socket left behind by a previous run.
+		let _ = std::fs::remove_file(&path);
+
+		let listener = match UnixListener::bind(&path) {
+                    Ok(listener) => listener,
+                    Err(err) => {
+			error!("Unix socket error: {:?}", err);
+			std::process::exit(exitcode::CONFIG);
+                    }
+		};
+
+		let mode = parse_file_mode(&config.general.unix_socket_mode).unwrap();
+
+		if let Err(err) = std::fs::set_permissions(&path, std::fs::Permissions::from_mode(mode)) {
+                    error!("Could not set the Unix socket permissions: {:?}", err);
+                    std::process::exit(exitcode::CONFIG);
+		}
+
+		info!("Running on {}", path.display());
+
+		Some((listener, path))
+            }
+
+            None => None,
+	};
+
 	config.show();
 
 	// Tracks which client is connected to which server for query cancellation.
@@ -162,7 +206,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 	REPORTER.store(Arc::new(Reporter::new(stats_tx.clone())));
 
 	// Connection pool that allows to query all shards and replicas.
-	match ConnectionPool::from_config(client_server_map.clone()).await {
+	match ConnectionPool::from_config(&config, client_server_map.clone()).await {
             Ok(_) => (),
             Err(err) => {
 		error!("Pool error: {:?}", err);
@@ -194,6 +238,31 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             }
 	});
 
+	// Find out which servers are the primaries, e.g. after a failover.
+	tokio::task::spawn(async move {
+            loop {
+		let role_check_interval = get_config().general.role_check_interval;
+
+		if role_check_interval == 0 {
+                    tokio::time::sleep(tokio::time::Duration::from_millis(1_000)).await;
+                    continue;
+		}
+
+		tokio::time::sleep(tokio::time::Duration::from_millis(role_check_interval)).await;
+		check_roles().await;
+            }
+	});
+
+	// Measure the replica lag and LSN of the pools that need them.
+	tokio::task::spawn(async move {
+            loop {
+		let replica_check_interval = get_config().general.replica_check_interval;
+
+		tokio::time::sleep(tokio::time::Duration::from_millis(replica_check_interval)).await;
+		check_replicas().await;
+            }
+	});
+
 	let mut term_signal = unix_signal(SignalKind::terminate()).unwrap();
 	let mut interrupt_signal = unix_signal(SignalKind::interrupt()).unwrap();
 	let mut sighup_signal = unix_signal(SignalKind::hangup()).unwrap();
@@ -218,10 +287,17 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                     get_config().show();
 		},
 
-		// Initiate graceful shutdown sequence on sig int
-		_ = interrupt_signal.recv() => {
-                    info!("Got SIGINT, waiting for client connection drain now");
+		// Initiate graceful shutdown sequence on sig int,
+		// or SHUTDOWN in the admin database.
+		reason = async {
+                    tokio::select! {
+			_ = interrupt_signal.recv() => "SIGINT",
+			_ = admin::SHUTDOWN.notified() => "SHUTDOWN",
+                    }
+		} => {
+                    info!("Got {}, waiting for client connection drain now", reason);
                     admin_only = true;
+                    admin::SHUTTING_DOWN.store(true, std::sync::atomic::Ordering::Relaxed);
 
                     // Broadcast that client tasks need to finish
                     let _ = shutdown_tx.send(());
@@ -258,54 +334,33 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 			}
                     };
 
-                    let shutdown_rx = shutdown_tx.subscribe();
-                    let drain_tx = drain_tx.clone();
-                    let client_server_map = client_server_map.clone();
+                    spawn_client(
+                        socket,
+                        ClientAddress::Tcp(addr),
+                        client_server_map.clone(),
+                        shutdown_tx.subscribe(),
+                        drain_tx.clone(),
+                        admin_only,
+                    );
+		}
 
-                    let tls_certificate = config.general.tls_certificate.clone();
+		new_client = accept_unix(&unix_listener) => {
+                    let socket = match new_client {
+			Ok((socket, _)) => socket,
+			Err(err) => {
+                            error!("{:?}", err);
+                            continue;
+			}
+                    };
 
-                    tokio::task::spawn(async move {
-			let start = chrono::offset::Utc::now().naive_utc();
-
-			match client::client_entrypoint(
-                            socket,
-                            client_server_map,
-                            shutdown_rx,
-                            drain_tx,
-                            admin_only,
-                            tls_certificate.clone(),
-                            config.general.log_client_connections,
-			)
-			    .await
-			{
-                            Ok(()) => {
-
-				let duration = chrono::offset::Utc::now().naive_utc() - start;
-
-				if config.general.log_client_disconnections {
-                                    info!(
-					"Client {:?} disconnected, session duration: {}",
-					addr,
-					format_duration(&duration)
-                                    );
-				} else {
-                                    debug!(
-					"Client {:?} disconnected, session duration: {}",
-					addr,
-					format_duration(&duration)
-                                    );
-				}
-                            }
-
-                            Err(err) => {
-				match err {
-                                    errors::Error::ClientBadStartup => debug!("Client disconnected with error {:?}", err),
-                                    _ => warn!("Client disconnected with error {:?}", err),
-				}
-
-                            }
-			};
-                    });
+                    spawn_client(
+                        socket,
+                        ClientAddress::Unix,
+                        client_server_map.clone(),
+                        shutdown_tx.subscribe(),
+                        drain_tx.clone(),
+                        admin_only,
+                    );
 		}
 
 		_ = exit_rx.recv() => {
@@ -323,7 +378,76 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             }
 	}
 
+	if let Some((_, path)) = &unix_listener {
+            let _ = std::fs::remove_file(path);
+	}
+
 	info!("Shutting down...");
     });
     Ok(())
 }
+
+/// Accept a client on the Unix socket, if we're listening on one.
+async fn accept_unix(
+    listener: &Option<(UnixListener, PathBuf)>,
+) -> std::io::Result<(UnixStream, tokio::net::unix::SocketAddr)> {
+    match listener {
+        Some((listener, _)) => listener.accept().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Serve the client in its own task until it disconnects.
+fn spawn_client<S>(
+    socket: S,
+    addr: ClientAddress,
+    client_server_map: ClientServerMap,
+    shutdown_rx: broadcast::Receiver<()>,
+    drain_tx: mpsc::Sender<i32>,
+    admin_only: bool,
+) where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + std::marker::Unpin + Send + Sync + 'static,
+{
+    let config = get_config();
+
+    tokio::task::spawn(async move {
+        let start = chrono::offset::Utc::now().naive_utc();
+
+        match client::client_entrypoint(
+            socket,
+            addr.clone(),
+            client_server_map,
+            shutdown_rx,
+            drain_tx,
+            admin_only,
+            config.general.log_client_connections,
+        )
+        .await
+        {
+            Ok(()) => {
+                let duration = chrono::offset::Utc::now().naive_utc() - start;
+
+                if config.general.log_client_disconnections {
+                    info!(
+                        "Client {} disconnected, session duration: {}",
+                        addr,
+                        format_duration(&duration)
+                    );
+                } else {
+                    debug!(
+                        "Client {} disconnected, session duration: {}",
+                        addr,
+                        format_duration(&duration)
+                    );
+                }
+            }
+
+            Err(err) => match err {
+                errors::Error::ClientBadStartup => {
+                    debug!("Client disconnected with error {:?}", err)
+                }
+                _ => warn!("Client disconnected with error {:?}", err),
+            },
+        };
+    });
+}
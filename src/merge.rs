@@ -0,0 +1,610 @@
+/// Merge the results of a query sent to several shards, so the client
+/// gets the rows it would get if one server had all the data.
+use sqlparser::ast::{Expr, Function, Ident, OrderByExpr, Query, SelectItem, SetExpr, Value};
+use std::cmp::Ordering;
+
+/// Types compared as numbers, by OID: int8, int2, int4, oid, float4, float8 and numeric.
+const NUMERIC_TYPES: [i32; 7] = [20, 21, 23, 26, 700, 701, 1700];
+
+/// Types whose text sorts like their values byte by byte: bool and uuid.
+/// Shards sort anything else, like text, in ways we can't follow.
+const BYTEWISE_TYPES: [i32; 2] = [16, 2950];
+
+/// Aggregates we can't combine from the results of the shards.
+const OTHER_AGGREGATES: [&str; 20] = [
+    "avg",
+    "array_agg",
+    "string_agg",
+    "bool_and",
+    "bool_or",
+    "every",
+    "bit_and",
+    "bit_or",
+    "json_agg",
+    "jsonb_agg",
+    "json_object_agg",
+    "jsonb_object_agg",
+    "stddev",
+    "stddev_pop",
+    "stddev_samp",
+    "variance",
+    "var_pop",
+    "var_samp",
+    "percentile_cont",
+    "percentile_disc",
+];
+
+/// Aggregates we combine from the results of the shards.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Aggregate {
+    Count,
+    Sum,
+    Min,
+    Max,
+}
+
+/// A column of the ORDER BY, found in the RowDescription when we get it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SortColumn {
+    Position(usize),
+    Name(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SortKey {
+    pub column: SortColumn,
+    pub descending: bool,
+    pub nulls_first: bool,
+}
+
+/// How to merge the results of a query sent to several shards.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MergePlan {
+    /// The query to send to the shards, if it's not the client's.
+    pub query: Option<String>,
+
+    /// The aggregate of each column, if the query only selects aggregates.
+    pub aggregates: Vec<Aggregate>,
+
+    /// The shards return sorted rows, we merge them in the same order.
+    pub order_by: Vec<SortKey>,
+
+    pub limit: Option<u64>,
+    pub offset: u64,
+}
+
+impl MergePlan {
+    /// Plan the merge of a read-only query, or explain why we can't.
+    pub fn new(query: &Query) -> Result<MergePlan, String> {
+        let mut plan = MergePlan::default();
+
+        if query.fetch.is_some() {
+            return Err(unsupported("FETCH"));
+        }
+
+        match &*query.body {
+            SetExpr::Select(select) => {
+                if select.distinct {
+                    return Err(unsupported("DISTINCT"));
+                }
+
+                if !select.group_by.is_empty() || select.having.is_some() {
+                    return Err(unsupported("GROUP BY"));
+                }
+
+                let aggregates = select
+                    .projection
+                    .iter()
+                    .map(|item| match item {
+                        SelectItem::UnnamedExpr(expr) | SelectItem::ExprWithAlias { expr, .. } => {
+                            aggregate(expr)
+                        }
+                        _ => Ok(None),
+                    })
+                    .collect::<Result<Vec<_>, _>>()?;
+
+                if aggregates.iter().any(Option::is_some) {
+                    if aggregates.iter().any(Option::is_none) {
+                        return Err(unsupported("Selecting aggregates with other columns"));
+                    }
+
+                    plan.aggregates = aggregates.into_iter().flatten().collect();
+                }
+            }
+
+            _ => {
+                if !query.order_by.is_empty() || query.limit.is_some() || query.offset.is_some() {
+                    return Err(unsupported("ORDER BY, LIMIT or OFFSET outside of a SELECT"));
+                }
+            }
+        };
+
+        plan.order_by = query
+            .order_by
+            .iter()
+            .map(sort_key)
+            .collect::<Result<_, _>>()?;
+
+        plan.limit = match &query.limit {
+            Some(limit) => Some(number(limit)?),
+            None => None,
+        };
+
+        // Each shard could have the rows we skip, so they skip none
+        // and we skip them when merging.
+        if let Some(offset) = &query.offset {
+            plan.offset = number(&offset.value)?;
+
+            let mut shard_query = query.clone();
+            shard_query.offset = None;
+            shard_query.limit = plan
+                .limit
+                .map(|limit| Expr::Value(Value::Number((limit + plan.offset).to_string(), false)));
+
+            plan.query = Some(shard_query.to_string());
+        }
+
+        Ok(plan)
+    }
+
+    /// Find the ORDER BY columns in the columns of the RowDescription,
+    /// and check we can compare them and the columns of MIN and MAX.
+    pub fn sort_columns(&self, columns: &[(String, i32)]) -> Result<Vec<usize>, String> {
+        for (column, aggregate) in self.aggregates.iter().enumerate() {
+            if let (Aggregate::Min | Aggregate::Max, Some((name, type_oid))) =
+                (aggregate, columns.get(column))
+            {
+                if !comparable(*type_oid) {
+                    return Err(unsupported(&format!(
+                        "MIN or MAX of {} (type {})",
+                        name, type_oid
+                    )));
+                }
+            }
+        }
+
+        self.order_by
+            .iter()
+            .map(|key| {
+                let position = match &key.column {
+                    SortColumn::Position(position) if *position < columns.len() => *position,
+                    SortColumn::Name(name) => columns
+                        .iter()
+                        .position(|(column, _)| column == name)
+                        .ok_or_else(|| {
+                            unsupported(&format!("ORDER BY {} not in the results", name))
+                        })?,
+                    SortColumn::Position(position) => {
+                        return Err(format!(
+                            "ORDER BY position {} is not in select list",
+                            position + 1
+                        ))
+                    }
+                };
+
+                let (name, type_oid) = &columns[position];
+
+                match comparable(*type_oid) {
+                    true => Ok(position),
+                    false => Err(unsupported(&format!(
+                        "ORDER BY {} (type {})",
+                        name, type_oid
+                    ))),
+                }
+            })
+            .collect()
+    }
+
+    /// Compare two rows in the order of the ORDER BY.
+    pub fn compare(
+        &self,
+        sort_columns: &[usize],
+        columns: &[(String, i32)],
+        a: &[Option<String>],
+        b: &[Option<String>],
+    ) -> Ordering {
+        for (key, column) in self.order_by.iter().zip(sort_columns) {
+            let ordering = match (&a[*column], &b[*column]) {
+                (None, None) => Ordering::Equal,
+                (None, Some(_)) if key.nulls_first => Ordering::Less,
+                (None, Some(_)) => Ordering::Greater,
+                (Some(_), None) if key.nulls_first => Ordering::Greater,
+                (Some(_), None) => Ordering::Less,
+                (Some(a), Some(b)) => {
+                    let ordering = compare_values(columns[*column].1, a, b);
+
+                    match key.descending {
+                        true => ordering.reverse(),
+                        false => ordering,
+                    }
+                }
+            };
+
+            if ordering != Ordering::Equal {
+                return ordering;
+            }
+        }
+
+        Ordering::Equal
+    }
+
+    /// Combine the aggregates the shards returned into one row.
+    pub fn combine(
+        &self,
+        columns: &[(String, i32)],
+        rows: &[Vec<Option<String>>],
+    ) -> Vec<Option<String>> {
+        self.aggregates
+            .iter()
+            .enumerate()
+            .map(|(column, aggregate)| {
+                let type_oid = columns.get(column).map(|(_, type_oid)| *type_oid);
+                let mut values = rows
+                    .iter()
+                    .filter_map(|row| row.get(column).cloned().flatten());
+
+                match aggregate {
+                    Aggregate::Count => Some(
+                        values
+                            .filter_map(|value| value.parse::<i64>().ok())
+                            .sum::<i64>()
+                            .to_string(),
+                    ),
+                    Aggregate::Sum => {
+                        let first = values.next()?;
+                        Some(values.fold(first, |sum, value| add(&sum, &value)))
+                    }
+                    Aggregate::Min => {
+                        values.min_by(|a, b| compare_values(type_oid.unwrap_or(0), a, b))
+                    }
+                    Aggregate::Max => {
+                        values.max_by(|a, b| compare_values(type_oid.unwrap_or(0), a, b))
+                    }
+                }
+            })
+            .collect()
+    }
+}
+
+fn unsupported(what: &str) -> String {
+    format!("{} is not supported in multi-shard queries", what)
+}
+
+/// The aggregate we can combine in the column, if it's one.
+fn aggregate(expr: &Expr) -> Result<Option<Aggregate>, String> {
+    if let Expr::Function(function) = expr {
+        let name = function.name.to_string().to_lowercase();
+
+        let aggregate = match name.as_ref() {
+            "count" => Some(Aggregate::Count),
+            "sum" => Some(Aggregate::Sum),
+            "min" => Some(Aggregate::Min),
+            "max" => Some(Aggregate::Max),
+            _ => None,
+        };
+
+        if function.over.is_some() {
+            return Err(unsupported("Window functions"));
+        }
+
+        if let Some(aggregate) = aggregate {
+            if function.distinct {
+                return Err(unsupported(&format!("{}(DISTINCT)", name)));
+            }
+
+            return Ok(Some(aggregate));
+        }
+    }
+
+    if contains_aggregate(expr) {
+        return Err(unsupported(&format!("{}", expr)));
+    }
+
+    Ok(None)
+}
+
+/// Look for aggregates in the expression.
+fn contains_aggregate(expr: &Expr) -> bool {
+    match expr {
+        Expr::Function(function) => is_aggregate(function) || function.over.is_some(),
+        Expr::BinaryOp { left, right, .. } => contains_aggregate(left) || contains_aggregate(right),
+        Expr::UnaryOp { expr, .. } | Expr::Nested(expr) | Expr::Cast { expr, .. } => {
+            contains_aggregate(expr)
+        }
+        _ => false,
+    }
+}
+
+fn is_aggregate(function: &Function) -> bool {
+    let name = function.name.to_string().to_lowercase();
+
+    ["count", "sum", "min", "max"].contains(&name.as_ref())
+        || OTHER_AGGREGATES.contains(&name.as_ref())
+}
+
+fn sort_key(order_by: &OrderByExpr) -> Result<SortKey, String> {
+    let column = match &order_by.expr {
+        Expr::Value(Value::Number(position, _)) => position
+            .parse::<usize>()
+            .ok()
+            .filter(|position| *position > 0)
+            .map(|position| SortColumn::Position(position - 1)),
+        Expr::Identifier(ident) => Some(SortColumn::Name(column_name(ident))),
+        Expr::CompoundIdentifier(idents) => idents
+            .last()
+            .map(|ident| SortColumn::Name(column_name(ident))),
+        _ => None,
+    };
+
+    let column = column.ok_or_else(|| unsupported(&format!("ORDER BY {}", order_by.expr)))?;
+    let descending = order_by.asc == Some(false);
+
+    Ok(SortKey {
+        column,
+        descending,
+        // Postgres puts NULLs last in ascending order.
+        nulls_first: order_by.nulls_first.unwrap_or(descending),
+    })
+}
+
+/// Postgres folds identifiers to lower case, unless they're quoted.
+fn column_name(ident: &Ident) -> String {
+    match ident.quote_style {
+        Some(_) => ident.value.clone(),
+        None => ident.value.to_lowercase(),
+    }
+}
+
+fn number(expr: &Expr) -> Result<u64, String> {
+    match expr {
+        Expr::Value(Value::Number(value, _)) => value
+            .parse()
+            .map_err(|_| unsupported(&format!("LIMIT or OFFSET {}", value))),
+        _ => Err(unsupported(&format!("LIMIT or OFFSET {}", expr))),
+    }
+}
+
+/// We can compare values of the type like the shards do.
+fn comparable(type_oid: i32) -> bool {
+    NUMERIC_TYPES.contains(&type_oid) || BYTEWISE_TYPES.contains(&type_oid)
+}
+
+/// Compare two values in text format. Numbers are compared by value,
+/// the other comparable types byte by byte.
+fn compare_values(type_oid: i32, a: &str, b: &str) -> Ordering {
+    if NUMERIC_TYPES.contains(&type_oid) {
+        if let (Some(a), Some(b)) = (parse_decimal(a), parse_decimal(b)) {
+            if let Some((a, b)) = align(a, b) {
+                return a.cmp(&b);
+            }
+        }
+
+        if let (Ok(a), Ok(b)) = (a.parse::<f64>(), b.parse::<f64>()) {
+            return a.total_cmp(&b);
+        }
+    }
+
+    a.cmp(b)
+}
+
+/// Add two numbers in text format, exactly if we can.
+fn add(a: &str, b: &str) -> String {
+    if let (Some(x), Some(y)) = (parse_decimal(a), parse_decimal(b)) {
+        let scale = x.1.max(y.1);
+
+        if let Some(sum) = align(x, y).and_then(|(x, y)| x.checked_add(y)) {
+            return format_decimal(sum, scale);
+        }
+    }
+
+    match (a.parse::<f64>(), b.parse::<f64>()) {
+        (Ok(a), Ok(b)) => (a + b).to_string(),
+        _ => a.to_string(),
+    }
+}
+
+/// A decimal number like `-12.50`, as its digits and the number of them after the point.
+fn parse_decimal(value: &str) -> Option<(i128, u32)> {
+    let (negative, digits) = match value.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, value),
+    };
+
+    let (integer, fraction) = digits.split_once('.').unwrap_or((digits, ""));
+
+    if !(integer.chars().all(|c| c.is_ascii_digit())
+        && fraction.chars().all(|c| c.is_ascii_digit()))
+    {
+        return None;
+    }
+
+    let mantissa = format!("{}{}", integer, fraction).parse::<i128>().ok()?;
+
+    match negative {
+        true => Some((-mantissa, fraction.len() as u32)),
+        false => Some((mantissa, fraction.len() as u32)),
+    }
+}
+
+/// The digits of two decimals, with the same number of them after the point.
+fn align(a: (i128, u32), b: (i128, u32)) -> Option<(i128, i128)> {
+    let scale = a.1.max(b.1);
+
+    Some((
+        a.0.checked_mul(10_i128.checked_pow(scale - a.1)?)?,
+        b.0.checked_mul(10_i128.checked_pow(scale - b.1)?)?,
+    ))
+}
+
+fn format_decimal(mantissa: i128, scale: u32) -> String {
+    if scale == 0 {
+        return mantissa.to_string();
+    }
+
+    let digits = format!(
+        "{:0>width$}",
+        mantissa.unsigned_abs(),
+        width = scale as usize + 1
+    );
+    let (integer, fraction) = digits.split_at(digits.len() - scale as usize);
+
+    match mantissa < 0 {
+        true => format!("-{}.{}", integer, fraction),
+        false => format!("{}.{}", integer, fraction),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use sqlparser::ast::Statement;
+    use sqlparser::dialect::PostgreSqlDialect;
+    use sqlparser::parser::Parser;
+
+    fn plan(query: &str) -> Result<MergePlan, String> {
+        match Parser::parse_sql(&PostgreSqlDialect {}, query)
+            .unwrap()
+            .remove(0)
+        {
+            Statement::Query(query) => MergePlan::new(&query),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_merge_plan() {
+        assert_eq!(plan("SELECT * FROM users"), Ok(MergePlan::default()));
+
+        let merge_plan =
+            plan("SELECT id, name FROM users ORDER BY name DESC, 1 LIMIT 10 OFFSET 5").unwrap();
+        assert_eq!(
+            merge_plan.query,
+            Some(String::from(
+                "SELECT id, name FROM users ORDER BY name DESC, 1 LIMIT 15"
+            ))
+        );
+        assert_eq!(
+            merge_plan.order_by,
+            vec![
+                SortKey {
+                    column: SortColumn::Name(String::from("name")),
+                    descending: true,
+                    nulls_first: true,
+                },
+                SortKey {
+                    column: SortColumn::Position(0),
+                    descending: false,
+                    nulls_first: false,
+                },
+            ]
+        );
+        assert_eq!(merge_plan.limit, Some(10));
+        assert_eq!(merge_plan.offset, 5);
+
+        let merge_plan =
+            plan("SELECT COUNT(*), SUM(amount) AS total, MAX(id) FROM orders").unwrap();
+        assert_eq!(
+            merge_plan.aggregates,
+            vec![Aggregate::Count, Aggregate::Sum, Aggregate::Max]
+        );
+        assert_eq!(merge_plan.query, None);
+
+        assert!(plan("SELECT AVG(amount) FROM orders").is_err());
+        assert!(plan("SELECT COUNT(DISTINCT id) FROM orders").is_err());
+        assert!(plan("SELECT COUNT(*) + 1 FROM orders").is_err());
+        assert!(plan("SELECT id, COUNT(*) FROM orders GROUP BY id").is_err());
+        assert!(plan("SELECT DISTINCT id FROM orders").is_err());
+        assert!(plan("SELECT * FROM users ORDER BY lower(name)").is_err());
+    }
+
+    #[test]
+    fn test_merge_rows() {
+        let columns = vec![(String::from("id"), 20), (String::from("active"), 16)];
+        let row = |id: Option<&str>, active: &str| vec![id.map(String::from), Some(active.into())];
+
+        let merge_plan = plan("SELECT * FROM users ORDER BY id DESC, active").unwrap();
+        let sort_columns = merge_plan.sort_columns(&columns).unwrap();
+        assert_eq!(sort_columns, vec![0, 1]);
+
+        let compare = |a: &[Option<String>], b: &[Option<String>]| {
+            merge_plan.compare(&sort_columns, &columns, a, b)
+        };
+        assert_eq!(
+            compare(&row(Some("10"), "f"), &row(Some("9"), "f")),
+            Ordering::Less
+        );
+        assert_eq!(
+            compare(&row(Some("9"), "f"), &row(Some("9"), "t")),
+            Ordering::Less
+        );
+        assert_eq!(
+            compare(&row(None, "f"), &row(Some("9"), "f")),
+            Ordering::Less
+        );
+
+        assert!(plan("SELECT * FROM users ORDER BY email")
+            .unwrap()
+            .sort_columns(&columns)
+            .is_err());
+
+        let columns = vec![
+            (String::from("count"), 20),
+            (String::from("sum"), 1700),
+            (String::from("min"), 23),
+        ];
+        let merge_plan = plan("SELECT COUNT(*), SUM(amount), MIN(id) FROM orders").unwrap();
+        assert!(merge_plan.sort_columns(&columns).is_ok());
+        let rows = vec![
+            vec![Some("2".into()), Some("10.25".into()), Some("10".into())],
+            vec![Some("3".into()), Some("-0.5".into()), Some("9".into())],
+            vec![Some("0".into()), None, None],
+        ];
+
+        assert_eq!(
+            merge_plan.combine(&columns, &rows),
+            vec![Some("5".into()), Some("9.75".into()), Some("9".into())]
+        );
+        assert_eq!(
+            merge_plan.combine(&columns, &rows[2..]),
+            vec![Some("0".into()), None, None]
+        );
+    }
+
+    #[test]
+    fn test_merge_types() {
+        // Shards sort text by their collation, not byte by byte.
+        let columns = vec![(String::from("id"), 20), (String::from("name"), 25)];
+        assert!(plan("SELECT * FROM users ORDER BY name")
+            .unwrap()
+            .sort_columns(&columns)
+            .is_err());
+        assert!(plan("SELECT * FROM users ORDER BY id, 2")
+            .unwrap()
+            .sort_columns(&columns)
+            .is_err());
+        assert!(plan("SELECT * FROM users ORDER BY id")
+            .unwrap()
+            .sort_columns(&columns)
+            .is_ok());
+
+        let columns = vec![(String::from("min"), 25), (String::from("max"), 1082)];
+        assert!(plan("SELECT MIN(name) FROM users")
+            .unwrap()
+            .sort_columns(&columns[..1])
+            .is_err());
+        assert!(plan("SELECT COUNT(name), MAX(created_at) FROM users")
+            .unwrap()
+            .sort_columns(&columns)
+            .is_err());
+        assert!(plan("SELECT COUNT(name) FROM users")
+            .unwrap()
+            .sort_columns(&columns[..1])
+            .is_ok());
+
+        let columns = vec![(String::from("id"), 2950)];
+        assert!(plan("SELECT MAX(id) FROM users ORDER BY 1")
+            .unwrap()
+            .sort_columns(&columns)
+            .is_ok());
+    }
+}
@@ -5,8 +5,11 @@ use md5::{Digest, Md5};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::TcpStream;
 
+use crate::constants::*;
 use crate::errors::Error;
+use std::collections::hash_map::DefaultHasher;
 use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
 use std::io::{BufRead, Cursor};
 use std::mem;
 
@@ -65,6 +68,40 @@ where
     Ok(salt)
 }
 
+/// Offer SCRAM-SHA-256 authentication to the client.
+pub async fn scram_start_challenge<S>(stream: &mut S) -> Result<(), Error>
+where
+    S: tokio::io::AsyncWrite + std::marker::Unpin,
+{
+    let mut res = BytesMut::new();
+    res.put_u8(b'R');
+    res.put_i32(4 + 4 + SCRAM_SHA_256.len() as i32 + 2);
+    res.put_i32(SASL);
+    res.put_slice(SCRAM_SHA_256.as_bytes());
+    res.put_u8(0);
+    res.put_u8(0); // End of the mechanism list.
+
+    write_all(stream, res).await
+}
+
+/// Send a SCRAM challenge (SASL_CONTINUE) or the server signature (SASL_FINAL) to the client.
+pub async fn scram_server_response<S>(
+    stream: &mut S,
+    code: i32,
+    data: &BytesMut,
+) -> Result<(), Error>
+where
+    S: tokio::io::AsyncWrite + std::marker::Unpin,
+{
+    let mut res = BytesMut::new();
+    res.put_u8(b'R');
+    res.put_i32(4 + 4 + data.len() as i32);
+    res.put_i32(code);
+    res.put_slice(&data[..]);
+
+    write_all(stream, res).await
+}
+
 /// Give the client the process_id and secret we generated
 /// used in query cancellation.
 pub async fn backend_key_data<S>(
@@ -110,9 +147,23 @@ where
     write_all(stream, bytes).await
 }
 
+/// Ask the server to switch to TLS. It replies with a single byte,
+/// 'S' if it agrees, 'N' otherwise.
+pub async fn ssl_request(stream: &mut TcpStream) -> Result<(), Error> {
+    let mut bytes = BytesMut::with_capacity(8);
+
+    bytes.put_i32(8);
+    bytes.put_i32(SSL_REQUEST_CODE);
+
+    write_all(stream, bytes).await
+}
+
 /// Send the startup packet the server. We're pretending we're a Pg client.
 /// This tells the server which user we are and what database we want.
-pub async fn startup(stream: &mut TcpStream, user: &str, database: &str) -> Result<(), Error> {
+pub async fn startup<S>(stream: &mut S, user: &str, database: &str) -> Result<(), Error>
+where
+    S: tokio::io::AsyncWrite + std::marker::Unpin,
+{
     let mut bytes = BytesMut::with_capacity(25);
 
     bytes.put_i32(196608); // Protocol number
@@ -209,7 +260,24 @@ pub fn md5_hash_password(user: &str, password: &str, salt: &[u8]) -> Vec<u8> {
     let output = md5.finalize_reset();
 
     // Second pass
-    md5.update(format!("{:x}", output));
+    md5_hash_second_pass(&format!("md5{:x}", output), salt)
+}
+
+/// Is the password an md5 hash (`md5` followed by 32 hex digits),
+/// as Postgres stores it in pg_authid, rather than cleartext?
+pub fn is_md5_hash(password: &str) -> bool {
+    match password.strip_prefix("md5") {
+        Some(hash) => hash.len() == 32 && hash.chars().all(|c| c.is_ascii_hexdigit()),
+        None => false,
+    }
+}
+
+/// Create md5 password hash given a salt and the md5 hash of the password,
+/// e.g. the one stored in pg_authid.
+pub fn md5_hash_second_pass(hash: &str, salt: &[u8]) -> Vec<u8> {
+    let mut md5 = Md5::new();
+
+    md5.update(&hash.as_bytes()[3..]);
     md5.update(salt);
 
     let mut password = format!("md5{:x}", md5.finalize())
@@ -232,7 +300,11 @@ pub async fn md5_password<S>(
 where
     S: tokio::io::AsyncWrite + std::marker::Unpin,
 {
-    let password = md5_hash_password(user, password, salt);
+    let password = if is_md5_hash(password) {
+        md5_hash_second_pass(password, salt)
+    } else {
+        md5_hash_password(user, password, salt)
+    };
 
     let mut message = BytesMut::with_capacity(password.len() as usize + 5);
 
@@ -439,6 +511,92 @@ pub fn data_row(row: &Vec<String>) -> BytesMut {
     res
 }
 
+/// Create a DataRow message with NULLs.
+pub fn data_row_nullable(row: &[Option<String>]) -> BytesMut {
+    let mut data_row = BytesMut::new();
+
+    data_row.put_i16(row.len() as i16);
+
+    for column in row {
+        match column {
+            Some(column) => {
+                data_row.put_i32(column.len() as i32);
+                data_row.put_slice(column.as_bytes());
+            }
+            None => data_row.put_i32(-1),
+        }
+    }
+
+    message_with_body('D', data_row)
+}
+
+/// Parse a RowDescription message into the name and type OID of the columns.
+pub fn parse_row_description(message: &BytesMut) -> Result<Vec<(String, i32)>, Error> {
+    let mut bytes = message_body(message, 'T')?;
+
+    if bytes.len() < mem::size_of::<i16>() {
+        return Err(Error::ProtocolSyncError(
+            "RowDescription is too short".into(),
+        ));
+    }
+
+    let fields = bytes.get_i16();
+    let mut columns = Vec::with_capacity(fields.max(0) as usize);
+
+    for _ in 0..fields {
+        let name = read_cstring(&mut bytes)?;
+
+        // Table OID, column number, type OID, type size, type modifier and format.
+        if bytes.len() < 18 {
+            return Err(Error::ProtocolSyncError(
+                "RowDescription is too short".into(),
+            ));
+        }
+
+        bytes.advance(6);
+        let type_oid = bytes.get_i32();
+        bytes.advance(8);
+
+        columns.push((name, type_oid));
+    }
+
+    Ok(columns)
+}
+
+/// Parse the body of a DataRow message (without the code and length)
+/// into text values. NULLs are returned as `None`.
+pub fn parse_data_row(mut bytes: BytesMut) -> Result<Vec<Option<String>>, Error> {
+    if bytes.len() < mem::size_of::<i16>() {
+        return Err(Error::ProtocolSyncError("DataRow is too short".into()));
+    }
+
+    let columns = bytes.get_i16();
+    let mut row = Vec::with_capacity(columns.max(0) as usize);
+
+    for _ in 0..columns {
+        if bytes.len() < mem::size_of::<i32>() {
+            return Err(Error::ProtocolSyncError("DataRow is too short".into()));
+        }
+
+        let len = bytes.get_i32();
+
+        // NULL
+        if len < 0 {
+            row.push(None);
+            continue;
+        }
+
+        if bytes.len() < len as usize {
+            return Err(Error::ProtocolSyncError("DataRow is too short".into()));
+        }
+
+        let value = bytes.split_to(len as usize);
+        row.push(Some(String::from_utf8_lossy(&value).to_string()));
+    }
+
+    Ok(row)
+}
+
 /// Create a CommandComplete message.
 pub fn command_complete(command: &str) -> BytesMut {
     let cmd = BytesMut::from(format!("{}\0", command).as_bytes());
@@ -449,6 +607,64 @@ pub fn command_complete(command: &str) -> BytesMut {
     res
 }
 
+/// Create a ParseComplete message.
+pub fn parse_complete() -> BytesMut {
+    let mut res = BytesMut::with_capacity(5);
+    res.put_u8(b'1');
+    res.put_i32(4);
+    res
+}
+
+/// Create a CopyData message.
+pub fn copy_data(data: &[u8]) -> BytesMut {
+    let mut res = BytesMut::with_capacity(data.len() + 5);
+    res.put_u8(b'd');
+    res.put_i32(data.len() as i32 + 4);
+    res.put_slice(data);
+    res
+}
+
+/// Create a CopyDone message.
+pub fn copy_done() -> BytesMut {
+    let mut res = BytesMut::with_capacity(5);
+    res.put_u8(b'c');
+    res.put_i32(4);
+    res
+}
+
+/// Create a CopyFail message.
+pub fn copy_fail(reason: &str) -> BytesMut {
+    let reason = BytesMut::from(format!("{}\0", reason).as_bytes());
+    let mut res = BytesMut::new();
+    res.put_u8(b'f');
+    res.put_i32(reason.len() as i32 + 4);
+    res.put(reason);
+    res
+}
+
+/// Split a buffer of complete messages, like the ones we get from the server
+/// or buffer from the client, into the messages.
+pub fn split_messages(mut bytes: BytesMut) -> Result<Vec<BytesMut>, Error> {
+    let mut messages = Vec::new();
+
+    while !bytes.is_empty() {
+        if bytes.len() < mem::size_of::<u8>() + mem::size_of::<i32>() {
+            return Err(Error::ProtocolSyncError("Message is too short".into()));
+        }
+
+        let len = i32::from_be_bytes([bytes[1], bytes[2], bytes[3], bytes[4]]);
+        let len = len.max(4) as usize + mem::size_of::<u8>();
+
+        if len > bytes.len() {
+            return Err(Error::ProtocolSyncError("Message is too short".into()));
+        }
+
+        messages.push(bytes.split_to(len));
+    }
+
+    Ok(messages)
+}
+
 /// Write all data in the buffer to the TcpStream.
 pub async fn write_all<S>(stream: &mut S, buf: BytesMut) -> Result<(), Error>
 where
@@ -550,6 +766,260 @@ pub fn server_parameter_message(key: &str, value: &str) -> BytesMut {
     server_info
 }
 
+/// Parse (F) message, prepares a statement.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Parse {
+    pub name: String,
+    pub query: String,
+    pub param_types: Vec<i32>,
+}
+
+impl Parse {
+    /// Read a Parse message, including its code and length.
+    pub fn from_bytes(message: &BytesMut) -> Result<Parse, Error> {
+        let mut bytes = message_body(message, 'P')?;
+
+        let name = read_cstring(&mut bytes)?;
+        let query = read_cstring(&mut bytes)?;
+
+        if bytes.len() < mem::size_of::<i16>() {
+            return Err(Error::ProtocolSyncError("Parse is too short".into()));
+        }
+
+        let num_params = bytes.get_i16().max(0) as usize;
+
+        if bytes.len() < num_params * mem::size_of::<i32>() {
+            return Err(Error::ProtocolSyncError("Parse is too short".into()));
+        }
+
+        let param_types = (0..num_params).map(|_| bytes.get_i32()).collect();
+
+        Ok(Parse {
+            name,
+            query,
+            param_types,
+        })
+    }
+
+    /// Write the message, ready to be sent to the server.
+    pub fn to_bytes(&self) -> BytesMut {
+        let mut body = BytesMut::new();
+
+        body.put_slice(self.name.as_bytes());
+        body.put_u8(0);
+        body.put_slice(self.query.as_bytes());
+        body.put_u8(0);
+        body.put_i16(self.param_types.len() as i16);
+
+        for param_type in &self.param_types {
+            body.put_i32(*param_type);
+        }
+
+        message_with_body('P', body)
+    }
+
+    /// The name of the statement on the servers. The same statement gets the same name
+    /// for all clients, so they can use it on any server that has it prepared.
+    pub fn global_name(&self) -> String {
+        let mut hasher = DefaultHasher::new();
+        self.query.hash(&mut hasher);
+        self.param_types.hash(&mut hasher);
+
+        format!("PGCAT_{:016x}", hasher.finish())
+    }
+
+    /// The same statement under another name.
+    pub fn renamed(&self, name: &str) -> Parse {
+        Parse {
+            name: name.to_string(),
+            ..self.clone()
+        }
+    }
+}
+
+/// Bind (F) message, creates a portal from a prepared statement.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Bind {
+    pub portal: String,
+    pub statement: String,
+
+    /// Parameter formats and values, and result formats, as is.
+    rest: BytesMut,
+}
+
+impl Bind {
+    /// Read a Bind message, including its code and length.
+    pub fn from_bytes(message: &BytesMut) -> Result<Bind, Error> {
+        let mut bytes = message_body(message, 'B')?;
+
+        let portal = read_cstring(&mut bytes)?;
+        let statement = read_cstring(&mut bytes)?;
+
+        Ok(Bind {
+            portal,
+            statement,
+            rest: bytes,
+        })
+    }
+
+    /// Write the message, ready to be sent to the server.
+    pub fn to_bytes(&self) -> BytesMut {
+        let mut body = BytesMut::new();
+
+        body.put_slice(self.portal.as_bytes());
+        body.put_u8(0);
+        body.put_slice(self.statement.as_bytes());
+        body.put_u8(0);
+        body.put_slice(&self.rest);
+
+        message_with_body('B', body)
+    }
+
+    /// The format (0 for text, 1 for binary) and value of a parameter, `None` if it's NULL.
+    pub fn parameter(&self, position: usize) -> Result<Option<(i16, BytesMut)>, Error> {
+        let mut bytes = self.rest.clone();
+
+        if bytes.len() < mem::size_of::<i16>() {
+            return Err(Error::ProtocolSyncError("Bind is too short".into()));
+        }
+
+        let num_formats = bytes.get_i16().max(0) as usize;
+
+        if bytes.len() < (num_formats + 1) * mem::size_of::<i16>() {
+            return Err(Error::ProtocolSyncError("Bind is too short".into()));
+        }
+
+        let formats: Vec<i16> = (0..num_formats).map(|_| bytes.get_i16()).collect();
+        let num_params = bytes.get_i16().max(0) as usize;
+
+        for index in 0..num_params.min(position + 1) {
+            if bytes.len() < mem::size_of::<i32>() {
+                return Err(Error::ProtocolSyncError("Bind is too short".into()));
+            }
+
+            let len = bytes.get_i32();
+
+            // NULL
+            if len < 0 {
+                continue;
+            }
+
+            if bytes.len() < len as usize {
+                return Err(Error::ProtocolSyncError("Bind is too short".into()));
+            }
+
+            let value = bytes.split_to(len as usize);
+
+            if index == position {
+                // No formats means text, one format is for all the parameters.
+                let format = match formats.len() {
+                    0 => 0,
+                    1 => formats[0],
+                    _ => formats.get(index).copied().unwrap_or(0),
+                };
+
+                return Ok(Some((format, value)));
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+/// Describe (F) or Close (F) message, for a statement ('S') or a portal ('P').
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Target {
+    pub code: char,
+    pub kind: char,
+    pub name: String,
+}
+
+impl Target {
+    /// Read a Describe or Close message, including its code and length.
+    pub fn from_bytes(message: &BytesMut) -> Result<Target, Error> {
+        let code = *message.first().unwrap_or(&0) as char;
+        let mut bytes = message_body(message, code)?;
+
+        if bytes.is_empty() {
+            return Err(Error::ProtocolSyncError(format!("'{}' is too short", code)));
+        }
+
+        let kind = bytes.get_u8() as char;
+        let name = read_cstring(&mut bytes)?;
+
+        Ok(Target { code, kind, name })
+    }
+
+    /// Close the statement.
+    pub fn close_statement(name: &str) -> Target {
+        Target {
+            code: 'C',
+            kind: 'S',
+            name: name.to_string(),
+        }
+    }
+
+    /// Write the message, ready to be sent to the server.
+    pub fn to_bytes(&self) -> BytesMut {
+        let mut body = BytesMut::new();
+
+        body.put_u8(self.kind as u8);
+        body.put_slice(self.name.as_bytes());
+        body.put_u8(0);
+
+        message_with_body(self.code, body)
+    }
+}
+
+/// The body of a message, checking its code and length.
+fn message_body(message: &BytesMut, code: char) -> Result<BytesMut, Error> {
+    let mut bytes = message.clone();
+
+    if bytes.len() < mem::size_of::<u8>() + mem::size_of::<i32>() || bytes.get_u8() as char != code
+    {
+        return Err(Error::ProtocolSyncError(format!(
+            "Expected a '{}' message",
+            code
+        )));
+    }
+
+    let len = bytes.get_i32() as usize;
+
+    if len != bytes.len() + mem::size_of::<i32>() {
+        return Err(Error::ProtocolSyncError(format!(
+            "Invalid message length {} for '{}'",
+            len, code
+        )));
+    }
+
+    Ok(bytes)
+}
+
+/// Add the code and the length to the body of a message.
+fn message_with_body(code: char, body: BytesMut) -> BytesMut {
+    let mut message = BytesMut::with_capacity(body.len() + 5);
+
+    message.put_u8(code as u8);
+    message.put_i32(body.len() as i32 + 4);
+    message.put(body);
+
+    message
+}
+
+/// Read a null-terminated string.
+fn read_cstring(bytes: &mut BytesMut) -> Result<String, Error> {
+    match bytes.iter().position(|byte| *byte == 0) {
+        Some(end) => {
+            let string = bytes.split_to(end);
+            bytes.advance(1);
+            Ok(String::from_utf8_lossy(&string).to_string())
+        }
+        None => Err(Error::ProtocolSyncError(
+            "String is not null-terminated".into(),
+        )),
+    }
+}
+
 pub trait BytesMutReader {
     fn read_string(&mut self) -> Result<String, Error>;
 }
@@ -565,3 +1035,110 @@ impl BytesMutReader for Cursor<&BytesMut> {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_split_messages() {
+        let mut bytes = command_complete("SELECT 5");
+        bytes.put(command_complete("INSERT 0 12"));
+        bytes.put(simple_query("SELECT 1"));
+
+        let messages = split_messages(bytes.clone()).unwrap();
+
+        assert_eq!(messages.len(), 3);
+        assert_eq!(messages[1], command_complete("INSERT 0 12"));
+
+        assert!(split_messages(bytes.split_to(bytes.len() - 1)).is_err());
+    }
+
+    #[test]
+    fn test_parse_row_description() {
+        let columns = vec![("id", DataType::Int4), ("name", DataType::Text)];
+
+        assert_eq!(
+            parse_row_description(&row_description(&columns)).unwrap(),
+            vec![(String::from("id"), 23), (String::from("name"), 25)]
+        );
+
+        let row = vec![Some(String::from("1")), None];
+        let mut bytes = data_row_nullable(&row);
+        bytes.advance(5);
+        assert_eq!(parse_data_row(bytes).unwrap(), row);
+    }
+
+    #[test]
+    fn test_extended_protocol_messages() {
+        let parse = Parse {
+            name: String::from("s1"),
+            query: String::from("SELECT * FROM users WHERE id = $1"),
+            param_types: vec![23],
+        };
+
+        let bytes = parse.to_bytes();
+        assert_eq!(Parse::from_bytes(&bytes).unwrap(), parse);
+        assert_eq!(parse.global_name(), parse.renamed("s2").global_name());
+        assert!(parse.global_name().starts_with("PGCAT_"));
+
+        let mut bytes = BytesMut::new();
+        bytes.put_u8(b'B');
+        bytes.put_i32(4 + 3 + 3 + 6);
+        bytes.put_slice(b"p1\0s1\0");
+        bytes.put_i16(0);
+        bytes.put_i16(0);
+        bytes.put_i16(0);
+
+        let mut bind = Bind::from_bytes(&bytes).unwrap();
+        assert_eq!(bind.portal, "p1");
+        assert_eq!(bind.statement, "s1");
+        assert_eq!(bind.to_bytes(), bytes);
+
+        bind.statement = parse.global_name();
+        assert_eq!(Bind::from_bytes(&bind.to_bytes()).unwrap(), bind);
+
+        let close = Target::close_statement("s1");
+        assert_eq!(Target::from_bytes(&close.to_bytes()).unwrap(), close);
+
+        assert!(Parse::from_bytes(&bytes).is_err());
+        assert!(Target::from_bytes(&BytesMut::from(&b"D\0\0\0\x04"[..])).is_err());
+    }
+
+    #[test]
+    fn test_md5_hash_from_stored_hash() {
+        let salt = b"abcd";
+        let stored_hash = "md5d5d2b7621f9dfa5d09376c9c966bf109"; // foobar, postgres
+
+        assert!(is_md5_hash(stored_hash));
+        assert!(!is_md5_hash("foobar"));
+        assert!(!is_md5_hash("md5foobar"));
+
+        let mut expected = b"md53048996f18f5b9587ac20c73b9b2672b".to_vec();
+        expected.push(0);
+
+        assert_eq!(md5_hash_password("postgres", "foobar", salt), expected);
+        assert_eq!(md5_hash_second_pass(stored_hash, salt), expected);
+    }
+
+    #[test]
+    fn test_parse_data_row() {
+        let mut row = data_row(&vec!["postgres".to_string(), "".to_string()]);
+        row.advance(mem::size_of::<u8>() + mem::size_of::<i32>());
+
+        assert_eq!(
+            parse_data_row(row).unwrap(),
+            vec![Some("postgres".to_string()), Some("".to_string())]
+        );
+
+        let mut null = BytesMut::new();
+        null.put_i16(1);
+        null.put_i32(-1);
+        assert_eq!(parse_data_row(null).unwrap(), vec![None]);
+
+        let mut short = BytesMut::new();
+        short.put_i16(1);
+        short.put_i32(10);
+        assert!(parse_data_row(short).is_err());
+    }
+}
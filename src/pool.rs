@@ -8,23 +8,32 @@ use once_cell::sync::Lazy;
 use parking_lot::{Mutex, RwLock};
 use rand::seq::SliceRandom;
 use rand::thread_rng;
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::time::Instant;
+use tokio::sync::{broadcast, Notify};
 
-use crate::config::{get_config, Address, General, LoadBalancingMode, PoolMode, Role, User};
+use crate::config::{
+    get_config, Address, AuthMethod, Config, General, LoadBalancingMode, PoolMode, ReadYourWrites,
+    Role, User,
+};
 use crate::errors::Error;
 
+use crate::scram::ScramSecret;
 use crate::server::Server;
-use crate::sharding::ShardingFunction;
+use crate::sharding::{ShardKeys, ShardingFunction, ShardingKeyType};
 use crate::stats::{get_reporter, Reporter};
+use crate::tls::ServerTls;
 
 pub type ProcessId = i32;
 pub type SecretKey = i32;
 pub type ServerHost = String;
 pub type ServerPort = u16;
+/// A server in the pools of all users: its host, port and database.
+type ServerKey = (ServerHost, ServerPort, String);
 
-pub type BanList = Arc<RwLock<Vec<HashMap<Address, NaiveDateTime>>>>;
+pub type BanList = Arc<RwLock<Vec<HashMap<Address, Ban>>>>;
 pub type ClientServerMap =
     Arc<Mutex<HashMap<(ProcessId, SecretKey), (ProcessId, SecretKey, ServerHost, ServerPort)>>>;
 pub type PoolMap = HashMap<PoolIdentifier, ConnectionPool>;
@@ -35,6 +44,66 @@ pub static POOLS: Lazy<ArcSwap<PoolMap>> = Lazy::new(|| ArcSwap::from_pointee(Ha
 static POOLS_HASH: Lazy<ArcSwap<HashSet<crate::config::Pool>>> =
     Lazy::new(|| ArcSwap::from_pointee(HashSet::default()));
 
+/// Databases paused with the PAUSE admin command, by name. Kept across reloads,
+/// so the servers can be changed while the clients wait.
+static PAUSED: Lazy<RwLock<HashSet<String>>> = Lazy::new(|| RwLock::new(HashSet::new()));
+
+/// Wakes up the clients waiting for a paused database.
+static RESUMED: Lazy<Notify> = Lazy::new(Notify::new);
+
+/// Databases that don't accept new clients, with the DISABLE admin command.
+static DISABLED: Lazy<RwLock<HashSet<String>>> = Lazy::new(|| RwLock::new(HashSet::new()));
+
+/// Replicas banned with the BAN admin command, by host, port and database. Kept across
+/// reloads and reconnects, and shared by the pools of all users.
+static MANUAL_BANS: Lazy<RwLock<HashMap<ServerKey, Ban>>> =
+    Lazy::new(|| RwLock::new(HashMap::new()));
+
+/// The databases killed with the KILL admin command, their clients disconnect.
+static KILLED: Lazy<broadcast::Sender<String>> = Lazy::new(|| broadcast::channel(16).0);
+
+/// How far behind the primary a replica is (ms), 0 if it replayed everything it received,
+/// and the LSN it replayed.
+const REPLICA_STATUS_QUERY: &str = "SELECT CASE \
+    WHEN pg_last_wal_receive_lsn() = pg_last_wal_replay_lsn() THEN 0 \
+    ELSE (EXTRACT(EPOCH FROM now() - pg_last_xact_replay_timestamp()) * 1000)::bigint \
+    END, pg_last_wal_replay_lsn()";
+
+/// Addresses are numbered across all pools, including pools created
+/// after startup, so their statistics don't get mixed up.
+static ADDRESS_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// The ids of the addresses we numbered, so an address keeps its id
+/// (and its statistics) when its pool is created again, e.g. by RELOAD.
+static ADDRESS_IDS: Lazy<Mutex<HashMap<Address, usize>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Why and for how long a server is banned.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Ban {
+    /// When it was banned.
+    pub time: NaiveDateTime,
+
+    /// Banned with BAN in the admin database, not because of an error.
+    pub manual: bool,
+
+    /// How long it's banned (seconds), until UNBAN if None.
+    pub duration: Option<i64>,
+}
+
+impl Ban {
+    /// How many seconds are left, None if it's banned until UNBAN.
+    pub fn remaining(&self) -> Option<i64> {
+        let now = chrono::offset::Utc::now().naive_utc();
+
+        self.duration
+            .map(|duration| duration - (now.timestamp() - self.time.timestamp()))
+    }
+
+    fn expired(&self) -> bool {
+        self.remaining().map_or(false, |remaining| remaining < 0)
+    }
+}
+
 /// An identifier for a PgCat pool,
 /// a database visible to clients.
 #[derive(Hash, Debug, Clone, PartialEq, Eq)]
@@ -86,6 +155,18 @@ pub struct PoolSettings {
     // Sharding key
     pub automatic_sharding_key: Option<String>,
 
+    // Sharding key type
+    pub sharding_key_type: ShardingKeyType,
+
+    // Sharding key of each table
+    pub sharding_keys: BTreeMap<String, String>,
+
+    // Tables that are the same on all shards
+    pub reference_tables: Vec<String>,
+
+    // Keys of each shard, for range and list sharding.
+    pub shard_keys: Arc<ShardKeys>,
+
     // Health check timeout
     pub healthcheck_timeout: u64,
 
@@ -94,6 +175,19 @@ pub struct PoolSettings {
 
     // Ban time
     pub ban_time: i64,
+
+    // Client authentication method, if set for the user or the pool.
+    // Falls back to the general setting otherwise.
+    pub auth_method: Option<AuthMethod>,
+
+    // Skip replicas lagging more than this (ms).
+    pub max_replica_lag: Option<u64>,
+
+    // Read your writes mode.
+    pub read_your_writes: ReadYourWrites,
+
+    // How long reads stick to the primary after a write (ms).
+    pub read_your_writes_window: u64,
 }
 
 impl Default for PoolSettings {
@@ -108,9 +202,17 @@ impl Default for PoolSettings {
             primary_reads_enabled: true,
             sharding_function: ShardingFunction::PgBigintHash,
             automatic_sharding_key: None,
+            sharding_key_type: ShardingKeyType::Bigint,
+            sharding_keys: BTreeMap::new(),
+            reference_tables: Vec::new(),
+            shard_keys: Arc::default(),
             healthcheck_delay: General::default_healthcheck_delay(),
             healthcheck_timeout: General::default_healthcheck_timeout(),
             ban_time: General::default_ban_time(),
+            auth_method: None,
+            max_replica_lag: None,
+            read_your_writes: ReadYourWrites::Disabled,
+            read_your_writes_window: crate::config::Pool::default_read_your_writes_window(),
         }
     }
 }
@@ -123,12 +225,19 @@ pub struct ConnectionPool {
 
     /// The addresses (host, port, role) to handle
     /// failover and load balancing deterministically.
-    addresses: Vec<Vec<Address>>,
+    /// The roles can change at runtime, see `check_roles`.
+    addresses: Arc<RwLock<Vec<Vec<Address>>>>,
 
     /// List of banned addresses (see above)
     /// that should not be queried.
     banlist: BanList,
 
+    /// How far behind the primary each replica is (ms), by address id.
+    replica_lag: Arc<RwLock<HashMap<usize, u64>>>,
+
+    /// The LSN each replica replayed, by address id.
+    replica_lsn: Arc<RwLock<HashMap<usize, u64>>>,
+
     /// The statistics aggregator runs in a separate task
     /// and receives stats from clients, servers, and the pool.
     stats: Reporter,
@@ -136,7 +245,16 @@ pub struct ConnectionPool {
     /// The server information (K messages) have to be passed to the
     /// clients on startup. We pre-connect to all shards and replicas
     /// on pool creation and save the K messages here.
-    server_info: BytesMut,
+    server_info: Arc<RwLock<BytesMut>>,
+
+    /// We managed to connect to the servers and got the server information.
+    /// Pools that can only log in with a client's SCRAM proof are validated
+    /// when the first client authenticates.
+    validated: Arc<AtomicBool>,
+
+    /// ClientKey recovered from a client's SCRAM proof, used to log into the servers
+    /// when the config only has the SCRAM verifier for the user.
+    scram_client_key: Arc<RwLock<Option<Vec<u8>>>>,
 
     /// Pool configuration.
     pub settings: PoolSettings,
@@ -144,11 +262,11 @@ pub struct ConnectionPool {
 
 impl ConnectionPool {
     /// Construct the connection pool from the configuration.
-    pub async fn from_config(client_server_map: ClientServerMap) -> Result<(), Error> {
-        let config = get_config();
-
+    pub async fn from_config(
+        config: &Config,
+        client_server_map: ClientServerMap,
+    ) -> Result<(), Error> {
         let mut new_pools = HashMap::new();
-        let mut address_id = 0;
 
         let mut pools_hash = (*(*POOLS_HASH.load())).clone();
 
@@ -181,123 +299,56 @@ impl ConnectionPool {
                     pool_name, user.username
                 );
 
-                let mut shards = Vec::new();
-                let mut addresses = Vec::new();
-                let mut banlist = Vec::new();
-                let mut shard_ids = pool_config
-                    .shards
-                    .clone()
-                    .into_keys()
-                    .collect::<Vec<String>>();
-
-                // Sort by shard number to ensure consistency.
-                shard_ids.sort_by_key(|k| k.parse::<i64>().unwrap());
-
-                for shard_idx in &shard_ids {
-                    let shard = &pool_config.shards[shard_idx];
-                    let mut pools = Vec::new();
-                    let mut servers = Vec::new();
-                    let mut replica_number = 0;
-
-                    for (address_index, server) in shard.servers.iter().enumerate() {
-                        let address = Address {
-                            id: address_id,
-                            database: shard.database.clone(),
-                            host: server.host.clone(),
-                            port: server.port,
-                            role: server.role,
-                            address_index,
-                            replica_number,
-                            shard: shard_idx.parse::<usize>().unwrap(),
-                            username: user.username.clone(),
-                            pool_name: pool_name.clone(),
-                        };
-
-                        address_id += 1;
-
-                        if server.role == Role::Replica {
-                            replica_number += 1;
-                        }
-
-                        let manager = ServerPool::new(
-                            address.clone(),
-                            user.clone(),
-                            &shard.database,
-                            client_server_map.clone(),
-                            get_reporter(),
-                        );
-
-                        let connect_timeout = match pool_config.connect_timeout {
-                            Some(connect_timeout) => connect_timeout,
-                            None => config.general.connect_timeout,
-                        };
-
-                        let idle_timeout = match pool_config.idle_timeout {
-                            Some(idle_timeout) => idle_timeout,
-                            None => config.general.idle_timeout,
-                        };
-
-                        let pool = Pool::builder()
-                            .max_size(user.pool_size)
-                            .connection_timeout(std::time::Duration::from_millis(connect_timeout))
-                            .idle_timeout(Some(std::time::Duration::from_millis(idle_timeout)))
-                            .test_on_check_out(false)
-                            .build(manager)
-                            .await
-                            .unwrap();
-
-                        pools.push(pool);
-                        servers.push(address);
+                let pool = ConnectionPool::new(
+                    pool_name,
+                    pool_config,
+                    user,
+                    &config.general,
+                    client_server_map.clone(),
+                )
+                .await;
+
+                // Without the cleartext password, we log in with the ClientKey of a client's
+                // SCRAM proof. The clients of the old pool already gave it to us.
+                let scram = ScramSecret::parse(&user.password).is_some();
+
+                if scram {
+                    if let Some(old_pool) = get_pool(pool_name, &user.username) {
+                        pool.keep_scram_client_key(&old_pool);
                     }
-
-                    shards.push(pools);
-                    addresses.push(servers);
-                    banlist.push(HashMap::new());
                 }
 
-                assert_eq!(shards.len(), addresses.len());
-
-                let mut pool = ConnectionPool {
-                    databases: shards,
-                    addresses,
-                    banlist: Arc::new(RwLock::new(banlist)),
-                    stats: get_reporter(),
-                    server_info: BytesMut::new(),
-                    settings: PoolSettings {
-                        pool_mode: pool_config.pool_mode,
-                        load_balancing_mode: pool_config.load_balancing_mode,
-                        // shards: pool_config.shards.clone(),
-                        shards: shard_ids.len(),
-                        user: user.clone(),
-                        default_role: match pool_config.default_role.as_str() {
-                            "any" => None,
-                            "replica" => Some(Role::Replica),
-                            "primary" => Some(Role::Primary),
-                            _ => unreachable!(),
-                        },
-                        query_parser_enabled: pool_config.query_parser_enabled,
-                        primary_reads_enabled: pool_config.primary_reads_enabled,
-                        sharding_function: pool_config.sharding_function,
-                        automatic_sharding_key: pool_config.automatic_sharding_key.clone(),
-                        healthcheck_delay: config.general.healthcheck_delay,
-                        healthcheck_timeout: config.general.healthcheck_timeout,
-                        ban_time: config.general.ban_time,
-                    },
-                };
-
                 // Connect to the servers to make sure pool configuration is valid
-                // before setting it globally.
-                match pool.validate().await {
-                    Ok(_) => (),
-                    Err(err) => {
-                        error!("Could not validate connection pool: {:?}", err);
-                        return Err(err);
-                    }
-                };
+                // before setting it globally. Without the cleartext password,
+                // we can only log in once a client gave us its SCRAM proof.
+                if !scram || pool.scram_client_key.read().is_some() {
+                    match pool.validate().await {
+                        Ok(_) => (),
+                        Err(err) => {
+                            error!("Could not validate connection pool: {:?}", err);
+                            return Err(err);
+                        }
+                    };
+                } else {
+                    info!(
+                        "[pool: {}][user: {}] will be validated when the first client logs in",
+                        pool_name, user.username
+                    );
+                }
 
                 // There is one pool per database/user pair.
                 new_pools.insert(PoolIdentifier::new(pool_name, &user.username), pool);
             }
+
+            // Keep the pools of the users we found with auth_query. If the pool changed,
+            // they are created again when the users log in.
+            if !changed && pool_config.auth_query.is_some() {
+                for (identifier, pool) in get_all_pools() {
+                    if identifier.db == *pool_name && !new_pools.contains_key(&identifier) {
+                        new_pools.insert(identifier, pool);
+                    }
+                }
+            }
         }
 
         POOLS.store(Arc::new(new_pools.clone()));
@@ -306,12 +357,142 @@ impl ConnectionPool {
         Ok(())
     }
 
+    /// Create the pool for a database/user pair. The servers are not contacted
+    /// until the pool is validated or a client asks for a connection.
+    pub async fn new(
+        pool_name: &str,
+        pool_config: &crate::config::Pool,
+        user: &User,
+        general: &General,
+        client_server_map: ClientServerMap,
+    ) -> ConnectionPool {
+        let mut addresses = Vec::new();
+        let mut banlist = Vec::new();
+        let scram_client_key = Arc::new(RwLock::new(None));
+        let mut shard_ids = pool_config
+            .shards
+            .clone()
+            .into_keys()
+            .collect::<Vec<String>>();
+
+        // Sort by shard number to ensure consistency.
+        shard_ids.sort_by_key(|k| k.parse::<i64>().unwrap());
+
+        for shard_idx in &shard_ids {
+            let shard = &pool_config.shards[shard_idx];
+            let mut servers = Vec::new();
+            let mut replica_number = 0;
+
+            for (address_index, server) in shard.servers.iter().enumerate() {
+                let mut address = Address {
+                    id: 0,
+                    database: shard.database.clone(),
+                    host: server.host.clone(),
+                    port: server.port,
+                    role: server.role,
+                    address_index,
+                    replica_number,
+                    shard: shard_idx.parse::<usize>().unwrap(),
+                    username: user.username.clone(),
+                    pool_name: pool_name.to_string(),
+                };
+
+                if server.role == Role::Replica {
+                    replica_number += 1;
+                }
+
+                address.id = address_id(&address);
+                servers.push(address);
+            }
+
+            addresses.push(servers);
+            banlist.push(HashMap::new());
+        }
+
+        let addresses = Arc::new(RwLock::new(addresses));
+
+        let shards = server_pools(
+            &addresses,
+            pool_config,
+            user,
+            general,
+            &client_server_map,
+            &scram_client_key,
+        )
+        .await;
+
+        assert_eq!(shards.len(), addresses.read().len());
+
+        ConnectionPool {
+            databases: shards,
+            addresses,
+            banlist: Arc::new(RwLock::new(banlist)),
+            replica_lag: Arc::new(RwLock::new(HashMap::new())),
+            replica_lsn: Arc::new(RwLock::new(HashMap::new())),
+            stats: get_reporter(),
+            server_info: Arc::new(RwLock::new(BytesMut::new())),
+            validated: Arc::new(AtomicBool::new(false)),
+            scram_client_key,
+            settings: PoolSettings {
+                pool_mode: pool_config.pool_mode,
+                load_balancing_mode: pool_config.load_balancing_mode,
+                // shards: pool_config.shards.clone(),
+                shards: shard_ids.len(),
+                user: user.clone(),
+                default_role: match pool_config.default_role.as_str() {
+                    "any" => None,
+                    "replica" => Some(Role::Replica),
+                    "primary" => Some(Role::Primary),
+                    _ => unreachable!(),
+                },
+                query_parser_enabled: pool_config.query_parser_enabled,
+                primary_reads_enabled: pool_config.primary_reads_enabled,
+                sharding_function: pool_config.sharding_function,
+                automatic_sharding_key: pool_config.automatic_sharding_key.clone(),
+                sharding_key_type: pool_config.sharding_key_type,
+                sharding_keys: pool_config.sharding_keys.clone(),
+                reference_tables: pool_config.reference_tables.clone(),
+                shard_keys: Arc::new(pool_config.shard_keys()),
+                healthcheck_delay: general.healthcheck_delay,
+                healthcheck_timeout: general.healthcheck_timeout,
+                ban_time: general.ban_time,
+                auth_method: user.auth_method.or(pool_config.auth_method),
+                max_replica_lag: pool_config.max_replica_lag,
+                read_your_writes: pool_config.read_your_writes,
+                read_your_writes_window: pool_config.read_your_writes_window,
+            },
+        }
+    }
+
+    /// The same pool with new server connections. The addresses, with their ids and roles,
+    /// the bans and the replica lag are shared with this pool, whose servers are closed
+    /// when their clients release them.
+    pub async fn reconnect(
+        &self,
+        pool_config: &crate::config::Pool,
+        general: &General,
+        client_server_map: ClientServerMap,
+    ) -> ConnectionPool {
+        ConnectionPool {
+            databases: server_pools(
+                &self.addresses,
+                pool_config,
+                &self.settings.user,
+                general,
+                &client_server_map,
+                &self.scram_client_key,
+            )
+            .await,
+            ..self.clone()
+        }
+    }
+
     /// Connect to all shards and grab server information.
     /// Return server information we will pass to the clients
     /// when they connect.
     /// This also warms up the pool for clients that connect when
     /// the pooler starts up.
-    async fn validate(&mut self) -> Result<(), Error> {
+    pub async fn validate(&self) -> Result<(), Error> {
         let mut server_infos = Vec::new();
         for shard in 0..self.shards() {
             for server in 0..self.servers(shard) {
@@ -349,7 +530,8 @@ impl ConnectionPool {
 
         // We're assuming all servers are identical.
         // TODO: not true.
-        self.server_info = server_infos[0].clone();
+        *self.server_info.write() = server_infos[0].clone();
+        self.validated.store(true, Ordering::Relaxed);
 
         Ok(())
     }
@@ -357,15 +539,111 @@ impl ConnectionPool {
     /// Get a connection from the pool.
     pub async fn get(
         &self,
-        shard: usize,           // shard number
-        role: Option<Role>,     // primary or replica
-        client_process_id: i32, // client id
+        shard: usize,                 // shard number
+        role: Option<Role>,           // primary or replica
+        min_replica_lsn: Option<u64>, // replicas must have replayed this LSN
+        client_process_id: i32,       // client id
     ) -> Result<(PooledConnection<'_, ServerPool>, Address), Error> {
-        let mut candidates: Vec<&Address> = self.addresses[shard]
+        // The clients wait here until the database is resumed.
+        let pool_name = self.address(shard, 0).pool_name;
+
+        if database_paused(&pool_name) {
+            self.stats.client_waiting(client_process_id);
+            wait_for_resume(&pool_name).await;
+        }
+
+        self.checkout(shard, role, min_replica_lsn, client_process_id)
+            .await
+    }
+
+    /// Get a connection from each of the shards, for the queries sent to several shards.
+    /// They're taken in order, so two clients don't wait on each other's servers,
+    /// and given back if the database is paused in between, so PAUSE doesn't wait for
+    /// a client that waits for RESUME. The error comes with the shard that failed.
+    pub async fn get_all(
+        &self,
+        shards: &[usize],
+        role: Option<Role>,
+        min_replica_lsn: Option<u64>,
+        client_process_id: i32,
+    ) -> Result<Vec<(PooledConnection<'_, ServerPool>, Address)>, (usize, Error)> {
+        // All the shards are in the same database.
+        let pool_name = self.address(0, 0).pool_name;
+
+        'shards: loop {
+            if database_paused(&pool_name) {
+                self.stats.client_waiting(client_process_id);
+                wait_for_resume(&pool_name).await;
+            }
+
+            let mut connections: Vec<(PooledConnection<'_, ServerPool>, Address)> =
+                Vec::with_capacity(shards.len());
+
+            for shard in shards {
+                if database_paused(&pool_name) {
+                    for (server, _) in &connections {
+                        self.stats.server_idle(server.server_id());
+                    }
+
+                    continue 'shards;
+                }
+
+                match self
+                    .checkout(*shard, role, min_replica_lsn, client_process_id)
+                    .await
+                {
+                    Ok(connection) => connections.push(connection),
+                    Err(err) => return Err((*shard, err)),
+                }
+            }
+
+            return Ok(connections);
+        }
+    }
+
+    /// Get a connection from the shard, paused or not.
+    async fn checkout(
+        &self,
+        shard: usize,
+        role: Option<Role>,
+        min_replica_lsn: Option<u64>,
+        client_process_id: i32,
+    ) -> Result<(PooledConnection<'_, ServerPool>, Address), Error> {
+        let mut candidates: Vec<Address> = self.addresses.read()[shard]
             .iter()
             .filter(|address| address.role == role)
+            .cloned()
             .collect();
 
+        // Replicas too far behind the primary are skipped, unless they all are.
+        if candidates.iter().any(|address| !self.is_lagging(address)) {
+            candidates.retain(|address| !self.is_lagging(address));
+        } else if !candidates.is_empty() && self.settings.max_replica_lag.is_some() {
+            debug!(
+                "All replicas of shard {} are lagging, using them anyway",
+                shard
+            );
+        }
+
+        // Read your writes: only the replicas that replayed the client's last write,
+        // or the primary if none did yet.
+        if let Some(min_replica_lsn) = min_replica_lsn {
+            candidates.retain(|address| {
+                address.role == Role::Primary
+                    || self
+                        .replica_lsn(address)
+                        .map_or(false, |lsn| lsn >= min_replica_lsn)
+            });
+
+            if candidates.is_empty() {
+                candidates = self.addresses.read()[shard]
+                    .iter()
+                    .filter(|address| address.role == Role::Primary)
+                    .cloned()
+                    .collect();
+            }
+        }
+
         // We shuffle even if least_outstanding_queries is used to avoid imbalance
         // in cases where all candidates have more or less the same number of outstanding
         // queries
@@ -387,7 +665,7 @@ impl ConnectionPool {
 
             let mut force_healthcheck = false;
 
-            if self.is_banned(address) {
+            if self.is_banned(&address) {
                 if self.try_unban(&address).await {
                     force_healthcheck = true;
                 } else {
@@ -408,7 +686,7 @@ impl ConnectionPool {
                 Ok(conn) => conn,
                 Err(err) => {
                     error!("Banning instance {:?}, error: {:?}", address, err);
-                    self.ban(address, client_process_id);
+                    self.ban(&address, client_process_id);
                     self.stats
                         .client_checkout_error(client_process_id, address.id);
                     continue;
@@ -434,14 +712,14 @@ impl ConnectionPool {
                 );
                 self.stats
                     .server_active(client_process_id, server.server_id());
-                return Ok((conn, address.clone()));
+                return Ok((conn, address));
             }
 
             if self
-                .run_health_check(address, server, now, client_process_id)
+                .run_health_check(&address, server, now, client_process_id)
                 .await
             {
-                return Ok((conn, address.clone()));
+                return Ok((conn, address));
             } else {
                 continue;
             }
@@ -501,7 +779,7 @@ impl ConnectionPool {
         // Don't leave a bad connection in the pool.
         server.mark_bad();
 
-        self.ban(&address, client_process_id);
+        self.ban(address, client_process_id);
         return false;
     }
 
@@ -518,14 +796,63 @@ impl ConnectionPool {
         let mut guard = self.banlist.write();
         error!("Banning {:?}", address);
         self.stats.client_ban_error(client_id, address.id);
-        guard[address.shard].insert(address.clone(), now);
+
+        // A ban from the admin database is not shortened.
+        if manual_ban(address).is_some() {
+            return;
+        }
+
+        guard[address.shard].insert(
+            address.clone(),
+            Ban {
+                time: now,
+                manual: false,
+                duration: Some(self.settings.ban_time),
+            },
+        );
+    }
+
+    /// Ban a replica with BAN in the admin database, for some seconds or until UNBAN.
+    /// It's not unbanned when all the replicas are banned.
+    pub fn ban_manually(&self, address: &Address, duration: Option<i64>) {
+        if address.role == Role::Primary {
+            return;
+        }
+
+        warn!("Banning {:?} from the admin database", address);
+
+        MANUAL_BANS.write().insert(
+            server_key(address),
+            Ban {
+                time: chrono::offset::Utc::now().naive_utc(),
+                manual: true,
+                duration,
+            },
+        );
     }
 
     /// Clear the replica to receive traffic again. Takes effect immediately
     /// for all new transactions.
-    pub fn _unban(&self, address: &Address) {
+    pub fn unban(&self, address: &Address) {
         let mut guard = self.banlist.write();
         guard[address.shard].remove(address);
+        MANUAL_BANS.write().remove(&server_key(address));
+    }
+
+    /// The banned servers.
+    pub fn bans(&self) -> Vec<(Address, Ban)> {
+        let addresses = self.addresses.read().clone();
+        let guard = self.banlist.read();
+
+        addresses
+            .iter()
+            .flatten()
+            .filter_map(|address| {
+                manual_ban(address)
+                    .or_else(|| guard[address.shard].get(address).cloned())
+                    .map(|ban| (address.clone(), ban))
+            })
+            .collect()
     }
 
     /// Check if address is banned
@@ -533,12 +860,11 @@ impl ConnectionPool {
     pub fn is_banned(&self, address: &Address) -> bool {
         let guard = self.banlist.read();
 
-        match guard[address.shard].get(address) {
-            Some(_) => true,
-            None => {
-                debug!("{:?} is ok", address);
-                false
-            }
+        if guard[address.shard].contains_key(address) || manual_ban(address).is_some() {
+            true
+        } else {
+            debug!("{:?} is ok", address);
+            false
         }
     }
 
@@ -550,21 +876,24 @@ impl ConnectionPool {
         }
 
         // Check if all replicas are banned, in that case unban all of them
-        let replicas_available = self.addresses[address.shard]
+        let replicas = self.addresses.read()[address.shard]
             .iter()
             .filter(|addr| addr.role == Role::Replica)
-            .count();
+            .cloned()
+            .collect::<Vec<_>>();
 
-        debug!("Available targets: {}", replicas_available);
+        debug!("Available targets: {}", replicas.len());
 
-        let read_guard = self.banlist.read();
-        let all_replicas_banned = read_guard[address.shard].len() == replicas_available;
-        drop(read_guard);
+        let all_replicas_banned = replicas.iter().all(|replica| self.is_banned(replica));
+
+        // Except the ones banned from the admin database, e.g. for maintenance.
+        if manual_ban(address).is_some() {
+            return false;
+        }
 
         if all_replicas_banned {
-            let mut write_guard = self.banlist.write();
             warn!("Unbanning all replicas.");
-            write_guard[address.shard].clear();
+            self.banlist.write()[address.shard].clear();
 
             return true;
         }
@@ -572,10 +901,7 @@ impl ConnectionPool {
         // Check if ban time is expired
         let read_guard = self.banlist.read();
         let exceeded_ban_time = match read_guard[address.shard].get(address) {
-            Some(timestamp) => {
-                let now = chrono::offset::Utc::now().naive_utc();
-                now.timestamp() - timestamp.timestamp() > self.settings.ban_time
-            }
+            Some(ban) => ban.expired(),
             None => return true,
         };
         drop(read_guard);
@@ -593,6 +919,201 @@ impl ConnectionPool {
         }
     }
 
+    /// Ask the servers of each shard if they are in recovery and label the one
+    /// that isn't as the primary, e.g. after a replica was promoted.
+    /// Shards with more than one server not in recovery are left alone.
+    pub async fn check_roles(&self) {
+        for shard in 0..self.shards() {
+            if self.servers(shard) < 2 {
+                continue;
+            }
+
+            let mut primaries = Vec::new();
+
+            for server in 0..self.servers(shard) {
+                if self.is_in_recovery(shard, server).await == Some(false) {
+                    primaries.push(server);
+                }
+            }
+
+            match primaries.as_slice() {
+                [primary] => self.set_primary(shard, *primary),
+                [] => (),
+                _ => warn!(
+                    "[pool: {}][user: {}] shard {} has {} servers not in recovery, not changing roles",
+                    self.address(shard, 0).pool_name,
+                    self.settings.user.username,
+                    shard,
+                    primaries.len()
+                ),
+            };
+        }
+    }
+
+    /// Check if the server is in recovery, i.e. a replica.
+    /// None if we couldn't ask it.
+    async fn is_in_recovery(&self, shard: usize, server: usize) -> Option<bool> {
+        self.fetch_row(shard, server, "SELECT pg_is_in_recovery()")
+            .await
+            .and_then(|row| row.into_iter().next().flatten())
+            .map(|in_recovery| in_recovery == "t")
+    }
+
+    /// Measure how far behind the primary each replica is and which LSN it replayed,
+    /// if the pool has a max_replica_lag or uses the LSN to read your writes.
+    pub async fn check_replicas(&self) {
+        if self.settings.max_replica_lag.is_none()
+            && self.settings.read_your_writes != ReadYourWrites::Lsn
+        {
+            return;
+        }
+
+        for shard in 0..self.shards() {
+            for server in 0..self.servers(shard) {
+                let address = self.address(shard, server);
+
+                let row = match address.role {
+                    Role::Replica => self
+                        .fetch_row(shard, server, REPLICA_STATUS_QUERY)
+                        .await
+                        .unwrap_or_default(),
+                    Role::Primary => Vec::new(),
+                };
+
+                let lag = row
+                    .first()
+                    .cloned()
+                    .flatten()
+                    .and_then(|lag| lag.parse::<u64>().ok());
+                let lsn = row
+                    .get(1)
+                    .cloned()
+                    .flatten()
+                    .and_then(|lsn| parse_lsn(&lsn));
+
+                match lag {
+                    Some(lag) => self.replica_lag.write().insert(address.id, lag),
+                    None => self.replica_lag.write().remove(&address.id),
+                };
+
+                match lsn {
+                    Some(lsn) => self.replica_lsn.write().insert(address.id, lsn),
+                    None => self.replica_lsn.write().remove(&address.id),
+                };
+            }
+        }
+    }
+
+    /// How far behind the primary the replica was the last time we checked (ms).
+    pub fn replica_lag(&self, address: &Address) -> Option<u64> {
+        self.replica_lag.read().get(&address.id).cloned()
+    }
+
+    /// The LSN the replica replayed the last time we checked.
+    pub fn replica_lsn(&self, address: &Address) -> Option<u64> {
+        self.replica_lsn.read().get(&address.id).cloned()
+    }
+
+    /// The replica is further behind the primary than max_replica_lag.
+    fn is_lagging(&self, address: &Address) -> bool {
+        match (self.settings.max_replica_lag, self.replica_lag(address)) {
+            (Some(max_replica_lag), Some(lag)) => {
+                address.role == Role::Replica && lag > max_replica_lag
+            }
+            _ => false,
+        }
+    }
+
+    /// Run a query returning a single row on a server of the pool.
+    /// None if it failed.
+    async fn fetch_row(
+        &self,
+        shard: usize,
+        server: usize,
+        query: &str,
+    ) -> Option<Vec<Option<String>>> {
+        let timeout = tokio::time::Duration::from_millis(self.settings.healthcheck_timeout);
+
+        let mut conn = match self.databases[shard][server].get().await {
+            Ok(conn) => conn,
+            Err(err) => {
+                debug!(
+                    "Could not connect to {:?}: {:?}",
+                    self.address(shard, server),
+                    err
+                );
+                return None;
+            }
+        };
+
+        match tokio::time::timeout(timeout, conn.fetch_rows(query)).await {
+            Ok(Ok(rows)) => rows.into_iter().next(),
+
+            Ok(Err(err)) => {
+                debug!(
+                    "Query {} failed on {:?}: {:?}",
+                    query,
+                    self.address(shard, server),
+                    err
+                );
+                None
+            }
+
+            Err(_) => {
+                debug!(
+                    "Query {} timed out on {:?}",
+                    query,
+                    self.address(shard, server)
+                );
+                conn.mark_bad();
+                None
+            }
+        }
+    }
+
+    /// Make the server the primary of its shard and the other servers replicas.
+    fn set_primary(&self, shard: usize, primary: usize) {
+        let mut guard = self.addresses.write();
+        let addresses = &mut guard[shard];
+
+        if addresses
+            .iter()
+            .enumerate()
+            .all(|(index, address)| (address.role == Role::Primary) == (index == primary))
+        {
+            return;
+        }
+
+        let mut replica_number = 0;
+
+        for (index, address) in addresses.iter_mut().enumerate() {
+            let old_name = address.name();
+
+            if index == primary {
+                address.role = Role::Primary;
+                address.replica_number = 0;
+            } else {
+                address.role = Role::Replica;
+                address.replica_number = replica_number;
+                replica_number += 1;
+            }
+
+            if address.name() != old_name {
+                warn!(
+                    "{}:{} changed from {} to {}",
+                    address.host,
+                    address.port,
+                    old_name,
+                    address.name()
+                );
+                self.stats.address_role_changed(address.id, address.name());
+            }
+        }
+
+        // The bans were for the old roles.
+        self.banlist.write()[shard].clear();
+    }
+
     /// Get the number of configured shards.
     pub fn shards(&self) -> usize {
         self.databases.len()
@@ -601,7 +1122,7 @@ impl ConnectionPool {
     /// Get the number of servers (primary and replicas)
     /// configured for a shard.
     pub fn servers(&self, shard: usize) -> usize {
-        self.addresses[shard].len()
+        self.addresses.read()[shard].len()
     }
 
     /// Get the total number of servers (databases) we are connected to.
@@ -619,12 +1140,46 @@ impl ConnectionPool {
     }
 
     /// Get the address information for a shard server.
-    pub fn address(&self, shard: usize, server: usize) -> &Address {
-        &self.addresses[shard][server]
+    pub fn address(&self, shard: usize, server: usize) -> Address {
+        self.addresses.read()[shard][server].clone()
     }
 
     pub fn server_info(&self) -> BytesMut {
-        self.server_info.clone()
+        self.server_info.read().clone()
+    }
+
+    /// Did we manage to connect to the servers yet.
+    pub fn validated(&self) -> bool {
+        self.validated.load(Ordering::Relaxed)
+    }
+
+    /// Save the ClientKey from a client's SCRAM proof, so new server connections
+    /// can log in on its behalf.
+    pub fn set_scram_client_key(&self, client_key: Vec<u8>) {
+        let mut guard = self.scram_client_key.write();
+
+        if guard.as_ref() != Some(&client_key) {
+            *guard = Some(client_key);
+        }
+    }
+
+    /// Use the SCRAM ClientKey of the pool this one replaces, if the password didn't change.
+    fn keep_scram_client_key(&self, old_pool: &ConnectionPool) {
+        if old_pool.settings.user.password != self.settings.user.password {
+            return;
+        }
+
+        if let Some(client_key) = old_pool.scram_client_key.read().clone() {
+            self.set_scram_client_key(client_key);
+        }
+    }
+
+    /// No server connection is checked out, e.g. by a transaction in flight.
+    pub fn idle(&self) -> bool {
+        self.databases.iter().flatten().all(|pool| {
+            let state = pool.state();
+            state.idle_connections >= state.connections
+        })
     }
 
     fn busy_connection_count(&self, address: &Address) -> u32 {
@@ -642,31 +1197,102 @@ impl ConnectionPool {
     }
 }
 
+/// The bb8 pools of the servers of each shard, at the addresses.
+async fn server_pools(
+    addresses: &Arc<RwLock<Vec<Vec<Address>>>>,
+    pool_config: &crate::config::Pool,
+    user: &User,
+    general: &General,
+    client_server_map: &ClientServerMap,
+    scram_client_key: &Arc<RwLock<Option<Vec<u8>>>>,
+) -> Vec<Vec<Pool<ServerPool>>> {
+    let mut shard_ids = pool_config.shards.keys().collect::<Vec<_>>();
+    shard_ids.sort_by_key(|k| k.parse::<i64>().unwrap());
+
+    let mut shards = Vec::new();
+
+    for (shard, shard_idx) in shard_ids.into_iter().enumerate() {
+        let shard_config = &pool_config.shards[shard_idx];
+        let tls = ServerTls::new(pool_config, shard_idx);
+        let mut pools = Vec::new();
+
+        for address_index in 0..shard_config.servers.len() {
+            let manager = ServerPool::new(
+                addresses.clone(),
+                shard,
+                address_index,
+                user.clone(),
+                client_server_map.clone(),
+                scram_client_key.clone(),
+                tls.clone(),
+            );
+
+            let connect_timeout = match pool_config.connect_timeout {
+                Some(connect_timeout) => connect_timeout,
+                None => general.connect_timeout,
+            };
+
+            let idle_timeout = match pool_config.idle_timeout {
+                Some(idle_timeout) => idle_timeout,
+                None => general.idle_timeout,
+            };
+
+            let pool = Pool::builder()
+                .max_size(user.pool_size)
+                .connection_timeout(std::time::Duration::from_millis(connect_timeout))
+                .idle_timeout(Some(std::time::Duration::from_millis(idle_timeout)))
+                .test_on_check_out(false)
+                .build(manager)
+                .await
+                .unwrap();
+
+            pools.push(pool);
+        }
+
+        shards.push(pools);
+    }
+
+    shards
+}
+
 /// Wrapper for the bb8 connection pool.
 pub struct ServerPool {
-    address: Address,
+    addresses: Arc<RwLock<Vec<Vec<Address>>>>,
+    shard: usize,
+    address_index: usize,
     user: User,
-    database: String,
     client_server_map: ClientServerMap,
     stats: Reporter,
+    scram_client_key: Arc<RwLock<Option<Vec<u8>>>>,
+    tls: ServerTls,
 }
 
 impl ServerPool {
     pub fn new(
-        address: Address,
+        addresses: Arc<RwLock<Vec<Vec<Address>>>>,
+        shard: usize,
+        address_index: usize,
         user: User,
-        database: &str,
         client_server_map: ClientServerMap,
-        stats: Reporter,
+        scram_client_key: Arc<RwLock<Option<Vec<u8>>>>,
+        tls: ServerTls,
     ) -> ServerPool {
         ServerPool {
-            address,
+            addresses,
+            shard,
+            address_index,
             user,
-            database: database.to_string(),
             client_server_map,
-            stats,
+            stats: get_reporter(),
+            scram_client_key,
+            tls,
         }
     }
+
+    /// The address of the server, with its current role.
+    fn address(&self) -> Address {
+        self.addresses.read()[self.shard][self.address_index].clone()
+    }
 }
 
 #[async_trait]
@@ -676,26 +1302,31 @@ impl ManageConnection for ServerPool {
 
     /// Attempts to create a new connection.
     async fn connect(&self) -> Result<Self::Connection, Self::Error> {
-        info!("Creating a new server connection {:?}", self.address);
+        let address = self.address();
+
+        info!("Creating a new server connection {:?}", address);
         let server_id = rand::random::<i32>();
 
         self.stats.server_register(
             server_id,
-            self.address.id,
-            self.address.name(),
-            self.address.pool_name.clone(),
-            self.address.username.clone(),
+            address.id,
+            address.name(),
+            address.pool_name.clone(),
+            address.username.clone(),
         );
         self.stats.server_login(server_id);
 
+        let scram_client_key = self.scram_client_key.read().clone();
+
         // Connect to the PostgreSQL server.
         match Server::startup(
             server_id,
-            &self.address,
+            &address,
             &self.user,
-            &self.database,
+            &self.tls,
             self.client_server_map.clone(),
             self.stats.clone(),
+            scram_client_key,
         )
         .await
         {
@@ -733,10 +1364,572 @@ pub fn get_all_pools() -> HashMap<PoolIdentifier, ConnectionPool> {
     (*(*POOLS.load())).clone()
 }
 
-/// How many total servers we have in the config.
-pub fn get_number_of_addresses() -> usize {
+/// Add a pool created after startup, e.g. for a user found with auth_query.
+/// It replaces the existing pool for the database/user pair, if any.
+pub fn add_pool(identifier: PoolIdentifier, pool: ConnectionPool) {
+    POOLS.rcu(|pools| {
+        let mut pools = (**pools).clone();
+        pools.insert(identifier.clone(), pool.clone());
+        pools
+    });
+}
+
+/// The id of the address, the same as the last time we saw it.
+pub fn address_id(address: &Address) -> usize {
+    *ADDRESS_IDS
+        .lock()
+        .entry(address.clone())
+        .or_insert_with(|| ADDRESS_ID.fetch_add(1, Ordering::Relaxed))
+}
+
+/// The server of the address, in the pools of all users.
+fn server_key(address: &Address) -> ServerKey {
+    (address.host.clone(), address.port, address.database.clone())
+}
+
+/// The ban of the replica from the admin database, if it's not over.
+fn manual_ban(address: &Address) -> Option<Ban> {
+    if address.role != Role::Replica {
+        return None;
+    }
+
+    let key = server_key(address);
+    let ban = MANUAL_BANS.read().get(&key).cloned()?;
+
+    if ban.expired() {
+        MANUAL_BANS.write().remove(&key);
+        return None;
+    }
+
+    Some(ban)
+}
+
+/// Add the pool of a user, unless another client added one with the same password
+/// in the meantime, e.g. two clients of a new auth_query user. Returns the pool to use.
+pub fn add_pool_if_new(identifier: PoolIdentifier, pool: ConnectionPool) -> ConnectionPool {
+    let mut current = pool.clone();
+
+    POOLS.rcu(|pools| match pools.get(&identifier) {
+        Some(existing) if existing.settings.user.password == pool.settings.user.password => {
+            current = existing.clone();
+            Arc::clone(pools)
+        }
+        _ => {
+            current = pool.clone();
+
+            let mut pools = (**pools).clone();
+            pools.insert(identifier.clone(), pool.clone());
+            Arc::new(pools)
+        }
+    });
+
+    current
+}
+
+/// Hold the new server checkouts of the database, see `ConnectionPool::get`.
+pub fn pause_database(db: &str) {
+    PAUSED.write().insert(db.to_string());
+}
+
+/// Let the clients waiting for the database get their servers.
+pub fn resume_database(db: &str) {
+    PAUSED.write().remove(db);
+    RESUMED.notify_waiters();
+}
+
+pub fn database_paused(db: &str) -> bool {
+    PAUSED.read().contains(db)
+}
+
+/// Reject the new clients of the database.
+pub fn disable_database(db: &str) {
+    DISABLED.write().insert(db.to_string());
+}
+
+pub fn enable_database(db: &str) {
+    DISABLED.write().remove(db);
+}
+
+pub fn database_disabled(db: &str) -> bool {
+    DISABLED.read().contains(db)
+}
+
+/// Replace the pools of the database with new ones, so new server connections are made,
+/// e.g. after a DNS change. The old servers are closed when their clients release them.
+pub async fn reconnect_database(db: &str, client_server_map: ClientServerMap) {
+    let config = get_config();
+
+    let pool_config = match config.pools.get(db) {
+        Some(pool_config) => pool_config,
+        None => return,
+    };
+
+    for (identifier, pool) in get_all_pools() {
+        if identifier.db != db {
+            continue;
+        }
+
+        let new_pool = pool
+            .reconnect(pool_config, &config.general, client_server_map.clone())
+            .await;
+
+        add_pool(identifier, new_pool);
+    }
+}
+
+/// Disconnect the clients of the database right away, and close its servers.
+pub async fn kill_database(db: &str, client_server_map: ClientServerMap) {
+    reconnect_database(db, client_server_map).await;
+
+    // Nobody listening is fine.
+    let _ = KILLED.send(db.to_string());
+}
+
+/// Listen for the databases being killed, see `wait_for_kill`.
+pub fn database_kills() -> broadcast::Receiver<String> {
+    KILLED.subscribe()
+}
+
+/// Wait until the database is killed.
+pub async fn wait_for_kill(kills: &mut broadcast::Receiver<String>, db: &str) {
+    loop {
+        match kills.recv().await {
+            Ok(killed) if killed == db => return,
+            Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => (),
+            Err(broadcast::error::RecvError::Closed) => std::future::pending().await,
+        }
+    }
+}
+
+/// Wait until the database is not paused anymore.
+async fn wait_for_resume(db: &str) {
+    loop {
+        // Listen before checking, so we don't miss the RESUME in between.
+        let resumed = RESUMED.notified();
+        tokio::pin!(resumed);
+        resumed.as_mut().enable();
+
+        if !database_paused(db) {
+            return;
+        }
+
+        debug!("Database {} is paused, waiting", db);
+
+        resumed.await;
+    }
+}
+
+/// Check the roles of the servers in all pools, see `ConnectionPool::check_roles`.
+pub async fn check_roles() {
+    for pool in get_all_pools().values() {
+        pool.check_roles().await;
+    }
+}
+
+/// Check the replicas in all pools, see `ConnectionPool::check_replicas`.
+pub async fn check_replicas() {
+    for pool in get_all_pools().values() {
+        pool.check_replicas().await;
+    }
+}
+
+/// Parse an LSN, e.g. `16/B374D848`.
+pub fn parse_lsn(lsn: &str) -> Option<u64> {
+    let (high, low) = lsn.split_once('/')?;
+
+    Some((u64::from_str_radix(high, 16).ok()? << 32) | u64::from_str_radix(low, 16).ok()?)
+}
+
+/// The IDs of all the servers we are connected to.
+pub fn get_address_ids() -> Vec<usize> {
     get_all_pools()
-        .iter()
-        .map(|(_, pool)| pool.databases())
-        .sum()
+        .values()
+        .flat_map(|pool| {
+            pool.addresses
+                .read()
+                .iter()
+                .flatten()
+                .map(|address| address.id)
+                .collect::<Vec<usize>>()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::{ServerConfig, Shard};
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn test_parse_lsn() {
+        assert_eq!(parse_lsn("0/0"), Some(0));
+        assert_eq!(parse_lsn("16/B374D848"), Some(0x16_B374_D848));
+        assert_eq!(parse_lsn("16"), None);
+        assert_eq!(parse_lsn("X/1"), None);
+    }
+
+    #[tokio::test]
+    async fn test_set_primary() {
+        let shard = Shard {
+            servers: vec![
+                ServerConfig {
+                    host: String::from("localhost"),
+                    port: 5432,
+                    role: Role::Primary,
+                },
+                ServerConfig {
+                    host: String::from("localhost"),
+                    port: 5433,
+                    role: Role::Replica,
+                },
+                ServerConfig {
+                    host: String::from("localhost"),
+                    port: 5434,
+                    role: Role::Replica,
+                },
+            ],
+            ..Default::default()
+        };
+
+        let pool_config = crate::config::Pool {
+            shards: BTreeMap::from([(String::from("0"), shard)]),
+            ..Default::default()
+        };
+
+        let pool = ConnectionPool::new(
+            "test_db",
+            &pool_config,
+            &User::default(),
+            &General::default(),
+            Arc::new(Mutex::new(HashMap::new())),
+        )
+        .await;
+
+        pool.set_primary(0, 1);
+
+        assert_eq!(pool.address(0, 0).role, Role::Replica);
+        assert_eq!(pool.address(0, 0).name(), "test_db_shard_0_replica_0");
+        assert_eq!(pool.address(0, 1).role, Role::Primary);
+        assert_eq!(pool.address(0, 1).name(), "test_db_shard_0_primary");
+        assert_eq!(pool.address(0, 2).role, Role::Replica);
+        assert_eq!(pool.address(0, 2).name(), "test_db_shard_0_replica_1");
+    }
+
+    #[tokio::test]
+    async fn test_manual_ban() {
+        let replica = |port| ServerConfig {
+            host: String::from("localhost"),
+            port,
+            role: Role::Replica,
+        };
+
+        // Manual bans are global, these servers are only in this test.
+        let pool_config = crate::config::Pool {
+            shards: BTreeMap::from([(
+                String::from("0"),
+                Shard {
+                    servers: vec![replica(15432), replica(15433)],
+                    ..Default::default()
+                },
+            )]),
+            ..Default::default()
+        };
+
+        let pool = ConnectionPool::new(
+            "test_db",
+            &pool_config,
+            &User::default(),
+            &General::default(),
+            Arc::new(Mutex::new(HashMap::new())),
+        )
+        .await;
+
+        let (first, second) = (pool.address(0, 0), pool.address(0, 1));
+
+        pool.ban_manually(&first, None);
+        assert!(pool.is_banned(&first));
+        assert!(!pool.try_unban(&first).await);
+
+        // When all the replicas are banned, the manual bans stay.
+        pool.ban(&second, 0);
+        assert!(pool.try_unban(&second).await);
+        assert!(!pool.is_banned(&second));
+        assert!(pool.is_banned(&first));
+
+        // A failed health check doesn't shorten it.
+        pool.ban(&first, 0);
+        assert_eq!(pool.bans()[0].1.duration, None);
+
+        // It stays when the pool is rebuilt, e.g. by RELOAD or RECONNECT.
+        let rebuilt = ConnectionPool::new(
+            "test_db",
+            &pool_config,
+            &User::default(),
+            &General::default(),
+            Arc::new(Mutex::new(HashMap::new())),
+        )
+        .await;
+        assert!(rebuilt.is_banned(&rebuilt.address(0, 0)));
+        assert!(!rebuilt.is_banned(&rebuilt.address(0, 1)));
+        assert_eq!(rebuilt.bans().len(), 1);
+
+        pool.unban(&first);
+        assert!(!pool.is_banned(&first));
+        assert!(!rebuilt.is_banned(&rebuilt.address(0, 0)));
+
+        pool.ban_manually(&first, Some(-1));
+        assert!(pool.bans().is_empty());
+        assert!(pool.try_unban(&first).await);
+    }
+
+    #[tokio::test]
+    async fn test_reconnect() {
+        let server = |port, role| ServerConfig {
+            host: String::from("localhost"),
+            port,
+            role,
+        };
+
+        let pool_config = crate::config::Pool {
+            shards: BTreeMap::from([(
+                String::from("0"),
+                Shard {
+                    servers: vec![server(5432, Role::Primary), server(5433, Role::Replica)],
+                    ..Default::default()
+                },
+            )]),
+            ..Default::default()
+        };
+
+        let pool = ConnectionPool::new(
+            "test_db",
+            &pool_config,
+            &User::default(),
+            &General::default(),
+            Arc::new(Mutex::new(HashMap::new())),
+        )
+        .await;
+
+        let replica = pool.address(0, 1);
+        pool.ban(&replica, 0);
+
+        let reconnected = pool
+            .reconnect(
+                &pool_config,
+                &General::default(),
+                Arc::new(Mutex::new(HashMap::new())),
+            )
+            .await;
+
+        // Same addresses, so the stats and the bans carry over.
+        assert_eq!(reconnected.address(0, 0), pool.address(0, 0));
+        assert_eq!(reconnected.address(0, 1), replica);
+        assert!(reconnected.is_banned(&replica));
+        assert_eq!(reconnected.databases(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_address_ids() {
+        let pool_config = |port| crate::config::Pool {
+            shards: BTreeMap::from([(
+                String::from("0"),
+                Shard {
+                    servers: vec![ServerConfig {
+                        host: String::from("localhost"),
+                        port,
+                        role: Role::Primary,
+                    }],
+                    ..Default::default()
+                },
+            )]),
+            ..Default::default()
+        };
+
+        let pool = |port| async move {
+            ConnectionPool::new(
+                "address_ids_db",
+                &pool_config(port),
+                &User::default(),
+                &General::default(),
+                Arc::new(Mutex::new(HashMap::new())),
+            )
+            .await
+            .address(0, 0)
+            .id
+        };
+
+        // The address keeps its id when the pool is created again, e.g. by RELOAD.
+        assert_eq!(pool(5432).await, pool(5432).await);
+        assert_ne!(pool(5432).await, pool(5433).await);
+    }
+
+    #[tokio::test]
+    async fn test_add_pool_if_new() {
+        let pool = |password: &str| {
+            let user = User {
+                username: String::from("new_user"),
+                password: password.to_string(),
+                ..Default::default()
+            };
+
+            async move {
+                ConnectionPool::new(
+                    "add_pool_db",
+                    &crate::config::Pool::default(),
+                    &user,
+                    &General::default(),
+                    Arc::new(Mutex::new(HashMap::new())),
+                )
+                .await
+            }
+        };
+
+        let identifier = PoolIdentifier::new("add_pool_db", "new_user");
+
+        // The first client's pool is used by the second one.
+        let first = add_pool_if_new(identifier.clone(), pool("secret").await);
+        let second = add_pool_if_new(identifier.clone(), pool("secret").await);
+        assert!(Arc::ptr_eq(&first.validated, &second.validated));
+        assert!(Arc::ptr_eq(
+            &get_pool("add_pool_db", "new_user").unwrap().validated,
+            &first.validated
+        ));
+
+        // The password changed.
+        let third = add_pool_if_new(identifier, pool("changed").await);
+        assert!(!Arc::ptr_eq(&first.validated, &third.validated));
+        assert_eq!(
+            get_pool("add_pool_db", "new_user")
+                .unwrap()
+                .settings
+                .user
+                .password,
+            "changed"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_keep_scram_client_key() {
+        let pool = |password: &str| {
+            let user = User {
+                username: String::from("scram_user"),
+                password: password.to_string(),
+                ..Default::default()
+            };
+
+            async move {
+                ConnectionPool::new(
+                    "scram_db",
+                    &crate::config::Pool::default(),
+                    &user,
+                    &General::default(),
+                    Arc::new(Mutex::new(HashMap::new())),
+                )
+                .await
+            }
+        };
+
+        let old_pool = pool("SCRAM-SHA-256$4096:c2FsdA==$a2V5:a2V5").await;
+
+        // No client logged in yet.
+        let rebuilt = pool("SCRAM-SHA-256$4096:c2FsdA==$a2V5:a2V5").await;
+        rebuilt.keep_scram_client_key(&old_pool);
+        assert_eq!(*rebuilt.scram_client_key.read(), None);
+
+        old_pool.set_scram_client_key(vec![1, 2, 3]);
+        rebuilt.keep_scram_client_key(&old_pool);
+        assert_eq!(*rebuilt.scram_client_key.read(), Some(vec![1, 2, 3]));
+
+        // The key of the old password can't log in anymore.
+        let changed = pool("SCRAM-SHA-256$4096:c2FsdA==$b3RoZXI=:b3RoZXI=").await;
+        changed.keep_scram_client_key(&old_pool);
+        assert_eq!(*changed.scram_client_key.read(), None);
+    }
+
+    #[tokio::test]
+    async fn test_replica_lag() {
+        let replica = |port| ServerConfig {
+            host: String::from("localhost"),
+            port,
+            role: Role::Replica,
+        };
+
+        // Nothing listens on these ports: the replicas get() tries are banned.
+        let pool_config = crate::config::Pool {
+            shards: BTreeMap::from([(
+                String::from("0"),
+                Shard {
+                    servers: vec![replica(15434), replica(15435)],
+                    ..Default::default()
+                },
+            )]),
+            max_replica_lag: Some(1000),
+            connect_timeout: Some(100),
+            ..Default::default()
+        };
+
+        let pool = ConnectionPool::new(
+            "test_db",
+            &pool_config,
+            &User::default(),
+            &General::default(),
+            Arc::new(Mutex::new(HashMap::new())),
+        )
+        .await;
+
+        let (lagging, current) = (pool.address(0, 0), pool.address(0, 1));
+        pool.replica_lag.write().insert(lagging.id, 5000);
+        pool.replica_lag.write().insert(current.id, 10);
+
+        // Only the replica that isn't lagging is tried.
+        assert!(pool.get(0, Some(Role::Replica), None, 0).await.is_err());
+        assert!(pool.is_banned(&current));
+        assert!(!pool.is_banned(&lagging));
+
+        // Unless they all are.
+        pool.unban(&current);
+        pool.replica_lag.write().insert(current.id, 5000);
+        assert!(pool.get(0, Some(Role::Replica), None, 0).await.is_err());
+        assert!(pool.is_banned(&current));
+        assert!(pool.is_banned(&lagging));
+    }
+
+    #[tokio::test]
+    async fn test_pause_and_resume() {
+        pause_database("paused_db");
+        assert!(database_paused("paused_db"));
+        assert!(!database_paused("other_db"));
+
+        let waiting = tokio::spawn(wait_for_resume("paused_db"));
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        assert!(!waiting.is_finished());
+
+        resume_database("paused_db");
+        tokio::time::timeout(std::time::Duration::from_secs(1), waiting)
+            .await
+            .unwrap()
+            .unwrap();
+
+        disable_database("disabled_db");
+        assert!(database_disabled("disabled_db"));
+        enable_database("disabled_db");
+        assert!(!database_disabled("disabled_db"));
+    }
+
+    #[tokio::test]
+    async fn test_wait_for_kill() {
+        let mut kills = database_kills();
+        let waiting = tokio::spawn(async move { wait_for_kill(&mut kills, "killed_db").await });
+
+        KILLED.send(String::from("other_db")).unwrap();
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        assert!(!waiting.is_finished());
+
+        KILLED.send(String::from("killed_db")).unwrap();
+        tokio::time::timeout(std::time::Duration::from_secs(1), waiting)
+            .await
+            .unwrap()
+            .unwrap();
+    }
 }
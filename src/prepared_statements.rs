@@ -0,0 +1,463 @@
+/// Named prepared statements in transaction mode. The client's statements get
+/// a pooler-wide name, so they can be used on any server that has them prepared.
+use bytes::{BufMut, BytesMut};
+use log::warn;
+use std::collections::{HashMap, VecDeque};
+
+use crate::errors::Error;
+use crate::messages::{parse_complete, split_messages, Bind, Parse, Target};
+
+/// The response to a Parse or a Close, in the order the client expects them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Response {
+    /// The server sends it, we forward it to the client if the client sent the message.
+    Server { code: char, forward: bool },
+
+    /// The server already had the statement, we didn't send the Parse.
+    /// We send the ParseComplete after the responses to the messages before it.
+    ParseComplete,
+}
+
+/// The statements prepared on a server, by their pooler-wide name.
+#[derive(Debug)]
+pub struct ServerStatements {
+    /// When each statement was last used, on the `clock`.
+    statements: HashMap<String, u64>,
+
+    /// Counts the uses of the statements.
+    clock: u64,
+
+    /// How many statements we keep.
+    size: usize,
+
+    /// Statements prepared since the last ReadyForQuery. We forget them
+    /// if the server returns an error, they may not exist.
+    new_statements: Vec<String>,
+
+    /// The responses to the Parse and Close messages we are waiting for.
+    responses: VecDeque<Response>,
+}
+
+impl ServerStatements {
+    /// Keep up to `size` statements on the server.
+    pub fn new(size: usize) -> ServerStatements {
+        ServerStatements {
+            statements: HashMap::new(),
+            clock: 0,
+            size: size.max(1),
+            new_statements: Vec::new(),
+            responses: VecDeque::new(),
+        }
+    }
+
+    /// Check if the statement is prepared on the server.
+    pub fn contains(&mut self, name: &str) -> bool {
+        self.clock += 1;
+
+        match self.statements.get_mut(name) {
+            Some(used) => {
+                *used = self.clock;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Remember that the statement is prepared on the server. If we have too many,
+    /// the least recently used one is returned and must be closed.
+    pub fn add(&mut self, name: &str) -> Option<String> {
+        self.new_statements.push(name.to_string());
+
+        self.clock += 1;
+        self.statements.insert(name.to_string(), self.clock);
+
+        if self.statements.len() <= self.size {
+            return None;
+        }
+
+        let evicted = self
+            .statements
+            .iter()
+            .min_by_key(|(_, used)| **used)
+            .map(|(evicted, _)| evicted.clone())?;
+
+        self.statements.remove(&evicted);
+        Some(evicted)
+    }
+
+    /// We're sending a Parse ('1') or a Close ('3'). Its response is forwarded
+    /// to the client only if the client sent it.
+    pub fn expect_response(&mut self, code: char, forward: bool) {
+        self.responses.push_back(Response::Server { code, forward });
+    }
+
+    /// We're not sending the client's Parse, we send its ParseComplete ourselves.
+    pub fn skip_parse(&mut self) {
+        self.responses.push_back(Response::ParseComplete);
+    }
+
+    /// Check a message from the server. The ParseComplete messages we send before
+    /// it are added to the buffer, and we return if the message goes to the client.
+    pub fn forward_response(&mut self, code: char, buffer: &mut BytesMut) -> bool {
+        // The responses to the messages before them are done.
+        while self.responses.front() == Some(&Response::ParseComplete) {
+            self.responses.pop_front();
+            buffer.put(parse_complete());
+        }
+
+        match code {
+            // ParseComplete, CloseComplete
+            '1' | '3' => match self.responses.pop_front() {
+                Some(Response::Server {
+                    code: expected,
+                    forward,
+                }) if expected == code => forward,
+                Some(_) => {
+                    warn!("Unexpected '{}' from server", code);
+                    self.responses.clear();
+                    true
+                }
+                None => true,
+            },
+
+            // ErrorResponse: the server skips the rest of the messages until Sync,
+            // so the statements we just prepared may not exist.
+            'E' => {
+                for name in self.new_statements.drain(..) {
+                    self.statements.remove(&name);
+                }
+                self.responses.clear();
+                true
+            }
+
+            // ReadyForQuery
+            'Z' => {
+                self.new_statements.clear();
+                self.responses.clear();
+                true
+            }
+
+            _ => true,
+        }
+    }
+
+    /// The server forgot all its statements, e.g. after DISCARD ALL.
+    pub fn clear(&mut self) {
+        self.statements.clear();
+    }
+
+    /// The client didn't sync the statements we prepared for it, they were never sent.
+    pub fn checkin(&mut self) {
+        for name in self.new_statements.drain(..) {
+            self.statements.remove(&name);
+        }
+        self.responses.clear();
+    }
+}
+
+/// Rename the client's prepared statements in the buffered extended protocol messages
+/// to their pooler-wide names, preparing them first if the server doesn't have them.
+pub fn rewrite(
+    buffer: BytesMut,
+    client: &mut HashMap<String, Parse>,
+    server: &mut ServerStatements,
+) -> Result<BytesMut, Error> {
+    let mut rewritten = BytesMut::with_capacity(buffer.len());
+
+    // We know when the responses to the messages before a Parse end,
+    // only if they are all Parse or Close.
+    let mut tracked = true;
+
+    for message in split_messages(buffer)? {
+        match message[0] as char {
+            'P' => {
+                let parse = Parse::from_bytes(&message)?;
+
+                if parse.name.is_empty() {
+                    server.expect_response('1', true);
+                    rewritten.put(&message[..]);
+                    continue;
+                }
+
+                let global = parse.renamed(&parse.global_name());
+
+                // The client still expects a ParseComplete.
+                if tracked && server.contains(&global.name) {
+                    server.skip_parse();
+                } else {
+                    prepare(server, &global, true, &mut rewritten);
+                }
+
+                client.insert(parse.name, global);
+            }
+
+            'B' => {
+                let mut bind = Bind::from_bytes(&message)?;
+                tracked = false;
+
+                match client.get(&bind.statement) {
+                    Some(parse) => {
+                        if !server.contains(&parse.name) {
+                            prepare(server, parse, false, &mut rewritten);
+                        }
+
+                        bind.statement = parse.name.clone();
+                        rewritten.put(&bind.to_bytes()[..]);
+                    }
+                    None => rewritten.put(&message[..]),
+                };
+            }
+
+            'D' => {
+                let mut describe = Target::from_bytes(&message)?;
+                tracked = false;
+
+                match client.get(&describe.name) {
+                    Some(parse) if describe.kind == 'S' => {
+                        if !server.contains(&parse.name) {
+                            prepare(server, parse, false, &mut rewritten);
+                        }
+
+                        describe.name = parse.name.clone();
+                        rewritten.put(&describe.to_bytes()[..]);
+                    }
+                    _ => rewritten.put(&message[..]),
+                };
+            }
+
+            // The statement may be used by other clients, so we keep it on the server.
+            // Closing the client's name is harmless, the server doesn't know it.
+            'C' => {
+                let close = Target::from_bytes(&message)?;
+
+                if close.kind == 'S' {
+                    client.remove(&close.name);
+                }
+
+                server.expect_response('3', true);
+                rewritten.put(&message[..]);
+            }
+
+            _ => {
+                tracked = false;
+                rewritten.put(&message[..]);
+            }
+        };
+    }
+
+    Ok(rewritten)
+}
+
+/// Prepare the statement on the server, making room for it if needed.
+/// The client only sees the ParseComplete if it sent the Parse itself.
+fn prepare(server: &mut ServerStatements, parse: &Parse, forward: bool, buffer: &mut BytesMut) {
+    if let Some(evicted) = server.add(&parse.name) {
+        server.expect_response('3', false);
+        buffer.put(&Target::close_statement(&evicted).to_bytes()[..]);
+    }
+
+    // It may still be there if we forgot it after an error.
+    server.expect_response('3', false);
+    buffer.put(&Target::close_statement(&parse.name).to_bytes()[..]);
+
+    server.expect_response('1', forward);
+    buffer.put(&parse.to_bytes()[..]);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn parse(name: &str, query: &str) -> BytesMut {
+        Parse {
+            name: name.into(),
+            query: query.into(),
+            param_types: Vec::new(),
+        }
+        .to_bytes()
+    }
+
+    fn bind(statement: &str) -> BytesMut {
+        let mut body = BytesMut::new();
+        body.put_u8(0);
+        body.put_slice(statement.as_bytes());
+        body.put_u8(0);
+        body.put_i16(0);
+        body.put_i16(0);
+        body.put_i16(0);
+
+        let mut message = BytesMut::from(&b"B"[..]);
+        message.put_i32(body.len() as i32 + 4);
+        message.put(body);
+        message
+    }
+
+    fn close(name: &str) -> BytesMut {
+        Target::close_statement(name).to_bytes()
+    }
+
+    fn sync() -> BytesMut {
+        BytesMut::from(&b"S\0\0\0\x04"[..])
+    }
+
+    fn messages(buffers: &[BytesMut]) -> BytesMut {
+        buffers.iter().fold(BytesMut::new(), |mut buffer, message| {
+            buffer.put(&message[..]);
+            buffer
+        })
+    }
+
+    /// The codes of the messages in the buffer.
+    fn codes(buffer: BytesMut) -> String {
+        split_messages(buffer)
+            .unwrap()
+            .iter()
+            .map(|message| message[0] as char)
+            .collect()
+    }
+
+    /// What the client gets for the responses of the server.
+    fn respond(server: &mut ServerStatements, codes: &str) -> String {
+        let mut buffer = BytesMut::new();
+
+        for code in codes.chars() {
+            let mut response = BytesMut::new();
+
+            if server.forward_response(code, &mut response) {
+                response.put_u8(code as u8);
+                response.put_i32(4);
+            }
+
+            buffer.put(response);
+        }
+
+        self::codes(buffer)
+    }
+
+    #[test]
+    fn test_rewrite() {
+        let global = Parse::from_bytes(&parse("", "SELECT 1"))
+            .unwrap()
+            .global_name();
+
+        let mut server = ServerStatements::new(10);
+        let mut client = HashMap::new();
+
+        // The statement is prepared under its global name, the client only
+        // sees the ParseComplete of its own Parse.
+        let buffer = messages(&[parse("a", "SELECT 1"), bind("a"), sync()]);
+        let rewritten = rewrite(buffer, &mut client, &mut server).unwrap();
+        assert_eq!(
+            rewritten,
+            messages(&[
+                close(&global),
+                parse(&global, "SELECT 1"),
+                bind(&global),
+                sync()
+            ])
+        );
+        assert_eq!(client["a"].name, global);
+        assert_eq!(respond(&mut server, "3122Z"), "122Z");
+        assert!(server.contains(&global));
+
+        // Another client uses it as is, and gets a ParseComplete from us.
+        let mut other = HashMap::new();
+        let buffer = messages(&[parse("b", "SELECT 1"), bind("b"), sync()]);
+        let rewritten = rewrite(buffer, &mut other, &mut server).unwrap();
+        assert_eq!(rewritten, messages(&[bind(&global), sync()]));
+        assert_eq!(respond(&mut server, "2Z"), "12Z");
+
+        // After a Bind, we can't tell where its responses end, so it's prepared again.
+        let buffer = messages(&[bind("b"), parse("c", "SELECT 1"), sync()]);
+        let rewritten = rewrite(buffer, &mut other, &mut server).unwrap();
+        assert_eq!(codes(rewritten), "BCPS");
+        assert_eq!(respond(&mut server, "231Z"), "21Z");
+
+        // The client's Close doesn't touch the statement on the server.
+        let buffer = messages(&[close("b"), sync()]);
+        let rewritten = rewrite(buffer, &mut other, &mut server).unwrap();
+        assert_eq!(rewritten, messages(&[close("b"), sync()]));
+        assert_eq!(respond(&mut server, "3Z"), "3Z");
+        assert!(!other.contains_key("b"));
+        assert!(server.contains(&global));
+
+        // Unnamed statements are sent as is.
+        let buffer = messages(&[parse("", "SELECT 2"), bind(""), sync()]);
+        let rewritten = rewrite(buffer.clone(), &mut client, &mut server).unwrap();
+        assert_eq!(rewritten, buffer);
+        assert_eq!(respond(&mut server, "12Z"), "12Z");
+    }
+
+    #[test]
+    fn test_rewrite_missing_statement() {
+        let mut server = ServerStatements::new(10);
+        let mut client = HashMap::new();
+
+        let buffer = messages(&[parse("a", "SELECT 1"), sync()]);
+        rewrite(buffer, &mut client, &mut server).unwrap();
+        assert_eq!(respond(&mut server, "31Z"), "1Z");
+
+        // Another server doesn't have it, it's prepared before the Bind.
+        let mut server = ServerStatements::new(10);
+        let buffer = messages(&[bind("a"), sync()]);
+        let rewritten = rewrite(buffer, &mut client, &mut server).unwrap();
+        assert_eq!(codes(rewritten), "CPBS");
+        assert_eq!(respond(&mut server, "312Z"), "2Z");
+    }
+
+    #[test]
+    fn test_eviction() {
+        let mut server = ServerStatements::new(1);
+        let mut client = HashMap::new();
+
+        let buffer = messages(&[parse("a", "SELECT 1"), sync()]);
+        rewrite(buffer, &mut client, &mut server).unwrap();
+        respond(&mut server, "31Z");
+
+        // The least recently used statement is closed to make room.
+        let buffer = messages(&[parse("b", "SELECT 2"), sync()]);
+        let rewritten = rewrite(buffer, &mut client, &mut server).unwrap();
+        assert_eq!(codes(rewritten), "CCPS");
+        assert_eq!(respond(&mut server, "331Z"), "1Z");
+        assert!(!server.contains(&client["a"].name));
+        assert!(server.contains(&client["b"].name));
+
+        // Using a statement keeps it.
+        let mut server = ServerStatements::new(2);
+        assert_eq!(server.add("a"), None);
+        assert_eq!(server.add("b"), None);
+        assert!(server.contains("a"));
+        assert_eq!(server.add("c"), Some(String::from("b")));
+        assert_eq!(server.add("c"), None);
+        assert!(server.contains("a"));
+    }
+
+    #[test]
+    fn test_error() {
+        let mut server = ServerStatements::new(10);
+        let mut client = HashMap::new();
+
+        let buffer = messages(&[parse("a", "SELECT 1"), sync()]);
+        rewrite(buffer, &mut client, &mut server).unwrap();
+        respond(&mut server, "31Z");
+
+        // The server skips the messages after an error, we don't send
+        // a ParseComplete for them, and forget what we prepared.
+        let buffer = messages(&[parse("b", "SELECT oops"), parse("c", "SELECT 1"), sync()]);
+        rewrite(buffer, &mut client, &mut server).unwrap();
+        assert_eq!(respond(&mut server, "3EZ"), "EZ");
+        assert!(!server.contains(&client["b"].name));
+        assert!(server.contains(&client["c"].name));
+
+        // Responses before the error are still sent.
+        let buffer = messages(&[parse("d", "SELECT 1"), parse("e", "SELECT oops"), sync()]);
+        rewrite(buffer, &mut client, &mut server).unwrap();
+        assert_eq!(respond(&mut server, "3EZ"), "1EZ");
+
+        // Statements prepared for a client that never synced are forgotten.
+        let buffer = messages(&[parse("f", "SELECT 3")]);
+        rewrite(buffer, &mut client, &mut server).unwrap();
+        server.checkin();
+        assert!(!server.contains(&client["f"].name));
+    }
+}
@@ -135,6 +135,10 @@ static METRIC_HELP_AND_TYPES_LOOKUP: phf::Map<&'static str, MetricHelpType> = ph
         help: "Number of errors",
         ty: "gauge",
     },
+    "databases_replica_lag" => MetricHelpType {
+        help: "How far behind the primary the replica is in milliseconds",
+        ty: "gauge",
+    },
     "databases_pool_size" => MetricHelpType {
         help: "Maximum number of server connections",
         ty: "gauge",
@@ -265,7 +269,7 @@ fn push_address_stats(lines: &mut Vec<String>) {
     for (_, pool) in get_all_pools() {
         for shard in 0..pool.shards() {
             for server in 0..pool.servers(shard) {
-                let address = pool.address(shard, server);
+                let address = &pool.address(shard, server);
                 if let Some(address_stats) = address_stats.get(&address.id) {
                     for (key, value) in address_stats.iter() {
                         if let Some(prometheus_metric) =
@@ -306,13 +310,21 @@ fn push_database_stats(lines: &mut Vec<String>) {
         let pool_config = pool.settings.clone();
         for shard in 0..pool.shards() {
             for server in 0..pool.servers(shard) {
-                let address = pool.address(shard, server);
+                let address = &pool.address(shard, server);
                 let pool_state = pool.pool_state(shard, server);
 
-                let metrics = vec![
+                let mut metrics = vec![
                     ("pool_size", pool_config.user.pool_size),
                     ("current_connections", pool_state.connections),
                 ];
+
+                if let Some(replica_lag) = pool.replica_lag(address) {
+                    metrics.push((
+                        "replica_lag",
+                        u32::try_from(replica_lag).unwrap_or(u32::MAX),
+                    ));
+                }
+
                 for (key, value) in metrics {
                     if let Some(prometheus_metric) =
                         PrometheusMetric::<u32>::from_database_info(address, key, value)
@@ -338,7 +350,7 @@ fn push_server_stats(lines: &mut Vec<String>) {
     for (_, pool) in get_all_pools() {
         for shard in 0..pool.shards() {
             for server in 0..pool.servers(shard) {
-                let address = pool.address(shard, server);
+                let address = &pool.address(shard, server);
                 if let Some(server_info) = server_stats_by_addresses.get(&address.name()) {
                     let metrics = [
                         ("bytes_received", server_info.bytes_received),
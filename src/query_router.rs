@@ -4,23 +4,29 @@ use bytes::{Buf, BytesMut};
 use log::{debug, error};
 use once_cell::sync::OnceCell;
 use regex::{Regex, RegexSet};
-use sqlparser::ast::Statement::{Query, StartTransaction};
-use sqlparser::ast::{BinaryOperator, Expr, SetExpr, Value};
+use sqlparser::ast::Statement::{Delete, Insert, Query, StartTransaction, Update};
+use sqlparser::ast::{
+    BinaryOperator, CopyTarget, Expr, Ident, ObjectName, SetExpr, Statement, TableFactor,
+    TableWithJoins, UnaryOperator, Value,
+};
 use sqlparser::dialect::PostgreSqlDialect;
-use sqlparser::parser::Parser;
+use sqlparser::parser::{Parser, ParserError};
 
-use crate::config::Role;
-use crate::messages::BytesMutReader;
+use crate::config::{ReadYourWrites, Role};
+use crate::copy::CopySplitter;
+use crate::merge::MergePlan;
+use crate::messages::{Bind, BytesMutReader, Target};
 use crate::pool::PoolSettings;
-use crate::sharding::Sharder;
+use crate::sharding::{Sharder, ShardingKeyType};
 
-use std::collections::BTreeSet;
+use std::collections::{BTreeSet, HashMap};
 use std::io::Cursor;
+use std::time::{Duration, Instant};
 
 /// Regexes used to parse custom commands.
 const CUSTOM_SQL_REGEXES: [&str; 7] = [
     r"(?i)^ *SET SHARDING KEY TO '?([0-9]+)'? *;? *$",
-    r"(?i)^ *SET SHARD TO '?([0-9]+|ANY)'? *;? *$",
+    r"(?i)^ *SET SHARD TO '?([0-9]+|ANY|ALL)'? *;? *$",
     r"(?i)^ *SHOW SHARD *;? *$",
     r"(?i)^ *SET SERVER ROLE TO '(PRIMARY|REPLICA|ANY|AUTO|DEFAULT)' *;? *$",
     r"(?i)^ *SHOW SERVER ROLE *;? *$",
@@ -40,6 +46,120 @@ pub enum Command {
     ShowPrimaryReads,
 }
 
+/// A value of the sharding key found in a query.
+#[derive(Debug, PartialEq)]
+enum ShardingKey {
+    /// A literal, as written in the query, e.g. `5` or `'5'::bigint`.
+    Value(String),
+
+    /// A parameter of a prepared statement, e.g. `$1`, counting from 0.
+    Parameter(usize),
+}
+
+/// What we know about a prepared statement of the client.
+struct PreparedStatement {
+    /// The parameters that hold the sharding key.
+    sharding_key_parameters: Vec<usize>,
+
+    /// The shards of the sharding keys written in the query.
+    shards: BTreeSet<usize>,
+
+    /// The statement writes: it goes to the primary, and to one shard.
+    write: bool,
+
+    /// The statement reads sharded tables: without a sharding key, it needs every shard.
+    reads_shards: bool,
+}
+
+/// A sharding key column, as it can be written in a query.
+struct KeyColumn {
+    column: String,
+
+    /// The names and aliases of its tables in the query, any table if None.
+    tables: Option<Vec<String>>,
+}
+
+impl KeyColumn {
+    /// The sharding key `column` of the tables `has_key` accepts, in a query using these tables.
+    fn new(
+        column: &str,
+        has_key: impl Fn(&str) -> bool,
+        tables: &[(&ObjectName, Option<&Ident>)],
+    ) -> KeyColumn {
+        KeyColumn {
+            column: column.to_string(),
+            tables: Some(
+                tables
+                    .iter()
+                    .filter_map(|(name, alias)| match name.0.last() {
+                        Some(name) if has_key(&ident_name(name)) => Some((name, alias)),
+                        _ => None,
+                    })
+                    .flat_map(|(name, alias)| {
+                        std::iter::once(ident_name(name)).chain(alias.map(ident_name))
+                    })
+                    .collect(),
+            ),
+        }
+    }
+
+    /// The expression is the sharding key column, e.g. `id` or `users.id`.
+    fn matches(&self, expr: &Expr) -> bool {
+        match expr {
+            Expr::Identifier(ident) => self.matches_column(ident),
+
+            Expr::CompoundIdentifier(idents) => match idents.as_slice() {
+                [.., table, column] => {
+                    ident_name(column) == self.column
+                        && match &self.tables {
+                            Some(tables) => tables.contains(&ident_name(table)),
+                            None => true,
+                        }
+                }
+                _ => false,
+            },
+
+            Expr::Nested(expr) => self.matches(expr),
+
+            _ => false,
+        }
+    }
+
+    /// The column written without its table is the sharding key,
+    /// if the table is in the query.
+    fn matches_column(&self, column: &Ident) -> bool {
+        ident_name(column) == self.column
+            && match &self.tables {
+                Some(tables) => !tables.is_empty(),
+                None => true,
+            }
+    }
+}
+
+/// Writes must go to one shard.
+const WRITE_TO_SHARDS: &str =
+    "writes to more than one shard are not supported, send one query per shard";
+
+/// Writes to sharded tables must say which shard.
+const WRITE_WITHOUT_KEY: &str =
+    "writes to sharded tables need the sharding key in the query, e.g. INSERT INTO t (id, ...) or WHERE id = ..., or SET SHARDING KEY first";
+
+/// The pooler splits the rows of a COPY by the sharding key.
+const COPY_WITHOUT_KEY: &str =
+    "COPY into sharded tables needs the sharding key in its column list, e.g. COPY t (id, ...) FROM STDIN";
+
+/// Reference tables are written to on each shard, by the client.
+const WRITE_TO_REFERENCE_TABLE: &str =
+    "writes to reference tables must go to every shard, SET SHARD TO each shard and write to it";
+
+/// Only simple queries are sent to several shards and merged.
+const READ_FROM_SHARDS: &str =
+    "reads from more than one shard must be one simple query, not a prepared statement or several queries; add the sharding key, or SET SHARD TO a shard";
+
+/// Writes and transactions need a shard.
+const WRITE_TO_ALL_SHARDS: &str =
+    "writes and transactions after SET SHARD TO 'ALL' need a sharding key, or SET SHARD TO a shard";
+
 /// Quickly test for match when a query is received.
 static CUSTOM_SQL_REGEX_SET: OnceCell<RegexSet> = OnceCell::new();
 
@@ -51,6 +171,30 @@ pub struct QueryRouter {
     /// Which shard we should be talking to right now.
     active_shard: Option<usize>,
 
+    /// The client asked for all shards with `SET SHARD TO 'ALL'`.
+    all_shards: bool,
+
+    /// The client chose the shard with `SET SHARD` or `SET SHARDING KEY`.
+    manual_shard: bool,
+
+    /// The shards the current read-only query goes to, if it's more than one.
+    multi_shard: Option<Vec<usize>>,
+
+    /// How to merge the results of the shards, or why we can't.
+    merge_plan: Result<MergePlan, String>,
+
+    /// The client's prepared statements, by name.
+    prepared_statements: HashMap<String, PreparedStatement>,
+
+    /// The shards found from the sharding keys since Sync, in the extended protocol.
+    extended_shards: BTreeSet<usize>,
+
+    /// Why the current query can't be routed, e.g. a write to more than one shard.
+    rejected: Option<String>,
+
+    /// How to split the rows of the current COPY between the shards.
+    copy: Option<CopySplitter>,
+
     /// Which server should we be talking to.
     active_role: Option<Role>,
 
@@ -60,6 +204,15 @@ pub struct QueryRouter {
     /// Include the primary into the replica pool for reads.
     primary_reads_enabled: Option<bool>,
 
+    /// When the client last sent a write, so it can read it back.
+    last_write: Option<Instant>,
+
+    /// The client wrote since we last got the primary's LSN.
+    write_lsn_pending: bool,
+
+    /// The primary's LSN after the client's last write.
+    write_lsn: Option<u64>,
+
     /// Pool configuration.
     pool_settings: PoolSettings,
 }
@@ -96,9 +249,20 @@ impl QueryRouter {
     pub fn new() -> QueryRouter {
         QueryRouter {
             active_shard: None,
+            all_shards: false,
+            manual_shard: false,
+            multi_shard: None,
+            merge_plan: Ok(MergePlan::default()),
+            prepared_statements: HashMap::new(),
+            extended_shards: BTreeSet::new(),
+            rejected: None,
+            copy: None,
             active_role: None,
             query_parser_enabled: None,
             primary_reads_enabled: None,
+            last_write: None,
+            write_lsn_pending: false,
+            write_lsn: None,
             pool_settings: PoolSettings::default(),
         }
     }
@@ -171,7 +335,10 @@ impl QueryRouter {
                 }
             }
 
-            Command::ShowShard => self.shard().to_string(),
+            Command::ShowShard => match self.all_shards {
+                true => String::from("ALL"),
+                false => self.shard().to_string(),
+            },
             Command::ShowServerRole => match self.active_role {
                 Some(Role::Primary) => Role::Primary.to_string(),
                 Some(Role::Replica) => Role::Replica.to_string(),
@@ -192,18 +359,36 @@ impl QueryRouter {
 
         match command {
             Command::SetShardingKey => {
-                let sharder = Sharder::new(
-                    self.pool_settings.shards,
-                    self.pool_settings.sharding_function,
-                );
-                let shard = sharder.shard(value.parse::<i64>().unwrap());
-                self.active_shard = Some(shard);
-                value = shard.to_string();
+                match value
+                    .parse::<i64>()
+                    .ok()
+                    .and_then(|key| self.sharder().shard(key))
+                {
+                    Some(shard) => {
+                        self.active_shard = Some(shard);
+                        self.all_shards = false;
+                        self.manual_shard = true;
+                        value = shard.to_string();
+                    }
+                    None => self.rejected = Some(not_in_any_shard(&value)),
+                };
+            }
+
+            // Without the query parser, we can't tell reads from writes.
+            Command::SetShard
+                if value.eq_ignore_ascii_case("ALL") && !self.query_parser_enabled() =>
+            {
+                self.rejected = Some(String::from(
+                    "SET SHARD TO 'ALL' needs the query parser, enable query_parser_enabled",
+                ));
             }
 
             Command::SetShard => {
+                self.all_shards = value.eq_ignore_ascii_case("ALL");
+                self.manual_shard = !self.all_shards;
                 self.active_shard = match value.to_ascii_uppercase().as_ref() {
                     "ANY" => Some(rand::random::<usize>() % self.pool_settings.shards),
+                    "ALL" => None,
                     _ => Some(value.parse::<usize>().unwrap()),
                 };
             }
@@ -268,6 +453,8 @@ impl QueryRouter {
         let code = message_cursor.get_u8() as char;
         let _len = message_cursor.get_i32() as usize;
 
+        let mut statement_name = None;
+
         let query = match code {
             // Query
             'Q' => {
@@ -279,25 +466,47 @@ impl QueryRouter {
             // Parse (prepared statement)
             'P' => {
                 // Reads statement name
-                message_cursor.read_string().unwrap();
+                statement_name = Some(message_cursor.read_string().unwrap());
 
                 // Reads query string
                 let query = message_cursor.read_string().unwrap();
 
                 debug!("Prepared statement: '{}'", query);
 
-                query.replace('$', "") // Remove placeholders turning them into "values"
+                query
+            }
+
+            // Bind (prepared statement parameters)
+            'B' => return self.infer_shard_from_bind(message_buffer),
+
+            // Close (prepared statement or portal)
+            'C' => {
+                if let Ok(close) = Target::from_bytes(message_buffer) {
+                    if close.kind == 'S' {
+                        self.prepared_statements.remove(&close.name);
+                    }
+                }
+
+                return false;
             }
 
             _ => return false,
         };
 
-        let ast = match Parser::parse_sql(&PostgreSqlDialect {}, &query) {
+        // A new query.
+        self.multi_shard = None;
+        self.rejected = None;
+        self.copy = None;
+
+        let ast = match parse(&query) {
             Ok(ast) => ast,
             Err(err) => {
                 // SELECT ... FOR UPDATE won't get parsed correctly.
                 error!("{}: {}", err, query);
                 self.active_role = Some(Role::Primary);
+                self.wrote();
+                self.remember_statement(&statement_name, &[], true, false);
+                self.reject_write_to_all_shards();
                 return false;
             }
         };
@@ -307,6 +516,7 @@ impl QueryRouter {
         if ast.is_empty() {
             // That's weird, no idea, let's go to primary
             self.active_role = Some(Role::Primary);
+            self.remember_statement(&statement_name, &[], true, false);
             return false;
         }
 
@@ -315,40 +525,123 @@ impl QueryRouter {
                 // All transactions go to the primary, probably a write.
                 StartTransaction { .. } => {
                     self.active_role = Some(Role::Primary);
+                    self.wrote();
+                    self.remember_statement(&statement_name, &[], true, false);
+                    self.reject_write_to_all_shards();
                     break;
                 }
 
                 // Likely a read-only query
                 Query(query) => {
-                    match &self.pool_settings.automatic_sharding_key {
-                        Some(_) => {
-                            // TODO: if we have multiple queries in the same message,
-                            // we can either split them and execute them individually
-                            // or discard shard selection. If they point to the same shard though,
-                            // we can let them through as-is.
-                            // This is basically building a database now :)
-                            match self.infer_shard(query) {
-                                Some(shard) => {
-                                    self.active_shard = Some(shard);
-                                    debug!("Automatically using shard: {:?}", self.active_shard);
+                    let keys = match self.automatic_sharding() {
+                        true => self.sharding_keys(query),
+                        false => Vec::new(),
+                    };
+
+                    let reads_shards = !self.reads_reference_tables(query);
+                    self.remember_statement(&statement_name, &keys, false, reads_shards);
+                    let shards = self.shards(&keys);
+
+                    // TODO: if we have multiple queries in the same message,
+                    // we can either split them and execute them individually
+                    // or discard shard selection. If they point to the same shard though,
+                    // we can let them through as-is.
+                    // This is basically building a database now :)
+                    let single_query = code == 'Q' && ast.len() == 1;
+
+                    match shards.len() {
+                        0 => {
+                            debug!("No sharding keys found");
+
+                            // Reference tables are the same on all shards, any will do.
+                            // The Bind of a prepared statement may have the sharding key.
+                            if self.all_shards && reads_shards {
+                                if single_query {
+                                    self.multi_shard =
+                                        Some((0..self.pool_settings.shards).collect());
+                                    self.merge_plan = MergePlan::new(query);
+                                } else if code == 'Q' {
+                                    self.rejected = Some(String::from(READ_FROM_SHARDS));
                                 }
+                            }
+                        }
 
-                                None => (),
-                            };
+                        1 => {
+                            self.use_shard(shards.into_iter().last());
                         }
 
-                        None => (),
+                        _ => {
+                            debug!("More than one sharding key found");
+
+                            if single_query {
+                                self.multi_shard = Some(shards.into_iter().collect());
+                                self.merge_plan = MergePlan::new(query);
+                            } else {
+                                self.rejected = Some(String::from(READ_FROM_SHARDS));
+                            }
+                        }
                     };
 
-                    self.active_role = match self.primary_reads_enabled() {
-                        false => Some(Role::Replica), // If primary should not be receiving reads, use a replica.
-                        true => None,                 // Any server role is fine in this case.
-                    }
+                    self.read();
                 }
 
                 // Likely a write
-                _ => {
+                statement => {
                     self.active_role = Some(Role::Primary);
+                    self.wrote();
+
+                    if code == 'Q' && ast.len() == 1 {
+                        self.infer_copy(statement);
+                    }
+
+                    let keys = match self.automatic_sharding() {
+                        true => self.write_sharding_keys(statement),
+                        false => Vec::new(),
+                    };
+
+                    self.remember_statement(&statement_name, &keys, true, false);
+                    let shards = self.shards(&keys);
+
+                    match shards.len() {
+                        0 => {
+                            debug!("No sharding keys found");
+
+                            // The row could belong to any shard. Parameters are in the Bind.
+                            if keys.is_empty()
+                                && !self.manual_shard
+                                && self.pool_settings.shards > 1
+                                && self.writes_sharded_table(statement)
+                            {
+                                self.rejected = Some(String::from(WRITE_WITHOUT_KEY));
+                            }
+
+                            // The rows of a COPY go to their shards.
+                            if self.copy.is_none() {
+                                self.reject_write_to_all_shards();
+                            }
+                        }
+
+                        1 => {
+                            self.use_shard(shards.into_iter().next());
+                        }
+
+                        _ => {
+                            debug!("More than one sharding key found");
+                            self.rejected = Some(String::from(WRITE_TO_SHARDS));
+                        }
+                    };
+
+                    // The row would go to a shard it doesn't belong to.
+                    if let Some(key) = self.unmapped_key(&keys) {
+                        self.rejected = Some(not_in_any_shard(key));
+                    }
+
+                    // The copies on the other shards would be different.
+                    if !self.manual_shard && self.writes_reference_table(statement) {
+                        self.rejected = Some(String::from(WRITE_TO_REFERENCE_TABLE));
+                        self.copy = None;
+                    }
+
                     break;
                 }
             };
@@ -357,222 +650,834 @@ impl QueryRouter {
         true
     }
 
-    /// A `selection` is the `WHERE` clause. This parses
-    /// the clause and extracts the sharding key, if present.
-    fn selection_parser(&self, expr: &Expr) -> Vec<i64> {
-        let mut result = Vec::new();
-        let mut found = false;
-
-        // This parses `sharding_key = 5`. But it's technically
-        // legal to write `5 = sharding_key`. I don't judge the people
-        // who do that, but I think ORMs will still use the first variant,
-        // so we can leave the second as a TODO.
-        if let Expr::BinaryOp { left, op, right } = expr {
-            match &**left {
-                Expr::BinaryOp { .. } => result.extend(self.selection_parser(left)),
-                Expr::Identifier(ident) => {
-                    found =
-                        ident.value == *self.pool_settings.automatic_sharding_key.as_ref().unwrap();
-                }
-                _ => (),
-            };
+    /// Route the extended protocol messages the client sent before Sync.
+    /// They all go to the same server: the primary, if any of them writes.
+    pub fn infer_extended(&mut self, messages: &[BytesMut]) {
+        let mut write = false;
+        let mut rejected = None;
+        self.extended_shards.clear();
 
-            match op {
-                BinaryOperator::Eq => (),
-                BinaryOperator::Or => (),
-                BinaryOperator::And => (),
-                _ => {
-                    // TODO: support other operators than equality.
-                    debug!("Unsupported operation: {:?}", op);
-                    return Vec::new();
-                }
-            };
+        for message in messages {
+            self.infer(message);
 
-            match &**right {
-                Expr::BinaryOp { .. } => result.extend(self.selection_parser(right)),
-                Expr::Value(Value::Number(value, ..)) => {
-                    if found {
-                        match value.parse::<i64>() {
-                            Ok(value) => result.push(value),
-                            Err(_) => {
-                                debug!("Sharding key was not an integer: {}", value);
-                            }
-                        };
-                    }
-                }
-                _ => (),
-            };
+            // Parse and Bind tell us whether the statement writes.
+            if matches!(message.first(), Some(b'P') | Some(b'B')) {
+                write |= self.active_role == Some(Role::Primary);
+            }
+
+            // The next Parse forgets why this message was rejected.
+            rejected = rejected.or(self.rejected.take());
         }
 
-        debug!("Sharding keys found: {:?}", result);
+        if write {
+            self.active_role = Some(Role::Primary);
+        }
 
-        result
+        // They'd all go to the shard of the last one.
+        if self.extended_shards.len() > 1 {
+            rejected = rejected.or(Some(String::from(
+                "queries for different shards in one pipeline are not supported, send a Sync after each",
+            )));
+        }
+
+        self.rejected = rejected;
     }
 
-    /// Try to figure out which shard the query should go to.
-    fn infer_shard(&self, query: &sqlparser::ast::Query) -> Option<usize> {
-        let mut shards = BTreeSet::new();
+    /// Split the rows of a `COPY ... FROM STDIN` between the shards, by the sharding key.
+    fn infer_copy(&mut self, statement: &Statement) {
+        let (table_name, columns, options, legacy_options) = match statement {
+            Statement::Copy {
+                table_name,
+                columns,
+                to: false,
+                target: CopyTarget::Stdin,
+                options,
+                legacy_options,
+                ..
+            } => (table_name, columns, options, legacy_options),
+            _ => return,
+        };
 
-        match &*query.body {
-            SetExpr::Query(query) => {
-                match self.infer_shard(&*query) {
-                    Some(shard) => {
-                        shards.insert(shard);
-                    }
-                    None => (),
-                };
-            }
+        if !self.automatic_sharding() || self.pool_settings.shards < 2 {
+            return;
+        }
 
-            SetExpr::Select(select) => {
-                match &select.selection {
-                    Some(selection) => {
-                        let sharding_keys = self.selection_parser(selection);
+        let keys = self.key_columns(&[(table_name, None)]);
 
-                        // TODO: Add support for prepared statements here.
-                        // This should just give us the position of the value in the `B` message.
+        let column = match columns
+            .iter()
+            .position(|column| keys.iter().any(|key| key.matches_column(column)))
+        {
+            Some(column) => column,
+            None => {
+                debug!("No sharding key in the columns of the COPY");
+
+                // All the rows would go to one shard.
+                if !self.manual_shard && self.sharded(&[(table_name, None)]) {
+                    self.rejected = Some(String::from(COPY_WITHOUT_KEY));
+                }
 
-                        let sharder = Sharder::new(
-                            self.pool_settings.shards,
-                            self.pool_settings.sharding_function,
-                        );
+                return;
+            }
+        };
 
-                        for value in sharding_keys {
-                            let shard = sharder.shard(value);
-                            shards.insert(shard);
-                        }
-                    }
+        match CopySplitter::new(
+            options,
+            legacy_options,
+            column,
+            self.sharder(),
+            self.pool_settings.sharding_key_type,
+        ) {
+            Ok(copy) => self.copy = Some(copy),
+            Err(err) => self.rejected = Some(err),
+        };
+    }
 
-                    None => (),
-                };
+    /// A `selection` is the `WHERE` clause. This parses the clause and
+    /// extracts the values of the sharding key, e.g. `id = 5`, `5 = t.id`
+    /// or `id IN (1, 2)`. None if the rows can be on any shard.
+    fn selection_parser(&self, expr: &Expr, keys: &[KeyColumn]) -> Option<Vec<ShardingKey>> {
+        let result = match expr {
+            Expr::BinaryOp {
+                left,
+                op: BinaryOperator::And,
+                right,
+            } => match (
+                self.selection_parser(left, keys),
+                self.selection_parser(right, keys),
+            ) {
+                (Some(mut left), Some(right)) => {
+                    left.extend(right);
+                    Some(left)
+                }
+                (left, right) => left.or(right),
+            },
+
+            // Both sides must be on known shards.
+            Expr::BinaryOp {
+                left,
+                op: BinaryOperator::Or,
+                right,
+            } => {
+                let mut left = self.selection_parser(left, keys)?;
+                left.extend(self.selection_parser(right, keys)?);
+                Some(left)
             }
-            _ => (),
-        };
 
-        match shards.len() {
-            // Didn't find a sharding key, you're on your own.
-            0 => {
-                debug!("No sharding keys found");
-                None
+            Expr::BinaryOp {
+                left,
+                op: BinaryOperator::Eq,
+                right,
+            } => {
+                if is_sharding_key(keys, left) {
+                    sharding_key(right).map(|value| vec![value])
+                } else if is_sharding_key(keys, right) {
+                    sharding_key(left).map(|value| vec![value])
+                } else {
+                    None
+                }
             }
 
-            1 => Some(shards.into_iter().last().unwrap()),
+            Expr::InList {
+                expr,
+                list,
+                negated: false,
+            } if is_sharding_key(keys, expr) => list.iter().map(sharding_key).collect(),
 
-            // TODO: support querying multiple shards (some day...)
-            _ => {
-                debug!("More than one sharding key found");
-                None
-            }
-        }
-    }
+            Expr::Nested(expr) => self.selection_parser(expr, keys),
 
-    /// Get the current desired server role we should be talking to.
-    pub fn role(&self) -> Option<Role> {
-        self.active_role
-    }
+            _ => None,
+        };
 
-    /// Get desired shard we should be talking to.
-    pub fn shard(&self) -> usize {
-        self.active_shard.unwrap_or(0)
-    }
+        debug!("Sharding keys found: {:?}", result);
 
-    pub fn set_shard(&mut self, shard: usize) {
-        self.active_shard = Some(shard);
+        result
     }
 
-    /// Should we attempt to parse queries?
-    pub fn query_parser_enabled(&self) -> bool {
-        match self.query_parser_enabled {
-            None => self.pool_settings.query_parser_enabled,
-            Some(value) => value,
+    /// The sharding key columns, in a query using these tables.
+    fn key_columns(&self, tables: &[(&ObjectName, Option<&Ident>)]) -> Vec<KeyColumn> {
+        let sharding_keys = &self.pool_settings.sharding_keys;
+
+        let mut columns: Vec<KeyColumn> = sharding_keys
+            .iter()
+            .map(|(table, column)| KeyColumn::new(column, |name| name == table, tables))
+            .collect();
+
+        if let Some(key) = &self.pool_settings.automatic_sharding_key {
+            columns.push(match key.split_once('.') {
+                Some((table, column)) => KeyColumn::new(column, |name| name == table, tables),
+
+                // The tables with their own sharding key don't use it.
+                None if !sharding_keys.is_empty() => {
+                    KeyColumn::new(key, |name| !sharding_keys.contains_key(name), tables)
+                }
+
+                None => KeyColumn {
+                    column: key.clone(),
+                    tables: None,
+                },
+            });
         }
+
+        columns
     }
 
-    pub fn primary_reads_enabled(&self) -> bool {
-        match self.primary_reads_enabled {
-            None => self.pool_settings.primary_reads_enabled,
-            Some(value) => value,
+    /// Are we looking for the sharding key in the queries?
+    fn automatic_sharding(&self) -> bool {
+        self.pool_settings.automatic_sharding_key.is_some()
+            || !self.pool_settings.sharding_keys.is_empty()
+    }
+
+    /// The query only reads reference tables, they are the same on all shards.
+    fn reads_reference_tables(&self, query: &sqlparser::ast::Query) -> bool {
+        match &*query.body {
+            SetExpr::Query(query) => self.reads_reference_tables(query),
+
+            SetExpr::Select(select) => {
+                !select.from.is_empty()
+                    && select
+                        .from
+                        .iter()
+                        .flat_map(relations)
+                        .all(|relation| match relation {
+                            TableFactor::Table { name, .. } => {
+                                name.0.last().map_or(false, |name| {
+                                    self.pool_settings
+                                        .reference_tables
+                                        .contains(&ident_name(name))
+                                })
+                            }
+                            _ => false,
+                        })
+            }
+
+            _ => false,
         }
     }
-}
 
-#[cfg(test)]
-mod test {
-    use super::*;
-    use crate::config::PoolMode;
-    use crate::messages::simple_query;
-    use crate::sharding::ShardingFunction;
-    use bytes::BufMut;
+    /// Find the values of the sharding key in the query.
+    fn sharding_keys(&self, query: &sqlparser::ast::Query) -> Vec<ShardingKey> {
+        match &*query.body {
+            SetExpr::Query(query) => self.sharding_keys(query),
 
-    #[test]
-    fn test_defaults() {
-        QueryRouter::setup();
-        let qr = QueryRouter::new();
+            SetExpr::Select(select) => match &select.selection {
+                Some(selection) => {
+                    let keys =
+                        self.key_columns(&table_names(select.from.iter().flat_map(relations)));
+                    self.selection_parser(selection, &keys).unwrap_or_default()
+                }
+                None => Vec::new(),
+            },
 
-        assert_eq!(qr.role(), None);
+            _ => Vec::new(),
+        }
     }
 
-    #[test]
-    fn test_infer_replica() {
-        QueryRouter::setup();
-        let mut qr = QueryRouter::new();
-        assert!(qr.try_execute_command(&simple_query("SET SERVER ROLE TO 'auto'")) != None);
-        assert!(qr.query_parser_enabled());
+    /// Find the values of the sharding key in an INSERT, UPDATE or DELETE.
+    fn write_sharding_keys(&self, statement: &Statement) -> Vec<ShardingKey> {
+        let (tables, selection) = match statement {
+            // INSERT INTO t (id, ...) VALUES (5, ...), (6, ...)
+            Insert {
+                table_name,
+                columns,
+                source,
+                ..
+            } => {
+                let keys = self.key_columns(&[(table_name, None)]);
+
+                let position = match columns
+                    .iter()
+                    .position(|column| keys.iter().any(|key| key.matches_column(column)))
+                {
+                    Some(position) => position,
+                    None => return Vec::new(),
+                };
 
-        assert!(qr.try_execute_command(&simple_query("SET PRIMARY READS TO off")) != None);
+                return match &*source.body {
+                    SetExpr::Values(values) => values
+                        .rows
+                        .iter()
+                        .filter_map(|row| row.get(position).and_then(sharding_key))
+                        .collect(),
+                    _ => Vec::new(),
+                };
+            }
 
-        let queries = vec![
-            simple_query("SELECT * FROM items WHERE id = 5"),
-            simple_query(
-                "SELECT id, name, value FROM items INNER JOIN prices ON item.id = prices.item_id",
+            Update {
+                table,
+                from,
+                selection: Some(selection),
+                ..
+            } => (
+                table_names(relations(table).chain(from.iter().flat_map(relations))),
+                selection,
             ),
-            simple_query("WITH t AS (SELECT * FROM items) SELECT * FROM t"),
-        ];
 
-        for query in queries {
-            // It's a recognized query
-            assert!(qr.infer(&query));
-            assert_eq!(qr.role(), Some(Role::Replica));
-        }
+            Delete {
+                table_name,
+                using,
+                selection: Some(selection),
+                ..
+            } => (
+                table_names(std::iter::once(table_name).chain(using.iter())),
+                selection,
+            ),
+
+            _ => return Vec::new(),
+        };
+
+        let keys = self.key_columns(&tables);
+        self.selection_parser(selection, &keys).unwrap_or_default()
     }
 
-    #[test]
-    fn test_infer_primary() {
-        QueryRouter::setup();
-        let mut qr = QueryRouter::new();
+    /// The INSERT, UPDATE or DELETE writes to a table with a sharding key.
+    fn writes_sharded_table(&self, statement: &Statement) -> bool {
+        let relation = match statement {
+            Insert { table_name, .. } => {
+                return self.sharded(&[(table_name, None)]);
+            }
+            Update { table, .. } => &table.relation,
+            Delete { table_name, .. } => table_name,
+            _ => return false,
+        };
 
-        let queries = vec![
-            simple_query("UPDATE items SET name = 'pumpkin' WHERE id = 5"),
-            simple_query("INSERT INTO items (id, name) VALUES (5, 'pumpkin')"),
-            simple_query("DELETE FROM items WHERE id = 5"),
-            simple_query("BEGIN"), // Transaction start
-        ];
+        self.sharded(&table_names(std::iter::once(relation)))
+    }
 
-        for query in queries {
-            // It's a recognized query
-            assert!(qr.infer(&query));
-            assert_eq!(qr.role(), Some(Role::Primary));
-        }
+    /// The statement writes to a reference table, e.g. an INSERT or a COPY FROM.
+    fn writes_reference_table(&self, statement: &Statement) -> bool {
+        let name = match statement {
+            Insert { table_name, .. }
+            | Statement::Copy {
+                table_name,
+                to: false,
+                ..
+            } => table_name,
+            Update {
+                table:
+                    TableWithJoins {
+                        relation: TableFactor::Table { name, .. },
+                        ..
+                    },
+                ..
+            }
+            | Delete {
+                table_name: TableFactor::Table { name, .. },
+                ..
+            } => name,
+            _ => return false,
+        };
+
+        name.0.last().map_or(false, |name| {
+            self.pool_settings
+                .reference_tables
+                .contains(&ident_name(name))
+        })
     }
 
-    #[test]
-    fn test_infer_primary_reads_enabled() {
-        QueryRouter::setup();
-        let mut qr = QueryRouter::new();
-        let query = simple_query("SELECT * FROM items WHERE id = 5");
-        assert!(qr.try_execute_command(&simple_query("SET PRIMARY READS TO on")) != None);
+    /// Some of the tables have a sharding key, they're not all reference tables.
+    fn sharded(&self, tables: &[(&ObjectName, Option<&Ident>)]) -> bool {
+        let tables: Vec<_> = tables
+            .iter()
+            .filter(|(name, _)| {
+                !name.0.last().map_or(false, |name| {
+                    self.pool_settings
+                        .reference_tables
+                        .contains(&ident_name(name))
+                })
+            })
+            .copied()
+            .collect();
 
-        assert!(qr.infer(&query));
-        assert_eq!(qr.role(), None);
+        !tables.is_empty()
+            && self
+                .key_columns(&tables)
+                .iter()
+                .any(|key| match &key.tables {
+                    Some(tables) => !tables.is_empty(),
+                    None => true,
+                })
     }
 
-    #[test]
-    fn test_infer_parse_prepared() {
-        QueryRouter::setup();
-        let mut qr = QueryRouter::new();
-        qr.try_execute_command(&simple_query("SET SERVER ROLE TO 'auto'"));
-        assert!(qr.try_execute_command(&simple_query("SET PRIMARY READS TO off")) != None);
+    /// The shards of the values of the sharding key.
+    fn shards(&self, keys: &[ShardingKey]) -> BTreeSet<usize> {
+        let sharder = self.sharder();
+        let key_type = self.pool_settings.sharding_key_type;
 
-        let prepared_stmt = BytesMut::from(
+        keys.iter()
+            .filter_map(|key| match key {
+                ShardingKey::Value(value) => {
+                    let shard = sharder.shard_str(value, key_type);
+
+                    if shard.is_none() {
+                        debug!(
+                            "Sharding key is not a valid {} or not in any shard: {}",
+                            key_type, value
+                        );
+                    }
+
+                    shard
+                }
+                ShardingKey::Parameter(_) => None,
+            })
+            .collect()
+    }
+
+    /// Remember whether the prepared statement writes, and where the sharding key
+    /// is in the query and its parameters, for its Binds.
+    fn remember_statement(
+        &mut self,
+        statement_name: &Option<String>,
+        keys: &[ShardingKey],
+        write: bool,
+        reads_shards: bool,
+    ) {
+        let name = match statement_name {
+            Some(name) => name,
+            None => return,
+        };
+
+        let sharding_key_parameters = keys
+            .iter()
+            .filter_map(|key| match key {
+                ShardingKey::Parameter(position) => Some(*position),
+                ShardingKey::Value(_) => None,
+            })
+            .collect();
+
+        self.prepared_statements.insert(
+            name.clone(),
+            PreparedStatement {
+                sharding_key_parameters,
+                shards: self.shards(keys),
+                write,
+                reads_shards,
+            },
+        );
+    }
+
+    /// Route the Bind like its prepared statement, and find the shard in its parameters
+    /// if we know which ones hold the sharding key from its Parse.
+    fn infer_shard_from_bind(&mut self, message_buffer: &BytesMut) -> bool {
+        let bind = match Bind::from_bytes(message_buffer) {
+            Ok(bind) => bind,
+            Err(_) => return false,
+        };
+
+        let statement = match self.prepared_statements.get(&bind.statement) {
+            Some(statement) => statement,
+            None => return false,
+        };
+        let write = statement.write;
+        let reads_shards = statement.reads_shards;
+        let positions = statement.sharding_key_parameters.clone();
+        let mut shards = statement.shards.clone();
+
+        match write {
+            true => {
+                self.active_role = Some(Role::Primary);
+                self.wrote();
+            }
+            false => self.read(),
+        };
+
+        let sharder = self.sharder();
+
+        let key_type = self.pool_settings.sharding_key_type;
+
+        let parameter_shards: Vec<Option<usize>> = positions
+            .iter()
+            .map(|position| match bind.parameter(*position) {
+                Ok(Some((format, value))) => parameter_shard(&sharder, key_type, format, &value),
+                _ => None,
+            })
+            .collect();
+
+        // The row would go to a shard it doesn't belong to.
+        if write {
+            if let Some((position, _)) = positions
+                .iter()
+                .zip(&parameter_shards)
+                .find(|(_, shard)| shard.is_none())
+            {
+                self.rejected = Some(not_in_any_shard(&format!("${}", position + 1)));
+                return false;
+            }
+        }
+
+        shards.extend(parameter_shards.into_iter().flatten());
+
+        match shards.len() {
+            1 => {
+                self.use_shard(shards.into_iter().next());
+                true
+            }
+
+            0 => {
+                debug!("No sharding keys found in the parameters");
+
+                // Only simple queries are merged from all the shards.
+                if !write && reads_shards && self.all_shards {
+                    self.rejected = Some(String::from(READ_FROM_SHARDS));
+                }
+
+                false
+            }
+
+            _ => {
+                debug!("More than one sharding key found in the parameters");
+
+                self.rejected = Some(String::from(match write {
+                    true => WRITE_TO_SHARDS,
+                    false => READ_FROM_SHARDS,
+                }));
+
+                false
+            }
+        }
+    }
+
+    /// The sharder of the pool.
+    fn sharder(&self) -> Sharder {
+        Sharder::new(
+            self.pool_settings.shards,
+            self.pool_settings.sharding_function,
+        )
+        .with_keys(self.pool_settings.shard_keys.clone())
+    }
+
+    /// A value of the sharding key that's not in any shard, with range or list sharding.
+    fn unmapped_key<'a>(&self, keys: &'a [ShardingKey]) -> Option<&'a str> {
+        if self.pool_settings.sharding_key_type != ShardingKeyType::Bigint {
+            return None;
+        }
+
+        let sharder = self.sharder();
+
+        keys.iter().find_map(|key| match key {
+            ShardingKey::Value(value) => match value.trim().parse::<i64>() {
+                Ok(key) if sharder.shard(key).is_none() => Some(value.as_str()),
+                _ => None,
+            },
+            ShardingKey::Parameter(_) => None,
+        })
+    }
+
+    /// After `SET SHARD TO 'ALL'`, a write without a sharding key would go to one shard.
+    fn reject_write_to_all_shards(&mut self) {
+        if self.all_shards {
+            self.rejected = Some(String::from(WRITE_TO_ALL_SHARDS));
+        }
+    }
+
+    /// Use the shard of the sharding key found in the query.
+    fn use_shard(&mut self, shard: Option<usize>) {
+        self.active_shard = shard;
+        self.extended_shards.extend(shard);
+        debug!("Automatically using shard: {:?}", self.active_shard);
+    }
+
+    /// Reads go to a replica, unless the primary serves reads too.
+    fn read(&mut self) {
+        self.active_role = match self.primary_reads_enabled() {
+            false => Some(Role::Replica), // If primary should not be receiving reads, use a replica.
+            true => None,                 // Any server role is fine in this case.
+        };
+
+        // Read your writes: stay on the primary for a while after a write.
+        if self.in_read_your_writes_window() {
+            self.active_role = Some(Role::Primary);
+        }
+    }
+
+    /// Remember the client wrote, to read its own writes.
+    fn wrote(&mut self) {
+        self.last_write = Some(Instant::now());
+        self.write_lsn_pending = true;
+    }
+
+    /// The client wrote less than read_your_writes_window ago, in window mode.
+    fn in_read_your_writes_window(&self) -> bool {
+        match (self.pool_settings.read_your_writes, self.last_write) {
+            (ReadYourWrites::Window, Some(last_write)) => {
+                last_write.elapsed()
+                    < Duration::from_millis(self.pool_settings.read_your_writes_window)
+            }
+            _ => false,
+        }
+    }
+
+    /// We need the primary's LSN after the client's write, in lsn mode.
+    pub fn write_lsn_pending(&self) -> bool {
+        self.pool_settings.read_your_writes == ReadYourWrites::Lsn && self.write_lsn_pending
+    }
+
+    /// Save the primary's LSN after the client's write.
+    pub fn set_write_lsn(&mut self, lsn: u64) {
+        self.write_lsn = Some(lsn);
+        self.write_lsn_pending = false;
+    }
+
+    /// The LSN the replicas must have replayed to serve the client's reads, in lsn mode.
+    pub fn min_replica_lsn(&self) -> Option<u64> {
+        match self.pool_settings.read_your_writes {
+            ReadYourWrites::Lsn => self.write_lsn,
+            _ => None,
+        }
+    }
+
+    /// Get the current desired server role we should be talking to.
+    pub fn role(&self) -> Option<Role> {
+        self.active_role
+    }
+
+    /// Get desired shard we should be talking to.
+    pub fn shard(&self) -> usize {
+        self.active_shard.unwrap_or(0)
+    }
+
+    pub fn set_shard(&mut self, shard: usize) {
+        self.active_shard = Some(shard);
+    }
+
+    /// The shards to send the query to and merge the results of,
+    /// if it's a read-only query for more than one shard.
+    pub fn multi_shard(&self) -> Option<&Vec<usize>> {
+        self.multi_shard.as_ref()
+    }
+
+    /// Why the query can't be routed, if it can't. We only tell the client once.
+    pub fn take_rejected(&mut self) -> Option<String> {
+        self.rejected.take()
+    }
+
+    /// How to split the rows of the COPY between the shards, if it goes to more than one.
+    pub fn take_copy(&mut self) -> Option<CopySplitter> {
+        self.copy.take()
+    }
+
+    /// How to merge the results of the multi-shard query.
+    pub fn merge_plan(&self) -> &Result<MergePlan, String> {
+        &self.merge_plan
+    }
+
+    /// Should we attempt to parse queries?
+    pub fn query_parser_enabled(&self) -> bool {
+        match self.query_parser_enabled {
+            None => self.pool_settings.query_parser_enabled,
+            Some(value) => value,
+        }
+    }
+
+    pub fn primary_reads_enabled(&self) -> bool {
+        match self.primary_reads_enabled {
+            None => self.pool_settings.primary_reads_enabled,
+            Some(value) => value,
+        }
+    }
+}
+
+/// Parse the query. The parser wants a `;` after `COPY ... FROM STDIN`,
+/// clients don't always send one.
+fn parse(query: &str) -> Result<Vec<Statement>, ParserError> {
+    match Parser::parse_sql(&PostgreSqlDialect {}, query) {
+        Err(err) if is_copy(query) => {
+            Parser::parse_sql(&PostgreSqlDialect {}, &format!("{};", query)).map_err(|_| err)
+        }
+        result => result,
+    }
+}
+
+fn is_copy(query: &str) -> bool {
+    query
+        .trim_start()
+        .get(..4)
+        .map_or(false, |command| command.eq_ignore_ascii_case("copy"))
+}
+
+/// The value of the sharding key in an expression, e.g. `5`, `'5'::bigint` or `$1`.
+fn sharding_key(expr: &Expr) -> Option<ShardingKey> {
+    match expr {
+        Expr::Value(Value::Number(value, ..)) | Expr::Value(Value::SingleQuotedString(value)) => {
+            Some(ShardingKey::Value(value.clone()))
+        }
+
+        // uuid 'a0eebc99-9c0b-4ef8-bb6d-6bb9bd380a11'
+        Expr::TypedString { value, .. } => Some(ShardingKey::Value(value.clone())),
+
+        Expr::UnaryOp {
+            op: UnaryOperator::Minus,
+            expr,
+        } => match sharding_key(expr)? {
+            ShardingKey::Value(value) => Some(ShardingKey::Value(format!("-{}", value))),
+            ShardingKey::Parameter(_) => None,
+        },
+
+        // The type of the value doesn't matter, the sharding key type does.
+        Expr::Cast { expr, .. } | Expr::Nested(expr) => sharding_key(expr),
+
+        Expr::Value(Value::Placeholder(placeholder)) => match placeholder
+            .strip_prefix('$')
+            .and_then(|position| position.parse::<usize>().ok())
+        {
+            Some(position) if position > 0 => Some(ShardingKey::Parameter(position - 1)),
+            _ => {
+                debug!("Unsupported placeholder: {}", placeholder);
+                None
+            }
+        },
+
+        _ => None,
+    }
+}
+
+/// The expression is one of the sharding key columns.
+fn is_sharding_key(keys: &[KeyColumn], expr: &Expr) -> bool {
+    keys.iter().any(|key| key.matches(expr))
+}
+
+/// The tables of a FROM clause, with their joins.
+fn relations(table: &TableWithJoins) -> impl Iterator<Item = &TableFactor> {
+    std::iter::once(&table.relation).chain(table.joins.iter().map(|join| &join.relation))
+}
+
+/// The name of an identifier: unquoted names are case insensitive, like in Postgres.
+fn ident_name(ident: &Ident) -> String {
+    match ident.quote_style {
+        Some(_) => ident.value.clone(),
+        None => ident.value.to_lowercase(),
+    }
+}
+
+/// The names and aliases of the tables, leaving out subqueries and functions.
+fn table_names<'a>(
+    relations: impl Iterator<Item = &'a TableFactor>,
+) -> Vec<(&'a ObjectName, Option<&'a Ident>)> {
+    relations
+        .filter_map(|relation| match relation {
+            TableFactor::Table { name, alias, .. } => {
+                Some((name, alias.as_ref().map(|alias| &alias.name)))
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+/// The shard of a sharding key parameter of a Bind, in text or binary format.
+fn parameter_shard(
+    sharder: &Sharder,
+    key_type: ShardingKeyType,
+    format: i16,
+    value: &[u8],
+) -> Option<usize> {
+    match (format, key_type) {
+        // Text
+        (0, _) => sharder.shard_str(std::str::from_utf8(value).ok()?, key_type),
+
+        // Binary: int8, int4 or int2
+        (_, ShardingKeyType::Bigint) => {
+            let value = match value.len() {
+                8 => i64::from_be_bytes(value.try_into().ok()?),
+                4 => i32::from_be_bytes(value.try_into().ok()?) as i64,
+                2 => i16::from_be_bytes(value.try_into().ok()?) as i64,
+                _ => return None,
+            };
+
+            sharder.shard(value)
+        }
+
+        (_, ShardingKeyType::Text) => sharder.shard_text(std::str::from_utf8(value).ok()?),
+
+        (_, ShardingKeyType::Uuid) => {
+            sharder.shard_uuid(u128::from_be_bytes(value.try_into().ok()?))
+        }
+    }
+}
+
+/// The sharding key is not in any shard, with range or list sharding.
+fn not_in_any_shard(key: &str) -> String {
+    format!("sharding key {} is not in any shard", key)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::PoolMode;
+    use crate::messages::{simple_query, Parse};
+    use crate::sharding::{ShardKeys, ShardingFunction};
+    use bytes::BufMut;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn test_defaults() {
+        QueryRouter::setup();
+        let qr = QueryRouter::new();
+
+        assert_eq!(qr.role(), None);
+    }
+
+    #[test]
+    fn test_infer_replica() {
+        QueryRouter::setup();
+        let mut qr = QueryRouter::new();
+        assert!(qr.try_execute_command(&simple_query("SET SERVER ROLE TO 'auto'")) != None);
+        assert!(qr.query_parser_enabled());
+
+        assert!(qr.try_execute_command(&simple_query("SET PRIMARY READS TO off")) != None);
+
+        let queries = vec![
+            simple_query("SELECT * FROM items WHERE id = 5"),
+            simple_query(
+                "SELECT id, name, value FROM items INNER JOIN prices ON item.id = prices.item_id",
+            ),
+            simple_query("WITH t AS (SELECT * FROM items) SELECT * FROM t"),
+        ];
+
+        for query in queries {
+            // It's a recognized query
+            assert!(qr.infer(&query));
+            assert_eq!(qr.role(), Some(Role::Replica));
+        }
+    }
+
+    #[test]
+    fn test_infer_primary() {
+        QueryRouter::setup();
+        let mut qr = QueryRouter::new();
+
+        let queries = vec![
+            simple_query("UPDATE items SET name = 'pumpkin' WHERE id = 5"),
+            simple_query("INSERT INTO items (id, name) VALUES (5, 'pumpkin')"),
+            simple_query("DELETE FROM items WHERE id = 5"),
+            simple_query("BEGIN"), // Transaction start
+        ];
+
+        for query in queries {
+            // It's a recognized query
+            assert!(qr.infer(&query));
+            assert_eq!(qr.role(), Some(Role::Primary));
+        }
+    }
+
+    #[test]
+    fn test_infer_primary_reads_enabled() {
+        QueryRouter::setup();
+        let mut qr = QueryRouter::new();
+        let query = simple_query("SELECT * FROM items WHERE id = 5");
+        assert!(qr.try_execute_command(&simple_query("SET PRIMARY READS TO on")) != None);
+
+        assert!(qr.infer(&query));
+        assert_eq!(qr.role(), None);
+    }
+
+    #[test]
+    fn test_infer_parse_prepared() {
+        QueryRouter::setup();
+        let mut qr = QueryRouter::new();
+        qr.try_execute_command(&simple_query("SET SERVER ROLE TO 'auto'"));
+        assert!(qr.try_execute_command(&simple_query("SET PRIMARY READS TO off")) != None);
+
+        let prepared_stmt = BytesMut::from(
             &b"WITH t AS (SELECT * FROM items WHERE name = $1) SELECT * FROM t WHERE id = $2\0"[..],
         );
         let mut res = BytesMut::from(&b"P"[..]);
@@ -772,9 +1677,17 @@ mod test {
             primary_reads_enabled: false,
             sharding_function: ShardingFunction::PgBigintHash,
             automatic_sharding_key: Some(String::from("id")),
+            sharding_key_type: ShardingKeyType::Bigint,
+            shard_keys: Default::default(),
+            sharding_keys: Default::default(),
+            reference_tables: Vec::new(),
             healthcheck_delay: PoolSettings::default().healthcheck_delay,
             healthcheck_timeout: PoolSettings::default().healthcheck_timeout,
             ban_time: PoolSettings::default().ban_time,
+            auth_method: None,
+            max_replica_lag: None,
+            read_your_writes: ReadYourWrites::Disabled,
+            read_your_writes_window: 1000,
         };
         let mut qr = QueryRouter::new();
         assert_eq!(qr.active_role, None);
@@ -820,4 +1733,784 @@ mod test {
         )));
         assert_eq!(qr.role(), Role::Primary);
     }
+
+    #[test]
+    fn test_infer_multi_shard() {
+        QueryRouter::setup();
+
+        let mut qr = QueryRouter::new();
+        qr.update_pool_settings(PoolSettings {
+            shards: 3,
+            automatic_sharding_key: Some(String::from("id")),
+            query_parser_enabled: true,
+            ..Default::default()
+        });
+
+        assert!(qr.infer(&simple_query("SELECT * FROM users WHERE id = 1")));
+        assert_eq!(qr.multi_shard(), None);
+
+        assert!(qr.infer(&simple_query(
+            "SELECT * FROM users WHERE id = 1 OR id = 2 OR id = 3 OR id = 4"
+        )));
+        assert!(qr.multi_shard().unwrap().len() > 1);
+
+        // Writes and multiple statements go to one shard.
+        assert!(qr.infer(&simple_query(
+            "UPDATE users SET name = 'a' WHERE id = 1 OR id = 2"
+        )));
+        assert_eq!(qr.multi_shard(), None);
+
+        assert!(qr.infer(&simple_query(
+            "SELECT * FROM users WHERE id = 1 OR id = 2; SELECT 1"
+        )));
+        assert_eq!(qr.multi_shard(), None);
+        assert_eq!(qr.take_rejected(), Some(String::from(READ_FROM_SHARDS)));
+
+        // Queries without a sharding key go to one shard, unless the client asks for all of them.
+        assert!(qr.infer(&simple_query("SELECT * FROM users")));
+        assert_eq!(qr.multi_shard(), None);
+
+        assert_eq!(
+            qr.try_execute_command(&simple_query("SET SHARD TO 'ALL'")),
+            Some((Command::SetShard, String::from("ALL")))
+        );
+        assert_eq!(
+            qr.try_execute_command(&simple_query("SHOW SHARD")),
+            Some((Command::ShowShard, String::from("ALL")))
+        );
+
+        assert!(qr.infer(&simple_query("SELECT * FROM users")));
+        assert_eq!(qr.multi_shard(), Some(&vec![0, 1, 2]));
+        assert_eq!(qr.merge_plan(), &Ok(MergePlan::default()));
+
+        assert!(qr.infer(&simple_query("SELECT AVG(amount) FROM orders")));
+        assert!(qr.merge_plan().is_err());
+
+        // Only one simple query is merged, the others would only read one shard.
+        assert!(qr.infer(&simple_query("SELECT * FROM users; SELECT * FROM orders")));
+        assert_eq!(qr.multi_shard(), None);
+        assert_eq!(qr.take_rejected(), Some(String::from(READ_FROM_SHARDS)));
+
+        // Writes need a shard.
+        assert!(qr.infer(&simple_query("UPDATE users SET name = 'a'")));
+        assert_eq!(qr.take_rejected(), Some(String::from(WRITE_TO_ALL_SHARDS)));
+        assert!(qr.infer(&simple_query("BEGIN")));
+        assert!(qr.take_rejected().is_some());
+        assert!(qr.infer(&simple_query("UPDATE users SET name = 'a' WHERE id = 1")));
+        assert_eq!(qr.take_rejected(), None);
+
+        qr.try_execute_command(&simple_query("SET SHARD TO '1'"));
+        assert!(qr.infer(&simple_query("SELECT * FROM users")));
+        assert_eq!(qr.multi_shard(), None);
+        assert_eq!(qr.shard(), 1);
+
+        qr.try_execute_command(&simple_query("SET SERVER ROLE TO 'primary'"));
+        qr.try_execute_command(&simple_query("SET SHARD TO 'ALL'"));
+        assert!(qr.take_rejected().is_some());
+        assert_eq!(qr.shard(), 1);
+    }
+
+    #[test]
+    fn test_infer_shard_from_bind() {
+        QueryRouter::setup();
+
+        let mut qr = QueryRouter::new();
+        qr.update_pool_settings(PoolSettings {
+            shards: 5,
+            automatic_sharding_key: Some(String::from("id")),
+            query_parser_enabled: true,
+            ..Default::default()
+        });
+
+        let sharder = Sharder::new(5, ShardingFunction::PgBigintHash);
+
+        let parse = Parse {
+            name: String::from("s1"),
+            query: String::from("SELECT * FROM users WHERE id = $2 AND active = $1"),
+            param_types: Vec::new(),
+        };
+        assert!(qr.infer(&parse.to_bytes()));
+
+        let bind = |format: i16, value: &[u8]| {
+            let mut body = BytesMut::from(&b"\0s1\0"[..]);
+            body.put_i16(1);
+            body.put_i16(format);
+            body.put_i16(2);
+            body.put_i32(-1);
+            body.put_i32(value.len() as i32);
+            body.put_slice(value);
+            body.put_i16(0);
+
+            let mut message = BytesMut::from(&b"B"[..]);
+            message.put_i32(body.len() as i32 + 4);
+            message.put(body);
+            message
+        };
+
+        assert!(qr.infer(&bind(0, b"1234")));
+        assert_eq!(Some(qr.shard()), sharder.shard(1234));
+
+        assert!(qr.infer(&bind(1, &4321_i64.to_be_bytes())));
+        assert_eq!(Some(qr.shard()), sharder.shard(4321));
+
+        // Not a sharding key.
+        assert!(!qr.infer(&bind(0, b"abc")));
+        assert_eq!(Some(qr.shard()), sharder.shard(4321));
+
+        // The placeholder is not a sharding key value.
+        let parse = parse.renamed("s2");
+        let parse = Parse {
+            query: String::from("SELECT * FROM users WHERE id = 1 AND active = $1"),
+            ..parse
+        };
+        assert!(qr.infer(&parse.to_bytes()));
+        assert_eq!(Some(qr.shard()), sharder.shard(1));
+    }
+
+    /// A Bind of the statement, with the parameters in text.
+    fn bind_text(statement: &str, parameters: &[&str]) -> BytesMut {
+        let mut body = BytesMut::new();
+        body.put_u8(0);
+        body.put_slice(statement.as_bytes());
+        body.put_u8(0);
+        body.put_i16(0);
+        body.put_i16(parameters.len() as i16);
+        for parameter in parameters {
+            body.put_i32(parameter.len() as i32);
+            body.put_slice(parameter.as_bytes());
+        }
+        body.put_i16(0);
+
+        let mut message = BytesMut::from(&b"B"[..]);
+        message.put_i32(body.len() as i32 + 4);
+        message.put(body);
+        message
+    }
+
+    #[test]
+    fn test_infer_extended_role() {
+        QueryRouter::setup();
+
+        let mut qr = QueryRouter::new();
+        qr.update_pool_settings(PoolSettings {
+            query_parser_enabled: true,
+            primary_reads_enabled: false,
+            ..Default::default()
+        });
+
+        let parse = |name: &str, query: &str| {
+            Parse {
+                name: String::from(name),
+                query: String::from(query),
+                param_types: Vec::new(),
+            }
+            .to_bytes()
+        };
+        let execute = BytesMut::from(&b"E\0\0\0\x09\0\0\0\0\0"[..]);
+
+        qr.infer_extended(&[
+            parse("insert", "INSERT INTO users (id) VALUES ($1)"),
+            parse("select", "SELECT * FROM users WHERE id = $1"),
+        ]);
+        assert_eq!(qr.role(), Some(Role::Primary));
+
+        // A write prepared earlier.
+        qr.infer_extended(&[bind_text("insert", &["1"]), execute.clone()]);
+        assert_eq!(qr.role(), Some(Role::Primary));
+
+        qr.infer_extended(&[bind_text("select", &["1"]), execute.clone()]);
+        assert_eq!(qr.role(), Some(Role::Replica));
+
+        // The last message doesn't decide.
+        qr.infer_extended(&[
+            bind_text("insert", &["1"]),
+            execute.clone(),
+            parse("", "SELECT 1"),
+            bind_text("", &[]),
+            execute.clone(),
+        ]);
+        assert_eq!(qr.role(), Some(Role::Primary));
+
+        // Statements we can't parse are writes.
+        qr.infer_extended(&[parse("lock", "SELECT * FROM users FOR SHARE OF u NOWAIT x")]);
+        qr.infer_extended(&[bind_text("lock", &[]), execute.clone()]);
+        assert_eq!(qr.role(), Some(Role::Primary));
+
+        // Closed statements are forgotten.
+        let mut close = BytesMut::from(&b"C"[..]);
+        close.put_i32(4 + 1 + 7);
+        close.put_slice(b"Sinsert\0");
+        qr.infer_extended(&[close]);
+        qr.infer_extended(&[bind_text("select", &["1"]), bind_text("insert", &["1"])]);
+        assert_eq!(qr.role(), Some(Role::Replica));
+    }
+
+    #[test]
+    fn test_infer_extended_shards() {
+        QueryRouter::setup();
+
+        let mut qr = QueryRouter::new();
+        qr.update_pool_settings(PoolSettings {
+            shards: 5,
+            automatic_sharding_key: Some(String::from("id")),
+            query_parser_enabled: true,
+            ..Default::default()
+        });
+
+        let sharder = Sharder::new(5, ShardingFunction::PgBigintHash);
+        let other = (2..100)
+            .find(|id| sharder.shard(*id) != sharder.shard(1))
+            .unwrap()
+            .to_string();
+
+        let parse = Parse {
+            name: String::from("select"),
+            query: String::from("SELECT * FROM users WHERE id = $1"),
+            param_types: Vec::new(),
+        };
+
+        qr.infer_extended(&[parse.to_bytes(), bind_text("select", &["1"])]);
+        assert_eq!(qr.take_rejected(), None);
+        assert_eq!(Some(qr.shard()), sharder.shard(1));
+
+        // Each Sync starts over.
+        qr.infer_extended(&[bind_text("select", &[&other])]);
+        assert_eq!(qr.take_rejected(), None);
+
+        // Same shard.
+        qr.infer_extended(&[bind_text("select", &["1"]), bind_text("select", &["1"])]);
+        assert_eq!(qr.take_rejected(), None);
+
+        qr.infer_extended(&[bind_text("select", &["1"]), bind_text("select", &[&other])]);
+        assert!(qr.take_rejected().is_some());
+    }
+
+    #[test]
+    fn test_infer_extended_all_shards() {
+        QueryRouter::setup();
+
+        let mut qr = QueryRouter::new();
+        qr.update_pool_settings(PoolSettings {
+            shards: 5,
+            automatic_sharding_key: Some(String::from("id")),
+            query_parser_enabled: true,
+            reference_tables: vec![String::from("countries")],
+            ..Default::default()
+        });
+
+        let sharder = Sharder::new(5, ShardingFunction::PgBigintHash);
+        let other = (2..100)
+            .find(|id| sharder.shard(*id) != sharder.shard(1))
+            .unwrap();
+
+        let parse = |name: &str, query: &str| {
+            Parse {
+                name: String::from(name),
+                query: String::from(query),
+                param_types: Vec::new(),
+            }
+            .to_bytes()
+        };
+
+        // Keys of several shards.
+        qr.infer_extended(&[
+            parse(
+                "two",
+                &format!("SELECT * FROM users WHERE id IN (1, {})", other),
+            ),
+            bind_text("two", &[]),
+        ]);
+        assert_eq!(qr.take_rejected(), Some(String::from(READ_FROM_SHARDS)));
+
+        qr.infer_extended(&[
+            parse("one", "SELECT * FROM users WHERE id = $1 OR id = $2"),
+            bind_text("one", &["1", &other.to_string()]),
+        ]);
+        assert_eq!(qr.take_rejected(), Some(String::from(READ_FROM_SHARDS)));
+
+        qr.infer_extended(&[bind_text("one", &["1", "1"])]);
+        assert_eq!(qr.take_rejected(), None);
+
+        qr.infer_extended(&[parse("all", "SELECT * FROM users")]);
+        qr.infer_extended(&[bind_text("all", &[])]);
+        assert_eq!(qr.take_rejected(), None);
+
+        // After SET SHARD TO 'ALL', prepared statements without a key would read one shard.
+        qr.try_execute_command(&simple_query("SET SHARD TO 'ALL'"));
+
+        qr.infer_extended(&[bind_text("all", &[])]);
+        assert_eq!(qr.take_rejected(), Some(String::from(READ_FROM_SHARDS)));
+
+        qr.infer_extended(&[bind_text("one", &["abc", "abc"])]);
+        assert_eq!(qr.take_rejected(), Some(String::from(READ_FROM_SHARDS)));
+
+        // The key written in the statement is remembered for its Binds.
+        qr.infer_extended(&[parse("literal", "SELECT * FROM users WHERE id = 1")]);
+        qr.infer_extended(&[bind_text("literal", &[])]);
+        assert_eq!(qr.take_rejected(), None);
+        assert_eq!(Some(qr.shard()), sharder.shard(1));
+
+        qr.infer_extended(&[bind_text("one", &["1", "1"])]);
+        assert_eq!(qr.take_rejected(), None);
+
+        qr.infer_extended(&[
+            parse("countries", "SELECT * FROM countries"),
+            bind_text("countries", &[]),
+        ]);
+        assert_eq!(qr.take_rejected(), None);
+    }
+
+    #[test]
+    fn test_infer_write_shard() {
+        QueryRouter::setup();
+
+        let mut qr = QueryRouter::new();
+        qr.update_pool_settings(PoolSettings {
+            shards: 5,
+            automatic_sharding_key: Some(String::from("id")),
+            query_parser_enabled: true,
+            ..Default::default()
+        });
+
+        let sharder = Sharder::new(5, ShardingFunction::PgBigintHash);
+
+        assert!(qr.infer(&simple_query(
+            "INSERT INTO users (name, id) VALUES ('a', 1234)"
+        )));
+        assert_eq!(qr.role(), Some(Role::Primary));
+        assert_eq!(Some(qr.shard()), sharder.shard(1234));
+
+        assert!(qr.infer(&simple_query("UPDATE users SET name = 'b' WHERE id = 42")));
+        assert_eq!(Some(qr.shard()), sharder.shard(42));
+
+        assert!(qr.infer(&simple_query("DELETE FROM users WHERE id = 7")));
+        assert_eq!(Some(qr.shard()), sharder.shard(7));
+        assert_eq!(qr.take_rejected(), None);
+
+        // Rows for several shards.
+        let ids: Vec<i64> = (1..100)
+            .filter(|id| sharder.shard(*id) != sharder.shard(7))
+            .take(1)
+            .collect();
+        assert!(qr.infer(&simple_query(&format!(
+            "INSERT INTO users (id, name) VALUES (7, 'a'), ({}, 'b')",
+            ids[0]
+        ))));
+        assert!(qr.take_rejected().is_some());
+        assert_eq!(qr.take_rejected(), None);
+
+        // The sharding key is a parameter.
+        let parse = Parse {
+            name: String::from("insert"),
+            query: String::from("INSERT INTO users (id, name) VALUES ($1, $2)"),
+            param_types: Vec::new(),
+        };
+        assert!(qr.infer(&parse.to_bytes()));
+        assert_eq!(
+            qr.prepared_statements
+                .get("insert")
+                .unwrap()
+                .sharding_key_parameters,
+            vec![0]
+        );
+        assert_eq!(qr.take_rejected(), None);
+
+        // Unquoted names are case insensitive.
+        assert!(qr.infer(&simple_query("UPDATE Users SET name = 'b' WHERE ID = 42")));
+        assert_eq!(Some(qr.shard()), sharder.shard(42));
+        assert_eq!(qr.take_rejected(), None);
+
+        // Writes that don't say which shard.
+        for query in [
+            "INSERT INTO users VALUES (42, 'a')",
+            "INSERT INTO users (name) VALUES ('a')",
+            "UPDATE users SET name = 'b'",
+            "UPDATE users SET name = 'b' WHERE name = 'a'",
+            "DELETE FROM users WHERE \"ID\" = 42",
+        ] {
+            assert!(qr.infer(&simple_query(query)));
+            assert_eq!(
+                qr.take_rejected(),
+                Some(String::from(WRITE_WITHOUT_KEY)),
+                "{}",
+                query
+            );
+        }
+
+        // Not a write to a table.
+        assert!(qr.infer(&simple_query("CREATE TABLE t (id BIGINT)")));
+        assert_eq!(qr.take_rejected(), None);
+
+        // The client chose the shard.
+        qr.try_execute_command(&simple_query("SET SHARDING KEY TO '42'"));
+        assert!(qr.infer(&simple_query("INSERT INTO users VALUES (42, 'a')")));
+        assert_eq!(qr.take_rejected(), None);
+        assert_eq!(Some(qr.shard()), sharder.shard(42));
+    }
+
+    #[test]
+    fn test_infer_shard_from_expressions() {
+        QueryRouter::setup();
+
+        let mut qr = QueryRouter::new();
+        qr.update_pool_settings(PoolSettings {
+            shards: 5,
+            automatic_sharding_key: Some(String::from("id")),
+            query_parser_enabled: true,
+            ..Default::default()
+        });
+
+        let sharder = Sharder::new(5, ShardingFunction::PgBigintHash);
+
+        for query in [
+            "SELECT * FROM users WHERE 42 = id",
+            "SELECT * FROM users u WHERE u.id = 42",
+            "SELECT * FROM users WHERE id = '42'::bigint",
+            "SELECT * FROM users WHERE id = CAST('42' AS BIGINT) AND (name = 'a' OR name = 'b')",
+            "SELECT * FROM users WHERE id IN (42)",
+            "SELECT * FROM users WHERE (id = 42)",
+        ] {
+            qr.set_shard(0);
+            assert!(qr.infer(&simple_query(query)));
+            assert_eq!(Some(qr.shard()), sharder.shard(42), "{}", query);
+        }
+
+        assert!(qr.infer(&simple_query("SELECT * FROM users WHERE id = -7")));
+        assert_eq!(Some(qr.shard()), sharder.shard(-7));
+
+        assert!(qr.infer(&simple_query(
+            "SELECT * FROM users WHERE id IN (1, 2, 3, 4)"
+        )));
+        assert!(qr.multi_shard().unwrap().len() > 1);
+
+        // The rows could be on any shard.
+        assert!(qr.infer(&simple_query(
+            "SELECT * FROM users WHERE id = 1 OR name = 'a'"
+        )));
+        assert_eq!(qr.multi_shard(), None);
+        assert_eq!(
+            qr.selection_parser(&Expr::Value(Value::Boolean(true)), &[]),
+            None
+        );
+
+        // The sharding key of one table.
+        qr.update_pool_settings(PoolSettings {
+            shards: 5,
+            automatic_sharding_key: Some(String::from("users.id")),
+            query_parser_enabled: true,
+            ..Default::default()
+        });
+
+        qr.set_shard(0);
+        assert!(qr.infer(&simple_query(
+            "SELECT * FROM orders o JOIN users u ON o.user_id = u.id WHERE u.id = 42 AND o.id = 3"
+        )));
+        assert_eq!(Some(qr.shard()), sharder.shard(42));
+
+        qr.set_shard(0);
+        assert!(qr.infer(&simple_query("SELECT * FROM orders WHERE id = 42")));
+        assert_eq!(qr.shard(), 0);
+
+        assert!(qr.infer(&simple_query("DELETE FROM users WHERE id = 7")));
+        assert_eq!(Some(qr.shard()), sharder.shard(7));
+
+        qr.set_shard(0);
+        assert!(qr.infer(&simple_query("INSERT INTO orders (id) VALUES (7)")));
+        assert_eq!(qr.shard(), 0);
+
+        // Text and UUID sharding keys.
+        qr.update_pool_settings(PoolSettings {
+            shards: 5,
+            automatic_sharding_key: Some(String::from("id")),
+            sharding_key_type: ShardingKeyType::Text,
+            query_parser_enabled: true,
+            ..Default::default()
+        });
+
+        assert!(qr.infer(&simple_query("SELECT * FROM users WHERE id = 'pgcat'")));
+        assert_eq!(Some(qr.shard()), sharder.shard_text("pgcat"));
+
+        let uuid = "a0eebc99-9c0b-4ef8-bb6d-6bb9bd380a11";
+        qr.update_pool_settings(PoolSettings {
+            shards: 5,
+            automatic_sharding_key: Some(String::from("id")),
+            sharding_key_type: ShardingKeyType::Uuid,
+            query_parser_enabled: true,
+            ..Default::default()
+        });
+
+        assert!(qr.infer(&simple_query(&format!(
+            "UPDATE users SET name = 'a' WHERE id = '{}'::uuid",
+            uuid
+        ))));
+        assert_eq!(
+            qr.shard(),
+            sharder.shard_str(uuid, ShardingKeyType::Uuid).unwrap()
+        );
+
+        assert_eq!(
+            parameter_shard(
+                &sharder,
+                ShardingKeyType::Uuid,
+                1,
+                &crate::sharding::parse_uuid(uuid).unwrap().to_be_bytes()
+            ),
+            Some(qr.shard())
+        );
+    }
+
+    #[test]
+    fn test_infer_table_sharding_keys() {
+        QueryRouter::setup();
+
+        let mut qr = QueryRouter::new();
+        qr.update_pool_settings(PoolSettings {
+            shards: 5,
+            automatic_sharding_key: Some(String::from("id")),
+            sharding_keys: BTreeMap::from([(String::from("orders"), String::from("user_id"))]),
+            reference_tables: vec![String::from("countries")],
+            query_parser_enabled: true,
+            ..Default::default()
+        });
+
+        let sharder = Sharder::new(5, ShardingFunction::PgBigintHash);
+        let other = (1..100)
+            .find(|id| sharder.shard(*id) != sharder.shard(42))
+            .unwrap();
+
+        // The key of orders is user_id, not id.
+        qr.set_shard(sharder.shard(other).unwrap());
+        assert!(qr.infer(&simple_query("SELECT * FROM orders WHERE id = 42")));
+        assert_eq!(Some(qr.shard()), sharder.shard(other));
+
+        assert!(qr.infer(&simple_query("SELECT * FROM orders WHERE user_id = 42")));
+        assert_eq!(Some(qr.shard()), sharder.shard(42));
+
+        qr.set_shard(sharder.shard(other).unwrap());
+        assert!(qr.infer(&simple_query(&format!(
+            "SELECT * FROM users u JOIN orders o ON o.user_id = u.id WHERE o.id = {} AND u.id = 42",
+            other
+        ))));
+        assert_eq!(Some(qr.shard()), sharder.shard(42));
+
+        assert!(qr.infer(&simple_query(
+            "INSERT INTO orders (id, user_id) VALUES (1, 42)"
+        )));
+        assert_eq!(Some(qr.shard()), sharder.shard(42));
+
+        // Other tables use the automatic_sharding_key.
+        assert!(qr.infer(&simple_query(&format!(
+            "SELECT * FROM users WHERE id = {}",
+            other
+        ))));
+        assert_eq!(Some(qr.shard()), sharder.shard(other));
+
+        // Reference tables are read from one shard, not all of them.
+        qr.try_execute_command(&simple_query("SET SHARD TO 'ALL'"));
+        assert!(qr.infer(&simple_query("SELECT * FROM countries")));
+        assert_eq!(qr.multi_shard(), None);
+
+        assert!(qr.infer(&simple_query(
+            "SELECT * FROM orders JOIN countries ON countries.id = orders.country_id"
+        )));
+        assert_eq!(qr.multi_shard(), Some(&vec![0, 1, 2, 3, 4]));
+
+        // Writes to reference tables must go to every shard.
+        for query in [
+            "INSERT INTO countries (id, name) VALUES (1, 'a')",
+            "UPDATE Countries SET name = 'b' WHERE id = 1",
+            "DELETE FROM countries",
+            "COPY countries (id, name) FROM STDIN",
+        ] {
+            assert!(qr.infer(&simple_query(query)));
+            assert_eq!(
+                qr.take_rejected(),
+                Some(String::from(WRITE_TO_REFERENCE_TABLE)),
+                "{}",
+                query
+            );
+        }
+
+        for shard in 0..5 {
+            qr.try_execute_command(&simple_query(&format!("SET SHARD TO '{}'", shard)));
+            assert!(qr.infer(&simple_query("DELETE FROM countries")));
+            assert_eq!(qr.take_rejected(), None);
+            assert_eq!(qr.shard(), shard);
+        }
+    }
+
+    #[test]
+    fn test_infer_copy() {
+        QueryRouter::setup();
+
+        let mut qr = QueryRouter::new();
+        qr.update_pool_settings(PoolSettings {
+            shards: 3,
+            automatic_sharding_key: Some(String::from("id")),
+            sharding_keys: BTreeMap::from([(String::from("orders"), String::from("user_id"))]),
+            query_parser_enabled: true,
+            ..Default::default()
+        });
+
+        assert!(qr.infer(&simple_query("COPY users (name, id) FROM STDIN")));
+        assert_eq!(qr.role(), Some(Role::Primary));
+        assert!(qr.take_copy().is_some());
+        assert!(qr.take_copy().is_none());
+
+        assert!(qr.infer(&simple_query(
+            "COPY orders (id, user_id) FROM STDIN WITH (FORMAT csv)"
+        )));
+        assert!(qr.take_copy().is_some());
+
+        // No sharding key, the rows can't be split.
+        assert!(qr.infer(&simple_query("COPY orders (id) FROM STDIN")));
+        assert!(qr.take_copy().is_none());
+        assert_eq!(qr.take_rejected(), Some(String::from(COPY_WITHOUT_KEY)));
+
+        assert!(qr.infer(&simple_query("COPY users FROM STDIN")));
+        assert!(qr.take_copy().is_none());
+        assert_eq!(qr.take_rejected(), Some(String::from(COPY_WITHOUT_KEY)));
+
+        assert!(qr.infer(&simple_query("COPY users (id) TO STDOUT")));
+        assert!(qr.take_copy().is_none());
+        assert_eq!(qr.take_rejected(), None);
+
+        // Split between all shards.
+        qr.try_execute_command(&simple_query("SET SHARD TO 'ALL'"));
+        assert!(qr.infer(&simple_query("COPY users (name, id) FROM STDIN")));
+        assert!(qr.take_copy().is_some());
+        assert_eq!(qr.take_rejected(), None);
+
+        // The client chose the shard.
+        qr.try_execute_command(&simple_query("SET SHARD TO '1'"));
+        assert!(qr.infer(&simple_query("COPY users FROM STDIN")));
+        assert!(qr.take_copy().is_none());
+        assert_eq!(qr.take_rejected(), None);
+
+        assert!(qr.infer(&simple_query("COPY users (id) FROM STDIN BINARY")));
+        assert!(qr.take_copy().is_none());
+        assert!(qr.take_rejected().is_some());
+    }
+
+    #[test]
+    fn test_infer_range_shard() {
+        QueryRouter::setup();
+
+        let mut qr = QueryRouter::new();
+        qr.update_pool_settings(PoolSettings {
+            shards: 2,
+            automatic_sharding_key: Some(String::from("id")),
+            sharding_function: ShardingFunction::Range,
+            shard_keys: std::sync::Arc::new(ShardKeys::new(
+                vec![(0, 1000, 0), (1000, 2000, 1)],
+                HashMap::new(),
+            )),
+            query_parser_enabled: true,
+            ..Default::default()
+        });
+
+        assert_eq!(
+            qr.try_execute_command(&simple_query("SET SHARDING KEY TO '1500'")),
+            Some((Command::SetShardingKey, String::from("1")))
+        );
+        assert_eq!(qr.take_rejected(), None);
+
+        assert!(qr
+            .try_execute_command(&simple_query("SET SHARDING KEY TO '5000'"))
+            .is_some());
+        assert_eq!(
+            qr.take_rejected(),
+            Some(String::from("sharding key 5000 is not in any shard"))
+        );
+        assert_eq!(qr.shard(), 1);
+
+        assert!(qr.infer(&simple_query("SELECT * FROM users WHERE id = 42")));
+        assert_eq!(qr.shard(), 0);
+
+        // Reads of keys that are nowhere are fine, writes are not.
+        assert!(qr.infer(&simple_query("SELECT * FROM users WHERE id = 5000")));
+        assert_eq!(qr.take_rejected(), None);
+
+        assert!(qr.infer(&simple_query("INSERT INTO users (id) VALUES (5000)")));
+        assert!(qr.take_rejected().is_some());
+
+        // Same with the key in the parameters.
+        let insert = Parse {
+            name: String::from("insert"),
+            query: String::from("INSERT INTO users (id, name) VALUES ($1, $2)"),
+            param_types: Vec::new(),
+        };
+        let select = Parse {
+            name: String::from("select"),
+            query: String::from("SELECT * FROM users WHERE id = $1"),
+            param_types: Vec::new(),
+        };
+        qr.infer_extended(&[insert.to_bytes(), select.to_bytes()]);
+        assert_eq!(qr.take_rejected(), None);
+
+        qr.infer_extended(&[bind_text("insert", &["1500", "a"])]);
+        assert_eq!(qr.take_rejected(), None);
+        assert_eq!(qr.shard(), 1);
+
+        qr.infer_extended(&[bind_text("select", &["5000"])]);
+        assert_eq!(qr.take_rejected(), None);
+
+        qr.infer_extended(&[bind_text("insert", &["5000", "a"])]);
+        assert_eq!(
+            qr.take_rejected(),
+            Some(String::from("sharding key $1 is not in any shard"))
+        );
+    }
+
+    #[test]
+    fn test_read_your_writes() {
+        QueryRouter::setup();
+
+        let mut qr = QueryRouter::new();
+        qr.update_pool_settings(PoolSettings {
+            read_your_writes: ReadYourWrites::Window,
+            read_your_writes_window: 60_000,
+            ..Default::default()
+        });
+        qr.try_execute_command(&simple_query("SET PRIMARY READS TO off"));
+
+        assert!(qr.infer(&simple_query("SELECT * FROM items WHERE id = 5")));
+        assert_eq!(qr.role(), Some(Role::Replica));
+
+        assert!(qr.infer(&simple_query("UPDATE items SET name = 'a' WHERE id = 5")));
+        assert_eq!(qr.role(), Some(Role::Primary));
+
+        // The client reads its write from the primary.
+        assert!(qr.infer(&simple_query("SELECT * FROM items WHERE id = 5")));
+        assert_eq!(qr.role(), Some(Role::Primary));
+        assert_eq!(qr.min_replica_lsn(), None);
+
+        qr.update_pool_settings(PoolSettings {
+            read_your_writes: ReadYourWrites::Lsn,
+            ..Default::default()
+        });
+
+        assert!(qr.infer(&simple_query("SELECT * FROM items WHERE id = 5")));
+        assert_eq!(qr.role(), Some(Role::Replica));
+        assert!(qr.write_lsn_pending());
+
+        qr.set_write_lsn(100);
+        assert!(!qr.write_lsn_pending());
+        assert_eq!(qr.min_replica_lsn(), Some(100));
+
+        // Statements we can't parse are writes too.
+        let mut qr = QueryRouter::new();
+        qr.update_pool_settings(PoolSettings {
+            read_your_writes: ReadYourWrites::Window,
+            read_your_writes_window: 60_000,
+            ..Default::default()
+        });
+        qr.try_execute_command(&simple_query("SET PRIMARY READS TO off"));
+
+        assert!(!qr.infer(&simple_query("SELECT * FROM items FOR SHARE OF i NOWAIT x")));
+        assert_eq!(qr.role(), Some(Role::Primary));
+
+        assert!(qr.infer(&simple_query("SELECT * FROM items WHERE id = 5")));
+        assert_eq!(qr.role(), Some(Role::Primary));
+    }
 }
@@ -2,6 +2,7 @@
 // https://github.com/sfackler/rust-postgres/
 // SASL implementation.
 
+use base64::{engine::general_purpose, Engine as _};
 use bytes::BytesMut;
 use hmac::{Hmac, Mac};
 use rand::{self, Rng};
@@ -27,11 +28,38 @@ fn normalize(pass: &[u8]) -> Vec<u8> {
     }
 }
 
+/// Generate a random printable nonce, without commas.
+fn nonce() -> String {
+    let mut rng = rand::thread_rng();
+    (0..NONCE_LENGTH)
+        .map(|_| {
+            let mut v = rng.gen_range(0x21u8..0x7e);
+            if v == 0x2c {
+                v = 0x7e
+            }
+            v as char
+        })
+        .collect::<String>()
+}
+
+/// What we use to prove to the server we know the password.
+enum Credentials {
+    /// Cleartext password.
+    Password(String),
+
+    /// Keys taken from a SCRAM verifier and a client's proof,
+    /// when we don't know the cleartext password (ClientKey passthrough).
+    Keys {
+        client_key: Vec<u8>,
+        server_key: Vec<u8>,
+    },
+}
+
 /// Keep the SASL state through the exchange.
 /// It takes 3 messages to complete the authentication.
 pub struct ScramSha256 {
-    password: String,
-    salted_password: [u8; 32],
+    credentials: Credentials,
+    server_key: Vec<u8>,
     auth_message: String,
     message: BytesMut,
     nonce: String,
@@ -41,18 +69,19 @@ impl ScramSha256 {
     /// Create the Scram state from a password. It'll automatically
     /// generate a nonce.
     pub fn new(password: &str) -> ScramSha256 {
-        let mut rng = rand::thread_rng();
-        let nonce = (0..NONCE_LENGTH)
-            .map(|_| {
-                let mut v = rng.gen_range(0x21u8..0x7e);
-                if v == 0x2c {
-                    v = 0x7e
-                }
-                v as char
-            })
-            .collect::<String>();
-
-        Self::from_nonce(password, &nonce)
+        Self::from_nonce(password, &nonce())
+    }
+
+    /// Create the Scram state from the ClientKey we recovered from a client's proof
+    /// and the ServerKey from the SCRAM verifier. The verifier must be the one
+    /// the server has, since the salt it sends us is not used.
+    pub fn from_client_key(client_key: &[u8], server_key: &[u8]) -> ScramSha256 {
+        let mut scram = Self::new("");
+        scram.credentials = Credentials::Keys {
+            client_key: client_key.to_vec(),
+            server_key: server_key.to_vec(),
+        };
+        scram
     }
 
     /// Used for testing.
@@ -60,10 +89,10 @@ impl ScramSha256 {
         let message = BytesMut::from(format!("{}n=,r={}", "n,,", nonce).as_bytes());
 
         ScramSha256 {
-            password: password.to_string(),
+            credentials: Credentials::Password(password.to_string()),
             nonce: String::from(nonce),
             message,
-            salted_password: [0u8; 32],
+            server_key: Vec::new(),
             auth_message: String::new(),
         }
     }
@@ -81,28 +110,33 @@ impl ScramSha256 {
             return Err(Error::ProtocolSyncError(format!("SCRAM")));
         }
 
-        let salt = match base64::decode(&server_message.salt) {
-            Ok(salt) => salt,
-            Err(_) => return Err(Error::ProtocolSyncError(format!("SCRAM"))),
-        };
+        let client_key = match &self.credentials {
+            Credentials::Password(password) => {
+                let salt = match base64::decode(&server_message.salt) {
+                    Ok(salt) => salt,
+                    Err(_) => return Err(Error::ProtocolSyncError(format!("SCRAM"))),
+                };
 
-        let salted_password = Self::hi(
-            &normalize(self.password.as_bytes()),
-            &salt,
-            server_message.iterations,
-        );
+                let salted_password = Self::hi(
+                    &normalize(password.as_bytes()),
+                    &salt,
+                    server_message.iterations,
+                );
 
-        // Save for verification of final server message.
-        self.salted_password = salted_password;
-
-        let mut hmac = match Hmac::<Sha256>::new_from_slice(&salted_password) {
-            Ok(hmac) => hmac,
-            Err(_) => return Err(Error::ServerError),
-        };
+                // Save for verification of final server message.
+                self.server_key = hmac(&salted_password, b"Server Key");
 
-        hmac.update(b"Client Key");
+                hmac(&salted_password, b"Client Key")
+            }
 
-        let client_key = hmac.finalize().into_bytes();
+            Credentials::Keys {
+                client_key,
+                server_key,
+            } => {
+                self.server_key = server_key.clone();
+                client_key.clone()
+            }
+        };
 
         let mut hash = Sha256::default();
         hash.update(client_key.as_slice());
@@ -166,14 +200,7 @@ impl ScramSha256 {
             Err(_) => return Err(Error::ProtocolSyncError(format!("SCRAM"))),
         };
 
-        let mut hmac = match Hmac::<Sha256>::new_from_slice(&self.salted_password) {
-            Ok(hmac) => hmac,
-            Err(_) => return Err(Error::ServerError),
-        };
-        hmac.update(b"Server Key");
-        let server_key = hmac.finalize().into_bytes();
-
-        let mut hmac = match Hmac::<Sha256>::new_from_slice(&server_key) {
+        let mut hmac = match Hmac::<Sha256>::new_from_slice(&self.server_key) {
             Ok(hmac) => hmac,
             Err(_) => return Err(Error::ServerError),
         };
@@ -209,6 +236,190 @@ impl ScramSha256 {
     }
 }
 
+/// Number of iterations used to salt passwords for client authentication.
+/// Matches the Postgres default (scram_iterations).
+const SCRAM_ITERATIONS: u32 = 4096;
+
+/// SCRAM-SHA-256 verifier, as Postgres stores it in pg_authid, i.e.
+/// `SCRAM-SHA-256$<iterations>:<salt>$<StoredKey>:<ServerKey>`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScramSecret {
+    pub iterations: u32,
+    pub salt: Vec<u8>,
+    pub stored_key: Vec<u8>,
+    pub server_key: Vec<u8>,
+}
+
+impl ScramSecret {
+    /// Parse the verifier. Returns None if this is not a SCRAM verifier,
+    /// e.g. a cleartext password.
+    pub fn parse(secret: &str) -> Option<ScramSecret> {
+        let secret = secret.strip_prefix("SCRAM-SHA-256$")?;
+        let (iterations_and_salt, keys) = secret.split_once('$')?;
+        let (iterations, salt) = iterations_and_salt.split_once(':')?;
+        let (stored_key, server_key) = keys.split_once(':')?;
+
+        let secret = ScramSecret {
+            iterations: iterations.parse::<u32>().ok()?,
+            salt: general_purpose::STANDARD.decode(salt).ok()?,
+            stored_key: general_purpose::STANDARD.decode(stored_key).ok()?,
+            server_key: general_purpose::STANDARD.decode(server_key).ok()?,
+        };
+
+        if secret.stored_key.len() != 32 || secret.server_key.len() != 32 {
+            return None;
+        }
+
+        Some(secret)
+    }
+}
+
+/// Keep the SASL state while authenticating a client,
+/// i.e. the server side of the exchange.
+/// It takes 2 client messages to complete the authentication.
+pub struct ScramSha256Server {
+    salt: Vec<u8>,
+    iterations: u32,
+    stored_key: Vec<u8>,
+    server_key: Vec<u8>,
+    nonce: String,
+    gs2_header: String,
+    client_first_bare: String,
+    server_first: String,
+    client_key: Option<Vec<u8>>,
+}
+
+impl ScramSha256Server {
+    /// Create the Scram state from a cleartext password. It'll automatically
+    /// generate a salt and a nonce.
+    pub fn new(password: &str) -> ScramSha256Server {
+        let salt: [u8; 16] = rand::random();
+        Self::from_salt(password, &salt, SCRAM_ITERATIONS, &nonce())
+    }
+
+    /// Used for testing.
+    pub fn from_salt(
+        password: &str,
+        salt: &[u8],
+        iterations: u32,
+        nonce: &str,
+    ) -> ScramSha256Server {
+        let salted_password = ScramSha256::hi(&normalize(password.as_bytes()), salt, iterations);
+
+        let client_key = hmac(&salted_password, b"Client Key");
+
+        Self::from_secret_and_nonce(
+            &ScramSecret {
+                iterations,
+                salt: salt.to_vec(),
+                stored_key: Sha256::digest(client_key).to_vec(),
+                server_key: hmac(&salted_password, b"Server Key"),
+            },
+            nonce,
+        )
+    }
+
+    /// Create the Scram state from a SCRAM verifier, without knowing the password.
+    pub fn from_secret(secret: &ScramSecret) -> ScramSha256Server {
+        Self::from_secret_and_nonce(secret, &nonce())
+    }
+
+    fn from_secret_and_nonce(secret: &ScramSecret, nonce: &str) -> ScramSha256Server {
+        ScramSha256Server {
+            salt: secret.salt.clone(),
+            iterations: secret.iterations,
+            stored_key: secret.stored_key.clone(),
+            server_key: secret.server_key.clone(),
+            nonce: String::from(nonce),
+            gs2_header: String::new(),
+            client_first_bare: String::new(),
+            server_first: String::new(),
+            client_key: None,
+        }
+    }
+
+    /// The ClientKey the client proved it knows. Available once the exchange is finished;
+    /// it can be used to log into the server on the client's behalf.
+    pub fn client_key(&self) -> Option<Vec<u8>> {
+        self.client_key.clone()
+    }
+
+    /// Handle the client-first-message and produce our challenge.
+    pub fn update(&mut self, message: &BytesMut) -> Result<BytesMut, Error> {
+        let client_first = ClientFirstMessage::parse(message)?;
+
+        self.server_first = format!(
+            "r={}{},s={},i={}",
+            client_first.nonce,
+            self.nonce,
+            general_purpose::STANDARD.encode(&self.salt),
+            self.iterations
+        );
+
+        self.nonce = format!("{}{}", client_first.nonce, self.nonce);
+        self.gs2_header = client_first.gs2_header;
+        self.client_first_bare = client_first.bare;
+
+        Ok(BytesMut::from(self.server_first.as_bytes()))
+    }
+
+    /// Verify the client proof and produce the server signature
+    /// the client uses to authenticate us in turn.
+    pub fn finish(&mut self, message: &BytesMut) -> Result<BytesMut, Error> {
+        let client_final = ClientFinalMessage::parse(message)?;
+
+        if client_final.channel_binding != general_purpose::STANDARD.encode(&self.gs2_header) {
+            return Err(Error::ProtocolSyncError(String::from(
+                "SCRAM channel binding",
+            )));
+        }
+
+        if client_final.nonce != self.nonce {
+            return Err(Error::ProtocolSyncError(String::from("SCRAM nonce")));
+        }
+
+        let auth_message = format!(
+            "{},{},{}",
+            self.client_first_bare, self.server_first, client_final.without_proof
+        );
+
+        let client_signature = hmac(&self.stored_key, auth_message.as_bytes());
+
+        if client_final.proof.len() != client_signature.len() {
+            return Err(Error::ProtocolSyncError(String::from("SCRAM proof")));
+        }
+
+        // The proof is the client key signed with the stored key,
+        // so we can recover the client key and check it against ours.
+        let client_key = client_final
+            .proof
+            .iter()
+            .zip(client_signature)
+            .map(|(proof, signature)| proof ^ signature)
+            .collect::<Vec<u8>>();
+
+        if Sha256::digest(&client_key).as_slice() != self.stored_key.as_slice() {
+            return Err(Error::ClientError(String::from("SCRAM proof mismatch")));
+        }
+
+        self.client_key = Some(client_key);
+
+        let server_signature = hmac(&self.server_key, auth_message.as_bytes());
+
+        Ok(BytesMut::from(
+            format!("v={}", general_purpose::STANDARD.encode(server_signature)).as_bytes(),
+        ))
+    }
+}
+
+/// HMAC-SHA-256 of the message with the key.
+fn hmac(key: &[u8], message: &[u8]) -> Vec<u8> {
+    let mut hmac =
+        Hmac::<Sha256>::new_from_slice(key).expect("HMAC is able to accept all key sizes");
+    hmac.update(message);
+    hmac.finalize().into_bytes().to_vec()
+}
+
 /// Parse the server challenge.
 struct Message {
     nonce: String,
@@ -261,10 +472,96 @@ impl FinalMessage {
     }
 }
 
+/// Parse the client-first-message, e.g. `n,,n=,r=nonce`.
+struct ClientFirstMessage {
+    gs2_header: String,
+    bare: String,
+    nonce: String,
+}
+
+impl ClientFirstMessage {
+    fn parse(message: &BytesMut) -> Result<ClientFirstMessage, Error> {
+        let message = String::from_utf8_lossy(&message[..]).to_string();
+
+        // The GS2 header is the channel binding flag and an optional authzid.
+        let mut parts = message.splitn(3, ',');
+        let (cbind_flag, authzid, bare) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(cbind_flag), Some(authzid), Some(bare)) => (cbind_flag, authzid, bare),
+            _ => return Err(Error::ProtocolSyncError(String::from("SCRAM"))),
+        };
+
+        // We don't advertise SCRAM-SHA-256-PLUS, so the client can't require channel binding.
+        if cbind_flag != "n" && cbind_flag != "y" {
+            return Err(Error::ProtocolSyncError(String::from(
+                "SCRAM channel binding is not supported",
+            )));
+        }
+
+        let nonce = match bare.split(',').find_map(|attr| attr.strip_prefix("r=")) {
+            Some(nonce) if !nonce.is_empty() => nonce.to_string(),
+            _ => return Err(Error::ProtocolSyncError(String::from("SCRAM"))),
+        };
+
+        Ok(ClientFirstMessage {
+            gs2_header: format!("{},{},", cbind_flag, authzid),
+            bare: bare.to_string(),
+            nonce,
+        })
+    }
+}
+
+/// Parse the client-final-message, e.g. `c=biws,r=nonce,p=proof`.
+struct ClientFinalMessage {
+    channel_binding: String,
+    nonce: String,
+    without_proof: String,
+    proof: Vec<u8>,
+}
+
+impl ClientFinalMessage {
+    fn parse(message: &BytesMut) -> Result<ClientFinalMessage, Error> {
+        let message = String::from_utf8_lossy(&message[..]).to_string();
+
+        let (without_proof, proof) = match message.rsplit_once(",p=") {
+            Some(parts) => parts,
+            None => return Err(Error::ProtocolSyncError(String::from("SCRAM"))),
+        };
+
+        let proof = match general_purpose::STANDARD.decode(proof) {
+            Ok(proof) => proof,
+            Err(_) => return Err(Error::ProtocolSyncError(String::from("SCRAM"))),
+        };
+
+        let mut channel_binding = None;
+        let mut nonce = None;
+
+        for attr in without_proof.split(',') {
+            if let Some(value) = attr.strip_prefix("c=") {
+                channel_binding = Some(value.to_string());
+            } else if let Some(value) = attr.strip_prefix("r=") {
+                nonce = Some(value.to_string());
+            }
+        }
+
+        match (channel_binding, nonce) {
+            (Some(channel_binding), Some(nonce)) => Ok(ClientFinalMessage {
+                channel_binding,
+                nonce,
+                without_proof: without_proof.to_string(),
+                proof,
+            }),
+            _ => Err(Error::ProtocolSyncError(String::from("SCRAM"))),
+        }
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
 
+    // Verifier for the password "foobar" used in the recorded exchange below.
+    const SECRET: &str = "SCRAM-SHA-256$4096:fs3IXBy7U7+IvVjZ$tmgbRn9qfDg3ip++wAxsFIk0Zl9PF0NDB5npDjVeECM=:bSU5hgS4vu9S/BceyyM0+b0RxdaRtdH5LRjmCPcHxhg=";
+
     #[test]
     fn parse_server_first_message() {
         let message = BytesMut::from(
@@ -317,4 +614,93 @@ mod test {
             .finish(&BytesMut::from(server_final.as_bytes()))
             .unwrap();
     }
+    // Same recorded exchange, but with us playing the server.
+    #[test]
+    fn server_exchange() {
+        let password = "foobar";
+        let salt = general_purpose::STANDARD
+            .decode("fs3IXBy7U7+IvVjZ")
+            .unwrap();
+
+        let client_first = "n,,n=,r=9IZ2O01zb9IgiIZ1WJ/zgpJB";
+        let server_first =
+            "r=9IZ2O01zb9IgiIZ1WJ/zgpJBjx/oIRLs02gGSHcw1KEty3eY,s=fs3IXBy7U7+IvVjZ,i\
+             =4096";
+        let client_final =
+            "c=biws,r=9IZ2O01zb9IgiIZ1WJ/zgpJBjx/oIRLs02gGSHcw1KEty3eY,p=AmNKosjJzS3\
+             1NTlQYNs5BTeQjdHdk7lOflDo5re2an8=";
+        let server_final = "v=U+ppxD5XUKtradnv8e2MkeupiA8FU87Sg8CXzXHDAzw=";
+
+        let mut scram =
+            ScramSha256Server::from_salt(password, &salt, 4096, "jx/oIRLs02gGSHcw1KEty3eY");
+
+        let result = scram
+            .update(&BytesMut::from(client_first.as_bytes()))
+            .unwrap();
+        assert_eq!(std::str::from_utf8(&result).unwrap(), server_first);
+
+        let result = scram
+            .finish(&BytesMut::from(client_final.as_bytes()))
+            .unwrap();
+        assert_eq!(std::str::from_utf8(&result).unwrap(), server_final);
+    }
+
+    #[test]
+    fn server_exchange_wrong_password() {
+        let mut server = ScramSha256Server::new("foobar");
+        let mut client = ScramSha256::new("barfoo");
+
+        let server_first = server.update(&client.message()).unwrap();
+        let client_final = client.update(&server_first).unwrap();
+
+        assert!(server.finish(&client_final).is_err());
+    }
+    #[test]
+    fn parse_secret() {
+        let secret = ScramSecret::parse(SECRET).unwrap();
+        assert_eq!(secret.iterations, 4096);
+        assert_eq!(
+            secret.salt,
+            general_purpose::STANDARD
+                .decode("fs3IXBy7U7+IvVjZ")
+                .unwrap()
+        );
+
+        assert!(ScramSecret::parse("foobar").is_none());
+        assert!(ScramSecret::parse("md5d5d2b7621f9dfa5d09376c9c966bf109").is_none());
+        assert!(ScramSecret::parse("SCRAM-SHA-256$4096:fs3IXBy7U7+IvVjZ$abc:def").is_none());
+    }
+
+    // The recorded exchange again, but the server only knows the verifier.
+    // The client key we recover lets us log into the server in turn.
+    #[test]
+    fn server_exchange_from_secret() {
+        let secret = ScramSecret::parse(SECRET).unwrap();
+
+        let client_first = "n,,n=,r=9IZ2O01zb9IgiIZ1WJ/zgpJB";
+        let client_final =
+            "c=biws,r=9IZ2O01zb9IgiIZ1WJ/zgpJBjx/oIRLs02gGSHcw1KEty3eY,p=AmNKosjJzS3\
+             1NTlQYNs5BTeQjdHdk7lOflDo5re2an8=";
+        let server_final = "v=U+ppxD5XUKtradnv8e2MkeupiA8FU87Sg8CXzXHDAzw=";
+
+        let mut server =
+            ScramSha256Server::from_secret_and_nonce(&secret, "jx/oIRLs02gGSHcw1KEty3eY");
+        let server_first = server
+            .update(&BytesMut::from(client_first.as_bytes()))
+            .unwrap();
+        server
+            .finish(&BytesMut::from(client_final.as_bytes()))
+            .unwrap();
+
+        let mut client =
+            ScramSha256::from_client_key(&server.client_key().unwrap(), &secret.server_key);
+        client.nonce = String::from("9IZ2O01zb9IgiIZ1WJ/zgpJB");
+
+        let result = client.update(&server_first).unwrap();
+        assert_eq!(std::str::from_utf8(&result).unwrap(), client_final);
+
+        client
+            .finish(&BytesMut::from(server_final.as_bytes()))
+            .unwrap();
+    }
 }
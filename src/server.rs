@@ -3,20 +3,22 @@
 use bytes::{Buf, BufMut, BytesMut};
 use log::{debug, error, info, trace, warn};
 use std::io::Read;
+use std::mem;
 use std::time::SystemTime;
 use tokio::io::{AsyncReadExt, BufReader};
-use tokio::net::{
-    tcp::{OwnedReadHalf, OwnedWriteHalf},
-    TcpStream,
-};
+use tokio_rustls::rustls::ServerName;
+use tokio_rustls::TlsConnector;
 
-use crate::config::{Address, User};
+use crate::config::{get_config, Address, ServerTlsMode, User};
 use crate::constants::*;
 use crate::errors::Error;
 use crate::messages::*;
 use crate::pool::ClientServerMap;
-use crate::scram::ScramSha256;
+use crate::prepared_statements::ServerStatements;
+use crate::scram::{ScramSecret, ScramSha256};
 use crate::stats::Reporter;
+use crate::stream::{ServerReadHalf, ServerStream, ServerWriteHalf};
+use crate::tls::ServerTls;
 
 /// Server state.
 pub struct Server {
@@ -26,11 +28,11 @@ pub struct Server {
     /// port, e.g. 5432, and role, e.g. primary or replica.
     address: Address,
 
-    /// Buffered read socket.
-    read: BufReader<OwnedReadHalf>,
+    /// Buffered read socket, plain text or TLS.
+    read: BufReader<ServerReadHalf>,
 
     /// Unbuffered write socket (our client code buffers).
-    write: OwnedWriteHalf,
+    write: ServerWriteHalf,
 
     /// Our server response buffer. We buffer data before we give it to the client.
     buffer: BytesMut,
@@ -68,30 +70,38 @@ pub struct Server {
 
     // Last time that a successful server send or response happened
     last_activity: SystemTime,
+
+    /// Statements prepared on this server, and the responses we expect for them.
+    prepared_statements: ServerStatements,
 }
 
 impl Server {
     /// Pretend to be the Postgres client and connect to the server given host, port and credentials.
     /// Perform the authentication and return the server in a ready for query state.
+    /// If the user's password is a SCRAM verifier, the ClientKey from a client's proof is used instead.
     pub async fn startup(
         server_id: i32,
         address: &Address,
         user: &User,
-        database: &str,
+        tls: &ServerTls,
         client_server_map: ClientServerMap,
         stats: Reporter,
+        scram_client_key: Option<Vec<u8>>,
     ) -> Result<Server, Error> {
-        let mut stream =
-            match TcpStream::connect(&format!("{}:{}", &address.host, address.port)).await {
-                Ok(stream) => stream,
-                Err(err) => {
-                    error!("Could not connect to server: {}", err);
-                    return Err(Error::SocketError(format!(
-                        "Could not connect to server: {}",
-                        err
-                    )));
-                }
-            };
+        let database = &address.database;
+
+        let stream = match ServerStream::connect(&address.host, address.port).await {
+            Ok(stream) => stream,
+            Err(err) => {
+                error!("Could not connect to server: {}", err);
+                return Err(Error::SocketError(format!(
+                    "Could not connect to server: {}",
+                    err
+                )));
+            }
+        };
+
+        let mut stream = Self::negotiate_tls(stream, address, tls).await?;
 
         trace!("Sending StartupMessage");
 
@@ -104,7 +114,15 @@ impl Server {
 
         // We'll be handling multiple packets, but they will all be structured the same.
         // We'll loop here until this exchange is complete.
-        let mut scram = ScramSha256::new(&user.password);
+        let scram_secret = ScramSecret::parse(&user.password);
+        let mut scram = match (&scram_secret, scram_client_key) {
+            (Some(secret), Some(client_key)) => Some(ScramSha256::from_client_key(
+                &client_key,
+                &secret.server_key,
+            )),
+            (Some(_), None) => None,
+            (None, _) => Some(ScramSha256::new(&user.password)),
+        };
 
         loop {
             let code = match stream.read_u8().await {
@@ -141,6 +159,11 @@ impl Server {
                                 Err(_) => return Err(Error::SocketError(format!("Error reading salt on server startup {{ username: {:?}, database: {:?} }}", user.username, database))),
                             };
 
+                            if scram_secret.is_some() {
+                                error!("Server asked for md5 authentication, but we only have the SCRAM verifier {{ username: {:?}, database: {:?} }}", user.username, database);
+                                return Err(Error::ServerError);
+                            }
+
                             md5_password(&mut stream, &user.username, &user.password, &salt[..])
                                 .await?;
                         }
@@ -159,6 +182,14 @@ impl Server {
 
                             let sasl_type = String::from_utf8_lossy(&sasl_auth[..sasl_len - 2]);
 
+                            let scram = match scram.as_mut() {
+                                Some(scram) => scram,
+                                None => {
+                                    error!("No client has logged in with SCRAM yet, can't authenticate with the SCRAM verifier {{ username: {:?}, database: {:?} }}", user.username, database);
+                                    return Err(Error::ServerError);
+                                }
+                            };
+
                             if sasl_type == SCRAM_SHA_256 {
                                 debug!("Using {}", SCRAM_SHA_256);
 
@@ -200,7 +231,10 @@ impl Server {
                             };
 
                             let msg = BytesMut::from(&sasl_data[..]);
-                            let sasl_response = scram.update(&msg)?;
+                            let sasl_response = match scram.as_mut() {
+                                Some(scram) => scram.update(&msg)?,
+                                None => return Err(Error::ServerError),
+                            };
 
                             // SASLResponse
                             let mut res = BytesMut::new();
@@ -220,6 +254,11 @@ impl Server {
                                 Err(_) => return Err(Error::SocketError(format!("Error reading sasl final message on server startup {{ username: {:?}, database: {:?} }}", user.username, database))),
                             };
 
+                            let scram = match scram.as_mut() {
+                                Some(scram) => scram,
+                                None => return Err(Error::ServerError),
+                            };
+
                             match scram.finish(&BytesMut::from(&sasl_final[..])) {
                                 Ok(_) => {
                                     debug!("SASL authentication successful");
@@ -333,6 +372,9 @@ impl Server {
                         stats,
                         application_name: String::new(),
                         last_activity: SystemTime::now(),
+                        prepared_statements: ServerStatements::new(
+                            get_config().general.prepared_statements_cache_size,
+                        ),
                     };
 
                     server.set_name("pgcat").await?;
@@ -353,6 +395,91 @@ impl Server {
         }
     }
 
+    /// Switch the connection to TLS if the pool's (or the shard's) server_tls_mode asks for it.
+    /// Unix sockets are local, so they never use TLS, like in libpq.
+    async fn negotiate_tls(
+        stream: ServerStream,
+        address: &Address,
+        tls: &ServerTls,
+    ) -> Result<ServerStream, Error> {
+        let mut stream = match stream {
+            ServerStream::Plain(stream) => stream,
+            stream => return Ok(stream),
+        };
+
+        let mode = tls.mode;
+
+        if mode == ServerTlsMode::Disable {
+            return Ok(ServerStream::Plain(stream));
+        }
+
+        ssl_request(&mut stream).await?;
+
+        let response = match stream.read_u8().await {
+            Ok(response) => response as char,
+            Err(err) => {
+                return Err(Error::SocketError(format!(
+                    "Error reading SSLRequest response from server {:?}: {}",
+                    address, err
+                )))
+            }
+        };
+
+        match response {
+            // Server supports TLS.
+            'S' => {
+                let tls_config = match &tls.config {
+                    Some(tls_config) => tls_config.clone(),
+                    None => {
+                        error!("No TLS configuration to connect to server {:?}", address);
+                        return Err(Error::TlsError);
+                    }
+                };
+
+                let server_name = match ServerName::try_from(address.host.as_str()) {
+                    Ok(server_name) => server_name,
+                    Err(err) => {
+                        error!("Invalid server name {}: {:?}", address.host, err);
+                        return Err(Error::TlsError);
+                    }
+                };
+
+                match TlsConnector::from(tls_config)
+                    .connect(server_name, stream)
+                    .await
+                {
+                    Ok(stream) => {
+                        debug!("TLS connection established with {:?}", address);
+                        Ok(ServerStream::Tls(Box::new(stream)))
+                    }
+                    Err(err) => {
+                        error!("TLS handshake with server {:?} failed: {}", address, err);
+                        Err(Error::TlsError)
+                    }
+                }
+            }
+
+            // Server doesn't support TLS.
+            'N' => {
+                if mode == ServerTlsMode::Prefer {
+                    debug!("Server {:?} doesn't support TLS, using plain text", address);
+                    Ok(ServerStream::Plain(stream))
+                } else {
+                    error!(
+                        "Server {:?} doesn't support TLS, but server_tls_mode is {}",
+                        address, mode
+                    );
+                    Err(Error::TlsError)
+                }
+            }
+
+            _ => Err(Error::ProtocolSyncError(format!(
+                "Unexpected response to SSLRequest: {}",
+                response
+            ))),
+        }
+    }
+
     /// Issue a query cancellation request to the server.
     /// Uses a separate connection that's not part of the connection pool.
     pub async fn cancel(
@@ -361,7 +488,7 @@ impl Server {
         process_id: i32,
         secret_key: i32,
     ) -> Result<(), Error> {
-        let mut stream = match TcpStream::connect(&format!("{}:{}", host, port)).await {
+        let mut stream = match ServerStream::connect(host, port).await {
             Ok(stream) => stream,
             Err(err) => {
                 error!("Could not connect to server: {}", err);
@@ -412,8 +539,14 @@ impl Server {
                 }
             };
 
-            // Buffer the message we'll forward to the client later.
-            self.buffer.put(&message[..]);
+            // Buffer the message we'll forward to the client later,
+            // unless it's the response to a message the client didn't send.
+            if self
+                .prepared_statements
+                .forward_response(message[0] as char, &mut self.buffer)
+            {
+                self.buffer.put(&message[..]);
+            }
 
             let code = message.get_u8() as char;
             let _len = message.get_i32();
@@ -480,6 +613,9 @@ impl Server {
                                     debug!("Server connection marked for clean up");
                                     self.needs_cleanup = true;
                                 }
+                                "DISCARD ALL\0" | "DEALLOCATE ALL\0" => {
+                                    self.prepared_statements.clear();
+                                }
                                 _ => (),
                             }
                         }
@@ -543,6 +679,11 @@ impl Server {
         Ok(bytes)
     }
 
+    /// The statements prepared on this server.
+    pub fn prepared_statements(&mut self) -> &mut ServerStatements {
+        &mut self.prepared_statements
+    }
+
     /// If the server is still inside a transaction.
     /// If the client disconnects while the server is in a transaction, we will clean it up.
     pub fn in_transaction(&self) -> bool {
@@ -606,9 +747,69 @@ impl Server {
         Ok(())
     }
 
+    /// Execute a query with the simple query protocol and return the rows
+    /// it produced, with the values in text format. NULLs are returned as `None`.
+    pub async fn fetch_rows(&mut self, query: &str) -> Result<Vec<Vec<Option<String>>>, Error> {
+        let query = simple_query(query);
+
+        self.send(&query).await?;
+
+        let mut rows = Vec::new();
+        let mut failed = false;
+
+        loop {
+            let mut response = self.recv().await?;
+
+            while response.len() > mem::size_of::<u8>() + mem::size_of::<i32>() {
+                let code = response.get_u8() as char;
+                let len = response.get_i32() as usize;
+
+                if len < mem::size_of::<i32>() || response.len() < len - mem::size_of::<i32>() {
+                    self.bad = true;
+                    return Err(Error::ProtocolSyncError(format!(
+                        "Invalid message length {} for '{}'",
+                        len, code
+                    )));
+                }
+
+                let message = response.split_to(len - mem::size_of::<i32>());
+
+                match code {
+                    // DataRow
+                    'D' => rows.push(parse_data_row(message)?),
+
+                    // ErrorResponse
+                    'E' => {
+                        error!(
+                            "Server {:?} returned an error: {}",
+                            self.address,
+                            String::from_utf8_lossy(&message).replace('\0', " ")
+                        );
+                        failed = true;
+                    }
+
+                    _ => (),
+                }
+            }
+
+            if !self.data_available {
+                break;
+            }
+        }
+
+        if failed {
+            Err(Error::ServerError)
+        } else {
+            Ok(rows)
+        }
+    }
+
     /// Perform any necessary cleanup before putting the server
     /// connection back in the pool
     pub async fn checkin_cleanup(&mut self) -> Result<(), Error> {
+        // The client didn't sync the statements we prepared for it, they were never sent.
+        self.prepared_statements.checkin();
+
         // Client disconnected with an open transaction on the server connection.
         // Pgbouncer behavior is to close the server connection but that can cause
         // server connection thrashing if clients repeatedly do this.
@@ -702,3 +903,76 @@ impl Drop for Server {
         );
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::tls::test::{server_acceptor, server_tls, CA_CERT, SERVER_CERT};
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::{TcpListener, TcpStream};
+
+    /// Connect to a server answering the SSLRequest with `response`, then negotiate TLS.
+    async fn negotiate(host: &str, response: u8, tls: &ServerTls) -> Result<ServerStream, Error> {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut ssl_request = [0u8; 8];
+
+            if stream.read_exact(&mut ssl_request).await.is_err() {
+                return;
+            }
+
+            stream.write_u8(response).await.unwrap();
+
+            if response == b'S' {
+                let _ = server_acceptor().accept(stream).await;
+            }
+        });
+
+        let address = Address {
+            host: host.to_string(),
+            ..Default::default()
+        };
+        let stream = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+
+        Server::negotiate_tls(ServerStream::Plain(stream), &address, tls).await
+    }
+
+    #[tokio::test]
+    async fn test_negotiate_tls() {
+        let is_tls =
+            |stream: Result<ServerStream, Error>| matches!(stream, Ok(ServerStream::Tls(_)));
+        let is_plain =
+            |stream: Result<ServerStream, Error>| matches!(stream, Ok(ServerStream::Plain(_)));
+
+        // Any certificate is accepted by require.
+        let require = server_tls(ServerTlsMode::Require, SERVER_CERT);
+        assert!(is_tls(negotiate("pgcat.test", b'S', &require).await));
+
+        // verify-full checks the CA and the host name, verify-ca only the CA.
+        let verify_full = server_tls(ServerTlsMode::VerifyFull, CA_CERT);
+        assert!(is_tls(negotiate("localhost", b'S', &verify_full).await));
+        assert!(negotiate("pgcat.test", b'S', &verify_full).await.is_err());
+
+        let verify_ca = server_tls(ServerTlsMode::VerifyCa, CA_CERT);
+        assert!(is_tls(negotiate("pgcat.test", b'S', &verify_ca).await));
+
+        // Signed by another CA.
+        let other_ca = server_tls(ServerTlsMode::VerifyCa, SERVER_CERT);
+        assert!(negotiate("localhost", b'S', &other_ca).await.is_err());
+        let other_ca = server_tls(ServerTlsMode::VerifyFull, SERVER_CERT);
+        assert!(negotiate("localhost", b'S', &other_ca).await.is_err());
+
+        // The server doesn't support TLS.
+        let prefer = server_tls(ServerTlsMode::Prefer, CA_CERT);
+        assert!(is_plain(negotiate("localhost", b'N', &prefer).await));
+        assert!(negotiate("localhost", b'N', &require).await.is_err());
+        assert!(negotiate("localhost", b'E', &prefer).await.is_err());
+
+        // No SSLRequest is sent.
+        let disable = server_tls(ServerTlsMode::Disable, CA_CERT);
+        assert!(is_plain(negotiate("localhost", b'S', &disable).await));
+    }
+}
@@ -1,6 +1,8 @@
 use serde_derive::{Deserialize, Serialize};
 /// Implements various sharding functions.
 use sha1::{Digest, Sha1};
+use std::collections::HashMap;
+use std::sync::Arc;
 
 /// See: <https://github.com/postgres/postgres/blob/27b77ecf9f4d5be211900eda54d8155ada50d696/src/include/catalog/partition.h#L20>.
 const PARTITION_HASH_SEED: u64 = 0x7A5B22367996DCFD;
@@ -12,6 +14,15 @@ pub enum ShardingFunction {
     PgBigintHash,
     #[serde(alias = "sha1", alias = "Sha1")]
     Sha1,
+    /// Key ranges of each shard, see `Shard::key_ranges`.
+    #[serde(alias = "range", alias = "Range")]
+    Range,
+    /// Keys of each shard, see `Shard::keys`.
+    #[serde(alias = "list", alias = "List")]
+    List,
+    /// Jump consistent hashing, adding a shard only moves the keys of the new shard.
+    #[serde(alias = "jump_hash", alias = "JumpHash")]
+    JumpHash,
 }
 
 impl ToString for ShardingFunction {
@@ -19,6 +30,59 @@ impl ToString for ShardingFunction {
         match *self {
             ShardingFunction::PgBigintHash => "pg_bigint_hash".to_string(),
             ShardingFunction::Sha1 => "sha1".to_string(),
+            ShardingFunction::Range => "range".to_string(),
+            ShardingFunction::List => "list".to_string(),
+            ShardingFunction::JumpHash => "jump_hash".to_string(),
+        }
+    }
+}
+
+/// The types of sharding keys we support.
+#[derive(Debug, PartialEq, Copy, Clone, Serialize, Deserialize, Hash, std::cmp::Eq)]
+pub enum ShardingKeyType {
+    #[serde(alias = "bigint", alias = "Bigint")]
+    Bigint,
+    #[serde(alias = "text", alias = "Text")]
+    Text,
+    #[serde(alias = "uuid", alias = "Uuid")]
+    Uuid,
+}
+
+impl std::fmt::Display for ShardingKeyType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match *self {
+            ShardingKeyType::Bigint => write!(f, "bigint"),
+            ShardingKeyType::Text => write!(f, "text"),
+            ShardingKeyType::Uuid => write!(f, "uuid"),
+        }
+    }
+}
+
+/// The sharding keys of each shard, for range and list sharding.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ShardKeys {
+    /// Start (inclusive), end (exclusive) and shard of the key ranges, sorted.
+    ranges: Vec<(i64, i64, usize)>,
+
+    /// The shard of each key.
+    list: HashMap<i64, usize>,
+}
+
+impl ShardKeys {
+    pub fn new(mut ranges: Vec<(i64, i64, usize)>, list: HashMap<i64, usize>) -> ShardKeys {
+        ranges.sort_unstable();
+        ShardKeys { ranges, list }
+    }
+
+    /// The shard of the range the key is in.
+    fn range(&self, key: i64) -> Option<usize> {
+        let next = self.ranges.partition_point(|(start, _, _)| *start <= key);
+        let (_, end, shard) = self.ranges.get(next.checked_sub(1)?)?;
+
+        if key < *end {
+            Some(*shard)
+        } else {
+            None
         }
     }
 }
@@ -30,6 +94,9 @@ pub struct Sharder {
 
     /// The sharding function in use.
     sharding_function: ShardingFunction,
+
+    /// The keys of each shard, for range and list sharding.
+    keys: Arc<ShardKeys>,
 }
 
 impl Sharder {
@@ -38,14 +105,57 @@ impl Sharder {
         Sharder {
             shards,
             sharding_function,
+            keys: Arc::default(),
         }
     }
 
+    /// Use the keys of each shard, for range and list sharding.
+    pub fn with_keys(mut self, keys: Arc<ShardKeys>) -> Sharder {
+        self.keys = keys;
+        self
+    }
+
     /// Compute the shard given sharding key.
-    pub fn shard(&self, key: i64) -> usize {
+    /// None if it's not in any shard, with range and list sharding.
+    pub fn shard(&self, key: i64) -> Option<usize> {
         match self.sharding_function {
-            ShardingFunction::PgBigintHash => self.pg_bigint_hash(key),
-            ShardingFunction::Sha1 => self.sha1(key),
+            ShardingFunction::PgBigintHash => Some(self.pg_bigint_hash(key)),
+            ShardingFunction::Sha1 => Some(self.sha1(key.to_string().as_bytes())),
+            ShardingFunction::Range => self.keys.range(key),
+            ShardingFunction::List => self.keys.list.get(&key).copied(),
+            ShardingFunction::JumpHash => Some(self.jump_hash(key as u64)),
+        }
+    }
+
+    /// Compute the shard given a text sharding key, like Postgres' `hashtext`.
+    /// Range and list sharding only support bigint keys.
+    pub fn shard_text(&self, key: &str) -> Option<usize> {
+        self.shard_bytes(key.as_bytes())
+    }
+
+    /// Compute the shard given a UUID sharding key, like Postgres' `uuid_hash`.
+    /// Range and list sharding only support bigint keys.
+    pub fn shard_uuid(&self, key: u128) -> Option<usize> {
+        self.shard_bytes(&key.to_be_bytes())
+    }
+
+    /// Compute the shard given the sharding key as written in a query or
+    /// sent as a text parameter, if it's a valid value of that type.
+    pub fn shard_str(&self, key: &str, key_type: ShardingKeyType) -> Option<usize> {
+        match key_type {
+            ShardingKeyType::Bigint => self.shard(key.trim().parse().ok()?),
+            ShardingKeyType::Text => self.shard_text(key),
+            ShardingKeyType::Uuid => self.shard_uuid(parse_uuid(key)?),
+        }
+    }
+
+    /// Compute the shard of a key hashed as bytes.
+    fn shard_bytes(&self, key: &[u8]) -> Option<usize> {
+        match self.sharding_function {
+            ShardingFunction::PgBigintHash => Some(self.pg_bytes_hash(key)),
+            ShardingFunction::Sha1 => Some(self.sha1(key)),
+            ShardingFunction::JumpHash => Some(self.jump_hash(Self::pg_hash_bytes(key))),
+            ShardingFunction::Range | ShardingFunction::List => None,
         }
     }
 
@@ -60,11 +170,17 @@ impl Sharder {
         Self::combine(0, Self::pg_u32_hash(lohalf)) as usize % self.shards
     }
 
+    /// Hash function used by Postgres for HASH(column) partitioning
+    /// of types hashed as bytes, e.g. text (with a deterministic collation) and uuid.
+    fn pg_bytes_hash(&self, key: &[u8]) -> usize {
+        Self::combine(0, Self::pg_hash_bytes(key)) as usize % self.shards
+    }
+
     /// Example of a hashing function based on SHA1.
-    fn sha1(&self, key: i64) -> usize {
+    fn sha1(&self, key: &[u8]) -> usize {
         let mut hasher = Sha1::new();
 
-        hasher.update(&key.to_string().as_bytes());
+        hasher.update(key);
 
         let result = hasher.finalize();
 
@@ -77,6 +193,21 @@ impl Sharder {
         key % self.shards
     }
 
+    /// Jump consistent hash, see <https://arxiv.org/abs/1406.2294>.
+    /// Going from n to n + 1 shards only moves 1 / (n + 1) of the keys, all to the new shard.
+    fn jump_hash(&self, mut key: u64) -> usize {
+        let mut b: i64 = -1;
+        let mut j: i64 = 0;
+
+        while j < self.shards as i64 {
+            b = j;
+            key = key.wrapping_mul(2862933555777941757).wrapping_add(1);
+            j = ((b + 1) as f64 * ((1_i64 << 31) as f64 / ((key >> 33) + 1) as f64)) as i64;
+        }
+
+        b as usize
+    }
+
     #[inline]
     fn rot(x: u32, k: u32) -> u32 {
         (x << k) | (x >> (32 - k))
@@ -155,6 +286,61 @@ impl Sharder {
 
         ((b as u64) << 32) | (c as u64)
     }
+
+    /// Source: <https://github.com/postgres/postgres/blob/27b77ecf9f4d5be211900eda54d8155ada50d696/src/common/hashfn.c#L373>.
+    /// Reads the key as little-endian words, like Postgres does on x86 and ARM.
+    fn pg_hash_bytes(key: &[u8]) -> u64 {
+        let mut a: u32 = 0x9e3779b9_u32
+            .wrapping_add(key.len() as u32)
+            .wrapping_add(3923095_u32);
+        let mut b = a;
+        let mut c = a;
+
+        a = a.wrapping_add((PARTITION_HASH_SEED >> 32) as u32);
+        b = b.wrapping_add(PARTITION_HASH_SEED as u32);
+        (a, b, c) = Self::mix(a, b, c);
+
+        let mut chunks = key.chunks_exact(12);
+
+        for chunk in &mut chunks {
+            a = a.wrapping_add(u32::from_le_bytes(chunk[0..4].try_into().unwrap()));
+            b = b.wrapping_add(u32::from_le_bytes(chunk[4..8].try_into().unwrap()));
+            c = c.wrapping_add(u32::from_le_bytes(chunk[8..12].try_into().unwrap()));
+            (a, b, c) = Self::mix(a, b, c);
+        }
+
+        // The last 11 bytes, the lowest byte of c is reserved for the length.
+        for (i, byte) in chunks.remainder().iter().enumerate() {
+            let byte = *byte as u32;
+
+            match i {
+                0..=3 => a = a.wrapping_add(byte << (8 * i)),
+                4..=7 => b = b.wrapping_add(byte << (8 * (i - 4))),
+                _ => c = c.wrapping_add(byte << (8 * (i - 7))),
+            };
+        }
+
+        let (_a, b, c) = Self::_final(a, b, c);
+
+        ((b as u64) << 32) | (c as u64)
+    }
+}
+
+/// Parse a UUID like `a0eebc99-9c0b-4ef8-bb6d-6bb9bd380a11`, with or
+/// without hyphens and braces, like Postgres accepts them.
+pub fn parse_uuid(value: &str) -> Option<u128> {
+    let value = value.trim();
+    let value = value
+        .strip_prefix('{')
+        .and_then(|value| value.strip_suffix('}'))
+        .unwrap_or(value);
+    let hex: String = value.chars().filter(|c| *c != '-').collect();
+
+    if hex.len() != 32 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+
+    u128::from_str_radix(&hex, 16).ok()
 }
 
 #[cfg(test)]
@@ -171,31 +357,31 @@ mod test {
         let shard_0 = vec![1, 4, 5, 14, 19, 39, 40, 46, 47, 53];
 
         for v in shard_0 {
-            assert_eq!(sharder.shard(v), 0);
+            assert_eq!(sharder.shard(v), Some(0));
         }
 
         let shard_1 = vec![2, 3, 11, 17, 21, 23, 30, 49, 51, 54];
 
         for v in shard_1 {
-            assert_eq!(sharder.shard(v), 1);
+            assert_eq!(sharder.shard(v), Some(1));
         }
 
         let shard_2 = vec![6, 7, 15, 16, 18, 20, 25, 28, 34, 35];
 
         for v in shard_2 {
-            assert_eq!(sharder.shard(v), 2);
+            assert_eq!(sharder.shard(v), Some(2));
         }
 
         let shard_3 = vec![8, 12, 13, 22, 29, 31, 33, 36, 41, 43];
 
         for v in shard_3 {
-            assert_eq!(sharder.shard(v), 3);
+            assert_eq!(sharder.shard(v), Some(3));
         }
 
         let shard_4 = vec![9, 10, 24, 26, 27, 32, 37, 38, 42, 45];
 
         for v in shard_4 {
-            assert_eq!(sharder.shard(v), 4);
+            assert_eq!(sharder.shard(v), Some(4));
         }
     }
 
@@ -210,7 +396,110 @@ mod test {
         ];
 
         for (i, id) in ids.iter().enumerate() {
-            assert_eq!(sharder.shard(*id), shards[i]);
+            assert_eq!(sharder.shard(*id), Some(shards[i]));
         }
     }
+
+    // CREATE TABLE t (k TEXT) PARTITION BY HASH (k) with 3 partitions,
+    // and the same with k UUID.
+    #[test]
+    fn test_pg_text_and_uuid_hash() {
+        let sharder = Sharder::new(3, ShardingFunction::PgBigintHash);
+
+        let text = vec![
+            ("", 2),
+            ("a", 2),
+            ("b", 0),
+            ("c", 2),
+            ("hello", 1),
+            ("pgcat", 0),
+            ("a much longer key than twelve", 0),
+        ];
+
+        for (key, shard) in text {
+            assert_eq!(sharder.shard_text(key), Some(shard));
+        }
+
+        let uuids = vec![
+            ("00000000-0000-0000-0000-000000000000", 0),
+            ("123e4567-e89b-12d3-a456-426614174000", 1),
+            ("550e8400-e29b-41d4-a716-446655440000", 2),
+            ("a0eebc99-9c0b-4ef8-bb6d-6bb9bd380a11", 1),
+            ("f47ac10b-58cc-4372-a567-0e02b2c3d479", 0),
+        ];
+
+        for (key, shard) in uuids {
+            assert_eq!(sharder.shard_str(key, ShardingKeyType::Uuid), Some(shard));
+        }
+
+        assert_eq!(
+            parse_uuid("{A0EEBC999C0B4EF8BB6D6BB9BD380A11}"),
+            parse_uuid("a0eebc99-9c0b-4ef8-bb6d-6bb9bd380a11")
+        );
+        assert_eq!(sharder.shard_str("not a uuid", ShardingKeyType::Uuid), None);
+        assert_eq!(
+            sharder.shard_str("5", ShardingKeyType::Bigint),
+            sharder.shard(5)
+        );
+    }
+
+    #[test]
+    fn test_range_and_list() {
+        let keys = Arc::new(ShardKeys::new(
+            vec![(100, 200, 1), (0, 100, 0), (500, 1000, 0)],
+            HashMap::from([(7, 1), (42, 0)]),
+        ));
+
+        let sharder = Sharder::new(2, ShardingFunction::Range).with_keys(keys.clone());
+
+        assert_eq!(sharder.shard(0), Some(0));
+        assert_eq!(sharder.shard(99), Some(0));
+        assert_eq!(sharder.shard(100), Some(1));
+        assert_eq!(sharder.shard(199), Some(1));
+        assert_eq!(sharder.shard(200), None);
+        assert_eq!(sharder.shard(750), Some(0));
+        assert_eq!(sharder.shard(-1), None);
+        assert_eq!(sharder.shard(1000), None);
+        assert_eq!(sharder.shard_text("a"), None);
+
+        let sharder = Sharder::new(2, ShardingFunction::List).with_keys(keys);
+
+        assert_eq!(sharder.shard(7), Some(1));
+        assert_eq!(sharder.shard(42), Some(0));
+        assert_eq!(sharder.shard(8), None);
+    }
+
+    #[test]
+    fn test_jump_hash() {
+        // Same as the reference implementation.
+        for (key, shards, shard) in [
+            (1, 1, 0),
+            (42, 57, 43),
+            (0xDEAD10CC, 1, 0),
+            (0xDEAD10CC, 666, 361),
+            (256, 1024, 520),
+        ] {
+            assert_eq!(
+                Sharder::new(shards, ShardingFunction::JumpHash).jump_hash(key),
+                shard
+            );
+        }
+
+        // Adding a shard only moves keys to the new shard.
+        let before = Sharder::new(5, ShardingFunction::JumpHash);
+        let after = Sharder::new(6, ShardingFunction::JumpHash);
+        let mut moved = 0;
+
+        for key in 0..10000 {
+            let (from, to) = (before.shard(key).unwrap(), after.shard(key).unwrap());
+
+            if from != to {
+                assert_eq!(to, 5);
+                moved += 1;
+            }
+        }
+
+        assert!(moved > 1000 && moved < 2400, "{}", moved);
+        assert!(after.shard_text("pgcat").unwrap() < 6);
+    }
 }
@@ -8,7 +8,7 @@ use tokio::sync::mpsc::error::TrySendError;
 use tokio::sync::mpsc::{channel, Receiver, Sender};
 use tokio::time::Instant;
 
-use crate::pool::{get_all_pools, get_number_of_addresses};
+use crate::pool::{get_address_ids, get_all_pools};
 
 /// Convenience types for various stats
 type ClientStatesLookup = HashMap<i32, ClientInformation>;
@@ -94,6 +94,9 @@ pub struct ClientInformation {
     pub username: String,
     pub pool_name: String,
 
+    /// Where the client connected from: host:port, or unix for the Unix socket.
+    pub client_addr: String,
+
     /// Total time spent waiting for a connection from pool, measures in microseconds
     pub total_wait_time: u64,
 
@@ -156,6 +159,7 @@ enum EventName {
         pool_name: String,
         username: String,
         application_name: String,
+        client_addr: String,
     },
     ClientIdle {
         client_id: i32,
@@ -206,6 +210,10 @@ enum EventName {
     ServerDisconnecting {
         server_id: i32,
     },
+    AddressRoleChanged {
+        address_id: usize,
+        address_name: String,
+    },
 
     UpdateStats {
         pool_name: String,
@@ -334,6 +342,7 @@ impl Reporter {
         pool_name: String,
         username: String,
         app_name: String,
+        client_addr: String,
     ) {
         let event = Event {
             name: EventName::ClientRegistered {
@@ -341,6 +350,7 @@ impl Reporter {
                 pool_name,
                 username,
                 application_name: app_name,
+                client_addr,
             },
             value: 1,
         };
@@ -483,6 +493,18 @@ impl Reporter {
         };
         self.send(event)
     }
+
+    /// Reports the role check found a new primary, which renamed the address.
+    pub fn address_role_changed(&self, address_id: usize, address_name: String) {
+        let event = Event {
+            name: EventName::AddressRoleChanged {
+                address_id,
+                address_name,
+            },
+            value: 1,
+        };
+        self.send(event)
+    }
 }
 
 /// The statistics collector which is receiving statistics
@@ -504,7 +526,7 @@ impl Collector {
     }
 
     /// The statistics collection handler. It will collect statistics
-    /// for `address_id`s starting at 0 up to `addresses`.
+    /// for the `address_id`s of all the pools.
     pub async fn collect(&mut self) {
         info!("Events reporter started");
 
@@ -539,7 +561,7 @@ impl Collector {
                 tokio::time::interval(tokio::time::Duration::from_millis(STAT_PERIOD));
             loop {
                 interval.tick().await;
-                for address_id in 0..get_number_of_addresses() {
+                for address_id in get_address_ids() {
                     let _ = tx.try_send(Event {
                         name: EventName::UpdateAverages { address_id },
                         value: 0,
@@ -712,6 +734,7 @@ impl Collector {
                     pool_name,
                     username,
                     application_name,
+                    client_addr,
                 } => {
                     match client_states.get_mut(&client_id) {
                         Some(_) => warn!("Client {:?} was double registered!", client_id),
@@ -725,6 +748,7 @@ impl Collector {
                                     pool_name: pool_name.clone(),
                                     username: username.clone(),
                                     application_name: application_name.clone(),
+                                    client_addr: client_addr.clone(),
                                     total_wait_time: 0,
                                     transaction_count: 0,
                                     query_count: 0,
@@ -885,6 +909,17 @@ impl Collector {
                     server_states.remove(&server_id);
                 }
 
+                EventName::AddressRoleChanged {
+                    address_id,
+                    address_name,
+                } => {
+                    for server_state in server_states.values_mut() {
+                        if server_state.address_id == address_id {
+                            server_state.address_name = address_name.clone();
+                        }
+                    }
+                }
+
                 EventName::UpdateStats {
                     pool_name,
                     username,
@@ -0,0 +1,240 @@
+/// Sockets to the servers, over TCP (in plain text or TLS) or a Unix socket.
+use std::io;
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll, Wake, Waker};
+use tokio::io::{split, AsyncRead, AsyncWrite, ReadBuf, ReadHalf, WriteHalf};
+use tokio::net::{tcp, unix, TcpStream, UnixStream};
+use tokio_rustls::client::TlsStream;
+
+/// Connection to a server.
+pub enum ServerStream {
+    Plain(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>),
+    Unix(UnixStream),
+}
+
+/// Read half of a server connection.
+pub enum ServerReadHalf {
+    Plain(tcp::OwnedReadHalf),
+    Tls(ReadHalf<Box<TlsStream<TcpStream>>>),
+    Unix(unix::OwnedReadHalf),
+}
+
+/// Write half of a server connection.
+pub enum ServerWriteHalf {
+    Plain(tcp::OwnedWriteHalf),
+    Tls(WriteHalf<Box<TlsStream<TcpStream>>>),
+    Unix(unix::OwnedWriteHalf),
+}
+
+impl ServerStream {
+    /// Connect to the server over TCP or, if the host is a directory like
+    /// `/var/run/postgresql`, over the Unix socket `<host>/.s.PGSQL.<port>` in it.
+    pub async fn connect(host: &str, port: u16) -> io::Result<ServerStream> {
+        if host.starts_with('/') {
+            let path = Path::new(host).join(format!(".s.PGSQL.{}", port));
+            Ok(ServerStream::Unix(UnixStream::connect(path).await?))
+        } else {
+            Ok(ServerStream::Plain(
+                TcpStream::connect(&format!("{}:{}", host, port)).await?,
+            ))
+        }
+    }
+
+    /// Split the stream so we can read and write to it independently.
+    pub fn into_split(self) -> (ServerReadHalf, ServerWriteHalf) {
+        match self {
+            ServerStream::Plain(stream) => {
+                let (read, write) = stream.into_split();
+                (ServerReadHalf::Plain(read), ServerWriteHalf::Plain(write))
+            }
+            ServerStream::Tls(stream) => {
+                let (read, write) = split(stream);
+                (ServerReadHalf::Tls(read), ServerWriteHalf::Tls(write))
+            }
+            ServerStream::Unix(stream) => {
+                let (read, write) = stream.into_split();
+                (ServerReadHalf::Unix(read), ServerWriteHalf::Unix(write))
+            }
+        }
+    }
+}
+
+impl ServerWriteHalf {
+    /// Write without waiting for the socket to be ready, used when we can't await.
+    pub fn try_write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            ServerWriteHalf::Plain(write) => write.try_write(buf),
+            ServerWriteHalf::Unix(write) => write.try_write(buf),
+
+            // Encrypt the record and send it, if the socket is ready.
+            ServerWriteHalf::Tls(write) => {
+                let waker = Waker::from(Arc::new(NoopWaker));
+                let mut cx = Context::from_waker(&waker);
+
+                let written = match Pin::new(&mut *write).poll_write(&mut cx, buf) {
+                    Poll::Ready(result) => result?,
+                    Poll::Pending => return Err(io::ErrorKind::WouldBlock.into()),
+                };
+
+                match Pin::new(write).poll_flush(&mut cx) {
+                    Poll::Ready(result) => result.map(|_| written),
+                    Poll::Pending => Err(io::ErrorKind::WouldBlock.into()),
+                }
+            }
+        }
+    }
+}
+
+/// We poll once and don't wait to be woken up.
+struct NoopWaker;
+
+impl Wake for NoopWaker {
+    fn wake(self: Arc<Self>) {}
+}
+
+impl AsyncRead for ServerStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            ServerStream::Plain(stream) => Pin::new(stream).poll_read(cx, buf),
+            ServerStream::Tls(stream) => Pin::new(stream).poll_read(cx, buf),
+            ServerStream::Unix(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for ServerStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            ServerStream::Plain(stream) => Pin::new(stream).poll_write(cx, buf),
+            ServerStream::Tls(stream) => Pin::new(stream).poll_write(cx, buf),
+            ServerStream::Unix(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            ServerStream::Plain(stream) => Pin::new(stream).poll_flush(cx),
+            ServerStream::Tls(stream) => Pin::new(stream).poll_flush(cx),
+            ServerStream::Unix(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            ServerStream::Plain(stream) => Pin::new(stream).poll_shutdown(cx),
+            ServerStream::Tls(stream) => Pin::new(stream).poll_shutdown(cx),
+            ServerStream::Unix(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+impl AsyncRead for ServerReadHalf {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            ServerReadHalf::Plain(read) => Pin::new(read).poll_read(cx, buf),
+            ServerReadHalf::Tls(read) => Pin::new(read).poll_read(cx, buf),
+            ServerReadHalf::Unix(read) => Pin::new(read).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for ServerWriteHalf {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            ServerWriteHalf::Plain(write) => Pin::new(write).poll_write(cx, buf),
+            ServerWriteHalf::Tls(write) => Pin::new(write).poll_write(cx, buf),
+            ServerWriteHalf::Unix(write) => Pin::new(write).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            ServerWriteHalf::Plain(write) => Pin::new(write).poll_flush(cx),
+            ServerWriteHalf::Tls(write) => Pin::new(write).poll_flush(cx),
+            ServerWriteHalf::Unix(write) => Pin::new(write).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            ServerWriteHalf::Plain(write) => Pin::new(write).poll_shutdown(cx),
+            ServerWriteHalf::Tls(write) => Pin::new(write).poll_shutdown(cx),
+            ServerWriteHalf::Unix(write) => Pin::new(write).poll_shutdown(cx),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::ServerTlsMode;
+    use crate::tls::test::{server_acceptor, server_tls, CA_CERT};
+    use tokio::io::AsyncReadExt;
+    use tokio::net::{TcpListener, UnixListener};
+    use tokio_rustls::rustls::ServerName;
+    use tokio_rustls::TlsConnector;
+
+    #[tokio::test]
+    async fn test_connect_unix_socket() {
+        let dir = std::env::temp_dir().join(format!("pgcat-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let listener = UnixListener::bind(dir.join(".s.PGSQL.5432")).unwrap();
+        let stream = ServerStream::connect(dir.to_str().unwrap(), 5432)
+            .await
+            .unwrap();
+
+        assert!(matches!(stream, ServerStream::Unix(_)));
+        assert!(listener.accept().await.is_ok());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_try_write_tls() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut stream = server_acceptor().accept(stream).await.unwrap();
+            let mut terminate = [0u8; 5];
+            stream.read_exact(&mut terminate).await.unwrap();
+            terminate
+        });
+
+        let config = server_tls(ServerTlsMode::VerifyFull, CA_CERT)
+            .config
+            .unwrap();
+        let stream = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        let stream = TlsConnector::from(config)
+            .connect(ServerName::try_from("localhost").unwrap(), stream)
+            .await
+            .unwrap();
+
+        // Like the Terminate we send when a server connection is dropped.
+        let (_read, mut write) = ServerStream::Tls(Box::new(stream)).into_split();
+        assert_eq!(write.try_write(b"X\0\0\0\x04").unwrap(), 5);
+
+        assert_eq!(&server.await.unwrap(), b"X\0\0\0\x04");
+    }
+}
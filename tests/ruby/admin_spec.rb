@@ -286,4 +286,43 @@ describe "Admin" do
       connections.map(&:close)
     end
   end
+
+  describe "KILL" do
+    it "disconnects the clients of the database" do
+      connection = PG::connect(pgcat_conn_str)
+      connection.async_exec("SELECT 1")
+
+      admin_conn = PG::connect(processes.pgcat.admin_connection_string)
+      admin_conn.async_exec("KILL sharded_db")
+      admin_conn.close
+
+      expect { connection.async_exec("SELECT 1") }.to raise_error(PG::Error)
+      connection.close
+    end
+  end
+
+  describe "RECONNECT" do
+    it "closes the servers when their clients release them" do
+      connection = PG::connect(pgcat_conn_str)
+      connection.async_exec("BEGIN")
+      old_pid = connection.async_exec("SELECT pg_backend_pid()")[0]["pg_backend_pid"]
+
+      admin_conn = PG::connect(processes.pgcat.admin_connection_string)
+      admin_conn.async_exec("RECONNECT sharded_db")
+      admin_conn.close
+
+      # The transaction keeps its server.
+      expect(connection.async_exec("SELECT pg_backend_pid()")[0]["pg_backend_pid"]).to eq(old_pid)
+      connection.async_exec("COMMIT")
+
+      expect(connection.async_exec("SELECT pg_backend_pid()")[0]["pg_backend_pid"]).to_not eq(old_pid)
+      connection.close
+
+      sleep(0.5) # Wait for the server to be closed
+      processes.primary.with_connection do |conn|
+        count = conn.async_exec("SELECT count(*) FROM pg_stat_activity WHERE pid = #{old_pid}")[0]["count"]
+        expect(count).to eq("0")
+      end
+    end
+  end
 end
@@ -215,4 +215,39 @@ describe "Miscellaneous" do
       end
     end
   end
+
+  describe "Statement mode" do
+    let(:processes) { Helpers::Pgcat.single_instance_setup("sharded_db", 1, "statement") }
+
+    it "releases the server after each statement" do
+      conn1 = PG::connect(processes.pgcat.connection_string("sharded_db", "sharding_user"))
+      conn2 = PG::connect(processes.pgcat.connection_string("sharded_db", "sharding_user"))
+
+      # Both clients share the only server, in turns.
+      pid = conn1.async_exec("SELECT pg_backend_pid()")[0]["pg_backend_pid"]
+      expect(conn2.async_exec("SELECT pg_backend_pid()")[0]["pg_backend_pid"]).to eq(pid)
+      expect(conn1.exec_params("SELECT pg_backend_pid() + $1", [0])[0]["?column?"]).to eq(pid)
+      expect(conn2.exec_params("SELECT pg_backend_pid() + $1", [0])[0]["?column?"]).to eq(pid)
+
+      conn1.close
+      conn2.close
+    end
+
+    it "rejects transactions and rolls them back" do
+      conn = PG::connect(processes.pgcat.connection_string("sharded_db", "sharding_user"))
+
+      # The client gets the server's answer to BEGIN, then the error and the disconnect.
+      expect do
+        conn.async_exec("BEGIN")
+        conn.async_exec("SELECT 1")
+      end.to raise_error(PG::Error, /transaction blocks not allowed in statement pooling mode/)
+      expect(processes.primary.count_query("ROLLBACK")).to eq(1)
+      conn.close
+
+      # The server is back in the pool, out of the transaction.
+      conn = PG::connect(processes.pgcat.connection_string("sharded_db", "sharding_user"))
+      expect(conn.async_exec("SELECT now() = statement_timestamp() AS outside")[0]["outside"]).to eq("t")
+      conn.close
+    end
+  end
 end
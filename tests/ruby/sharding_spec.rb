@@ -0,0 +1,111 @@
+# frozen_string_literal: true
+require_relative 'spec_helper'
+
+describe "Sharding" do
+  let(:processes) { Helpers::Pgcat.three_shard_setup("sharded_db", 5) }
+
+  before do
+    new_configs = processes.pgcat.current_config
+    new_configs["pools"]["sharded_db"]["query_parser_enabled"] = true
+    new_configs["pools"]["sharded_db"]["automatic_sharding_key"] = "id"
+    processes.pgcat.update_config(new_configs)
+    processes.pgcat.reload_config
+
+    # The same table in every shard database, with different OIDs.
+    processes.shards.each_with_index do |shard, index|
+      shard.with_connection do |conn|
+        conn.async_exec("DROP TABLE IF EXISTS fan_out")
+        conn.async_exec("CREATE TABLE fan_out (id BIGINT, value VARCHAR)")
+        conn.async_exec("INSERT INTO fan_out VALUES (#{index}, 'shard#{index}'), (#{index + 3}, 'shard#{index}')")
+      end
+    end
+  end
+
+  after do
+    processes.shards.each do |shard|
+      shard.with_connection { |conn| conn.async_exec("DROP TABLE IF EXISTS fan_out") }
+    end
+    processes.all_databases.map(&:reset)
+    processes.pgcat.shutdown
+  end
+
+  describe "SET SHARD TO 'ALL'" do
+    it "reads the table columns of all the shards" do
+      conn = PG::connect(processes.pgcat.connection_string("sharded_db", "sharding_user"))
+      conn.async_exec("SET SHARD TO 'ALL'")
+
+      rows = conn.async_exec("SELECT id, value FROM fan_out ORDER BY id").values
+      expect(rows).to eq((0..5).map { |id| [id.to_s, "shard#{id % 3}"] })
+
+      expect(conn.async_exec("SELECT count(*) FROM fan_out")[0]["count"]).to eq("6")
+      expect(conn.async_exec("SELECT max(id) FROM fan_out")[0]["max"]).to eq("5")
+
+      conn.close
+    end
+
+    it "rejects shards that return different columns" do
+      processes.shards.last.with_connection do |conn|
+        conn.async_exec("ALTER TABLE fan_out ALTER COLUMN id TYPE INTEGER")
+      end
+
+      conn = PG::connect(processes.pgcat.connection_string("sharded_db", "sharding_user"))
+      conn.async_exec("SET SHARD TO 'ALL'")
+
+      expect { conn.async_exec("SELECT id FROM fan_out") }.to raise_error(PG::Error, /shards returned different columns/)
+      expect(conn.async_exec("SELECT value FROM fan_out").ntuples).to eq(6)
+
+      conn.close
+    end
+
+    it "rejects the reads it can't send to all the shards" do
+      conn = PG::connect(processes.pgcat.connection_string("sharded_db", "sharding_user"))
+      conn.async_exec("SET SHARD TO 'ALL'")
+
+      error = /reads from more than one shard must be one simple query/
+      expect { conn.exec_params("SELECT * FROM fan_out WHERE value = $1", ["shard0"]) }.to raise_error(PG::Error, error)
+      expect { conn.async_exec("SELECT * FROM fan_out; SELECT 1") }.to raise_error(PG::Error, error)
+
+      conn.async_exec("SET SHARD TO '0'")
+      expect { conn.exec_params("SELECT * FROM fan_out WHERE id IN (1, 2)", []) }.to raise_error(PG::Error, error)
+      expect(conn.exec_params("SELECT * FROM fan_out WHERE value = $1", ["shard0"]).ntuples).to eq(2)
+
+      conn.close
+    end
+
+    it "gives back the servers it holds when the database is paused" do
+      new_configs = processes.pgcat.current_config
+      new_configs["pools"]["sharded_db"]["users"]["0"]["pool_size"] = 1
+      processes.pgcat.update_config(new_configs)
+      processes.pgcat.reload_config
+
+      holder = PG::connect(processes.pgcat.connection_string("sharded_db", "sharding_user"))
+      holder.async_exec("SET SHARD TO '1'")
+      holder.async_exec("BEGIN")
+      holder.async_exec("SELECT 1")
+
+      # Takes the server of shard 0, then waits for the one of shard 1.
+      reader = Thread.new do
+        conn = PG::connect(processes.pgcat.connection_string("sharded_db", "sharding_user"))
+        conn.async_exec("SET SHARD TO 'ALL'")
+        conn.async_exec("SELECT count(*) FROM fan_out")[0]["count"]
+      ensure
+        conn&.close
+      end
+      sleep(0.5)
+
+      admin = PG::connect(processes.pgcat.admin_connection_string)
+      pause = Thread.new { admin.async_exec("PAUSE") }
+      sleep(0.5)
+      holder.async_exec("COMMIT")
+
+      expect(pause.join(5)).not_to be_nil
+      expect(reader).to be_alive
+
+      admin.async_exec("RESUME")
+      expect(reader.value).to eq("6")
+
+      holder.close
+      admin.close
+    end
+  end
+end